@@ -3,10 +3,20 @@
 
 use clap::{ArgAction::SetTrue, Args as _, FromArgMatches as _, Parser};
 
-use crate::{config::Manager, util::AddressFamily};
+use crate::{
+    config::{Manager, ShowFormat},
+    util::AddressFamily,
+};
 
 /// Options that switch us into another mode i.e. which don't require source/destination arguments
-pub(crate) const MODE_OPTIONS: &[&str] = &["server", "help_buffers", "config_files", "show_config"];
+pub(crate) const MODE_OPTIONS: &[&str] = &[
+    "server",
+    "help_buffers",
+    "config_files",
+    "show_config",
+    "check_config",
+    "generate_man",
+];
 
 /// CLI argument definition
 #[derive(Debug, Parser, Clone)]
@@ -20,6 +30,12 @@ pub(crate) const MODE_OPTIONS: &[&str] = &["server", "help_buffers", "config_fil
 
   qcp will read your ssh config file to resolve any host name aliases you may have defined. The idea is, if you can ssh directly to a given host, you should be able to qcp to it by the same name. However, some particularly complicated ssh config files may be too much for qcp to understand. (In particular, Match directives are not currently supported.) In that case, you can use --ssh-config to provide an alternative configuration (or set it in your qcp configuration file).
     ",
+    after_long_help = r"Exit status:
+  0  Success
+  1  A transfer failed, or some other error occurred
+  2  Invalid arguments or configuration
+  3  Could not connect to the remote host, or the connection timed out
+  4  ssh could not authenticate: the login was refused, or host key verification failed",
     infer_long_args(true)
 )]
 #[command(help_template(
@@ -42,7 +58,7 @@ pub(crate) struct CliArgs {
     #[arg(
         long, help_heading("Modes"), hide = true,
         conflicts_with_all([
-            "help_buffers", "show_config", "config_files",
+            "help_buffers", "show_config", "config_files", "check_config", "generate_man",
             "quiet", "statistics", "remote_debug", "profile",
             "ssh", "ssh_options", "remote_port",
             "source", "destination",
@@ -50,6 +66,12 @@ pub(crate) struct CliArgs {
     )]
     pub server: bool,
 
+    /// Operate as a background process which holds a connection open for later runs (see `daemon`).
+    ///
+    /// The client starts this itself; it is not intended for interactive use.
+    #[arg(long, hide = true, value_name("[USER@]HOST"), conflicts_with("server"))]
+    pub daemon_serve: Option<String>,
+
     // CONFIGURABLE OPTIONS ================================================================
     #[command(flatten)]
     /// The set of options which may be set in a config file or via command-line.
@@ -67,14 +89,36 @@ pub(crate) struct CliArgs {
     ///
     #[arg(long, help_heading("Configuration"), display_order(0))]
     pub show_config: bool,
+    /// The output format for `--show-config` [default: table]
+    #[arg(
+        long,
+        value_name("FORMAT"),
+        requires("show_config"),
+        help_heading("Configuration"),
+        display_order(0)
+    )]
+    pub format: Option<ShowFormat>,
     /// Outputs the paths to configuration file(s), then exits
     #[arg(long, help_heading("Configuration"), display_order(0))]
     pub config_files: bool,
+    /// Checks the configuration file(s) for problems, then exits.
+    ///
+    /// Reports parse errors, invalid values and unknown keywords, checking the settings
+    /// for all hosts and for each `Host` pattern in the files.
+    /// The exit status is 2 if any problems were found.
+    #[arg(long, help_heading("Configuration"), display_order(0))]
+    pub check_config: bool,
 
     /// Outputs additional information about kernel UDP buffer sizes and platform-specific tips
     #[arg(long, action, help_heading("Network tuning"), display_order(100))]
     pub help_buffers: bool,
 
+    /// Outputs a manual page for qcp, in roff format, then exits.
+    ///
+    /// This is for packagers: `qcp --generate-man > qcp.1`
+    #[arg(long, hide = true)]
+    pub generate_man: bool,
+
     // CLIENT-SIDE NON-CONFIGURABLE OPTIONS ================================================
     // (including positional arguments!)
     #[command(flatten)]
@@ -108,10 +152,15 @@ pub(crate) struct CliArgs {
 }
 
 impl CliArgs {
+    /// Constructs the clap command which defines our arguments
+    pub(crate) fn cli_command() -> clap::Command {
+        let cli = clap::Command::new(clap::crate_name!());
+        CliArgs::augment_args(cli).version(crate::version::short())
+    }
+
     /// Sets up and executes our parser
     pub(crate) fn custom_parse() -> Self {
-        let cli = clap::Command::new(clap::crate_name!());
-        let cli = CliArgs::augment_args(cli).version(crate::version::short());
+        let cli = Self::cli_command();
         let mut args =
             CliArgs::from_arg_matches(&cli.get_matches_from(std::env::args_os())).unwrap();
         // Custom logic: '-4' and '-6' convenience aliases
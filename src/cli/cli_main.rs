@@ -9,7 +9,7 @@ use crate::{
     config::{Configuration, Manager},
     os,
     server::server_main,
-    util::setup_tracing,
+    util::{failure::FailureKind, setup_tracing, ColorMode, SyslogOptions},
 };
 
 use anstream::{eprintln, println};
@@ -32,6 +32,7 @@ fn trace_level(args: &ClientParameters) -> &str {
 /// Call this from `main`. It reads argv.
 /// # Exit status
 /// 0 indicates success; non-zero indicates failure.
+/// The status distinguishes the category of failure; see [`FailureKind`](crate::util::failure::FailureKind).
 #[tokio::main(flavor = "current_thread")]
 #[allow(clippy::missing_panics_doc)]
 pub async fn cli() -> anyhow::Result<ExitCode> {
@@ -44,6 +45,11 @@ pub async fn cli() -> anyhow::Result<ExitCode> {
         return Ok(ExitCode::SUCCESS);
     }
 
+    if args.generate_man {
+        super::manpage::write_man_page(&mut std::io::stdout())?;
+        return Ok(ExitCode::SUCCESS);
+    }
+
     let progress = (!args.server).then(|| {
         MultiProgress::with_draw_target(ProgressDrawTarget::stderr_with_hz(MAX_UPDATE_FPS))
     });
@@ -54,12 +60,25 @@ pub async fn cli() -> anyhow::Result<ExitCode> {
         return Ok(ExitCode::SUCCESS);
     }
 
+    if args.check_config {
+        let problems = Manager::check_files();
+        for p in &problems {
+            println!("{p}");
+        }
+        if problems.is_empty() {
+            println!("No problems found in {:?}", Manager::config_files());
+            return Ok(ExitCode::SUCCESS);
+        }
+        println!("{} problem(s) found", problems.len());
+        return Ok(FailureKind::Config.exit_code().into());
+    }
+
     // Now fold the arguments in with the CLI config (which may fail)
     let config_manager = match Manager::try_from(&args) {
         Ok(m) => m,
         Err(err) => {
             eprintln!("ERROR: {err}");
-            return Ok(ExitCode::FAILURE);
+            return Ok(FailureKind::Config.exit_code().into());
         }
     };
 
@@ -68,20 +87,39 @@ pub async fn cli() -> anyhow::Result<ExitCode> {
         Err(err) => {
             eprintln!("ERROR: Failed to parse configuration");
             err.into_iter().for_each(|e| eprintln!("{e}"));
-            return Ok(ExitCode::FAILURE);
+            return Ok(FailureKind::Config.exit_code().into());
         }
     };
 
+    if args.server && config.color == ColorMode::Auto {
+        // Our stderr is relayed to the client's console, so the client decides.
+        // (If the client doesn't want colour, it removes it.)
+        ColorMode::Always.apply();
+    } else {
+        config.color.apply();
+    }
+
     setup_tracing(
         trace_level(&args.client_params),
         progress.as_ref(),
         &args.client_params.log_file,
         config.time_format,
+        config.log_syslog.then_some(SyslogOptions {
+            facility: &config.syslog_facility,
+            ident: &config.syslog_ident,
+        }),
     )
     .inspect_err(|e| eprintln!("{e:?}"))?;
+    for w in config_manager.warnings() {
+        tracing::warn!("{w}");
+    }
 
     if args.show_config {
-        println!("{}", config_manager.to_display_adapter::<Configuration>());
+        let format = args.format.unwrap_or_default();
+        println!(
+            "{}",
+            config_manager.to_display_adapter::<Configuration>(format)?
+        );
         Ok(ExitCode::SUCCESS)
     } else if args.server {
         let _span = error_span!("REMOTE").entered();
@@ -89,17 +127,25 @@ pub async fn cli() -> anyhow::Result<ExitCode> {
             .await
             .map(|()| ExitCode::SUCCESS)
             .inspect_err(|e| tracing::error!("{e}"))
+    } else if let Some(user_hostname) = &args.daemon_serve {
+        #[cfg(unix)]
+        let result = crate::client::daemon_main(&config, &args.client_params, user_hostname).await;
+        #[cfg(not(unix))]
+        let result = Err(anyhow::anyhow!(
+            "The daemon is not supported on this platform ({user_hostname})"
+        ));
+        result
+            .map(|()| ExitCode::SUCCESS)
+            .inspect_err(|e| tracing::error!("{e:#}"))
     } else {
-        client_main(&config, progress.unwrap(), args.client_params)
-            .await
-            .inspect_err(|e| tracing::error!("{e}"))
-            .or_else(|_| Ok(false))
-            .map(|success| {
-                if success {
-                    ExitCode::SUCCESS
-                } else {
-                    ExitCode::FAILURE
-                }
-            })
+        let status = match client_main(&config, progress.unwrap(), args.client_params).await {
+            Ok(true) => ExitCode::SUCCESS,
+            Ok(false) => FailureKind::Transfer.exit_code().into(),
+            Err(e) => {
+                tracing::error!("{e}");
+                FailureKind::of(&e).exit_code().into()
+            }
+        };
+        Ok(status)
     }
 }
@@ -0,0 +1,40 @@
+//! Manual page generation
+// (c) 2024 Ross Younger
+
+use std::io::Write;
+
+use super::args::CliArgs;
+
+/// Writes a manual page for qcp, in roff format, generated from the command-line definition
+pub(crate) fn write_man_page(out: &mut dyn Write) -> std::io::Result<()> {
+    let cmd = CliArgs::cli_command();
+    // clap_mangen does not render `before_help`, which holds our usage notes, so fold it into the description
+    let about = cmd.get_about().map(ToString::to_string).unwrap_or_default();
+    let notes = cmd
+        .get_before_help()
+        .map(ToString::to_string)
+        .unwrap_or_default();
+    // Leading spaces are significant in roff
+    let notes = notes.trim().lines().map(str::trim).collect::<Vec<_>>();
+    let cmd = cmd.long_about(format!("{about}\n\n{}", notes.join("\n")));
+    clap_mangen::Man::new(cmd).render(out)
+}
+
+#[cfg(test)]
+mod test {
+    use super::write_man_page;
+
+    #[test]
+    fn man_page() {
+        let mut buf = Vec::new();
+        write_man_page(&mut buf).unwrap();
+        let page = String::from_utf8(buf).unwrap();
+        assert!(page.starts_with(".ie \\n(.g .ds Aq \\(aq"));
+        assert!(page.contains(".TH qcp 1"));
+        assert!(page.contains("Exactly one of source and destination must be remote."));
+        assert!(page.contains("\\-\\-rtt"));
+        assert!(page.contains("Exit status:"));
+        // Hidden options stay hidden
+        assert!(!page.contains("generate\\-man"));
+    }
+}
@@ -2,6 +2,7 @@
 // (c) 2024 Ross Younger
 mod args;
 mod cli_main;
+mod manpage;
 pub(crate) mod styles;
 pub(crate) use args::MODE_OPTIONS;
 pub use cli_main::cli;
@@ -6,23 +6,38 @@ use std::{process::Stdio, time::Duration};
 use anyhow::{anyhow, Context as _, Result};
 use indicatif::MultiProgress;
 use tokio::{
-    io::{AsyncBufReadExt, AsyncReadExt as _, BufReader},
+    io::{AsyncBufReadExt, AsyncReadExt as _, AsyncWriteExt as _, BufReader},
+    task::JoinHandle,
     time::timeout,
 };
 use tracing::{debug, trace, warn};
 
 use crate::{
     config::Configuration,
-    protocol::control::{ClientMessage, ClosedownReport, ConnectionType, ServerMessage, BANNER},
-    util::Credentials,
+    protocol::control::{
+        check_server_version, ClientMessage, ClosedownReport, ConnectionType, ServerMessage, BANNER,
+    },
+    util::{failure::FailureKind, stderr_is_colored, Credentials},
 };
 
-use super::Parameters;
+use super::{ssh::HostSettings, Parameters};
 
 /// Control channel abstraction
 #[derive(Debug)]
 pub struct Channel {
     process: tokio::process::Child,
+    /// Relays the process's stderr; returns whether ssh reported an authentication failure
+    stderr_relay: JoinHandle<bool>,
+}
+
+/// Does this line of ssh output indicate that it could not authenticate?
+fn is_ssh_auth_failure(line: &str) -> bool {
+    const MESSAGES: [&str; 3] = [
+        "Permission denied (",
+        "Host key verification failed",
+        "Too many authentication failures",
+    ];
+    MESSAGES.iter().any(|m| line.contains(m))
 }
 
 impl Channel {
@@ -35,26 +50,44 @@ impl Channel {
     }
 
     /// Opens the control channel, checks the banner, sends the Client Message, reads the Server Message.
+    ///
+    /// `ssh_destination` is passed to ssh as-is, so may be of the form `user@host`.
+    /// `ssh_settings` are passed to ssh as command-line options.
     pub async fn transact(
         credentials: &Credentials,
-        remote_host: &str,
+        ssh_destination: &str,
+        ssh_settings: &HostSettings,
         connection_type: ConnectionType,
         display: &MultiProgress,
         config: &Configuration,
         parameters: &Parameters,
     ) -> Result<(Channel, ServerMessage)> {
         trace!("opening control channel");
-        let mut new1 = Self::launch(display, config, parameters, remote_host, connection_type)?;
-        new1.wait_for_banner().await?;
+        let mut new1 = Self::launch(
+            display,
+            config,
+            parameters,
+            ssh_destination,
+            ssh_settings,
+            connection_type,
+        )?;
+        if let Err(e) = new1.wait_for_banner().await {
+            return Err(new1.classify_failure(e).await);
+        }
 
         let mut pipe = new1
             .process
             .stdin
             .as_mut()
             .ok_or(anyhow!("could not access process stdin (can't happen?)"))?;
-        ClientMessage::write(&mut pipe, &credentials.certificate, connection_type)
-            .await
-            .with_context(|| "writing client message")?;
+        ClientMessage::write(
+            &mut pipe,
+            &credentials.certificate,
+            connection_type,
+            config.compress,
+        )
+        .await
+        .with_context(|| "writing client message")?;
 
         let mut server_output = new1
             .process
@@ -68,6 +101,7 @@ impl Channel {
             .with_context(|| "reading server message")?;
 
         trace!("Got server message {message:?}");
+        check_server_version(message.protocol_version)?;
         if let Some(w) = message.warning.as_ref() {
             warn!("Remote endpoint warning: {w}");
         }
@@ -75,14 +109,14 @@ impl Channel {
         Ok((new1, message))
     }
 
-    /// This is effectively a constructor. At present, it launches a subprocess.
-    fn launch(
-        display: &MultiProgress,
+    /// Creates the ssh command which runs the remote server
+    fn ssh_command(
         config: &Configuration,
         parameters: &Parameters,
-        remote_host: &str,
+        ssh_destination: &str,
+        ssh_settings: &HostSettings,
         connection_type: ConnectionType,
-    ) -> Result<Self> {
+    ) -> tokio::process::Command {
         let mut server = tokio::process::Command::new(&config.ssh);
         let _ = server.kill_on_drop(true);
         let _ = match connection_type {
@@ -90,8 +124,14 @@ impl Channel {
             ConnectionType::Ipv6 => server.arg("-6"),
         };
         let _ = server.args(&config.ssh_options);
+        if let Some(port) = ssh_settings.port {
+            let _ = server.args(["-p", &port.to_string()]);
+        }
+        if let Some(identity) = &ssh_settings.identity_file {
+            let _ = server.args(["-i", identity]);
+        }
         let _ = server.args([
-            remote_host,
+            ssh_destination,
             "qcp",
             "--server",
             // Remote receive bandwidth = our transmit bandwidth
@@ -116,38 +156,152 @@ impl Channel {
                 let _ = server.args(["--initial-congestion-window", &w.to_string()]);
             }
         }
+        if config.persistent_congestion_threshold != 0 {
+            let _ = server.args([
+                "--persistent-congestion-threshold",
+                &config.persistent_congestion_threshold.to_string(),
+            ]);
+        }
+        if config.keepalive != Configuration::default().keepalive {
+            let _ = server.args(["--keepalive", &config.keepalive.to_string()]);
+        }
+        if config.idle_timeout != 0 {
+            let _ = server.args(["--idle-timeout", &config.idle_timeout.to_string()]);
+        }
+        if config.accept_timeout != 0 {
+            let _ = server.args(["--accept-timeout", &config.accept_timeout.to_string()]);
+        }
+        if config.protocol_timeout != Configuration::default().protocol_timeout {
+            let _ = server.args(["--protocol-timeout", &config.protocol_timeout.to_string()]);
+        }
+        if config.udp_payload_size != 0 {
+            let _ = server.args(["--mtu", &config.udp_payload_size.to_string()]);
+        }
+        if config.no_gso {
+            let _ = server.arg("--no-gso");
+        }
+        if config.dscp != 0 {
+            let _ = server.args(["--dscp", &config.dscp.to_string()]);
+        }
         if !config.remote_port.is_default() {
             let _ = server.args(["--port", &config.remote_port.to_string()]);
         }
+        if config.bwlimit() != 0 {
+            let _ = server.args(["--bwlimit", &config.bwlimit().to_string()]);
+        }
+        if config.preallocate {
+            let _ = server.arg("--preallocate");
+        }
+        if config.mmap {
+            let _ = server.arg("--mmap");
+        }
+        if config.io_concurrency != 0 {
+            let _ = server.args(["--io-concurrency", &config.io_concurrency.to_string()]);
+        }
+        if config.advertise_port != 0 {
+            let _ = server.args(["--advertise-port", &config.advertise_port.to_string()]);
+        }
+        if !config.advertise_address.is_empty() {
+            let _ = server.args(["--advertise-address", &config.advertise_address]);
+        }
+        server
+    }
+
+    /// This is effectively a constructor. At present, it launches a subprocess.
+    fn launch(
+        display: &MultiProgress,
+        config: &Configuration,
+        parameters: &Parameters,
+        ssh_destination: &str,
+        ssh_settings: &HostSettings,
+        connection_type: ConnectionType,
+    ) -> Result<Self> {
+        let mut server = Self::ssh_command(
+            config,
+            parameters,
+            ssh_destination,
+            ssh_settings,
+            connection_type,
+        );
         let _ = server
             .stdin(Stdio::piped())
             .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
             .kill_on_drop(true);
-        if !parameters.quiet {
-            let _ = server.stderr(Stdio::piped());
-        } // else inherit
+        let remote_log = parameters
+            .remote_log
+            .as_ref()
+            .map(|path| {
+                std::fs::File::create(path)
+                    .map(tokio::fs::File::from_std)
+                    .with_context(|| format!("could not create remote log file {path}"))
+            })
+            .transpose()?;
         debug!("spawning command: {:?}", server);
         let mut process = server
             .spawn()
             .context("Could not launch control connection to remote server")?;
+        let Some(stderr) = process.stderr.take() else {
+            anyhow::bail!("could not get stderr of remote process");
+        };
 
         // Whatever the remote outputs, send it to our output in a way that doesn't mess things up.
-        if !parameters.quiet {
-            let stderr = process.stderr.take();
-            let Some(stderr) = stderr else {
-                anyhow::bail!("could not get stderr of remote process");
-            };
+        // Or, if so requested, to a file.
+        // Either way, watch for ssh reporting that it could not log in.
+        let stderr_relay = if let Some(mut log) = remote_log {
+            tokio::spawn(async move {
+                let mut auth_failed = false;
+                let mut reader = BufReader::new(stderr).lines();
+                while let Ok(Some(line)) = reader.next_line().await {
+                    auth_failed |= is_ssh_auth_failure(&line);
+                    // The remote doesn't know it's writing to a file, so may have used colour codes.
+                    let line = format!("{}\n", console::strip_ansi_codes(&line));
+                    if let Err(e) = log.write_all(line.as_bytes()).await {
+                        warn!("writing remote log file: {e}");
+                        break;
+                    }
+                }
+                let _ = log.flush().await;
+                auth_failed
+            })
+        } else {
             let cloned = display.clone();
-            let _reader = tokio::spawn(async move {
+            let colored = stderr_is_colored();
+            tokio::spawn(async move {
+                let mut auth_failed = false;
                 let mut reader = BufReader::new(stderr).lines();
                 while let Ok(Some(line)) = reader.next_line().await {
+                    auth_failed |= is_ssh_auth_failure(&line);
+                    // The remote colours its output, as it cannot tell whether we want that; so remove it if we don't.
+                    let line = if colored {
+                        line
+                    } else {
+                        console::strip_ansi_codes(&line).into_owned()
+                    };
                     // Calling cloned.println() sometimes messes up; there seems to be a concurrency issue.
                     // But we don't need to worry too much about that. Just write it out.
                     cloned.suspend(|| eprintln!("{line}"));
                 }
-            });
+                auth_failed
+            })
+        };
+        Ok(Self {
+            process,
+            stderr_relay,
+        })
+    }
+
+    /// Works out whether the control channel failed to open because ssh could not authenticate
+    async fn classify_failure(&mut self, error: anyhow::Error) -> anyhow::Error {
+        // ssh reports the failure on stderr, then exits. We may see the end of its stdout before we have read that.
+        let auth_failed = timeout(Duration::from_secs(1), &mut self.stderr_relay)
+            .await
+            .is_ok_and(|r| r.unwrap_or(false));
+        if auth_failed {
+            FailureKind::Authentication.tag(error)
+        } else {
+            FailureKind::Connection.tag(error)
         }
-        Ok(Self { process })
     }
 
     async fn wait_for_banner(&mut self) -> Result<()> {
@@ -195,3 +349,104 @@ impl Channel {
         Ok(stats)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::Channel;
+    use crate::{
+        client::{ssh::HostSettings, Parameters},
+        config::Configuration,
+        protocol::control::ConnectionType,
+    };
+
+    #[test]
+    fn ssh_destination_keeps_user() {
+        let cmd = Channel::ssh_command(
+            &Configuration::default(),
+            &Parameters::default(),
+            "alice@server",
+            &HostSettings::default(),
+            ConnectionType::Ipv4,
+        );
+        let args = cmd.as_std().get_args().collect::<Vec<_>>();
+        assert_eq!(args[0], "-4");
+        assert!(args.contains(&"alice@server".as_ref()));
+        let qcp = args.iter().position(|a| *a == "qcp").unwrap();
+        assert_eq!(args[qcp - 1], "alice@server");
+        // Older servers do not understand --color; we remove any colour they send instead
+        assert!(!args
+            .iter()
+            .any(|a| a.to_string_lossy().starts_with("--color")));
+    }
+
+    #[test]
+    fn ssh_auth_failures() {
+        use super::is_ssh_auth_failure;
+        assert!(is_ssh_auth_failure(
+            "alice@server: Permission denied (publickey,password)."
+        ));
+        assert!(is_ssh_auth_failure("Host key verification failed."));
+        assert!(!is_ssh_auth_failure(
+            "ssh: connect to host server port 22: Connection refused"
+        ));
+    }
+
+    #[test]
+    fn keepalive_passed_if_not_default() {
+        let args = |config: &Configuration| {
+            Channel::ssh_command(
+                config,
+                &Parameters::default(),
+                "host",
+                &HostSettings::default(),
+                ConnectionType::Ipv4,
+            )
+            .as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect::<Vec<_>>()
+        };
+        let defaults = args(&Configuration::default());
+        assert!(!defaults.contains(&"--keepalive".into()));
+        assert!(!defaults.contains(&"--protocol-timeout".into()));
+        assert!(!defaults.contains(&"--accept-timeout".into()));
+        let config = Configuration {
+            keepalive: 0,
+            protocol_timeout: 300,
+            accept_timeout: 45,
+            ..Default::default()
+        };
+        let args = args(&config);
+        let i = args.iter().position(|a| a == "--keepalive").unwrap();
+        assert_eq!(args[i + 1], "0");
+        let i = args.iter().position(|a| a == "--protocol-timeout").unwrap();
+        assert_eq!(args[i + 1], "300");
+        let i = args.iter().position(|a| a == "--accept-timeout").unwrap();
+        assert_eq!(args[i + 1], "45");
+    }
+
+    #[test]
+    fn ssh_settings_passed() {
+        let settings = HostSettings {
+            user: None,
+            port: Some(2222),
+            identity_file: Some("~/.ssh/key".into()),
+        };
+        let cmd = Channel::ssh_command(
+            &Configuration::default(),
+            &Parameters::default(),
+            "server",
+            &settings,
+            ConnectionType::Ipv4,
+        );
+        let args = cmd
+            .as_std()
+            .get_args()
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect::<Vec<_>>();
+        let qcp = args.iter().position(|a| a == "qcp").unwrap();
+        let ssh_args = &args[..qcp];
+        assert!(ssh_args.windows(2).any(|w| w == ["-p", "2222"]));
+        assert!(ssh_args.windows(2).any(|w| w == ["-i", "~/.ssh/key"]));
+    }
+}
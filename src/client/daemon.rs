@@ -0,0 +1,695 @@
+//! Reusing a connection across runs, via a background process
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! Every run of qcp pays for an ssh login and a QUIC handshake before it can send anything.
+//! For a small file, that is most of the time taken; a script which copies many files one at a time pays it every time.
+//!
+//! With the `daemon` option, the first run starts a background qcp process (`--daemon-serve`) which connects to the
+//! remote host, then listens on a Unix socket. Each run, the first included, sends its jobs down the socket.
+//! The background process runs them on its connection, reporting their progress and results as it goes.
+//! It exits once it has been idle for `daemon_idle_timeout`, or if its connection fails.
+//!
+//! There is one background process per remote host and configuration: the socket is named for a hash of both
+//! (and of the qcp version), so a run with different settings gets its own. The socket lives in a directory
+//! which only the user can access.
+//!
+//! The background process has its own working directory, so local paths are made absolute before they are sent.
+//! Runs which need something it can't provide (standard input or output, or options which act on the run as a whole,
+//! such as `--delete`) connect directly, as do runs to more than one host.
+//! If the destination of a job exists and the user is to be asked whether to overwrite it, the question is asked
+//! when the other jobs have finished, and the affected jobs are sent again.
+
+use std::{
+    collections::HashMap,
+    os::unix::{fs::DirBuilderExt as _, fs::MetadataExt as _, process::CommandExt as _},
+    path::PathBuf,
+    process::Stdio,
+    sync::Arc,
+};
+
+use anyhow::{Context as _, Result};
+use human_repr::{HumanCount as _, HumanDuration as _};
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use quinn::Connection;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    io::{AsyncBufReadExt as _, AsyncWriteExt as _, BufReader},
+    net::{UnixListener, UnixStream},
+    sync::mpsc,
+    task::JoinSet,
+    time::{Duration, Instant},
+};
+use tracing::{debug, error, info, warn};
+
+use super::{
+    job::FileSpec,
+    main_loop::{progress_bar_for, run_or_skip, HostConnection, JobOptions},
+    overwrite::{DestinationExists, Overwrite},
+    CopyJobSpec, Parameters, Preserve, ProgressSink,
+};
+use crate::{
+    config::Configuration,
+    protocol::session::CommandType,
+    transport::ThroughputMode,
+    util::{compress::Compress, io::IoLimiter, stats::DataRate, time::StopwatchChain, Credentials},
+};
+
+/// How often to check whether a newly started background process is ready
+const STARTUP_POLL: Duration = Duration::from_millis(50);
+
+/// What a run sends to the background process: one line of JSON
+#[derive(Debug, Deserialize, Serialize)]
+struct Request {
+    jobs: Vec<CopyJobSpec>,
+    options: RequestOptions,
+}
+
+/// The options from the command line which affect how each job is run
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone, Debug, Default, Deserialize, Serialize)]
+struct RequestOptions {
+    force: bool,
+    resume: bool,
+    append: bool,
+    mkpath: bool,
+    partial: bool,
+    partial_dir: Option<String>,
+    remove_source_files: bool,
+    allow_growing: bool,
+    inplace: bool,
+    fsync: bool,
+    preserve: Option<Preserve>,
+    ignore_existing: bool,
+    size_only: bool,
+    update: bool,
+}
+
+impl From<&Parameters> for RequestOptions {
+    fn from(p: &Parameters) -> Self {
+        Self {
+            force: p.force,
+            resume: p.resume,
+            append: p.append,
+            mkpath: p.mkpath,
+            partial: p.partial,
+            partial_dir: p.partial_dir.clone(),
+            remove_source_files: p.remove_source_files,
+            allow_growing: p.allow_growing,
+            inplace: p.inplace,
+            fsync: p.fsync,
+            preserve: p.preserve,
+            ignore_existing: p.ignore_existing,
+            size_only: p.size_only,
+            update: p.update,
+        }
+    }
+}
+
+impl RequestOptions {
+    /// The parameters to run the jobs with, in the background process
+    fn parameters(&self) -> Parameters {
+        Parameters {
+            quiet: true,
+            force: self.force,
+            resume: self.resume,
+            append: self.append,
+            mkpath: self.mkpath,
+            partial: self.partial,
+            partial_dir: self.partial_dir.clone(),
+            remove_source_files: self.remove_source_files,
+            allow_growing: self.allow_growing,
+            inplace: self.inplace,
+            fsync: self.fsync,
+            preserve: self.preserve,
+            ignore_existing: self.ignore_existing,
+            size_only: self.size_only,
+            update: self.update,
+            ..Parameters::default()
+        }
+    }
+}
+
+/// What the background process sends back, one line of JSON each.
+/// Jobs are identified by their index in the request.
+#[derive(Debug, Deserialize, Serialize)]
+enum Event {
+    /// The payload length of a job
+    Length(usize, u64),
+    /// Some more of a job's payload has been transferred
+    Progress(usize, u64),
+    /// A job has finished
+    Finished(usize, Outcome),
+}
+
+/// How a job went
+#[derive(Debug, Deserialize, Serialize, PartialEq, Eq)]
+enum Outcome {
+    /// The payload size
+    Transferred(u64),
+    /// The destination was already present
+    Skipped,
+    /// The destination exists, and there was nobody to ask whether to overwrite it
+    Exists(String),
+    /// The error message
+    Failed(String),
+}
+
+impl From<Result<Option<u64>>> for Outcome {
+    fn from(result: Result<Option<u64>>) -> Self {
+        match result {
+            Ok(Some(bytes)) => Self::Transferred(bytes),
+            Ok(None) => Self::Skipped,
+            Err(e) => match e.downcast::<DestinationExists>() {
+                Ok(DestinationExists(destination)) => Self::Exists(destination),
+                Err(e) => Self::Failed(e.to_string()),
+            },
+        }
+    }
+}
+
+/// Reports the progress of a job back to the run which asked for it
+struct EventSink {
+    job: usize,
+    events: mpsc::UnboundedSender<Event>,
+}
+
+impl ProgressSink for EventSink {
+    fn set_length(&self, length: u64) {
+        let _ = self.events.send(Event::Length(self.job, length));
+    }
+    fn inc(&self, delta: u64) {
+        let _ = self.events.send(Event::Progress(self.job, delta));
+    }
+    fn message(&self, _message: &str) {}
+}
+
+/// The directory holding our sockets, which only we may access
+fn socket_dir() -> Result<PathBuf> {
+    let uid = nix::unistd::getuid();
+    let dir = match dirs::runtime_dir() {
+        Some(dir) => dir.join("qcp"),
+        None => std::env::temp_dir().join(format!("qcp-{uid}")),
+    };
+    match std::fs::DirBuilder::new().mode(0o700).create(&dir) {
+        Err(e) if e.kind() != std::io::ErrorKind::AlreadyExists => {
+            return Err(e).with_context(|| dir.display().to_string())
+        }
+        _ => (),
+    }
+    let meta = std::fs::symlink_metadata(&dir)?;
+    anyhow::ensure!(
+        meta.is_dir() && meta.uid() == uid.as_raw() && meta.mode() & 0o777 == 0o700,
+        "{} is not a private directory",
+        dir.display()
+    );
+    Ok(dir)
+}
+
+/// The socket of the background process for a host and configuration
+fn socket_path(user_hostname: &str, config: &Configuration) -> Result<PathBuf> {
+    let mut hasher = blake3::Hasher::new();
+    let _ = hasher
+        .update(env!("CARGO_PKG_VERSION").as_bytes())
+        .update(user_hostname.as_bytes())
+        .update(format!("{config:?}").as_bytes());
+    let hash = hasher.finalize().to_hex();
+    Ok(socket_dir()?.join(format!("{}.sock", &hash[..16])))
+}
+
+/// Why a run can't use a background process, if it can't
+fn unsuitable(parameters: &Parameters, jobs: &[CopyJobSpec]) -> Option<&'static str> {
+    let first = jobs.first()?.remote_user_host();
+    let reason = if parameters.dry_run {
+        "--dry-run"
+    } else if parameters.delete {
+        "--delete"
+    } else if parameters.state_file.is_some() {
+        "--state-file"
+    } else if parameters.stats_fd.is_some() {
+        "--stats-fd"
+    } else if parameters.exec_after.is_some() {
+        // The command would run in the background process, in its working directory and environment
+        "--exec-after"
+    } else if parameters.follow {
+        // It only ends when interrupted
+        "--follow"
+    } else if parameters.print_hash || parameters.verify_source {
+        "hashing"
+    } else if jobs
+        .iter()
+        .any(|j| j.source.is_stdio() || j.destination.is_stdio())
+    {
+        "standard input or output"
+    } else if jobs.iter().any(|j| j.remote_user_host() != first) {
+        "more than one host"
+    } else {
+        return None;
+    };
+    Some(reason)
+}
+
+/// Makes a local file specification absolute
+fn absolute(spec: &FileSpec) -> Result<FileSpec> {
+    if spec.host.is_some() || spec.is_url() {
+        return Ok(spec.clone());
+    }
+    let filename = std::path::absolute(&spec.filename)?
+        .into_os_string()
+        .into_string()
+        .map_err(|f| anyhow::anyhow!("{}: not valid UTF-8", f.to_string_lossy()))?;
+    Ok(FileSpec {
+        host: None,
+        filename,
+    })
+}
+
+/// Runs the jobs via the background process for their host, starting it if need be.
+///
+/// Returns None if the jobs are to be run directly instead; otherwise, whether they all succeeded,
+/// and how many bytes were transferred.
+pub(super) async fn run_via_daemon(
+    config: &Configuration,
+    parameters: &Parameters,
+    jobs: &[CopyJobSpec],
+    display: &MultiProgress,
+    spinner: &ProgressBar,
+) -> Result<Option<(bool, u64)>> {
+    if let Some(reason) = unsuitable(parameters, jobs) {
+        debug!("Connecting directly, as the daemon does not support {reason}");
+        return Ok(None);
+    }
+    let jobs = match jobs
+        .iter()
+        .map(|j| {
+            Ok(CopyJobSpec {
+                source: absolute(&j.source)?,
+                destination: absolute(&j.destination)?,
+                tree: j.tree.clone(),
+            })
+        })
+        .collect::<Result<Vec<_>>>()
+    {
+        Ok(jobs) => jobs,
+        Err(e) => {
+            debug!("Connecting directly: {e}");
+            return Ok(None);
+        }
+    };
+    let user_hostname = jobs[0].remote_user_host().to_string();
+    spinner.set_message("Contacting daemon");
+    let mut stream = match connect(&user_hostname, config).await {
+        Ok(stream) => stream,
+        Err(e) => {
+            warn!("Could not use the daemon for {user_hostname} ({e:#}); connecting directly");
+            return Ok(None);
+        }
+    };
+    spinner.set_message("Transferring data");
+
+    let start = Instant::now();
+    let overwrite = Overwrite::new(parameters.force);
+    let mut request = Request {
+        jobs,
+        options: parameters.into(),
+    };
+    let (mut success, mut bytes, mut skipped) = (true, 0, 0);
+    loop {
+        let outcomes = exchange(&mut stream, &request, display, parameters.quiet).await?;
+        let mut again = Vec::new();
+        for (job, outcome) in request.jobs.into_iter().zip(outcomes) {
+            match outcome {
+                Outcome::Transferred(n) => bytes += n,
+                Outcome::Skipped => skipped += 1,
+                Outcome::Exists(destination) => {
+                    match overwrite.confirm(&destination, display).await {
+                        Ok(true) => again.push(job),
+                        Ok(false) => info!("Not overwriting {destination}"),
+                        Err(e) => {
+                            error!("{e}");
+                            success = false;
+                        }
+                    }
+                }
+                Outcome::Failed(e) => {
+                    error!("{e}");
+                    success = false;
+                }
+            }
+        }
+        if again.is_empty() {
+            break;
+        }
+        request = Request {
+            jobs: again,
+            options: RequestOptions {
+                force: true,
+                ..request.options
+            },
+        };
+    }
+
+    if !parameters.quiet {
+        if skipped > 0 {
+            info!("Skipped {skipped} file(s) already present at the destination");
+        }
+        if bytes != 0 {
+            let elapsed = start.elapsed();
+            info!(
+                "Transferred {} in {}; average {}",
+                bytes.human_count_bytes(),
+                elapsed.human_duration(),
+                DataRate::new(bytes, Some(elapsed))
+            );
+        }
+    }
+    Ok(Some((success, bytes)))
+}
+
+/// Connects to the background process for a host, starting it if there isn't one
+async fn connect(user_hostname: &str, config: &Configuration) -> Result<UnixStream> {
+    let path = socket_path(user_hostname, config)?;
+    if let Ok(stream) = UnixStream::connect(&path).await {
+        debug!("Using the daemon at {}", path.display());
+        return Ok(stream);
+    }
+    let log = path.with_extension("log");
+    debug!(
+        "Starting a daemon for {user_hostname}, logging to {}",
+        log.display()
+    );
+    // It runs with our arguments, so arrives at the same configuration
+    let mut child = std::process::Command::new(std::env::current_exe()?)
+        .args(std::env::args_os().skip(1))
+        .arg(format!("--daemon-serve={user_hostname}"))
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(std::fs::File::create(&log)?)
+        // Keep it out of the way of Ctrl-C at the terminal
+        .process_group(0)
+        .spawn()?;
+    loop {
+        tokio::time::sleep(STARTUP_POLL).await;
+        if let Ok(stream) = UnixStream::connect(&path).await {
+            return Ok(stream);
+        }
+        if let Some(status) = child.try_wait()? {
+            anyhow::bail!("it exited ({status}); see {}", log.display());
+        }
+    }
+}
+
+/// Sends a request to the background process, and follows it until every job has finished.
+///
+/// Returns the outcome of each job, in order.
+async fn exchange(
+    stream: &mut UnixStream,
+    request: &Request,
+    display: &MultiProgress,
+    quiet: bool,
+) -> Result<Vec<Outcome>> {
+    let (read, mut write) = stream.split();
+    let mut line = serde_json::to_string(request)?;
+    line.push('\n');
+    write.write_all(line.as_bytes()).await?;
+
+    let mut events = BufReader::new(read).lines();
+    let mut outcomes: Vec<Option<Outcome>> = request.jobs.iter().map(|_| None).collect();
+    let mut remaining = outcomes.len();
+    let mut bars: HashMap<usize, ProgressBar> = HashMap::new();
+    while remaining > 0 {
+        let line = events
+            .next_line()
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("the daemon went away"))?;
+        let (job, event) = match serde_json::from_str(&line)? {
+            Event::Length(job, length) => (job, Ok(length)),
+            Event::Progress(job, delta) => {
+                if let Some(bar) = bars.get(&job) {
+                    bar.inc(delta);
+                }
+                continue;
+            }
+            Event::Finished(job, outcome) => (job, Err(outcome)),
+        };
+        let spec = request
+            .jobs
+            .get(job)
+            .ok_or_else(|| anyhow::anyhow!("the daemon sent an unknown job {job}"))?;
+        match event {
+            Ok(length) => match bars.get(&job) {
+                Some(bar) => bar.set_length(length),
+                None => {
+                    let _ = bars.insert(job, progress_bar_for(display, spec, length, quiet)?);
+                }
+            },
+            Err(outcome) => {
+                if let Some(bar) = bars.remove(&job) {
+                    bar.finish_and_clear();
+                }
+                if outcomes[job].replace(outcome).is_none() {
+                    remaining -= 1;
+                }
+            }
+        }
+    }
+    Ok(outcomes.into_iter().flatten().collect())
+}
+
+/// The background process: connects to `user_hostname`, then runs the jobs sent by other runs of qcp,
+/// until it has been idle for `daemon_idle_timeout`.
+pub(crate) async fn daemon_main(
+    config: &Configuration,
+    parameters: &Parameters,
+    user_hostname: &str,
+) -> Result<()> {
+    let path = socket_path(user_hostname, config)?;
+    if UnixStream::connect(&path).await.is_ok() {
+        info!("A daemon for {user_hostname} is already running");
+        return Ok(());
+    }
+    // There is nobody to type a password
+    let mut ssh_options = config.ssh_options.clone();
+    ssh_options.extend(["-o".into(), "BatchMode=yes".into()]);
+    let config = Configuration {
+        ssh_options,
+        ..config.clone()
+    };
+    let credentials = Credentials::load_or_generate(&config.tls_cert, &config.tls_key)?;
+    let display = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    let mut host = HostConnection::establish(
+        user_hostname,
+        &credentials,
+        &display,
+        &ProgressBar::hidden(),
+        &mut StopwatchChain::new_running("setup"),
+        &config,
+        parameters,
+        ThroughputMode::Both,
+    )
+    .await?;
+    // Anything here is left over from a previous daemon
+    let _ = std::fs::remove_file(&path);
+    let listener = UnixListener::bind(&path)?;
+    info!(
+        "Connected to {user_hostname}; listening on {}",
+        path.display()
+    );
+
+    // We can only compress what we send if the remote can decompress it
+    let config = Configuration {
+        compress: if host.compression {
+            config.compress
+        } else {
+            Compress::Off
+        },
+        ..config
+    };
+    let remote = Remote {
+        user_hostname: user_hostname.into(),
+        connection: host.connection.clone(),
+        allowed_commands: host.allowed_commands.clone().into(),
+        protocol_version: host.protocol_version,
+    };
+    let result = serve(&listener, &remote, &config).await;
+    let _ = std::fs::remove_file(&path);
+    let _ = host
+        .close(&config)
+        .await
+        .inspect_err(|e| debug!("closing {user_hostname}: {e}"));
+    result
+}
+
+/// What the background process knows about its remote host
+#[derive(Clone)]
+struct Remote {
+    user_hostname: String,
+    connection: Connection,
+    allowed_commands: Arc<[CommandType]>,
+    protocol_version: u16,
+}
+
+/// Accepts runs of qcp until idle, or until the connection fails
+async fn serve(listener: &UnixListener, remote: &Remote, config: &Configuration) -> Result<()> {
+    let idle_timeout = Duration::from_secs(config.daemon_idle_timeout.into());
+    let mut clients = JoinSet::new();
+    let mut idle_since = Instant::now();
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let (stream, _) = accepted?;
+                let _ = clients.spawn(handle_client(stream, remote.clone(), config.clone()));
+            }
+            Some(result) = clients.join_next() => {
+                if let Ok(Err(e)) = result {
+                    debug!("client: {e:#}");
+                }
+                idle_since = Instant::now();
+            }
+            () = tokio::time::sleep_until(idle_since + idle_timeout), if clients.is_empty() => {
+                info!("Idle for {}; exiting", idle_timeout.human_duration());
+                return Ok(());
+            }
+            e = remote.connection.closed() => {
+                warn!("Connection to {} closed: {e}", remote.user_hostname);
+                return Ok(());
+            }
+            _ = terminate.recv() => return Ok(()),
+            _ = tokio::signal::ctrl_c() => return Ok(()),
+        }
+    }
+}
+
+/// Runs the requests from one run of qcp
+async fn handle_client(stream: UnixStream, remote: Remote, config: Configuration) -> Result<()> {
+    let (read, mut write) = stream.into_split();
+    let mut requests = BufReader::new(read).lines();
+    while let Some(line) = requests.next_line().await? {
+        let request: Request = serde_json::from_str(&line)?;
+        let (events, mut outbound) = mpsc::unbounded_channel();
+        let forward = async {
+            while let Some(event) = outbound.recv().await {
+                let mut line = serde_json::to_string(&event)?;
+                line.push('\n');
+                write.write_all(line.as_bytes()).await?;
+            }
+            anyhow::Ok(())
+        };
+        // If the client goes away, we can't report progress; dropping the jobs cancels them
+        let ((), ()) = tokio::try_join!(run_request(request, &remote, &config, events), forward)?;
+    }
+    Ok(())
+}
+
+/// Runs the jobs in a request, reporting their progress and outcomes as events
+async fn run_request(
+    request: Request,
+    remote: &Remote,
+    config: &Configuration,
+    events: mpsc::UnboundedSender<Event>,
+) -> Result<()> {
+    let options =
+        JobOptions::new(&request.options.parameters()).for_remote_version(remote.protocol_version);
+    let io_limiter = IoLimiter::new(config.io_concurrency);
+    let display = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    // In a recursive copy, the directories must exist before we can send any files into them.
+    let (directories, files): (Vec<_>, Vec<_>) = request
+        .jobs
+        .into_iter()
+        .enumerate()
+        .partition(|(_, j)| j.is_directory());
+    for batch in [directories, files] {
+        let mut tasks = JoinSet::new();
+        for (index, job) in batch {
+            if !remote.allowed_commands.contains(&job.command_type()) {
+                let message = format!(
+                    "{} does not allow {} commands",
+                    remote.user_hostname,
+                    job.command_type()
+                );
+                let _ = events.send(Event::Finished(index, Outcome::Failed(message)));
+                continue;
+            }
+            let mut options = options.clone();
+            options.progress = Some(Arc::new(EventSink {
+                job: index,
+                events: events.clone(),
+            }));
+            let run = run_or_skip(
+                remote.connection.clone(),
+                job,
+                display.clone(),
+                ProgressBar::hidden(),
+                config.clone(),
+                io_limiter.clone(),
+                options,
+            );
+            let _ = tasks.spawn(async move { (index, run.await.1) });
+        }
+        while let Some(result) = tasks.join_next().await {
+            let (index, result) = result?;
+            let _ = events.send(Event::Finished(index, result.into()));
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use super::{absolute, unsuitable, Event, Outcome, RequestOptions};
+    use crate::client::{job::job, overwrite::DestinationExists, Parameters};
+
+    #[test]
+    fn suitability() {
+        let jobs = [job("file", "host:dir/")];
+        let params = Parameters::default();
+        assert_eq!(unsuitable(&params, &jobs), None);
+        let dry_run = Parameters {
+            dry_run: true,
+            ..Default::default()
+        };
+        assert_eq!(unsuitable(&dry_run, &jobs), Some("--dry-run"));
+        assert_eq!(
+            unsuitable(&params, &[job("-", "host:file")]),
+            Some("standard input or output")
+        );
+        assert_eq!(
+            unsuitable(&params, &[job("a", "host:a"), job("b", "other:b")]),
+            Some("more than one host")
+        );
+    }
+
+    #[test]
+    fn paths_made_absolute() {
+        let job = job("some/file", "host:relative/dir");
+        let source = absolute(&job.source).unwrap();
+        assert!(std::path::Path::new(&source.filename).is_absolute());
+        assert!(source.filename.ends_with("some/file"));
+        assert_eq!(absolute(&job.destination).unwrap(), job.destination);
+    }
+
+    #[test]
+    fn messages() {
+        let options = RequestOptions {
+            force: true,
+            partial_dir: Some(".partial".into()),
+            ..Default::default()
+        };
+        let parameters = options.parameters();
+        assert!(parameters.quiet && parameters.force);
+        assert_eq!(parameters.partial_dir.as_deref(), Some(".partial"));
+
+        let event = Event::Finished(3, Outcome::Exists("file".into()));
+        let line = serde_json::to_string(&event).unwrap();
+        let Event::Finished(3, outcome) = serde_json::from_str(&line).unwrap() else {
+            panic!("{line}");
+        };
+        assert_eq!(outcome, Outcome::Exists("file".into()));
+
+        let exists = Err(DestinationExists("dest".into()).into());
+        assert_eq!(Outcome::from(exists), Outcome::Exists("dest".into()));
+        assert_eq!(Outcome::from(Ok(None)), Outcome::Skipped);
+        assert_eq!(Outcome::from(Ok(Some(5))), Outcome::Transferred(5));
+    }
+}
@@ -0,0 +1,159 @@
+//! Exclusion patterns for recursive copies
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! When sending a directory tree, there are often files that aren't wanted at the destination
+//! (build output, editor backups, version control metadata).
+//! `--exclude` skips them during the directory walk, before any stream is opened.
+//!
+//! # Pattern syntax
+//! Patterns are shell-style wildcards, loosely following `rsync`:
+//! * A pattern without a `/` matches the name of a file or directory at any depth, e.g. `*.tmp`.
+//! * A pattern containing a `/` matches the path relative to the source directory, e.g. `sub/*.log`.
+//!   A leading `/` is allowed, and has no further effect.
+//!   Wildcards in such a pattern do not match `/`.
+//! * A trailing `/` means the pattern only matches directories, e.g. `target/`.
+//!
+//! When a directory is excluded, nothing inside it is walked.
+//!
+//! Files given to `--exclude-from` contain one pattern per line. Blank lines and lines starting with `#` are ignored.
+
+use std::path::Path;
+
+use anyhow::{Context as _, Result};
+use glob::{MatchOptions, Pattern};
+
+/// A single compiled pattern
+#[derive(Debug, Clone)]
+struct Rule {
+    pattern: Pattern,
+    /// Match against the relative path, not just the file name
+    anchored: bool,
+    /// Match only directories
+    dir_only: bool,
+}
+
+impl Rule {
+    fn new(text: &str) -> Result<Self> {
+        let (text, dir_only) = match text.strip_suffix('/') {
+            Some(t) => (t, true),
+            None => (text, false),
+        };
+        let anchored = text.contains('/');
+        let text = text.trim_start_matches('/');
+        let pattern =
+            Pattern::new(text).with_context(|| format!("invalid exclude pattern `{text}`"))?;
+        Ok(Self {
+            pattern,
+            anchored,
+            dir_only,
+        })
+    }
+
+    fn matches(&self, relative: &Path, is_dir: bool) -> bool {
+        const OPTIONS: MatchOptions = MatchOptions {
+            case_sensitive: true,
+            require_literal_separator: true,
+            require_literal_leading_dot: false,
+        };
+        if self.dir_only && !is_dir {
+            return false;
+        }
+        if self.anchored {
+            self.pattern.matches_path_with(relative, OPTIONS)
+        } else {
+            relative
+                .file_name()
+                .and_then(|n| n.to_str())
+                .is_some_and(|n| self.pattern.matches_with(n, OPTIONS))
+        }
+    }
+}
+
+/// A set of exclusion patterns, compiled once
+#[derive(Debug, Clone, Default)]
+pub(crate) struct Exclusions(Vec<Rule>);
+
+impl Exclusions {
+    /// Compiles the patterns given directly, and those read from files
+    pub(crate) fn new(patterns: &[String], files: &[String]) -> Result<Self> {
+        let mut rules = patterns
+            .iter()
+            .map(|p| Rule::new(p))
+            .collect::<Result<Vec<_>>>()?;
+        for file in files {
+            let text = std::fs::read_to_string(file)
+                .with_context(|| format!("reading exclude patterns from {file}"))?;
+            for line in text.lines().map(str::trim) {
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                rules.push(Rule::new(line).with_context(|| format!("in {file}"))?);
+            }
+        }
+        Ok(Self(rules))
+    }
+
+    /// Should the entry at this path, relative to the source directory, be skipped?
+    pub(crate) fn is_excluded(&self, relative: &Path, is_dir: bool) -> bool {
+        self.0.iter().any(|r| r.matches(relative, is_dir))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::Exclusions;
+    use crate::util::make_test_tempfile;
+
+    fn excluded(patterns: &[&str], path: &str, is_dir: bool) -> bool {
+        let patterns = patterns
+            .iter()
+            .map(|s| (*s).to_string())
+            .collect::<Vec<_>>();
+        Exclusions::new(&patterns, &[])
+            .unwrap()
+            .is_excluded(Path::new(path), is_dir)
+    }
+
+    #[test]
+    fn names() {
+        assert!(excluded(&["*.tmp"], "a.tmp", false));
+        assert!(excluded(&["*.tmp"], "sub/deeper/a.tmp", false));
+        assert!(!excluded(&["*.tmp"], "a.tmp.txt", false));
+        assert!(excluded(&[".git"], "sub/.git", true));
+        assert!(!excluded(&[], "anything", false));
+    }
+
+    #[test]
+    fn paths() {
+        assert!(excluded(&["sub/*.log"], "sub/a.log", false));
+        assert!(!excluded(&["sub/*.log"], "sub/deeper/a.log", false));
+        assert!(!excluded(&["sub/*.log"], "other/sub/a.log", false));
+        assert!(excluded(&["/top"], "top", true));
+        assert!(!excluded(&["/top"], "sub/top", true));
+    }
+
+    #[test]
+    fn directories_only() {
+        assert!(excluded(&["target/"], "target", true));
+        assert!(!excluded(&["target/"], "target", false));
+    }
+
+    #[test]
+    fn from_file() {
+        let (path, _tempdir) = make_test_tempfile("# comment\n\n*.o\n  build/  \n", "excludes");
+        let uut = Exclusions::new(&["*.tmp".into()], &[path.to_str().unwrap().into()]).unwrap();
+        assert!(uut.is_excluded(Path::new("x.o"), false));
+        assert!(uut.is_excluded(Path::new("build"), true));
+        assert!(uut.is_excluded(Path::new("x.tmp"), false));
+        assert!(!uut.is_excluded(Path::new("comment"), false));
+        assert!(Exclusions::new(&[], &["/nonexistent/file".into()]).is_err());
+    }
+
+    #[test]
+    fn invalid() {
+        assert!(Exclusions::new(&["[".into()], &[]).is_err());
+    }
+}
@@ -0,0 +1,173 @@
+//! Running a command after each file is transferred
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! A pipeline often wants to act on each file as soon as it arrives (importing it, say, or registering its checksum).
+//! `--exec-after` does that without a separate layer to watch for new files.
+//!
+//! The command is run by the shell, once for each file transferred successfully, as soon as it is complete.
+//! `{}` in the command is replaced by the destination (quoted for the shell, as GNU parallel does):
+//! a local path for a GET, or `HOST:PATH` for a PUT.
+//! Jobs which are skipped, directories, symbolic links, and transfers to or from standard input or output
+//! don't run it.
+//!
+//! If the command fails, that is reported as a warning, or with `--exec-strict` as the failure of the job.
+
+use std::{io::Write as _, process::Stdio};
+
+use anyhow::Result;
+use indicatif::MultiProgress;
+use tracing::{debug, warn};
+
+use super::{main_loop::put_protocol_filename, CopyJobSpec};
+use crate::{protocol::session::CommandType, util};
+
+/// Prepares to run a command with the shell
+pub(super) fn shell(command: &str) -> tokio::process::Command {
+    #[cfg(unix)]
+    let mut shell = tokio::process::Command::new("sh");
+    #[cfg(unix)]
+    let _ = shell.arg("-c");
+    #[cfg(not(unix))]
+    let mut shell = tokio::process::Command::new("cmd");
+    #[cfg(not(unix))]
+    let _ = shell.arg("/C");
+    let _ = shell.arg(command);
+    shell
+}
+
+/// Quotes a string so the shell passes it on unchanged
+fn quote(s: &str) -> String {
+    if cfg!(unix) {
+        format!("'{}'", s.replace('\'', r"'\''"))
+    } else {
+        format!("\"{}\"", s.replace('"', "\"\""))
+    }
+}
+
+/// The destination of a job, as substituted for `{}`; None if the hook does not apply to the job
+fn destination(job: &CopyJobSpec) -> Option<String> {
+    if job.is_directory()
+        || job.symlink_target().is_some()
+        || job.source.is_stdio()
+        || job.destination.is_stdio()
+    {
+        return None;
+    }
+    match job.command_type() {
+        CommandType::Get => Some(
+            util::io::local_destination(&job.destination.filename, &job.source.filename)
+                .to_string_lossy()
+                .into_owned(),
+        ),
+        CommandType::Put => put_protocol_filename(job)
+            .ok()
+            .map(|f| job.remote_destination_display(&f)),
+        CommandType::Delete => unreachable!("a copy job is never a Delete"),
+    }
+}
+
+/// Runs the `--exec-after` command for a job which has completed successfully.
+///
+/// Anything the command prints is passed on. Returns an error if the command fails.
+pub(super) async fn exec_after(
+    command: &str,
+    job: &CopyJobSpec,
+    display: &MultiProgress,
+) -> Result<()> {
+    let Some(dest) = destination(job) else {
+        return Ok(());
+    };
+    let command = command.replace("{}", &quote(&dest));
+    debug!("running {command}");
+    let output = shell(&command)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .stderr(Stdio::piped())
+        .output()
+        .await
+        .map_err(|e| anyhow::anyhow!("could not run the command after {dest}: {e}"))?;
+    display.suspend(|| {
+        let _ = std::io::stdout().write_all(&output.stdout);
+        let _ = std::io::stderr().write_all(&output.stderr);
+    });
+    anyhow::ensure!(
+        output.status.success(),
+        "the command after {dest} failed ({})",
+        output.status
+    );
+    Ok(())
+}
+
+/// Runs the `--exec-after` command for a job, if there is one and the job transferred a file.
+///
+/// A failure fails the job only if `strict`.
+pub(super) async fn after_job(
+    command: Option<&str>,
+    strict: bool,
+    job: &CopyJobSpec,
+    result: Result<Option<u64>>,
+    display: &MultiProgress,
+) -> Result<Option<u64>> {
+    let (Some(command), Ok(Some(_))) = (command, &result) else {
+        return result;
+    };
+    match exec_after(command, job, display).await {
+        Ok(()) => result,
+        Err(e) if strict => Err(e),
+        Err(e) => {
+            warn!("{e}");
+            result
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use indicatif::MultiProgress;
+
+    use super::{after_job, destination, quote};
+    use crate::client::job::job;
+
+    #[test]
+    fn destinations() {
+        assert_eq!(
+            destination(&job("host:dir/file", "/tmp/")).as_deref(),
+            Some("/tmp/file")
+        );
+        assert_eq!(
+            destination(&job("/tmp/file", "host:dir/")).as_deref(),
+            Some("host:dir/file")
+        );
+        assert_eq!(destination(&job("host:file", "-")), None);
+        assert_eq!(destination(&job("-", "host:file")), None);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn hook() {
+        assert_eq!(quote("it's"), r"'it'\''s'");
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("a file");
+        let out = tmp.path().join("out");
+        let job = job("host:a file", dest.to_str().unwrap());
+        let display = MultiProgress::with_draw_target(indicatif::ProgressDrawTarget::hidden());
+
+        let command = format!("echo {{}} > {}", quote(out.to_str().unwrap()));
+        let result = after_job(Some(&command), true, &job, Ok(Some(4)), &display).await;
+        assert_eq!(result.unwrap(), Some(4));
+        let written = std::fs::read_to_string(&out).unwrap();
+        assert_eq!(written.trim_end(), dest.to_str().unwrap());
+
+        // Skipped jobs don't run it
+        std::fs::remove_file(&out).unwrap();
+        let result = after_job(Some(&command), true, &job, Ok(None), &display).await;
+        assert_eq!(result.unwrap(), None);
+        assert!(!out.exists());
+
+        let result = after_job(Some("false"), false, &job, Ok(Some(4)), &display).await;
+        assert_eq!(result.unwrap(), Some(4));
+        let result = after_job(Some("false"), true, &job, Ok(Some(4)), &display).await;
+        assert!(result.is_err());
+    }
+}
@@ -0,0 +1,159 @@
+//! Following a remote file as it grows
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! Keeping an eye on a remote log usually means leaving `ssh host tail -F` running, which is no help
+//! if the output is to be kept, and suffers from the same long, congested links as any other transfer.
+//! `--follow` copies the file, then carries on appending to the copy as the remote file grows, until interrupted.
+//!
+//! The destination is written in place, not via a temporary file, and flushed as each piece of data arrives,
+//! so whatever is watching it sees the data straight away. It may be standard output.
+//! With `--resume`, only what the destination does not already have is requested.
+//!
+//! If the remote file is rotated (a new file appears in its place) or truncated, the server says so,
+//! and the client starts again from the beginning of whatever is now at the path, on a new stream.
+//! Everything received is kept, so the destination carries on growing, as the output of `tail -F` would.
+
+use anyhow::{Context as _, Result};
+use indicatif::{MultiProgress, ProgressBar};
+use quinn::Connection;
+use tokio::io::{AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _};
+use tracing::{info, trace};
+
+use super::main_loop::{check_response, progress_bar_for, JobOptions};
+use super::progress::ProgressWriter;
+use super::CopyJobSpec;
+use crate::{
+    config::Configuration,
+    protocol::{
+        session::{with_timeout, Command, FollowEvent, Response},
+        RawStreamPair, StreamPair,
+    },
+    util,
+};
+
+/// Opens the destination of a job, to append to it.
+///
+/// Returns the destination, and how much of the file it already has.
+async fn open_destination(
+    job: &CopyJobSpec,
+    resume: bool,
+) -> Result<(Box<dyn AsyncWrite + Send + Unpin>, u64)> {
+    if job.destination.is_stdio() {
+        return Ok((Box::new(tokio::io::stdout()), 0));
+    }
+    let path = util::io::local_destination(&job.destination.filename, &job.source.filename);
+    if resume {
+        let (file, len) = util::io::open_for_append(&path).await?;
+        return Ok((Box::new(file), len));
+    }
+    let file = tokio::fs::File::create(&path)
+        .await
+        .with_context(|| format!("Could not create {}", path.display()))?;
+    Ok((Box::new(file), 0))
+}
+
+/// Receives the data sent in response to a Follow command, writing it to `out` as it arrives.
+///
+/// This carries on until the remote file is rotated or truncated, when it returns the event saying so.
+async fn receive<R, W>(recv: &mut R, out: &mut W) -> Result<FollowEvent>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    loop {
+        let Some(event) = FollowEvent::try_read(recv).await? else {
+            anyhow::bail!("the remote stopped sending data");
+        };
+        let FollowEvent::Data(len) = event else {
+            return Ok(event);
+        };
+        let received = tokio::io::copy(&mut (&mut *recv).take(len), out).await?;
+        anyhow::ensure!(received == len, "the remote stopped sending data");
+        out.flush().await?;
+    }
+}
+
+/// Actions a GET with `--follow`.
+///
+/// This only finishes if something goes wrong; otherwise it runs until the job is cancelled.
+pub(super) async fn do_follow(
+    sp: RawStreamPair,
+    connection: &Connection,
+    job: &CopyJobSpec,
+    display: MultiProgress,
+    spinner: ProgressBar,
+    config: &Configuration,
+    options: JobOptions,
+) -> Result<u64> {
+    let filename = &job.source.filename;
+    let (out, mut offset) = open_destination(job, options.resume).await?;
+
+    // There is no telling how much there will be
+    let progress_bar = progress_bar_for(&display, job, 0, options.quiet)?;
+    progress_bar.unset_length();
+    let progress = options.progress_sink(&progress_bar, job, None);
+    let mut out = ProgressWriter::new(out, progress);
+    let mut meter = options.meter(&progress_bar, spinner, config.effective_rx());
+    meter.start().await;
+
+    let limit = config.protocol_timeout_duration();
+    let mut stream: StreamPair = sp.into();
+    loop {
+        trace!("send command");
+        let cmd = Command::new_follow(filename, offset);
+        stream.send.write_all(&cmd.serialize()).await?;
+        stream.send.flush().await?;
+        check_response(
+            with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+            format_args!("FOLLOW ({filename}) failed"),
+        )?;
+        let event = receive(&mut stream.recv, &mut out)
+            .await
+            .map_err(|e| anyhow::anyhow!("FOLLOW ({filename}) failed: {e}"))?;
+        let what = if event == FollowEvent::Rotated {
+            "rotated"
+        } else {
+            "truncated"
+        };
+        info!("{} was {what}; following it from the beginning", job.source);
+        offset = 0;
+        // Only one stream may be open at a time
+        drop(stream);
+        stream = connection.open_bi().await?.into();
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::receive;
+    use crate::protocol::session::FollowEvent;
+
+    #[tokio::test]
+    async fn received_data() {
+        let mut wire = FollowEvent::Data(6).serialize();
+        wire.extend_from_slice(b"hello ");
+        wire.extend(FollowEvent::Data(5).serialize());
+        wire.extend_from_slice(b"world");
+        wire.extend(FollowEvent::Rotated.serialize());
+        wire.extend(FollowEvent::Data(3).serialize());
+        wire.extend_from_slice(b"new");
+
+        let mut read = wire.as_slice();
+        let mut out = Vec::new();
+        assert_eq!(
+            receive(&mut read, &mut out).await.unwrap(),
+            FollowEvent::Rotated
+        );
+        assert_eq!(out, b"hello world");
+
+        // The data is cut short
+        let mut wire = FollowEvent::Data(10).serialize();
+        wire.extend_from_slice(b"short");
+        assert!(receive(&mut wire.as_slice(), &mut Vec::new())
+            .await
+            .is_err());
+        // The stream ends
+        assert!(receive(&mut read, &mut out).await.is_err());
+    }
+}
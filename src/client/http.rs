@@ -0,0 +1,70 @@
+//! HTTP(S) sources for PUT ("gateway mode")
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! Sometimes the data you want to put on a remote machine lives behind an HTTP URL
+//! which the client can reach, but the remote can't.
+//! With the `http-source` feature enabled, qcp accepts such a URL as the source of a PUT.
+//! The client fetches the data and streams it straight into the session, without touching local disk.
+//!
+//! If the HTTP server does not report a `Content-Length` (as with a chunked response),
+//! the data is streamed in the same way as standard input, so cannot be resumed.
+
+use anyhow::Result;
+use futures_util::TryStreamExt as _;
+use reqwest::Url;
+use tokio::io::AsyncRead;
+use tokio_util::io::StreamReader;
+
+/// Filename used when the URL doesn't suggest one (same as `wget`)
+const DEFAULT_FILENAME: &str = "index.html";
+
+/// An open HTTP response body, ready to be read
+pub(crate) struct HttpSource {
+    /// The response body
+    pub(crate) reader: Box<dyn AsyncRead + Send + Unpin>,
+    /// Length of the response body, if the server reported it
+    pub(crate) len: Option<u64>,
+    /// A filename to use in the session protocol, derived from the URL
+    pub(crate) filename: String,
+}
+
+impl HttpSource {
+    /// Starts fetching the given URL
+    pub(crate) async fn open(url: &str) -> Result<Self> {
+        let response = reqwest::get(url)
+            .await
+            .and_then(reqwest::Response::error_for_status)?;
+        let len = response.content_length();
+        let filename = filename_for(response.url());
+        let stream = response.bytes_stream().map_err(std::io::Error::other);
+        Ok(Self {
+            reader: Box::new(StreamReader::new(stream)),
+            len,
+            filename,
+        })
+    }
+}
+
+/// Derives a filename from the last non-empty path segment of a URL
+fn filename_for(url: &Url) -> String {
+    url.path_segments()
+        .and_then(|mut segments| segments.rfind(|s| !s.is_empty()))
+        .unwrap_or(DEFAULT_FILENAME)
+        .to_string()
+}
+
+#[cfg(test)]
+mod test {
+    use super::filename_for;
+    use reqwest::Url;
+
+    #[test]
+    fn filenames() {
+        let f = |s: &str| filename_for(&Url::parse(s).unwrap());
+        assert_eq!(f("https://example.com/dir/file.tar.gz"), "file.tar.gz");
+        assert_eq!(f("https://example.com/dir/"), "dir");
+        assert_eq!(f("https://example.com/file?query=1"), "file");
+        assert_eq!(f("https://example.com"), "index.html");
+    }
+}
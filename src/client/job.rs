@@ -1,12 +1,18 @@
 //! Job specifications for the client
 // (c) 2024 Ross Younger
 
-use std::str::FromStr;
+use std::{path::Path, str::FromStr};
 
-use crate::transport::ThroughputMode;
+use anyhow::Context as _;
+use serde::{Deserialize, Serialize};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+use super::{exclude::Exclusions, LinkMode};
+use crate::{protocol::session::CommandType, transport::ThroughputMode};
 
 /// A file source or destination specified by the user
-#[derive(Debug, Clone, Default, PartialEq, Eq)]
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
 pub struct FileSpec {
     /// The remote host for the file. This may be a hostname or an IP address.
     /// It may also be a _hostname alias_ that matches a Host section in the user's ssh config file.
@@ -17,16 +23,52 @@ pub struct FileSpec {
     /// Filename
     ///
     /// If this is a destination, it might be a directory.
+    ///
+    /// If this is a source, it might be an HTTP(S) URL; see [`FileSpec::is_url`].
     pub filename: String,
 }
 
+impl FileSpec {
+    /// Is this an HTTP(S) URL?
+    ///
+    /// URLs are only supported as a source, and only when qcp is built with the `http-source` feature.
+    #[must_use]
+    pub fn is_url(&self) -> bool {
+        self.host.is_none()
+            && (self.filename.starts_with("http://") || self.filename.starts_with("https://"))
+    }
+
+    /// Is this `-`, meaning standard input (as a source) or standard output (as a destination)?
+    #[must_use]
+    pub fn is_stdio(&self) -> bool {
+        self.host.is_none() && self.filename == "-"
+    }
+}
+
+impl std::fmt::Display for FileSpec {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.host {
+            Some(host) if host.contains(':') => write!(f, "[{host}]:{}", self.filename),
+            Some(host) => write!(f, "{host}:{}", self.filename),
+            None => write!(f, "{}", self.filename),
+        }
+    }
+}
+
 impl FromStr for FileSpec {
     type Err = anyhow::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        if s.starts_with('[') {
+        if s.starts_with("http://") || s.starts_with("https://") {
+            // URL; not to be confused with host:file
+            Ok(Self {
+                host: None,
+                filename: s.to_owned(),
+            })
+        } else if s.starts_with('[') {
             // Assume raw IPv6 address [1:2:3::4]:File
             match s.split_once("]:") {
+                Some(("[", _)) => anyhow::bail!("{s}: empty IPv6 address"),
                 Some((hostish, filename)) => Ok(Self {
                     // lose the leading bracket as well so it can be looked up as if a hostname
                     host: Some(hostish[1..].to_owned()),
@@ -54,13 +96,249 @@ impl FromStr for FileSpec {
 }
 
 /// Details of a file copy job.
-#[derive(Debug, Clone)]
+#[derive(Debug, Clone, Deserialize, Serialize)]
 pub struct CopyJobSpec {
     pub(crate) source: FileSpec,
     pub(crate) destination: FileSpec,
+    /// Set if this job is part of a recursive copy
+    pub(crate) tree: Option<TreeEntry>,
+}
+
+/// The role of a job within a recursive copy (see [`CopyJobSpec::expand_tree`])
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub(crate) enum TreeEntry {
+    /// Create a directory. The destination is its full path.
+    Directory,
+    /// Send a file. The destination is the top-level destination directory;
+    /// this is the path of the file relative to it, with `/` separators.
+    File(String),
+    /// Create a symbolic link to this target. The destination is the full path of the link.
+    Symlink(String),
+}
+
+/// Characters which make a filename a wildcard pattern
+const WILDCARDS: &[char] = &['*', '?', '['];
+
+/// Joins a relative path onto a remote directory, which may be empty (meaning the remote working directory)
+pub(crate) fn join_remote(dir: &str, relative: &str) -> String {
+    if dir.is_empty() || dir.ends_with('/') {
+        format!("{dir}{relative}")
+    } else {
+        format!("{dir}/{relative}")
+    }
 }
 
 impl CopyJobSpec {
+    /// Validating constructor.
+    ///
+    /// Exactly one of the source and destination must be remote.
+    pub fn try_new(source: FileSpec, destination: FileSpec) -> anyhow::Result<Self> {
+        if destination.is_url() {
+            anyhow::bail!("URLs are only supported as a source");
+        }
+        if !(source.host.is_none() ^ destination.host.is_none()) {
+            anyhow::bail!("One file argument must be remote");
+        }
+        if source.is_url() && !cfg!(feature = "http-source") {
+            anyhow::bail!(
+                "This build of qcp does not support URL sources (requires the http-source feature)"
+            );
+        }
+        Ok(Self {
+            source,
+            destination,
+            tree: None,
+        })
+    }
+
+    /// Expands a wildcard pattern in a local source filename into one job per matching path.
+    ///
+    /// This is for when the shell didn't expand the pattern, for example because it was quoted.
+    /// Matching directories are included only if `recursive` is set; otherwise they are skipped with a warning.
+    /// Wildcards in remote filenames are not supported, since we can't see the remote filesystem;
+    /// they are passed through literally.
+    ///
+    /// Jobs whose source is not a local wildcard pattern are returned unchanged.
+    pub(crate) fn expand_glob(self, recursive: bool) -> anyhow::Result<Vec<Self>> {
+        let pattern = &self.source.filename;
+        if !pattern.contains(WILDCARDS) || self.source.is_url() {
+            return Ok(vec![self]);
+        }
+        if self.source.host.is_some() {
+            warn!(
+                "Wildcards in remote filenames are not supported; looking for {pattern} literally"
+            );
+            return Ok(vec![self]);
+        }
+        if Path::new(pattern).exists() {
+            // The filename really does contain wildcard characters
+            return Ok(vec![self]);
+        }
+
+        let mut jobs = Vec::new();
+        let paths = glob::glob(pattern)
+            .map_err(|e| anyhow::anyhow!("Invalid wildcard pattern {pattern}: {e}"))?;
+        for path in paths {
+            let path = path?;
+            if path.is_dir() && !recursive {
+                warn!(
+                    "Skipping directory {} (use --recursive to copy directories)",
+                    path.display()
+                );
+                continue;
+            }
+            let Some(filename) = path.to_str() else {
+                anyhow::bail!("{}: filename is not valid UTF-8", path.display());
+            };
+            jobs.push(Self {
+                source: FileSpec {
+                    host: None,
+                    filename: filename.to_string(),
+                },
+                ..self.clone()
+            });
+        }
+        if jobs.is_empty() {
+            anyhow::bail!("{pattern}: no matching files");
+        }
+        let dest = &self.destination.filename;
+        if jobs.len() > 1 && !dest.is_empty() && !dest.ends_with('/') {
+            anyhow::bail!(
+                "{pattern} matches several files, so the destination must be a directory ending in `/`"
+            );
+        }
+        Ok(jobs)
+    }
+
+    /// Expands a PUT of a local directory into the jobs needed to send the whole tree.
+    ///
+    /// Like `scp -r`, the directory is copied _into_ the destination directory, which is created if necessary.
+    /// The output contains all the directories (parents first), then all the files and symbolic links.
+    /// Symbolic links within the tree are treated according to `links`.
+    /// Entries matching `exclude` are skipped; an excluded directory is not walked at all.
+    ///
+    /// Jobs which do not send a local directory are returned unchanged.
+    pub(crate) fn expand_tree(
+        self,
+        links: LinkMode,
+        exclude: &Exclusions,
+    ) -> anyhow::Result<Vec<Self>> {
+        let root = Path::new(&self.source.filename);
+        if self.command_type() != CommandType::Put
+            || self.source.is_url()
+            || self.source.is_stdio()
+            || !root.is_dir()
+        {
+            return Ok(vec![self]);
+        }
+        // Work out the name of the directory, even if we were given `.` or `dir/..`
+        let name = std::fs::canonicalize(root)?
+            .file_name()
+            .and_then(|n| n.to_str())
+            .map(str::to_string)
+            .with_context(|| format!("{}: cannot copy this directory by name", self.source))?;
+
+        let mut directories = Vec::new();
+        let mut files = Vec::new();
+        let walker = WalkDir::new(root)
+            .follow_links(links == LinkMode::Follow)
+            .into_iter()
+            .filter_entry(|entry| {
+                let relative = entry.path().strip_prefix(root).unwrap_or(entry.path());
+                let excluded =
+                    entry.depth() > 0 && exclude.is_excluded(relative, entry.file_type().is_dir());
+                if excluded {
+                    debug!("Excluding {}", entry.path().display());
+                }
+                !excluded
+            });
+        for entry in walker {
+            let entry = match entry {
+                Ok(entry) => entry,
+                Err(e) if e.loop_ancestor().is_some() => {
+                    warn!("Skipping symbolic link loop: {e}");
+                    continue;
+                }
+                Err(e) => return Err(e.into()),
+            };
+            let relative = entry.path().strip_prefix(root)?;
+            let mut tree_path = name.clone();
+            for component in relative {
+                let component = component.to_str().with_context(|| {
+                    format!("{}: filename is not valid UTF-8", entry.path().display())
+                })?;
+                tree_path.push('/');
+                tree_path.push_str(component);
+            }
+            let Some(source) = entry.path().to_str() else {
+                anyhow::bail!("{}: filename is not valid UTF-8", entry.path().display());
+            };
+            let source = FileSpec {
+                host: None,
+                filename: source.to_string(),
+            };
+            if entry.file_type().is_dir() {
+                directories.push(Self {
+                    source,
+                    destination: FileSpec {
+                        host: self.destination.host.clone(),
+                        filename: join_remote(&self.destination.filename, &tree_path),
+                    },
+                    tree: Some(TreeEntry::Directory),
+                });
+            } else if entry.file_type().is_file() {
+                files.push(Self {
+                    source,
+                    destination: self.destination.clone(),
+                    tree: Some(TreeEntry::File(tree_path)),
+                });
+            } else if entry.file_type().is_symlink() {
+                // We only see links here if we're not following them
+                if links == LinkMode::Skip {
+                    debug!("Skipping symbolic link {}", entry.path().display());
+                    continue;
+                }
+                let target = std::fs::read_link(entry.path())?;
+                let target = target.to_str().with_context(|| {
+                    format!("{}: link target is not valid UTF-8", entry.path().display())
+                })?;
+                files.push(Self {
+                    source,
+                    destination: FileSpec {
+                        host: self.destination.host.clone(),
+                        filename: join_remote(&self.destination.filename, &tree_path),
+                    },
+                    tree: Some(TreeEntry::Symlink(target.to_string())),
+                });
+            } else {
+                warn!("Skipping {}: not a regular file", entry.path().display());
+            }
+        }
+        directories.append(&mut files);
+        Ok(directories)
+    }
+
+    /// Does this job create a directory, as part of a recursive copy?
+    pub(crate) fn is_directory(&self) -> bool {
+        self.tree == Some(TreeEntry::Directory)
+    }
+
+    /// If this job creates a symbolic link as part of a recursive copy, returns its target
+    pub(crate) fn symlink_target(&self) -> Option<&str> {
+        match &self.tree {
+            Some(TreeEntry::Symlink(target)) => Some(target),
+            _ => None,
+        }
+    }
+
+    /// If this job is part of a recursive copy, the full remote path of the entry it creates
+    pub(crate) fn remote_tree_path(&self) -> Option<String> {
+        match self.tree.as_ref()? {
+            TreeEntry::File(path) => Some(join_remote(&self.destination.filename, path)),
+            TreeEntry::Directory | TreeEntry::Symlink(_) => Some(self.destination.filename.clone()),
+        }
+    }
+
     /// What direction of data flow should we optimise for?
     pub(crate) fn throughput_mode(&self) -> ThroughputMode {
         if self.source.host.is_some() {
@@ -70,8 +348,30 @@ impl CopyJobSpec {
         }
     }
 
+    /// The session command needed to action this job
+    pub(crate) fn command_type(&self) -> CommandType {
+        if self.source.host.is_some() {
+            CommandType::Get
+        } else {
+            CommandType::Put
+        }
+    }
+
+    /// A display form of the remote destination of a PUT, in `HOST:PATH` form.
+    ///
+    /// If the destination appears to be a directory, the filename is appended.
+    pub(crate) fn remote_destination_display(&self, filename: &str) -> String {
+        let host = self.destination.host.as_deref().unwrap_or_default();
+        let dest = &self.destination.filename;
+        if dest.is_empty() || dest.ends_with('/') || self.tree.is_some() {
+            format!("{host}:{}", join_remote(dest, filename))
+        } else {
+            format!("{host}:{dest}")
+        }
+    }
+
     /// The `[user@]hostname` portion of whichever of the arguments contained a hostname.
-    fn remote_user_host(&self) -> &str {
+    pub(crate) fn remote_user_host(&self) -> &str {
         self.source
             .host
             .as_ref()
@@ -80,10 +380,22 @@ impl CopyJobSpec {
 
     /// The hostname portion of whichever of the arguments contained one.
     pub(crate) fn remote_host(&self) -> &str {
-        let user_host = self.remote_user_host();
-        // It might be user@host, or it might be just the hostname or IP.
-        let (_, host) = user_host.split_once('@').unwrap_or(("", user_host));
-        host
+        split_user_host(self.remote_user_host()).1
+    }
+}
+
+/// Makes a job from source and destination arguments, for tests
+#[cfg(test)]
+pub(crate) fn job(source: &str, destination: &str) -> CopyJobSpec {
+    CopyJobSpec::try_new(source.parse().unwrap(), destination.parse().unwrap()).unwrap()
+}
+
+/// Splits a `[user@]hostname` string into its username (if any) and hostname.
+pub(crate) fn split_user_host(user_host: &str) -> (Option<&str>, &str) {
+    // It might be user@host, or it might be just the hostname or IP.
+    match user_host.split_once('@') {
+        Some((user, host)) => (Some(user), host),
+        None => (None, user_host),
     }
 }
 
@@ -92,8 +404,8 @@ mod test {
     type Res = anyhow::Result<()>;
     use human_repr::HumanCount;
 
-    use super::FileSpec;
-    use std::str::FromStr;
+    use super::{CopyJobSpec, FileSpec};
+    use std::{path::PathBuf, str::FromStr};
 
     #[test]
     fn filename_no_host() -> Res {
@@ -141,6 +453,211 @@ mod test {
         assert_eq!(fs.filename, "file");
         Ok(())
     }
+    #[test]
+    fn malformed_ipv6() -> Res {
+        assert!(FileSpec::from_str("[]:file").is_err());
+        // Without a closing `]:` these might be local files (or wildcard patterns)
+        for s in ["[::1", "[::1]file", "["] {
+            let fs = FileSpec::from_str(s)?;
+            assert_eq!(fs.host, None);
+            assert_eq!(fs.filename, s);
+        }
+        Ok(())
+    }
+    #[test]
+    fn display_round_trip() -> Res {
+        for s in [
+            "/dir/file",
+            "host:file",
+            "user@host:",
+            "[::1]:file",
+            "https://x/y",
+        ] {
+            assert_eq!(FileSpec::from_str(s)?.to_string(), s);
+        }
+        Ok(())
+    }
+    #[test]
+    fn url() -> Res {
+        let fs = FileSpec::from_str("https://example.com:8443/file")?;
+        assert!(fs.host.is_none());
+        assert!(fs.is_url());
+        assert_eq!(fs.filename, "https://example.com:8443/file");
+        assert!(!FileSpec::from_str("http:file")?.is_url());
+        assert!(!FileSpec::from_str("/tmp/file")?.is_url());
+        Ok(())
+    }
+    #[test]
+    fn stdio() -> Res {
+        assert!(FileSpec::from_str("-")?.is_stdio());
+        assert!(!FileSpec::from_str("host:-")?.is_stdio());
+        assert!(!FileSpec::from_str("./-")?.is_stdio());
+        Ok(())
+    }
+    #[test]
+    fn remote_destination_display() -> Res {
+        let job = |dest: &str| -> anyhow::Result<CopyJobSpec> {
+            Ok(CopyJobSpec {
+                source: FileSpec::from_str("/tmp/file")?,
+                destination: FileSpec::from_str(dest)?,
+                tree: None,
+            })
+        };
+        assert_eq!(
+            job("host:")?.remote_destination_display("file"),
+            "host:file"
+        );
+        assert_eq!(
+            job("host:dir/")?.remote_destination_display("file"),
+            "host:dir/file"
+        );
+        assert_eq!(
+            job("host:other")?.remote_destination_display("file"),
+            "host:other"
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn expand_glob() -> Res {
+        let tmp = tempfile::tempdir()?;
+        let dir = tmp.path();
+        std::fs::write(dir.join("a.iso"), "a")?;
+        std::fs::write(dir.join("b.iso"), "b")?;
+        std::fs::write(dir.join("c.txt"), "c")?;
+        std::fs::create_dir(dir.join("d.iso"))?;
+        let job = |src: &str, dest: &str| -> anyhow::Result<CopyJobSpec> {
+            CopyJobSpec::try_new(FileSpec::from_str(src)?, FileSpec::from_str(dest)?)
+        };
+        let pattern = dir.join("*.iso");
+        let pattern = pattern.to_str().unwrap();
+        let sources = |jobs: Vec<CopyJobSpec>| {
+            jobs.into_iter()
+                .map(|j| {
+                    PathBuf::from(j.source.filename)
+                        .file_name()
+                        .unwrap()
+                        .to_string_lossy()
+                        .to_string()
+                })
+                .collect::<Vec<_>>()
+        };
+
+        // directories are skipped unless recursive
+        let jobs = job(pattern, "host:dest/")?.expand_glob(false)?;
+        assert_eq!(sources(jobs), ["a.iso", "b.iso"]);
+        let jobs = job(pattern, "host:")?.expand_glob(true)?;
+        assert_eq!(sources(jobs), ["a.iso", "b.iso", "d.iso"]);
+
+        // several files need a directory destination
+        assert!(job(pattern, "host:dest")?.expand_glob(false).is_err());
+        // no matches
+        let none = dir.join("*.zip");
+        assert!(job(none.to_str().unwrap(), "host:")?
+            .expand_glob(false)
+            .is_err());
+        // remote patterns pass through
+        let jobs = job("host:*.iso", "/tmp/")?.expand_glob(false)?;
+        assert_eq!(jobs[0].source.filename, "*.iso");
+        Ok(())
+    }
+
+    #[test]
+    fn expand_tree() -> Res {
+        use super::{Exclusions, LinkMode, TreeEntry};
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path().join("top");
+        std::fs::create_dir_all(root.join("sub"))?;
+        std::fs::create_dir_all(root.join("empty"))?;
+        std::fs::write(root.join("a"), "a")?;
+        std::fs::write(root.join("sub/b"), "b")?;
+        #[cfg(unix)]
+        std::os::unix::fs::symlink("..", root.join("sub/loop"))?;
+
+        let job = CopyJobSpec::try_new(
+            FileSpec::from_str(root.to_str().unwrap())?,
+            FileSpec::from_str("host:dest")?,
+        )?;
+        let mut jobs = job.expand_tree(LinkMode::Follow, &Exclusions::default())?;
+        // Directories come first
+        let files = jobs.split_off(jobs.iter().position(|j| !j.is_directory()).unwrap());
+        let mut dirs = jobs
+            .iter()
+            .map(|j| j.destination.to_string())
+            .collect::<Vec<_>>();
+        dirs.sort();
+        assert_eq!(
+            dirs,
+            ["host:dest/top", "host:dest/top/empty", "host:dest/top/sub"]
+        );
+        assert_eq!(
+            files[0].remote_destination_display("top/a"),
+            "host:dest/top/a"
+        );
+        let mut files = files
+            .iter()
+            .map(|j| {
+                assert_eq!(j.destination.filename, "dest");
+                match &j.tree {
+                    Some(TreeEntry::File(path)) => path.clone(),
+                    other => panic!("unexpected {other:?}"),
+                }
+            })
+            .collect::<Vec<_>>();
+        files.sort();
+        assert_eq!(files, ["top/a", "top/sub/b"]);
+
+        // Plain files pass through unchanged
+        let job = CopyJobSpec::try_new(
+            FileSpec::from_str(root.join("a").to_str().unwrap())?,
+            FileSpec::from_str("host:dest")?,
+        )?;
+        let jobs = job.expand_tree(LinkMode::Follow, &Exclusions::default())?;
+        assert_eq!(jobs.len(), 1);
+        assert!(jobs[0].tree.is_none());
+        Ok(())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn expand_tree_links() -> Res {
+        use super::{Exclusions, LinkMode};
+        let tmp = tempfile::tempdir()?;
+        let root = tmp.path().join("top");
+        std::fs::create_dir_all(root.join("sub"))?;
+        std::fs::write(root.join("a"), "a")?;
+        std::os::unix::fs::symlink("../a", root.join("sub/link"))?;
+        std::os::unix::fs::symlink("..", root.join("sub/loop"))?;
+        let job = CopyJobSpec::try_new(
+            FileSpec::from_str(root.to_str().unwrap())?,
+            FileSpec::from_str("host:dest")?,
+        )?;
+
+        let jobs = job
+            .clone()
+            .expand_tree(LinkMode::Skip, &Exclusions::default())?;
+        assert_eq!(jobs.len(), 3); // top, top/sub, top/a
+        assert!(jobs.iter().all(|j| j.symlink_target().is_none()));
+
+        let mut links = job
+            .expand_tree(LinkMode::Copy, &Exclusions::default())?
+            .into_iter()
+            .filter_map(|j| {
+                j.symlink_target()
+                    .map(|t| (j.destination.to_string(), t.to_string()))
+            })
+            .collect::<Vec<_>>();
+        links.sort();
+        assert_eq!(
+            links,
+            [
+                ("host:dest/top/sub/link".to_string(), "../a".to_string()),
+                ("host:dest/top/sub/loop".to_string(), "..".to_string())
+            ]
+        );
+        Ok(())
+    }
+
     #[test]
     fn size_is_kb_not_kib() {
         // same mechanism that clap uses
@@ -0,0 +1,151 @@
+//! Programmatic interface, for embedding qcp in other tools
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! [`client_main`](super::client_main) is driven by command-line [`Parameters`](super::Parameters) and draws
+//! progress bars on the console. A tool which embeds qcp wants neither; it wants to make a copy and find out
+//! how it went. [`copy`] runs a single job with no console output of its own, and returns a [`TransferReport`].
+//! To follow the progress of the job, use [`copy_with_progress`] with your own [`ProgressSink`].
+//!
+//! Errors are classified as they would be for the exit status of the `qcp` command; see [`FailureKind::of`].
+//!
+//! Logging is via [`tracing`], so appears wherever the calling program's subscriber sends it.
+//! Output from the remote, and any ssh prompts, go to the terminal as usual.
+
+use std::{sync::Arc, time::Duration};
+
+use anyhow::Result;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget};
+use quinn::ConnectionStats;
+use tokio::time::Instant;
+use tracing::trace_span;
+
+use super::{
+    main_loop::{run_job, HostConnection, JobOptions},
+    CopyJobSpec, Parameters, ProgressSink,
+};
+use crate::{
+    config::Configuration,
+    util::{
+        compress::Compress,
+        failure::{Classify as _, FailureKind},
+        io::IoLimiter,
+        time::StopwatchChain,
+        Credentials,
+    },
+};
+
+/// The outcome of a successful [`copy`]
+#[derive(Debug, Clone)]
+pub struct TransferReport {
+    /// Payload bytes transferred
+    pub bytes: u64,
+    /// Time taken by the transfer itself, excluding connection setup and closedown
+    pub duration: Duration,
+    /// Statistics for the QUIC connection, from our side
+    pub stats: ConnectionStats,
+}
+
+/// Copies a single file to or from a remote host.
+///
+/// This connects to the remote (via ssh, then QUIC), runs the job, and tears down the connection,
+/// just as the `qcp` command does. There is no console output other than any from ssh.
+/// An existing destination file is overwritten, as if `--force` had been given.
+///
+/// # Example
+/// ```no_run
+/// # async fn example() -> anyhow::Result<()> {
+/// use qcp::{client::CopyJobSpec, config::Configuration};
+/// let job = CopyJobSpec::try_new("local.txt".parse()?, "myhost:remote.txt".parse()?)?;
+/// let report = qcp::client::copy(job, &Configuration::default()).await?;
+/// println!("sent {} bytes in {:?}", report.bytes, report.duration);
+/// # Ok(())
+/// # }
+/// ```
+pub async fn copy(job: CopyJobSpec, config: &Configuration) -> Result<TransferReport> {
+    run(job, config, None).await
+}
+
+/// Copies a single file to or from a remote host, reporting the progress of the transfer.
+///
+/// This is the same as [`copy`], except that `progress` is told the size and name of the file,
+/// and then the number of bytes transferred as the transfer proceeds.
+pub async fn copy_with_progress(
+    job: CopyJobSpec,
+    config: &Configuration,
+    progress: Arc<dyn ProgressSink>,
+) -> Result<TransferReport> {
+    run(job, config, Some(progress)).await
+}
+
+async fn run(
+    job: CopyJobSpec,
+    config: &Configuration,
+    progress: Option<Arc<dyn ProgressSink>>,
+) -> Result<TransferReport> {
+    let _guard = trace_span!("CLIENT").entered();
+    // There is nobody to ask whether to overwrite an existing destination
+    let parameters = Parameters {
+        quiet: true,
+        force: true,
+        ..Parameters::default()
+    };
+    let display = MultiProgress::with_draw_target(ProgressDrawTarget::hidden());
+    let spinner = ProgressBar::hidden();
+    let credentials = Credentials::load_or_generate(&config.tls_cert, &config.tls_key)
+        .classify(FailureKind::Config)?;
+    let mut timers = StopwatchChain::new_running("setup");
+    let user_hostname = job.remote_user_host().to_string();
+    let mut host = HostConnection::establish(
+        &user_hostname,
+        &credentials,
+        &display,
+        &spinner,
+        &mut timers,
+        config,
+        &parameters,
+        job.throughput_mode(),
+    )
+    .await
+    .classify(FailureKind::Connection)?;
+
+    let command = job.command_type();
+    let result = if host.allowed_commands.contains(&command) {
+        // We can only compress what we send if the remote can decompress it
+        let config = Configuration {
+            compress: if host.compression {
+                config.compress
+            } else {
+                Compress::Off
+            },
+            ..config.clone()
+        };
+        let mut options = JobOptions::new(&parameters).for_host(&host);
+        options.progress = progress;
+        let start = Instant::now();
+        let (_, result) = run_job(
+            host.connection.clone(),
+            job,
+            display,
+            spinner,
+            config.clone(),
+            IoLimiter::new(config.io_concurrency),
+            options,
+        )
+        .await;
+        result.map(|bytes| (bytes, start.elapsed()))
+    } else {
+        Err(anyhow::anyhow!(
+            "{user_hostname} does not allow {command} commands"
+        ))
+    };
+    let stats = host.connection.stats();
+    let closed = host.close(config).await;
+    let (bytes, duration) = result?;
+    let _ = closed?;
+    Ok(TransferReport {
+        bytes,
+        duration,
+        stats,
+    })
+}
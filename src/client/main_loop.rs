@@ -5,37 +5,521 @@ use crate::{
     client::{control::Channel, progress::spinner_style},
     config::Configuration,
     protocol::{
-        session::{FileHeader, FileTrailer, Response, Status},
+        control::{ClosedownReport, PROTOCOL_VERSION},
+        session::{with_timeout, Command, CommandType, FileHeader, FileTrailer, Response, Status},
         RawStreamPair, StreamPair,
     },
     transport::ThroughputMode,
-    util::{self, lookup_host_by_family, time::Stopwatch, time::StopwatchChain, Credentials},
+    util::{
+        self,
+        compress::{self, Compress},
+        failure::{Classify as _, FailureKind},
+        mmap::{self, SourceReader},
+        time::Stopwatch,
+        time::StopwatchChain,
+        AddressFamily, Credentials, PortRange, Resolver,
+    },
 };
 
-use anyhow::{Context, Result};
-use futures_util::TryFutureExt as _;
+use anyhow::Result;
+use human_repr::{HumanCount as _, HumanDuration as _};
 use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
 use quinn::crypto::rustls::QuicClientConfig;
-use quinn::{rustls, Connection, EndpointConfig};
+use quinn::{rustls, Connection};
 use rustls::RootCertStore;
 use rustls_pki_types::CertificateDer;
-use std::net::{SocketAddr, SocketAddrV4, SocketAddrV6};
-use std::path::PathBuf;
+use std::net::{IpAddr, SocketAddr, SocketAddrV4, SocketAddrV6};
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
-use tokio::io::{AsyncWriteExt, BufReader};
+use tokio::io::{
+    AsyncBufRead, AsyncBufReadExt as _, AsyncReadExt as _, AsyncSeekExt as _, AsyncWriteExt,
+    BufReader,
+};
 use tokio::time::Instant;
-use tokio::{self, io::AsyncReadExt, time::timeout, time::Duration};
+use tokio::{self, time::timeout, time::Duration};
 use tracing::{debug, error, info, span, trace, trace_span, warn, Instrument as _, Level};
 
-use super::job::CopyJobSpec;
-use super::Parameters as ClientParameters;
+use super::job::{split_user_host, CopyJobSpec, FileSpec, TreeEntry};
+use super::meter::{InstaMeterRunner, StatsOutput};
+use super::mirror::Mirror;
+use super::overwrite::{DestinationExists, Overwrite};
+use super::partial::{Partial, PartialGuard};
+use super::progress::{ProgressReader, ProgressSink, ProgressWriter};
+use super::skip::SkipMode;
+use super::state::StateFile;
+use super::total::{TotalProgress, WithTotal};
+use super::{Parameters as ClientParameters, Preserve};
 
 /// a shared definition string used in a couple of places
 const SHOW_TIME: &str = "file transfer";
 
+/// A control channel and QUIC connection to a single remote host.
+///
+/// This is held open for as long as there are jobs for that host, so it may be reused
+/// by multiple calls to [`manage_request`].
+pub(super) struct HostConnection {
+    /// The `[user@]hostname` as given by the user (this is the lookup key)
+    pub(super) user_hostname: String,
+    control: Channel,
+    endpoint: quinn::Endpoint,
+    pub(super) connection: Connection,
+    /// Session commands the server permits
+    pub(super) allowed_commands: Vec<CommandType>,
+    /// Whether the server accepts compressed file data
+    pub(super) compression: bool,
+    /// The control protocol version in use with the server
+    pub(super) protocol_version: u16,
+    /// Number of jobs run on this connection so far
+    jobs: usize,
+    /// Payload bytes transferred on this connection so far
+    transferred: Transferred,
+}
+
+/// Payload byte counts, by direction
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Transferred {
+    /// Bytes sent to the remote (PUT)
+    sent: u64,
+    /// Bytes received from the remote (GET)
+    received: u64,
+    /// Jobs skipped because their destinations were already present; these transferred nothing
+    skipped: usize,
+}
+
+impl Transferred {
+    fn add(&mut self, command: CommandType, bytes: u64) {
+        match command {
+            CommandType::Get => self.received += bytes,
+            CommandType::Put => self.sent += bytes,
+            CommandType::Delete => unreachable!("a copy job is never a Delete"),
+        }
+    }
+
+    fn total(self) -> u64 {
+        self.sent + self.received
+    }
+}
+
+/// How long to wait for a connection via the preferred address family before also trying the other.
+///
+/// RFC 8305 recommends 250ms, but that is for a TCP handshake; here we are racing an ssh login
+/// and a QUIC handshake, which take rather longer.
+const HAPPY_EYEBALLS_DELAY: Duration = Duration::from_secs(2);
+
+/// How long to wait before the first retry of a failed connection; this doubles with each retry
+const RETRY_INITIAL_DELAY: Duration = Duration::from_secs(1);
+
+/// Everything needed to attempt a connection to a host, other than its address
+struct ConnectionTarget<'a> {
+    user_hostname: &'a str,
+    host: &'a str,
+    ssh_destination: String,
+    ssh_settings: super::ssh::HostSettings,
+    credentials: &'a Credentials,
+    display: &'a MultiProgress,
+    spinner: &'a ProgressBar,
+    config: &'a Configuration,
+    parameters: &'a ClientParameters,
+    mode: ThroughputMode,
+}
+
+impl HostConnection {
+    /// Opens the control channel to a host, then the QUIC connection.
+    ///
+    /// If the address family is not specified and the host has both IPv4 and IPv6 addresses,
+    /// the two are tried in a staggered race (in the manner of RFC 8305 "happy eyeballs"),
+    /// and whichever connects first is used.
+    #[allow(clippy::too_many_arguments)]
+    pub(super) async fn establish(
+        user_hostname: &str,
+        credentials: &Credentials,
+        display: &MultiProgress,
+        spinner: &ProgressBar,
+        timers: &mut StopwatchChain,
+        config: &Configuration,
+        parameters: &ClientParameters,
+        mode: ThroughputMode,
+    ) -> Result<Self> {
+        let (user, host) = split_user_host(user_hostname);
+        if let Some(jump) = super::ssh::jump_host(host, &config.ssh_options, &config.ssh_config) {
+            anyhow::bail!(
+                "{host} is reached via the jump host {jump} (ProxyJump), which qcp does not support: \
+                the data channel is a direct UDP connection to the remote host, which ssh cannot carry. \
+                If {host} is in fact directly reachable, try `-S -oProxyJump=none`."
+            );
+        }
+        let remote_host =
+            super::ssh::resolve_host_alias(host, &config.ssh_config).unwrap_or_else(|| host.into());
+        let resolver = config.resolver()?;
+        let (remote_host, srv_config) = srv_target(remote_host, &resolver, config).await;
+        let config = srv_config.as_ref().unwrap_or(config);
+
+        // If the user didn't specify the address family: we do the DNS lookup, figure it out and tell ssh to use that.
+        // (Otherwise if we resolved a v4 and ssh a v6 - as might happen with round-robin DNS - that could be surprising.)
+        let candidates = resolver
+            .lookup_host_candidates(&remote_host, config.address_family)
+            .await?;
+
+        // As we give ssh the resolved host name, it won't apply the settings for the alias itself
+        let ssh_settings = super::ssh::host_settings(host, &config.ssh_options, &config.ssh_config);
+        // ssh needs to know the username, if one was given
+        let ssh_destination = user
+            .or(ssh_settings.user.as_deref())
+            .map_or_else(|| remote_host.clone(), |u| format!("{u}@{remote_host}"));
+        let target = ConnectionTarget {
+            user_hostname,
+            host,
+            ssh_destination,
+            ssh_settings,
+            credentials,
+            display,
+            spinner,
+            config,
+            parameters,
+            mode,
+        };
+        target.connect_with_retries(&candidates, timers).await
+    }
+
+    /// Gracefully tears down the QUIC connection and the control channel.
+    /// Returns the closedown report from the remote.
+    pub(super) async fn close(&mut self, config: &Configuration) -> Result<ClosedownReport> {
+        debug!(
+            "Closing connection to {} after {} job(s)",
+            self.user_hostname, self.jobs
+        );
+        // Forcibly (but gracefully) tear down QUIC. All the requests have completed or errored.
+        self.endpoint.close(1u8.into(), "finished".as_bytes());
+        let remote_stats = self.control.read_closedown_report().await?;
+
+        let control_fut = self.control.close();
+        let _ = timeout(
+            config.closedown_timeout_duration(),
+            self.endpoint.wait_idle(),
+        )
+        .await
+        .inspect_err(|_| warn!("QUIC shutdown timed out")); // otherwise ignore errors
+        trace!("QUIC closed; waiting for control channel");
+        let _ = timeout(config.closedown_timeout_duration(), control_fut)
+            .await
+            .inspect_err(|_| warn!("control channel timed out"));
+        // Ignore errors. If the control channel closedown times out, we expect its drop handler will do the Right Thing.
+        Ok(remote_stats)
+    }
+}
+
+/// With `srv`, looks up where to find the qcp service for a host in DNS.
+///
+/// Returns the host to connect to, and, if the remote port is to come from the SRV record,
+/// the configuration to use instead of `config`.
+/// If there is no SRV record, or the lookup fails, the host is used as it is.
+async fn srv_target(
+    host: String,
+    resolver: &Resolver,
+    config: &Configuration,
+) -> (String, Option<Configuration>) {
+    if !config.srv {
+        return (host, None);
+    }
+    let (target, port) = match resolver.lookup_srv(&host).await {
+        Ok(Some(found)) => found,
+        Ok(None) => {
+            debug!("no SRV record for {host}; resolving it as usual");
+            return (host, None);
+        }
+        Err(e) => {
+            warn!("{e:#}; resolving {host} as usual");
+            return (host, None);
+        }
+    };
+    debug!("SRV record for {host}: {target} port {port}");
+    if !config.remote_port.is_default() {
+        // An explicit setting takes precedence
+        return (target, None);
+    }
+    let config = Configuration {
+        remote_port: PortRange {
+            begin: port,
+            end: port,
+        },
+        ..config.clone()
+    };
+    (target, Some(config))
+}
+
+impl ConnectionTarget<'_> {
+    /// Makes connection attempts, retrying connection failures as configured.
+    ///
+    /// Only failures tagged as [`FailureKind::Connection`] are retried; authentication failures are not,
+    /// and nor are errors in the protocol. The delay doubles with each retry.
+    /// No retry starts after the configured deadline, and a retry which is still running at the deadline
+    /// is abandoned. (The first attempt is not, as ssh may be waiting for the user to type a password.)
+    async fn connect_with_retries(
+        &self,
+        candidates: &[IpAddr],
+        timers: &mut StopwatchChain,
+    ) -> Result<HostConnection> {
+        let deadline = Instant::now() + self.config.retry_deadline_duration();
+        let mut delay = RETRY_INITIAL_DELAY;
+        let mut error = match self.attempt(candidates, timers).await {
+            Ok(connection) => return Ok(connection),
+            Err(e) => e,
+        };
+        for attempt in 1..=self.config.retries {
+            if FailureKind::of(&error) != FailureKind::Connection
+                || Instant::now() + delay >= deadline
+            {
+                break;
+            }
+            info!(
+                "Connection to {} failed: {error:#}; retry {attempt} of {} in {}",
+                self.host,
+                self.config.retries,
+                delay.human_duration()
+            );
+            self.spinner.set_message("Waiting to retry connection");
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+            error = match tokio::time::timeout_at(deadline, self.attempt(candidates, timers)).await
+            {
+                Ok(Ok(connection)) => return Ok(connection),
+                Ok(Err(e)) => e,
+                Err(_) => FailureKind::Connection.tag(anyhow::anyhow!(
+                    "connection retries to {} timed out after {}",
+                    self.host,
+                    self.config.retry_deadline_duration().human_duration()
+                )),
+            };
+        }
+        Err(error)
+    }
+
+    /// Makes one connection attempt, racing the two candidate addresses if there are two
+    async fn attempt(
+        &self,
+        candidates: &[IpAddr],
+        timers: &mut StopwatchChain,
+    ) -> Result<HostConnection> {
+        match *candidates {
+            [preferred, other] => {
+                timers.next("control and data channels");
+                self.race(preferred, other).await
+            }
+            [address] => self.connect(address, self.config, Some(timers)).await,
+            _ => unreachable!("lookup_host_candidates returns one or two addresses"),
+        }
+    }
+
+    /// Attempts to connect via the given address: opens the control channel, then the QUIC connection.
+    ///
+    /// `config` may differ from the target's own configuration in its ssh options.
+    /// If `timers` is given, the phases of setup are recorded in it.
+    async fn connect(
+        &self,
+        remote_address: IpAddr,
+        config: &Configuration,
+        mut timers: Option<&mut StopwatchChain>,
+    ) -> Result<HostConnection> {
+        let spinner = self.spinner;
+        // Control channel ---------------
+        spinner.set_message("Opening control channel");
+        spinner.disable_steady_tick(); // otherwise the spinner messes with ssh passphrase prompting; as we're using tokio spinner.suspend() isn't helpful
+        if let Some(t) = timers.as_deref_mut() {
+            t.next("control channel");
+        }
+        let (control, server_message) = Channel::transact(
+            self.credentials,
+            &self.ssh_destination,
+            &self.ssh_settings,
+            remote_address.into(),
+            self.display,
+            config,
+            self.parameters,
+        )
+        .await?;
+
+        // Data channel ------------------
+        let remote_address = match server_message.advertised_address.as_deref() {
+            Some(addr) => {
+                debug!("Server advertised address {addr}");
+                // The server bound its endpoint for the address family we asked for
+                let family = if remote_address.is_ipv4() {
+                    AddressFamily::Inet
+                } else {
+                    AddressFamily::Inet6
+                };
+                config
+                    .resolver()?
+                    .lookup_host_by_family(addr, family)
+                    .await?
+            }
+            None => remote_address,
+        };
+        let server_address_port = match remote_address {
+            std::net::IpAddr::V4(ip) => SocketAddrV4::new(ip, server_message.port).into(),
+            std::net::IpAddr::V6(ip) => SocketAddrV6::new(ip, server_message.port, 0, 0).into(),
+        };
+
+        spinner.enable_steady_tick(Duration::from_millis(150));
+        spinner.set_message("Establishing data channel");
+        if let Some(t) = timers {
+            t.next("data channel setup");
+        }
+        let (endpoint, warning) = create_endpoint(
+            self.credentials,
+            server_message.cert.into(),
+            &server_address_port,
+            config,
+            self.mode,
+        )?;
+        if self.parameters.strict_buffers {
+            if let Some(w) = warning.or(server_message.warning) {
+                anyhow::bail!("{w}\n(--strict-buffers is in force. For help setting UDP buffer sizes, run `qcp --help-buffers`.)");
+            }
+        }
+
+        if config.compress != Compress::Off && !server_message.compression {
+            info!(
+                "{} does not support compression; files sent to it will not be compressed",
+                self.host
+            );
+        }
+
+        debug!("Opening QUIC connection to {server_address_port:?}");
+        debug!("Local endpoint address is {:?}", endpoint.local_addr()?);
+        let Ok(connection) = timeout(
+            config.timeout_duration(),
+            endpoint.connect(server_address_port, &server_message.name)?,
+        )
+        .await
+        else {
+            return Err(udp_timeout_advice(server_address_port, config, spinner).await);
+        };
+
+        Ok(HostConnection {
+            connection: connection.classify(FailureKind::Connection)?,
+            user_hostname: self.user_hostname.to_string(),
+            control,
+            endpoint,
+            allowed_commands: server_message.allowed_commands,
+            compression: server_message.compression,
+            protocol_version: server_message.protocol_version,
+            jobs: 0,
+            transferred: Transferred::default(),
+        })
+    }
+
+    /// Races connection attempts via two addresses, the preferred one first.
+    ///
+    /// The second attempt starts when the first fails, or after [`HAPPY_EYEBALLS_DELAY`].
+    /// If the two are running at once, the second runs ssh in batch mode, so the user is not
+    /// asked for a password twice.
+    /// The attempt that loses is dropped, which kills its ssh process and with it the remote server.
+    async fn race(&self, preferred: IpAddr, other: IpAddr) -> Result<HostConnection> {
+        let first = self.connect(preferred, self.config, None);
+        tokio::pin!(first);
+        let first_error = tokio::select! {
+            result = &mut first => match result {
+                Ok(connection) => return Ok(connection),
+                Err(e) => {
+                    debug!("Connection via {preferred} failed: {e:#}");
+                    Some(e)
+                }
+            },
+            () = tokio::time::sleep(HAPPY_EYEBALLS_DELAY) => None,
+        };
+        if let Some(first_error) = first_error {
+            return self
+                .connect(other, self.config, None)
+                .await
+                .map_err(|e| race_failed(preferred, &first_error, other, &e));
+        }
+
+        debug!("No connection via {preferred} yet; also trying {other}");
+        let mut batch_options = self.config.ssh_options.clone();
+        batch_options.extend(["-o".into(), "BatchMode=yes".into()]);
+        let batch_config = Configuration {
+            ssh_options: batch_options,
+            ..self.config.clone()
+        };
+        let second = self.connect(other, &batch_config, None);
+        tokio::pin!(second);
+        tokio::select! {
+            result = &mut first => match result {
+                Ok(connection) => Ok(connection),
+                Err(e1) => second.await.map_err(|e2| race_failed(preferred, &e1, other, &e2)),
+            },
+            result = &mut second => match result {
+                Ok(connection) => Ok(connection),
+                Err(e2) => first.await.map_err(|e1| race_failed(preferred, &e1, other, &e2)),
+            },
+        }
+    }
+}
+
+/// Constructs the error for when the control channel worked but the QUIC connection timed out.
+///
+/// This is almost always a firewall dropping UDP, so we probe to find out more, and give advice.
+async fn udp_timeout_advice(
+    server: SocketAddr,
+    config: &Configuration,
+    spinner: &ProgressBar,
+) -> anyhow::Error {
+    spinner.set_message("Probing UDP connectivity");
+    let wait = (config.rtt_duration() * 2).max(Duration::from_secs(1));
+    let probe = match config.bind_ip() {
+        Ok(local) => super::probe::udp_probe(server, local, config.port, wait).await,
+        Err(e) => super::probe::ProbeResult::Failed(e.to_string()),
+    };
+    FailureKind::Connection.tag(anyhow::anyhow!(
+        "UDP connection to QUIC endpoint {server} timed out. {probe}\n\
+        The ssh connection worked, so check that firewalls allow UDP to port {port} on the remote \
+        (and outbound UDP from here). To make the remote use a predictable port range that you can open, \
+        set --remote-port (e.g. `--remote-port 60000-60100`).",
+        port = server.port()
+    ))
+}
+
+/// Constructs the error for when connection attempts via both address families have failed
+fn race_failed(
+    first: IpAddr,
+    first_error: &anyhow::Error,
+    second: IpAddr,
+    second_error: &anyhow::Error,
+) -> anyhow::Error {
+    // If ssh reached the host but could not log in, that is the thing to fix
+    let kind = if [first_error, second_error]
+        .iter()
+        .any(|e| FailureKind::of(e) == FailureKind::Authentication)
+    {
+        FailureKind::Authentication
+    } else {
+        FailureKind::Connection
+    };
+    kind.tag(anyhow::anyhow!(
+        "could not connect via {first} ({first_error:#}) or via {second} ({second_error:#})"
+    ))
+}
+
+/// Determines the throughput mode to configure for a host, given all the jobs to be run against it
+fn throughput_mode_for(jobs: &[CopyJobSpec], user_hostname: &str) -> ThroughputMode {
+    let mut modes = jobs
+        .iter()
+        .filter(|j| j.remote_user_host() == user_hostname)
+        .map(CopyJobSpec::throughput_mode);
+    let Some(first) = modes.next() else {
+        return ThroughputMode::Both;
+    };
+    if modes.all(|m| m == first) {
+        first
+    } else {
+        ThroughputMode::Both
+    }
+}
+
 /// Main client mode event loop
 // Caution: As we are using ProgressBar, anything to be printed to console should use progress.println() !
-#[allow(clippy::module_name_repetitions)]
+#[allow(clippy::module_name_repetitions, clippy::too_many_lines)]
 pub async fn client_main(
     config: &Configuration,
     display: MultiProgress,
@@ -44,6 +528,7 @@ pub async fn client_main(
     // N.B. While we have a MultiProgress we do not set up any `ProgressBar` within it yet...
     // not until the control channel is in place, in case ssh wants to ask for a password or passphrase.
     let _guard = trace_span!("CLIENT").entered();
+    let started = Instant::now();
     let mut timers = StopwatchChain::new_running("setup");
 
     let spinner = if parameters.quiet {
@@ -55,144 +540,764 @@ pub async fn client_main(
 
     // Prep --------------------------
     spinner.set_message("Preparing");
-    let job_spec = crate::client::CopyJobSpec::try_from(&parameters)?;
-    let credentials = Credentials::generate()?;
-    let user_hostname = job_spec.remote_host();
-    let remote_host = super::ssh::resolve_host_alias(user_hostname, &config.ssh_config)
-        .unwrap_or_else(|| user_hostname.into());
-
-    // If the user didn't specify the address family: we do the DNS lookup, figure it out and tell ssh to use that.
-    // (Otherwise if we resolved a v4 and ssh a v6 - as might happen with round-robin DNS - that could be surprising.)
-    let remote_address = lookup_host_by_family(&remote_host, config.address_family)?;
-
-    // Control channel ---------------
-    spinner.set_message("Opening control channel");
-    spinner.disable_steady_tick(); // otherwise the spinner messes with ssh passphrase prompting; as we're using tokio spinner.suspend() isn't helpful
-    timers.next("control channel");
-    let (mut control, server_message) = Channel::transact(
-        &credentials,
-        &remote_host,
-        remote_address.into(),
-        &display,
-        config,
-        &parameters,
-    )
-    .await?;
-
-    // Data channel ------------------
-    let server_address_port = match remote_address {
-        std::net::IpAddr::V4(ip) => SocketAddrV4::new(ip, server_message.port).into(),
-        std::net::IpAddr::V6(ip) => SocketAddrV6::new(ip, server_message.port, 0, 0).into(),
-    };
+    let mut jobs = parameters.jobs().classify(FailureKind::Config)?;
+    if config.daemon && !parameters.no_daemon {
+        #[cfg(unix)]
+        if let Some((success, bytes)) =
+            super::daemon::run_via_daemon(config, &parameters, &jobs, &display, &spinner).await?
+        {
+            display.clear()?;
+            super::notify::notify(config, success, bytes, started.elapsed()).await;
+            return Ok(success);
+        }
+        #[cfg(not(unix))]
+        warn!("The daemon option is not supported on this platform; connecting directly");
+    }
+    // This must see all the jobs, before any are skipped
+    let mirror = parameters
+        .delete
+        .then(|| Mirror::new(&jobs, &parameters))
+        .transpose()
+        .classify(FailureKind::Config)?;
+    let mut state = parameters
+        .state_file
+        .as_ref()
+        .map(StateFile::open)
+        .transpose()
+        .classify(FailureKind::Config)?;
+    let mut options = JobOptions::new(&parameters);
+    options.stats = parameters
+        .stats_fd
+        .map(StatsOutput::open)
+        .transpose()
+        .classify(FailureKind::Config)?;
+    if let Some(state) = &state {
+        let before = jobs.len();
+        jobs.retain(|j| !state.is_complete(j));
+        let skipped = before - jobs.len();
+        if skipped > 0 {
+            info!("Skipping {skipped} job(s) already completed according to the state file");
+        }
+        if jobs.is_empty() {
+            display.clear()?;
+            return Ok(true);
+        }
+    }
+    let credentials = Credentials::load_or_generate(&config.tls_cert, &config.tls_key)
+        .classify(FailureKind::Config)?;
+    let locale = config.number_locale().classify(FailureKind::Config)?;
+    // This must happen before any sockets are created. The previous limits are restored when it is dropped.
+    let _sysctl = parameters
+        .auto_sysctl
+        .then(|| {
+            crate::os::raise_udp_buffer_limits(
+                Configuration::recv_buffer(),
+                Configuration::send_buffer(),
+            )
+            .inspect_err(|e| warn!("{e}"))
+            .ok()
+        })
+        .flatten();
 
-    spinner.enable_steady_tick(Duration::from_millis(150));
-    spinner.set_message("Establishing data channel");
-    timers.next("data channel setup");
-    let endpoint = create_endpoint(
-        &credentials,
-        server_message.cert.into(),
-        &server_address_port,
-        config,
-        job_spec.throughput_mode(),
-    )?;
+    // Connections -------------------
+    // We open one control channel and one QUIC connection per remote host, which are reused for all jobs to that host.
+    let mut hosts: Vec<HostConnection> = Vec::new();
+    for job in &jobs {
+        let user_hostname = job.remote_user_host();
+        if hosts.iter().any(|h| h.user_hostname == user_hostname) {
+            continue;
+        }
+        hosts.push(
+            HostConnection::establish(
+                user_hostname,
+                &credentials,
+                &display,
+                &spinner,
+                &mut timers,
+                config,
+                &parameters,
+                throughput_mode_for(&jobs, user_hostname),
+            )
+            .await
+            .classify(FailureKind::Connection)?,
+        );
+    }
 
-    debug!("Opening QUIC connection to {server_address_port:?}");
-    debug!("Local endpoint address is {:?}", endpoint.local_addr()?);
-    let connection = timeout(
-        config.timeout_duration(),
-        endpoint.connect(server_address_port, &server_message.name)?,
-    )
-    .await
-    .with_context(|| "UDP connection to QUIC endpoint timed out")??;
+    if jobs.len() > 1 && !parameters.quiet && !parameters.no_total && !parameters.dry_run {
+        spinner.set_message("Checking file sizes");
+        let connection_for = |job: &CopyJobSpec| {
+            hosts
+                .iter()
+                .find(|h| h.user_hostname == job.remote_user_host())
+                .map(|h| &h.connection)
+        };
+        options.total = TotalProgress::new(
+            &jobs,
+            connection_for,
+            &display,
+            config.protocol_timeout_duration(),
+        )
+        .await?
+        .map(Arc::new);
+    }
 
     // Show time! ---------------------
     spinner.set_message("Transferring data");
     timers.next(SHOW_TIME);
-    let result = manage_request(
-        &connection,
-        job_spec,
-        display.clone(),
-        spinner.clone(),
-        config,
-        parameters.quiet,
-    )
-    .await;
-    let total_bytes = match result {
-        Err(b) | Ok(b) => b,
-    };
+    let mut interrupt = watch_for_interrupt();
+    let mut interrupted = false;
+    let mut success = true;
+    for host in &mut hosts {
+        if interrupted {
+            break;
+        }
+        // All the jobs for a host run concurrently, each on its own stream.
+        let (permitted, refused): (Vec<_>, Vec<_>) = jobs
+            .iter()
+            .filter(|j| j.remote_user_host() == host.user_hostname)
+            .cloned()
+            .partition(|j| host.allowed_commands.contains(&j.command_type()));
+        for job in refused {
+            error!(
+                "{} does not allow {} commands",
+                host.user_hostname,
+                job.command_type()
+            );
+            success = false;
+        }
+        if permitted.is_empty() {
+            continue;
+        }
+        if permitted.len() > 1 {
+            debug!(
+                "Running {} jobs concurrently on the connection to {}",
+                permitted.len(),
+                host.user_hostname,
+            );
+        }
+        host.jobs += permitted.len();
+        let options = &options.for_host(host);
+        // We can only compress what we send if the remote can decompress it
+        let config = &Configuration {
+            compress: if host.compression {
+                config.compress
+            } else {
+                Compress::Off
+            },
+            ..config.clone()
+        };
+        let monitor = super::window::WindowMonitor::start(
+            &host.connection,
+            config,
+            throughput_mode_for(&permitted, &host.user_hostname),
+        );
+        // On interrupt, dropping the request cancels its jobs. Partially received files are cleaned up as they are dropped.
+        let request = async {
+            if parameters.dry_run {
+                dry_run(&host.connection, &permitted, &display, config, options).await
+            } else {
+                manage_request(
+                    &host.connection,
+                    permitted,
+                    display.clone(),
+                    spinner.clone(),
+                    config,
+                    options,
+                    state.as_mut(),
+                )
+                .await
+            }
+        };
+        let result = tokio::select! {
+            result = request => result,
+            Ok(_) = interrupt.wait_for(|i| *i) => {
+                interrupted = true;
+                Err(Transferred::default())
+            }
+        };
+        monitor.stop().await;
+        host.transferred = match result {
+            Err(t) | Ok(t) => t,
+        };
+        success &= result.is_ok();
+        if let Some(mirror) = mirror.as_ref().filter(|_| !interrupted) {
+            if success {
+                success &= tokio::select! {
+                    ok = mirror.delete_extraneous(host, &display, parameters.dry_run, config.protocol_timeout_duration()) => ok,
+                    Ok(_) = interrupt.wait_for(|i| *i) => {
+                        interrupted = true;
+                        false
+                    }
+                };
+            } else {
+                warn!(
+                    "Not deleting anything on {}, as some jobs failed",
+                    host.user_hostname
+                );
+            }
+        }
+    }
 
     // Closedown ----------------------
     timers.next("shutdown");
     spinner.set_message("Shutting down");
-    // Forcibly (but gracefully) tear down QUIC. All the requests have completed or errored.
-    endpoint.close(1u8.into(), "finished".as_bytes());
-    let remote_stats = control.read_closedown_report().await?;
-
-    let control_fut = control.close();
-    let _ = timeout(config.timeout_duration(), endpoint.wait_idle())
-        .await
-        .inspect_err(|_| warn!("QUIC shutdown timed out")); // otherwise ignore errors
-    trace!("QUIC closed; waiting for control channel");
-    let _ = timeout(config.timeout_duration(), control_fut)
-        .await
-        .inspect_err(|_| warn!("control channel timed out"));
-    // Ignore errors. If the control channel closedown times out, we expect its drop handler will do the Right Thing.
+    let mut remote_stats = Vec::with_capacity(hosts.len());
+    for host in &mut hosts {
+        match host.close(config).await {
+            Ok(report) => remote_stats.push(report),
+            // ssh may have seen the interrupt too, in which case the remote has already gone away
+            Err(e) if interrupted => debug!("closing {}: {e}", host.user_hostname),
+            Err(e) => return Err(e),
+        }
+    }
+    if interrupted {
+        display.clear()?;
+        error!("Interrupted");
+        return Ok(false);
+    }
 
     timers.stop();
 
     // Post-transfer chatter -----------
     if !parameters.quiet {
         let transport_time = timers.find(SHOW_TIME).and_then(Stopwatch::elapsed);
-        crate::util::stats::process_statistics(
-            &connection.stats(),
-            total_bytes,
-            transport_time,
-            remote_stats,
-            config,
-            parameters.statistics,
-        );
+        for (host, remote_stats) in hosts.iter().zip(remote_stats) {
+            let t = host.transferred;
+            if t.skipped > 0 {
+                info!(
+                    "Skipped {} file(s) already present at the destination",
+                    t.skipped
+                );
+            }
+            if t.sent != 0 && t.received != 0 {
+                info!(
+                    "Sent {}; received {}",
+                    t.sent.human_count_bytes(),
+                    t.received.human_count_bytes()
+                );
+            }
+            crate::util::stats::process_statistics(
+                &host.connection.stats(),
+                t.total(),
+                transport_time,
+                remote_stats,
+                config,
+                parameters.statistics,
+                &locale,
+            );
+        }
     }
 
     if parameters.profile {
         info!("Elapsed time by phase:\n{timers}");
     }
     display.clear()?;
-    Ok(result.is_ok())
+    let bytes = hosts.iter().map(|h| h.transferred.total()).sum();
+    super::notify::notify(config, success, bytes, started.elapsed()).await;
+    Ok(success)
+}
+
+/// Watches for Ctrl-C.
+///
+/// The first sets the returned flag, so we can close down cleanly.
+/// A second exits immediately, in case closedown hangs.
+fn watch_for_interrupt() -> tokio::sync::watch::Receiver<bool> {
+    let (tx, rx) = tokio::sync::watch::channel(false);
+    let _jh = tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_err() {
+            return; // dropping the sender means the flag is never set
+        }
+        debug!("Interrupted; closing down");
+        let _ = tx.send(true);
+        if tokio::signal::ctrl_c().await.is_ok() {
+            eprintln!("Interrupted again; exiting");
+            std::process::exit(130);
+        }
+    });
+    rx
 }
 
 /// Do whatever it is we were asked to.
+///
+/// The jobs are run concurrently, each on its own stream, so they may be in different directions.
+///
 /// On success: returns the number of bytes transferred.
 /// On error: returns the number of bytes that were transferred, as far as we know.
+///
+/// If a state file is given, each job is recorded in it as it completes.
 async fn manage_request(
     connection: &Connection,
-    copy_spec: CopyJobSpec,
+    jobs: Vec<CopyJobSpec>,
     display: MultiProgress,
     spinner: ProgressBar,
     config: &Configuration,
-    quiet: bool,
-) -> Result<u64, u64> {
-    let mut tasks = tokio::task::JoinSet::new();
-    let connection = connection.clone();
-    let config = config.clone();
-    let _jh = tasks.spawn(async move {
-        // This async block returns a Result<u64>
-        let sp = connection.open_bi().map_err(|e| anyhow::anyhow!(e)).await?;
-        // Called function returns its payload size.
-        // This async block reports on errors.
-        if copy_spec.source.host.is_some() {
-            // This is a Get
-            do_get(sp, &copy_spec, display, spinner, &config, quiet)
+    options: &JobOptions,
+    mut state: Option<&mut StateFile>,
+) -> Result<Transferred, Transferred> {
+    let io_limiter = util::io::IoLimiter::new(config.io_concurrency);
+    let mut transferred = Transferred::default();
+    let mut success = true;
+    // In a recursive copy, the directories must exist before we can send any files into them.
+    let (directories, files): (Vec<_>, Vec<_>) =
+        jobs.into_iter().partition(CopyJobSpec::is_directory);
+    for batch in [directories, files] {
+        let mut tasks = tokio::task::JoinSet::new();
+        for copy_spec in batch {
+            let _jh = tasks.spawn(run_or_skip(
+                connection.clone(),
+                copy_spec,
+                display.clone(),
+                spinner.clone(),
+                config.clone(),
+                io_limiter.clone(),
+                options.clone(),
+            ));
+        }
+        success &= collect_results(tasks, &mut transferred, state.as_deref_mut()).await;
+    }
+    if success {
+        Ok(transferred)
+    } else {
+        Err(transferred)
+    }
+}
+
+/// Options from the command line which affect how each job is run
+#[allow(clippy::struct_excessive_bools)]
+#[derive(Clone)]
+pub(super) struct JobOptions {
+    pub(super) quiet: bool,
+    print_hash: bool,
+    pub(super) resume: bool,
+    /// Keep partially received files if the job fails
+    keep_partial: bool,
+    /// Append to the destination of a Put
+    append: bool,
+    /// Create missing parent directories of the destination of a Put
+    mkpath: bool,
+    /// Write the destination of a Get directly, not via a temporary file
+    in_place: bool,
+    /// Where to keep partially received files (`--partial-dir`)
+    partial_dir: Option<PathBuf>,
+    /// Delete each source file once it has been transferred
+    remove_source: bool,
+    /// Send only the first part of a local file which grows while it is being sent
+    allow_growing: bool,
+    /// The command to run after each file is transferred (`--exec-after`)
+    exec_after: Option<String>,
+    /// Fail the job if the `--exec-after` command fails
+    exec_strict: bool,
+    /// Keep receiving the source of a Get as it grows
+    follow: bool,
+    /// Sync each destination to disk before reporting success
+    fsync: bool,
+    /// What to do if a destination already exists
+    overwrite: Overwrite,
+    /// Which jobs to skip because their destinations are already present
+    skip: SkipMode,
+    /// The control protocol version in use with the remote
+    pub(super) remote_version: u16,
+    /// Apply the source's metadata to the destination
+    preserve: Option<Preserve>,
+    /// The expected hash of the source, if it is to be verified
+    expected_hash: Option<blake3::Hash>,
+    /// Where to report progress, instead of the progress bars
+    pub(super) progress: Option<Arc<dyn ProgressSink>>,
+    /// Where to write per-second statistics (`--stats-fd`)
+    stats: Option<StatsOutput>,
+    /// The total progress of the batch, if shown
+    total: Option<Arc<TotalProgress>>,
+}
+
+impl JobOptions {
+    pub(super) fn new(parameters: &ClientParameters) -> Self {
+        Self {
+            quiet: parameters.quiet,
+            print_hash: parameters.print_hash,
+            resume: parameters.resume,
+            keep_partial: parameters.partial
+                || parameters.resume
+                || parameters.partial_dir.is_some(),
+            append: parameters.append,
+            mkpath: parameters.mkpath,
+            in_place: parameters.inplace,
+            partial_dir: parameters.partial_dir.as_ref().map(PathBuf::from),
+            remove_source: parameters.remove_source_files,
+            allow_growing: parameters.allow_growing,
+            exec_after: parameters.exec_after.clone(),
+            exec_strict: parameters.exec_strict,
+            follow: parameters.follow,
+            fsync: parameters.fsync,
+            overwrite: Overwrite::new(parameters.force),
+            skip: SkipMode::new(parameters),
+            remote_version: PROTOCOL_VERSION,
+            preserve: parameters.preserve,
+            expected_hash: parameters
+                .expected_hash
+                .filter(|_| parameters.verify_source),
+            progress: None,
+            stats: None,
+            total: None,
+        }
+    }
+
+    /// The options for the jobs on a particular connection
+    pub(super) fn for_host(&self, host: &HostConnection) -> Self {
+        self.for_remote_version(host.protocol_version)
+    }
+
+    /// The options for the jobs on a connection using the given control protocol version
+    pub(super) fn for_remote_version(&self, remote_version: u16) -> Self {
+        Self {
+            remote_version,
+            ..self.clone()
+        }
+    }
+
+    /// Checks that the remote qcp can do everything the job asks of it.
+    ///
+    /// A remote which predates protocol version negotiation (version 1) only understands a plain GET or PUT
+    /// of a single file. It ignores anything more it is asked to do, and would quietly do something else:
+    /// for example, it would empty the destination of an `--append`.
+    pub(super) fn check_remote(&self, job: &CopyJobSpec) -> Result<()> {
+        if self.remote_version >= 2 {
+            return Ok(());
+        }
+        let put = job.command_type() == CommandType::Put;
+        let needs = [
+            (job.tree.is_some(), "copy a directory tree"),
+            (self.resume, "resume a transfer (--resume)"),
+            (
+                self.skip != SkipMode::Never,
+                "check the destination before copying",
+            ),
+            (self.follow, "follow a file (--follow)"),
+            (
+                self.remove_source && !put,
+                "remove a source file (--remove-source-files)",
+            ),
+            (self.append && put, "append to a file (--append)"),
+            (self.mkpath && put, "create missing directories (--mkpath)"),
+            (self.fsync && put, "sync a file to disk (--fsync)"),
+            (
+                self.preserve.is_some() && put,
+                "preserve file metadata (--preserve)",
+            ),
+        ];
+        match needs.into_iter().find(|(needed, _)| *needed) {
+            Some((_, what)) => anyhow::bail!("{}: the remote qcp is too old to {what}", job.source),
+            None => Ok(()),
+        }
+    }
+
+    /// Checks that the remote qcp can receive a PUT of `len` bytes, which may not be known in advance
+    fn check_put_size(&self, len: Option<u64>, job: &CopyJobSpec) -> Result<()> {
+        anyhow::ensure!(
+            len.is_some() || self.remote_version >= 2,
+            "{}: the remote qcp is too old to receive data of unknown size",
+            job.source
+        );
+        Ok(())
+    }
+
+    /// Whether to ask the server not to overwrite the destination of a PUT.
+    ///
+    /// `--resume` and `--append` expect the destination to exist already.
+    /// Fails if the server is too old to check.
+    fn put_no_clobber(&self, destination: &str) -> Result<bool> {
+        let no_clobber = self.overwrite.checks() && !self.resume && !self.append;
+        anyhow::ensure!(
+            !no_clobber || self.remote_version >= 2,
+            "The remote qcp is too old to check whether {destination} exists; use --force to overwrite it"
+        );
+        Ok(no_clobber)
+    }
+
+    /// With `--partial-dir`, where to receive the data for a GET to `dest`.
+    ///
+    /// A relative directory is relative to the directory containing the destination.
+    fn partial_path(&self, dest: &Path) -> Option<PathBuf> {
+        let dir = self.partial_dir.as_ref()?;
+        let dir = dest.parent().unwrap_or(Path::new("")).join(dir);
+        Some(dir.join(dest.file_name()?))
+    }
+
+    /// Creates the instant throughput meter for a job
+    pub(super) fn meter(
+        &self,
+        bar: &ProgressBar,
+        spinner: ProgressBar,
+        max_throughput: u64,
+    ) -> InstaMeterRunner {
+        InstaMeterRunner::new(bar, spinner, max_throughput, self.stats.clone())
+    }
+
+    /// Works out where to report the progress of a job's payload:
+    /// to the custom sink if there is one, otherwise to the job's progress bar.
+    ///
+    /// A custom sink is told the length, if known, and the job's file name.
+    pub(super) fn progress_sink(
+        &self,
+        bar: &ProgressBar,
+        job: &CopyJobSpec,
+        length: Option<u64>,
+    ) -> Arc<dyn ProgressSink> {
+        let Some(sink) = &self.progress else {
+            return match &self.total {
+                Some(total) => Arc::new(WithTotal::new(bar, total, job)),
+                None => Arc::new(bar.clone()),
+            };
+        };
+        if let Some(length) = length {
+            sink.set_length(length);
+        }
+        sink.message(&display_filename(job));
+        sink.clone()
+    }
+}
+
+/// Runs a single job, unless its destination is already present and the options say to skip it.
+///
+/// Returns the job, and its payload size (`None` if it was skipped) or failure.
+pub(super) async fn run_or_skip(
+    connection: Connection,
+    copy_spec: CopyJobSpec,
+    display: MultiProgress,
+    spinner: ProgressBar,
+    config: Configuration,
+    io_limiter: util::io::IoLimiter,
+    options: JobOptions,
+) -> (CopyJobSpec, Result<Option<u64>>) {
+    let limit = config.protocol_timeout_duration();
+    let total = options.total.clone();
+    let (exec_after, exec_strict) = (options.exec_after.clone(), options.exec_strict);
+    let skip = match options.check_remote(&copy_spec) {
+        Ok(()) => options.skip.applies(&connection, &copy_spec, limit).await,
+        Err(e) => Err(e),
+    };
+    let (copy_spec, result) = match skip {
+        Ok(true) => (copy_spec, Ok(None)),
+        Ok(false) => {
+            let (copy_spec, result) = run_job(
+                connection,
+                copy_spec,
+                display.clone(),
+                spinner,
+                config,
+                io_limiter,
+                options,
+            )
+            .await;
+            (copy_spec, result.map(Some))
+        }
+        Err(e) => (copy_spec, Err(e)),
+    };
+    let command = exec_after.as_deref();
+    let result = super::exec::after_job(command, exec_strict, &copy_spec, result, &display).await;
+    if let Some(total) = total {
+        total.finish(&copy_spec, result.is_ok());
+    }
+    (copy_spec, result)
+}
+
+/// Runs a single job, on its own stream.
+///
+/// Returns the job, and its payload size or failure.
+pub(super) async fn run_job(
+    connection: Connection,
+    copy_spec: CopyJobSpec,
+    display: MultiProgress,
+    spinner: ProgressBar,
+    config: Configuration,
+    io_limiter: util::io::IoLimiter,
+    mut options: JobOptions,
+) -> (CopyJobSpec, Result<u64>) {
+    let command = copy_spec.command_type();
+    options.stats = options
+        .stats
+        .map(|s| s.for_job(&connection, display_filename(&copy_spec)));
+    // Hold this for the duration of the job; we don't open a stream until we have disk access
+    let _permit = io_limiter.acquire().await;
+    let resume_offset = match prepare_job(
+        &connection,
+        &copy_spec,
+        &display,
+        &spinner,
+        &config,
+        &options,
+    )
+    .await
+    {
+        Ok(Some(offset)) => offset,
+        Ok(None) => return (copy_spec, Ok(0)),
+        Err(e) => return (copy_spec, Err(e)),
+    };
+    let sp = match connection.open_bi().await {
+        Ok(sp) => sp,
+        Err(e) => return (copy_spec, Err(e.into())),
+    };
+    // Called function returns its payload size.
+    let result = match command {
+        CommandType::Get if options.follow => {
+            super::follow::do_follow(
+                sp,
+                &connection,
+                &copy_spec,
+                display,
+                spinner,
+                &config,
+                options,
+            )
+            .instrument(trace_span!("FOLLOW", filename = copy_spec.source.filename))
+            .await
+        }
+        CommandType::Get if copy_spec.destination.is_stdio() => {
+            do_get_to_stdout(sp, &copy_spec, display, spinner, &config, options)
                 .instrument(trace_span!("GET", filename = copy_spec.source.filename))
                 .await
-        } else {
-            // This is a Put
-            do_put(sp, &copy_spec, display, spinner, &config, quiet)
-                .instrument(trace_span!("PUT", filename = copy_spec.source.filename))
+        }
+        CommandType::Get => {
+            let span = trace_span!("GET", filename = copy_spec.source.filename);
+            let get = |sp, options: JobOptions| {
+                do_get(
+                    sp,
+                    &copy_spec,
+                    display.clone(),
+                    spinner.clone(),
+                    &config,
+                    options,
+                )
+                .instrument(span.clone())
+            };
+            match get(sp, options.clone()).await {
+                Err(e) => retry_job(e, options, &display, &connection, get).await,
+                result => result,
+            }
+        }
+        CommandType::Put if copy_spec.is_directory() => {
+            do_mkdir(sp, &copy_spec, config.protocol_timeout_duration())
+                .instrument(trace_span!(
+                    "MKDIR",
+                    dirname = copy_spec.destination.filename
+                ))
                 .await
         }
-    });
+        CommandType::Put if copy_spec.symlink_target().is_some() => {
+            do_symlink(sp, &copy_spec, config.protocol_timeout_duration())
+                .instrument(trace_span!(
+                    "SYMLINK",
+                    linkpath = copy_spec.destination.filename
+                ))
+                .await
+        }
+        CommandType::Put => {
+            let span = trace_span!("PUT", filename = copy_spec.source.filename);
+            let put = |sp, options| {
+                do_put(
+                    sp,
+                    &copy_spec,
+                    display.clone(),
+                    spinner.clone(),
+                    &config,
+                    options,
+                    resume_offset,
+                )
+                .instrument(span.clone())
+            };
+            match put(sp, options.clone()).await {
+                Err(e) => retry_job(e, options, &display, &connection, put).await,
+                result => result,
+            }
+        }
+        CommandType::Delete => unreachable!("a copy job is never a Delete"),
+    };
+    (copy_spec, result)
+}
+
+/// Does whatever a job needs before it opens a stream, so that the remote sees nothing if it does not proceed.
+///
+/// Returns where to resume a PUT (0 if not resuming), or None if there is nothing to do.
+async fn prepare_job(
+    connection: &Connection,
+    copy_spec: &CopyJobSpec,
+    display: &MultiProgress,
+    spinner: &ProgressBar,
+    config: &Configuration,
+    options: &JobOptions,
+) -> Result<Option<u64>> {
+    options.check_remote(copy_spec)?;
+    let command = copy_spec.command_type();
+    if let (CommandType::Put, Some(expected)) = (command, options.expected_hash) {
+        spinner.set_message("Verifying source");
+        verify_source(copy_spec, &expected).await?;
+        spinner.set_message("Transferring data");
+    }
+    let sends_data = matches!(copy_spec.tree, None | Some(TreeEntry::File(_)));
+    let resume_offset = if options.resume && command == CommandType::Put && sends_data {
+        let limit = config.protocol_timeout_duration();
+        let Some(offset) = put_resume_point(connection, copy_spec, limit).await? else {
+            info!("{} is already complete", copy_spec.source);
+            return Ok(None);
+        };
+        offset
+    } else {
+        0
+    };
+    Ok(confirm_local_overwrite(copy_spec, options, display)
+        .await?
+        .then_some(resume_offset))
+}
+
+/// Decides whether a failed job can be run again on a new stream, and if so, does that.
+///
+/// * If the source of a resumed GET has changed ([`SourceChanged`]), the job starts again from the beginning.
+/// * If the destination exists ([`DestinationExists`]) and the user agrees to overwrite it, the job runs again
+///   with overwriting allowed. If the user declines, the job is skipped.
+///
+/// Other errors are returned as they are.
+async fn retry_job<F, Fut>(
+    error: anyhow::Error,
+    options: JobOptions,
+    display: &MultiProgress,
+    connection: &Connection,
+    job: F,
+) -> Result<u64>
+where
+    F: FnOnce(RawStreamPair, JobOptions) -> Fut,
+    Fut: std::future::Future<Output = Result<u64>>,
+{
+    let options = if error.is::<SourceChanged>() {
+        info!("{error}; starting again");
+        // The file at the destination is our own partial copy
+        JobOptions {
+            resume: false,
+            overwrite: Overwrite::Always,
+            ..options
+        }
+    } else if let Some(DestinationExists(destination)) = error.downcast_ref() {
+        if !options.overwrite.confirm(destination, display).await? {
+            info!("Not overwriting {destination}");
+            return Ok(0);
+        }
+        JobOptions {
+            overwrite: Overwrite::Always,
+            ..options
+        }
+    } else {
+        return Err(error);
+    };
+    let sp = connection.open_bi().await?;
+    job(sp, options).await
+}
 
-    let mut total_bytes = 0u64;
+/// Waits for a set of job tasks to finish, accounting for their results.
+///
+/// Returns true if all the jobs succeeded.
+async fn collect_results(
+    mut tasks: tokio::task::JoinSet<(CopyJobSpec, Result<Option<u64>>)>,
+    transferred: &mut Transferred,
+    mut state: Option<&mut StateFile>,
+) -> bool {
     let mut success = true;
     loop {
         let Some(result) = tasks.join_next().await else {
@@ -209,29 +1314,46 @@ async fn manage_request(
                 } else {
                     // task cancellation (not currently in use, but might be later; this is conceptually benign)
                     warn!("unexpected task join failure (shouldn't happen)");
-                    Ok(0)
+                    continue;
                 }
             }
         };
 
         // The second layer of possible errors are failures in the protocol. Continue with other jobs as far as possible.
         match result {
-            Ok(size) => total_bytes += size,
-            Err(e) => {
-                error!("{e}");
-                success = false;
-            }
+            (job, Ok(size)) => {
+                if let Some(size) = size {
+                    transferred.add(job.command_type(), size);
+                } else {
+                    debug!("Skipped {}: the destination is already present", job.source);
+                    transferred.skipped += 1;
+                }
+                if let Some(state) = state.as_deref_mut() {
+                    let _ = state
+                        .mark_complete(&job)
+                        .inspect_err(|e| warn!("could not update state file: {e}"));
+                }
+            }
+            (_, Err(e)) => {
+                error!("{e}");
+                success = false;
+            }
         }
     }
-    if success {
-        Ok(total_bytes)
-    } else {
-        Err(total_bytes)
-    }
+    success
+}
+
+/// The name of a job, as shown in its progress bar
+fn display_filename(job: &CopyJobSpec) -> String {
+    PathBuf::from(&job.source.filename)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .to_string()
 }
 
 /// Adds a progress bar to the stack (in `MultiProgress`) for the current job
-fn progress_bar_for(
+pub(super) fn progress_bar_for(
     display: &MultiProgress,
     job: &CopyJobSpec,
     steps: u64,
@@ -240,14 +1362,7 @@ fn progress_bar_for(
     if quiet {
         return Ok(ProgressBar::hidden());
     }
-    let display_filename = {
-        let component = PathBuf::from(&job.source.filename);
-        component
-            .file_name()
-            .unwrap_or_default()
-            .to_string_lossy()
-            .to_string()
-    };
+    let display_filename = display_filename(job);
     Ok(display.add(
         ProgressBar::new(steps)
             .with_style(indicatif::ProgressStyle::with_template(
@@ -265,28 +1380,34 @@ fn progress_bar_for(
 /// `credentials` are generated locally.
 /// `server_cert` comes from the control channel server message.
 /// `destination` is the server's address (port from the control channel server message).
+///
+/// Returns the endpoint, and a warning message if the UDP buffer sizes could not be set.
 pub(crate) fn create_endpoint(
     credentials: &Credentials,
     server_cert: CertificateDer<'_>,
     server_addr: &SocketAddr,
     options: &Configuration,
     mode: ThroughputMode,
-) -> Result<quinn::Endpoint> {
+) -> Result<(quinn::Endpoint, Option<String>)> {
     let _ = span!(Level::TRACE, "create_endpoint").entered();
     let mut root_store = RootCertStore::empty();
     root_store.add(server_cert)?;
 
-    let tls_config = Arc::new(
-        rustls::ClientConfig::builder()
-            .with_root_certificates(root_store)
-            .with_client_auth_cert(credentials.cert_chain(), credentials.keypair.clone_key())?,
-    );
+    let mut tls_config = rustls::ClientConfig::builder()
+        .with_root_certificates(root_store)
+        .with_client_auth_cert(credentials.cert_chain(), credentials.keypair.clone_key())?;
+    if let Some(key_log) = util::key_log() {
+        tls_config.key_log = key_log;
+    }
 
-    let mut config = quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(tls_config)?));
+    let mut config =
+        quinn::ClientConfig::new(Arc::new(QuicClientConfig::try_from(Arc::new(tls_config))?));
     let _ = config.transport_config(crate::transport::create_config(options, mode)?);
 
     trace!("bind & configure socket, port={:?}", options.port);
-    let mut socket = util::socket::bind_range_for_peer(server_addr, options.port)?;
+    let mut socket =
+        util::socket::bind_range_for_peer(server_addr, options.port, options.bind_ip()?)?;
+    util::socket::bind_to_interface(&socket, &options.bind_interface)?;
     let wanted_send = match mode {
         ThroughputMode::Both | ThroughputMode::Tx => Some(Configuration::send_buffer().try_into()?),
         ThroughputMode::Rx => None,
@@ -296,99 +1417,627 @@ pub(crate) fn create_endpoint(
         ThroughputMode::Tx => None,
     };
 
-    let _ = util::socket::set_udp_buffer_sizes(&mut socket, wanted_send, wanted_recv)?;
+    let warning = util::socket::set_udp_buffer_sizes(&mut socket, wanted_send, wanted_recv)?;
+    util::socket::set_dscp(&socket, options.dscp)?;
 
     trace!("create endpoint");
-    // SOMEDAY: allow user to specify max_udp_payload_size in endpoint config, to support jumbo frames
     let runtime =
         quinn::default_runtime().ok_or_else(|| anyhow::anyhow!("no async runtime found"))?;
-    let mut endpoint = quinn::Endpoint::new(EndpointConfig::default(), None, socket, runtime)?;
+    let mut endpoint = quinn::Endpoint::new(
+        crate::transport::endpoint_config(options)?,
+        None,
+        socket,
+        runtime,
+    )?;
     endpoint.set_default_client_config(config);
 
-    Ok(endpoint)
+    Ok((endpoint, warning))
+}
+
+/// Outputs a file hash for `--print-hash`
+fn print_hash(display: &MultiProgress, hash: Option<blake3::Hash>, destination: &str) {
+    if let Some(hash) = hash {
+        display.suspend(|| println!("{}  {destination}", hash.to_hex()));
+    }
+}
+
+/// The remote file has changed since an interrupted attempt to receive it, so the transfer cannot be resumed
+#[derive(Debug)]
+struct SourceChanged(String);
+
+impl std::fmt::Display for SourceChanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{} has changed since the previous attempt", self.0)
+    }
+}
+
+impl std::error::Error for SourceChanged {}
+
+/// Determines whether we can pick up where a previous GET left off.
+///
+/// Returns the marker left by the previous attempt, if any, and the offset to resume from.
+async fn get_resume_point(dest_path: &Path, options: &JobOptions) -> (Option<Partial>, u64) {
+    // Files which can't be replaced are written in place, even with a partial directory
+    let partial_path = if options.in_place || !util::io::is_replaceable(dest_path).await {
+        None
+    } else {
+        options.partial_path(dest_path)
+    };
+    let dest_path = partial_path.as_deref().unwrap_or(dest_path);
+    let Some(partial) = Partial::read(dest_path).await else {
+        return (None, 0);
+    };
+    let offset = tokio::fs::metadata(dest_path)
+        .await
+        .map(|m| m.len())
+        .ok()
+        .filter(|len| *len <= partial.size)
+        .unwrap_or_default();
+    (Some(partial), offset)
+}
+
+/// Checks that a GET can be resumed, i.e. that the remote file is the one we were receiving before
+fn check_resumable(
+    job: &CopyJobSpec,
+    dest_path: &Path,
+    partial: Option<Partial>,
+    remote: Partial,
+    resume_offset: u64,
+) -> Result<()> {
+    if partial != Some(remote) {
+        return Err(SourceChanged(job.source.to_string()).into());
+    }
+    info!(
+        "Resuming {} after {}",
+        dest_path.display(),
+        resume_offset.human_count_bytes()
+    );
+    Ok(())
+}
+
+/// Works out whether a GET should receive into a temporary file, and if so, where.
+///
+/// This is done unless `--inplace` was given, or a partial file is to be kept for later,
+/// so that an incomplete file never appears at the destination.
+/// With `--partial-dir`, the temporary file is in that directory, which is created if necessary,
+/// and is kept for later if the transfer fails.
+async fn get_temporary_path(dest_path: &Path, options: &JobOptions) -> Result<Option<PathBuf>> {
+    if options.in_place || !util::io::is_replaceable(dest_path).await {
+        return Ok(None);
+    }
+    if let Some(partial_path) = options.partial_path(dest_path) {
+        if let Some(dir) = partial_path.parent() {
+            tokio::fs::create_dir_all(dir).await.map_err(|e| {
+                anyhow::anyhow!("Could not create partial directory {}: {e}", dir.display())
+            })?;
+        }
+        return Ok(Some(partial_path));
+    }
+    Ok((!options.keep_partial).then(|| util::io::temporary_destination(dest_path)))
+}
+
+/// Puts a verified received file in place.
+///
+/// If `fsync` is set, the data is synced to disk, then the file is renamed into place (if it was received into
+/// a temporary file), and then its directory is synced. In this order, the destination never holds incomplete data.
+///
+/// Any partial file marker is removed, as is a relative `--partial-dir` if it is now empty.
+async fn commit_received(
+    file: tokio::fs::File,
+    write_path: &Path,
+    dest_path: &Path,
+    options: &JobOptions,
+) -> Result<()> {
+    if options.fsync {
+        util::io::sync_file(&file, write_path).await?;
+    }
+    drop(file);
+    if write_path != dest_path {
+        util::io::rename_into_place(write_path, dest_path).await?;
+        Partial::remove(write_path).await;
+    }
+    if options.fsync {
+        util::io::sync_parent_directory(dest_path).await?;
+    }
+    if options
+        .partial_dir
+        .as_ref()
+        .is_some_and(|d| d.is_relative())
+    {
+        if let Some(dir) = write_path
+            .parent()
+            .filter(|d| *d != dest_path.parent().unwrap_or(d))
+        {
+            // This fails harmlessly if the directory is not empty
+            let _ = tokio::fs::remove_dir(dir).await;
+        }
+    }
+    Partial::remove(dest_path).await;
+    Ok(())
+}
+
+/// Checks whether a GET may write to its local destination, asking the user if it already exists.
+///
+/// Returns false if the user declined, in which case the job is skipped.
+async fn confirm_local_overwrite(
+    job: &CopyJobSpec,
+    options: &JobOptions,
+    display: &MultiProgress,
+) -> Result<bool> {
+    // A resumed GET expects the destination to exist already
+    if job.command_type() != CommandType::Get
+        || job.destination.is_stdio()
+        || options.resume
+        || !options.overwrite.checks()
+    {
+        return Ok(true);
+    }
+    let dest_path = util::io::local_destination(&job.destination.filename, &job.source.filename);
+    if tokio::fs::symlink_metadata(&dest_path).await.is_err() {
+        return Ok(true);
+    }
+    let destination = dest_path.to_string_lossy();
+    let confirmed = options.overwrite.confirm(&destination, display).await?;
+    if !confirmed {
+        info!("Not overwriting {destination}");
+    }
+    Ok(confirmed)
+}
+
+/// Reads the server's response to a GET, and the header of the file it is about to send.
+///
+/// If the response is unsuccessful, the error message is prefixed by `what`.
+async fn get_response(
+    recv: &mut quinn::RecvStream,
+    limit: Duration,
+    what: &str,
+) -> Result<FileHeader> {
+    trace!("await response");
+    check_response(
+        with_timeout(limit, "response", Response::read(recv)).await?,
+        what,
+    )?;
+    with_timeout(limit, "file header", FileHeader::read(recv)).await
 }
 
 /// Actions a GET command
+///
+/// If resuming, and a previous attempt was interrupted, only the rest of the file is requested.
+/// If the remote file has changed since then, this fails with [`SourceChanged`].
+/// Otherwise, the file is received into a temporary file which is renamed into place once verified,
+/// unless `--inplace` was given.
+/// Returns the number of bytes received.
 async fn do_get(
     sp: RawStreamPair,
     job: &CopyJobSpec,
     display: MultiProgress,
     spinner: ProgressBar,
     config: &Configuration,
-    quiet: bool,
+    options: JobOptions,
 ) -> Result<u64> {
+    let JobOptions {
+        quiet,
+        print_hash: compute_hash,
+        resume,
+        ..
+    } = options;
     let filename = &job.source.filename;
-    let dest = &job.destination.filename;
+    let dest_path = crate::util::io::local_destination(&job.destination.filename, filename);
+    let remove_source = options.remove_source;
+
+    let (partial, resume_offset) = if resume {
+        get_resume_point(&dest_path, &options).await
+    } else {
+        (None, 0)
+    };
+    if compute_hash && resume_offset > 0 {
+        warn!("Cannot output the hash of a resumed transfer ({filename})");
+    }
 
     let mut stream: StreamPair = sp.into();
     let real_start = Instant::now();
     trace!("send command");
-    stream
-        .send
-        .write_all(&crate::protocol::session::Command::new_get(filename).serialize())
-        .await?;
+    let cmd = Command::new_resumed_get(filename, resume_offset).with_remove_source(remove_source);
+    stream.send.write_all(&cmd.serialize()).await?;
     stream.send.flush().await?;
 
-    // TODO protocol timeout?
-    trace!("await response");
-    let response = Response::read(&mut stream.recv).await?;
-    if response.status != Status::Ok {
-        anyhow::bail!(format!("GET ({filename}) failed: {response}"));
-    }
-
-    let header = FileHeader::read(&mut stream.recv).await?;
+    let limit = config.protocol_timeout_duration();
+    let header = get_response(&mut stream.recv, limit, &format!("GET ({filename}) failed")).await?;
     trace!("{header:?}");
-
-    let mut file = crate::util::io::create_truncate_file(dest, &header).await?;
+    let remote = Partial {
+        size: header.size,
+        mtime: header.mtime,
+    };
+    if resume_offset > 0 {
+        check_resumable(job, &dest_path, partial, remote, resume_offset)?;
+    }
+    let temp_path = get_temporary_path(&dest_path, &options).await?;
+    let in_place = temp_path.is_none();
+    let write_path = temp_path.unwrap_or_else(|| dest_path.clone());
+    // A file that may be kept must not be extended ahead of the data, or --resume would think it complete
+    let size = if options.keep_partial {
+        resume_offset
+    } else {
+        header.size
+    };
+    let file =
+        util::io::open_destination(&write_path, size, resume_offset, config.preallocate).await?;
+    // N.B. This must be dropped after the file, so is declared first
+    let guard = PartialGuard::new(&write_path, options.keep_partial);
+    let mut file = file;
+    if (in_place || options.partial_dir.is_some()) && resume_offset == 0 {
+        // Record what we're receiving, in case we are interrupted
+        let _ = remote
+            .write(&write_path)
+            .await
+            .inspect_err(|e| debug!("could not write partial file marker: {e}"));
+    }
 
     // Now we know how much we're receiving, update the chrome.
-    // File Trailers are currently 16 bytes on the wire.
+    // The progress bar counts payload bytes written to the destination, not bytes on the wire,
+    // so it reflects what the user cares about regardless of protocol overheads.
 
     // Unfortunately, the file data is already well in flight at this point, leading to a flood of packets
     // that causes the estimated rate to spike unhelpfully at the beginning of the transfer.
     // Therefore we incorporate time in flight so far to get the estimate closer to reality.
-    let progress_bar = progress_bar_for(&display, job, header.size + 16, quiet)?
+    let progress_bar = progress_bar_for(&display, job, header.size, quiet)?
         .with_elapsed(Instant::now().duration_since(real_start));
+    let progress = options.progress_sink(&progress_bar, job, Some(header.size));
+    if resume_offset > 0 {
+        progress.inc(resume_offset);
+        progress_bar.reset_eta();
+    }
 
-    let mut meter =
-        crate::client::meter::InstaMeterRunner::new(&progress_bar, spinner, config.rx());
+    let mut meter = options.meter(&progress_bar, spinner, config.effective_rx());
     meter.start().await;
 
-    let inbound = progress_bar.wrap_async_read(stream.recv);
+    let mut writer = ProgressWriter::new(&mut file, progress);
 
-    let mut inbound = inbound.take(header.size);
+    let to_receive = header.size - resume_offset;
     trace!("payload");
-    let _ = tokio::io::copy(&mut inbound, &mut file).await?;
-    // Retrieve the stream from within the Take wrapper for further operations
-    let mut inbound = inbound.into_inner();
-
-    trace!("trailer");
-    let _trailer = FileTrailer::read(&mut inbound).await?;
-    // Trailer is empty for now, but its existence means the server believes the file was sent correctly
+    let (trailer, hash) = compress::receive_payload(
+        &mut stream.recv,
+        &mut writer,
+        to_receive,
+        header.compressed,
+        limit,
+    )
+    .await
+    .map_err(|e| get_write_error(e, filename, &write_path, progress_bar.position(), &options))?;
 
     // Note that the Quinn send stream automatically calls finish on drop.
     meter.stop().await;
-    file.flush().await?;
+    drop(writer);
+    if !trailer.verify(&hash) {
+        drop(file);
+        progress_bar.abandon();
+        return Err(discard_corrupt(filename, &write_path).await);
+    }
+    // If this fails, the guard removes the file
+    commit_received(file, &write_path, &dest_path, &options).await?;
+    guard.disarm();
+    if let Some(preserve) = options.preserve {
+        crate::util::io::apply_mtime(&dest_path, header.mtime);
+        crate::util::io::apply_mode(&dest_path, header.mode, preserve == Preserve::Mode);
+    }
+    if remove_source {
+        remove_remote_source(&mut stream, limit, &job.source).await?;
+    }
     trace!("complete");
     progress_bar.finish_and_clear();
-    Ok(header.size)
+    print_hash(
+        &display,
+        Some(hash).filter(|_| compute_hash && resume_offset == 0),
+        &dest_path.to_string_lossy(),
+    );
+    Ok(to_receive)
 }
 
-/// Actions a PUT command
-async fn do_put(
+/// Interprets a failure to receive the payload of a GET.
+///
+/// Running out of disk space is reported as such, with the size the destination had reached (`written`),
+/// and what happens to it (the [`PartialGuard`] removes it, unless it is being kept).
+fn get_write_error(
+    e: anyhow::Error,
+    filename: &str,
+    write_path: &Path,
+    written: u64,
+    options: &JobOptions,
+) -> anyhow::Error {
+    if !util::io::is_disk_full(&e) {
+        return e;
+    }
+    let outcome = if options.keep_partial {
+        "kept"
+    } else {
+        "removed"
+    };
+    anyhow::anyhow!(
+        "GET ({filename}) failed: destination disk full after {written} bytes; {outcome} {}",
+        write_path.display()
+    )
+}
+
+/// Removes a received file which failed verification, and its partial file marker.
+///
+/// Returns the error to report.
+async fn discard_corrupt(filename: &str, write_path: &Path) -> anyhow::Error {
+    let _ = tokio::fs::remove_file(write_path).await;
+    Partial::remove(write_path).await;
+    anyhow::anyhow!(
+        "GET ({filename}) failed: {}; removed {}",
+        crate::protocol::session::status_description(Status::ChecksumMismatch),
+        write_path.display()
+    )
+}
+
+/// Confirms to the server that a GET with `remove_source` was received intact, so that it deletes the source
+async fn remove_remote_source(
+    stream: &mut StreamPair,
+    limit: Duration,
+    source: &FileSpec,
+) -> Result<()> {
+    trace!("confirm receipt");
+    stream
+        .send
+        .write_all(&Response::serialize_direct(Status::Ok, None))
+        .await?;
+    stream.send.flush().await?;
+    check_response(
+        with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+        format_args!("Could not remove {source}"),
+    )
+}
+
+/// Actions a GET command whose destination is standard output.
+///
+/// Unlike [`do_get`], this cannot resume, and there is no file to remove if the data fails verification.
+/// Returns the number of bytes received.
+async fn do_get_to_stdout(
     sp: RawStreamPair,
     job: &CopyJobSpec,
     display: MultiProgress,
     spinner: ProgressBar,
     config: &Configuration,
-    quiet: bool,
+    options: JobOptions,
 ) -> Result<u64> {
+    let filename = &job.source.filename;
     let mut stream: StreamPair = sp.into();
+    let real_start = Instant::now();
+    trace!("send command");
+    stream
+        .send
+        .write_all(&Command::new_get(filename).serialize())
+        .await?;
+    stream.send.flush().await?;
+
+    let limit = config.protocol_timeout_duration();
+    let header = get_response(&mut stream.recv, limit, &format!("GET ({filename}) failed")).await?;
+    trace!("{header:?}");
+
+    // The progress bar is drawn on stderr, so does not get mixed up with the data
+    let progress_bar = progress_bar_for(&display, job, header.size, options.quiet)?
+        .with_elapsed(Instant::now().duration_since(real_start));
+    let mut meter = options.meter(&progress_bar, spinner, config.effective_rx());
+    meter.start().await;
+
+    let progress = options.progress_sink(&progress_bar, job, Some(header.size));
+    let mut stdout = ProgressWriter::new(tokio::io::stdout(), progress);
+    trace!("payload");
+    let (trailer, hash) = compress::receive_payload(
+        &mut stream.recv,
+        &mut stdout,
+        header.size,
+        header.compressed,
+        limit,
+    )
+    .await?;
+    meter.stop().await;
+    // Writes to stdout may be buffered; make sure they have all gone before we report success
+    stdout.flush().await?;
+    if !trailer.verify(&hash) {
+        progress_bar.abandon();
+        anyhow::bail!(
+            "GET ({filename}) failed: {}; the data written to standard output is not to be trusted",
+            crate::protocol::session::status_description(Status::ChecksumMismatch),
+        );
+    }
+    trace!("complete");
+    progress_bar.finish_and_clear();
+    if options.print_hash {
+        // stdout is taken up with the data
+        display.suspend(|| eprintln!("{}  {}", hash.to_hex(), job.destination));
+    }
+    Ok(header.size)
+}
+
+/// Reports what a set of jobs would do, without transferring any data.
+///
+/// Each remote destination is checked by the server as if for a real transfer;
+/// directories and symbolic links are listed but not created.
+/// Returns an empty [`Transferred`], as an error if any job would fail.
+async fn dry_run(
+    connection: &Connection,
+    jobs: &[CopyJobSpec],
+    display: &MultiProgress,
+    config: &Configuration,
+    options: &JobOptions,
+) -> Result<Transferred, Transferred> {
+    let mut success = true;
+    for job in jobs {
+        match dry_run_job(connection, job, config, options).await {
+            Ok(report) => display.suspend(|| println!("{report}")),
+            Err(e) => {
+                error!("{e}");
+                success = false;
+            }
+        }
+    }
+    if success {
+        Ok(Transferred::default())
+    } else {
+        Err(Transferred::default())
+    }
+}
+
+/// Checks a single job as far as possible without transferring any data.
+///
+/// Returns a description of what the job would do.
+async fn dry_run_job(
+    connection: &Connection,
+    job: &CopyJobSpec,
+    config: &Configuration,
+    options: &JobOptions,
+) -> Result<String> {
+    options.check_remote(job)?;
+    let append = options.append;
+    let limit = config.protocol_timeout_duration();
+    if job.is_directory() {
+        return Ok(format!("Would create directory {}", job.destination));
+    }
+    if let Some(target) = job.symlink_target() {
+        return Ok(format!(
+            "Would create symbolic link {} -> {target}",
+            job.destination
+        ));
+    }
+    let filename = &job.source.filename;
+    let mut stream: StreamPair = connection.open_bi().await?.into();
+    if job.command_type() == CommandType::Get {
+        let cmd = crate::protocol::session::Command::new_get(filename);
+        stream.send.write_all(&cmd.serialize()).await?;
+        stream.send.flush().await?;
+        let what = format!("GET ({filename}) would fail");
+        let header = get_response(&mut stream.recv, limit, &what).await?;
+        // Dropping the stream tells the server to stop sending
+        drop(stream);
+        if job.destination.is_stdio() {
+            return Ok(format!(
+                "Would receive {} ({}) to standard output",
+                job.source,
+                header.size.human_count_bytes()
+            ));
+        }
+        let dest_path = util::io::local_destination(&job.destination.filename, filename);
+        let dest_dir = match dest_path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p.to_path_buf(),
+            _ => PathBuf::from("."),
+        };
+        let writeable = if dest_path.exists() {
+            util::io::dest_is_writeable(&dest_path).await
+        } else {
+            dest_dir.is_dir() && util::io::dest_is_writeable(&dest_dir).await
+        };
+        anyhow::ensure!(
+            writeable,
+            "GET ({filename}) would fail: cannot write to {}",
+            dest_path.display()
+        );
+        return Ok(format!(
+            "Would receive {} ({}) to {}",
+            job.source,
+            header.size.human_count_bytes(),
+            dest_path.display()
+        ));
+    }
+    let source = open_put_source(job, 0, false).await?;
+    options.check_put_size(source.len, job)?;
+    let cmd = if append {
+        Command::new_appending_put(&job.destination.filename)
+    } else {
+        Command::new_put(&job.destination.filename)
+    }
+    .with_mkpath(options.mkpath)
+    .with_recursive(job.tree.is_some());
+    stream.send.write_all(&cmd.serialize()).await?;
+    stream.send.flush().await?;
+    check_response(
+        with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+        format_args!("PUT ({filename}) would fail"),
+    )?;
+    // Finishing the stream without sending a FileHeader tells the server there is nothing to come.
+    // Wait for it to close its end, so it is not surprised by the connection closing.
+    let _ = stream.send.finish();
+    let _ = stream.recv.read_to_end(0).await;
+    Ok(format!(
+        "Would {} {} ({}) to {}",
+        if append { "append" } else { "send" },
+        job.source,
+        source.len.map_or_else(
+            || "size unknown".into(),
+            |l| l.human_count_bytes().to_string()
+        ),
+        job.remote_destination_display(&source.filename)
+    ))
+}
+
+/// Converts an unsuccessful session [`Response`] into an error.
+///
+/// The error message is prefixed by `what`. The underlying [`StatusError`](crate::protocol::session::StatusError) may be recovered with `downcast_ref`.
+pub(crate) fn check_response(response: Response, what: impl std::fmt::Display) -> Result<()> {
+    response.into_result().map_err(|e| {
+        let message = format!("{what}: {e}");
+        anyhow::Error::new(e).context(message)
+    })
+}
+
+/// The filename to send in the session protocol for a PUT from a local file
+pub(super) fn put_protocol_filename(job: &CopyJobSpec) -> Result<String> {
+    if let Some(TreeEntry::File(path)) = &job.tree {
+        // Within a recursive copy, the server needs the path relative to the destination
+        return Ok(path.clone());
+    }
+    PathBuf::from(&job.source.filename)
+        .file_name()
+        .and_then(|f| f.to_str())
+        .map(str::to_string)
+        .ok_or_else(|| anyhow::anyhow!("{}: invalid source filename", job.source))
+}
+
+/// The filename to send in the session protocol for a PUT from standard input
+const STDIN_FILENAME: &str = "stdin";
+
+/// An open source for a PUT
+struct PutSource {
+    reader: SourceReader,
+    /// Full payload length, if known in advance
+    len: Option<u64>,
+    /// The filename to use in the session protocol
+    filename: String,
+    /// Metadata, if this is a local file
+    meta: Option<std::fs::Metadata>,
+}
+
+/// Opens the source of a PUT, positioned at `offset`.
+///
+/// If `mmap` is set, a large enough file is read by mapping it into memory.
+async fn open_put_source(job: &CopyJobSpec, offset: u64, mmap: bool) -> Result<PutSource> {
     let src_filename = &job.source.filename;
-    let dest_filename = &job.destination.filename;
+    let buffer_size = Configuration::send_buffer().try_into()?;
+
+    #[cfg(feature = "http-source")]
+    if job.source.is_url() {
+        anyhow::ensure!(offset == 0, "Cannot resume a URL source");
+        let source = super::http::HttpSource::open(src_filename).await?;
+        return Ok(PutSource {
+            reader: Box::new(BufReader::with_capacity(buffer_size, source.reader)),
+            len: source.len,
+            filename: source.filename,
+            meta: None,
+        });
+    }
 
-    let path = PathBuf::from(src_filename);
-    let (file, meta) = match crate::util::io::open_file(src_filename).await {
+    if job.source.is_stdio() {
+        anyhow::ensure!(offset == 0, "Cannot resume sending standard input");
+        return Ok(PutSource {
+            reader: Box::new(BufReader::with_capacity(buffer_size, tokio::io::stdin())),
+            len: None,
+            filename: STDIN_FILENAME.into(),
+            meta: None,
+        });
+    }
+
+    let (mut file, meta) = match crate::util::io::open_file(src_filename).await {
         Ok(res) => res,
         Err((_, _, error)) => {
             return Err(error.into());
@@ -397,88 +2046,649 @@ async fn do_put(
     if meta.is_dir() {
         anyhow::bail!("PUT: Source is a directory");
     }
+    if offset > 0 {
+        let _ = file.seek(std::io::SeekFrom::Start(offset)).await?;
+    }
+    Ok(PutSource {
+        reader: mmap::source_reader(file, meta.len(), offset, buffer_size, mmap),
+        len: Some(meta.len()),
+        filename: put_protocol_filename(job)?,
+        meta: Some(meta),
+    })
+}
+
+/// Sends the payload of a PUT, but no more than the header declared (`to_send`, if known)
+async fn send_put_payload<R, W>(
+    file: &mut R,
+    writer: &mut W,
+    compressed: bool,
+    to_send: Option<u64>,
+) -> std::io::Result<compress::Sent>
+where
+    R: AsyncBufRead + Unpin,
+    W: tokio::io::AsyncWrite + Unpin,
+{
+    match to_send {
+        Some(expected) => {
+            compress::send_payload(&mut file.take(expected), writer, compressed).await
+        }
+        None => compress::send_payload(file, writer, compressed).await,
+    }
+}
 
-    let payload_len = meta.len();
+/// Checks that a PUT source was the size its header declared, once `sent` bytes of the `expected` have been sent.
+///
+/// `rest` is the remainder of the source. If there is more, the file grew while it was being sent;
+/// that is an error unless `allow_growing` is set.
+async fn check_source_size<R: AsyncBufRead + Unpin>(
+    source: &str,
+    expected: u64,
+    sent: u64,
+    rest: &mut R,
+    allow_growing: bool,
+) -> Result<()> {
+    anyhow::ensure!(
+        sent == expected,
+        "{source} shrank while it was being sent: only {sent} of the expected {expected} bytes could be read"
+    );
+    if !rest.fill_buf().await?.is_empty() {
+        anyhow::ensure!(
+            allow_growing,
+            "{source} grew while it was being sent (use --allow-growing to send only the first {expected} bytes)"
+        );
+        info!("{source} grew while it was being sent; sent the first {expected} bytes");
+    }
+    Ok(())
+}
+
+/// Decides where to resume sending a file, given the length of the source and that of any existing destination.
+///
+/// Returns `None` if there is nothing left to send.
+fn resume_point(source_len: u64, dest_len: Option<u64>) -> Result<Option<u64>> {
+    match dest_len {
+        None => Ok(Some(0)),
+        Some(dest_len) if dest_len > source_len => anyhow::bail!(
+            "destination is larger than the source ({dest_len} > {source_len} bytes), so cannot resume"
+        ),
+        Some(dest_len) if dest_len == source_len => Ok(None),
+        Some(dest_len) => Ok(Some(dest_len)),
+    }
+}
+
+/// Asks the server for the size and modification time of a file, with a Stat command.
+///
+/// `path` and `filename` are resolved as for the destination of a PUT.
+/// Returns `None` if the file does not exist.
+pub(super) async fn stat_remote(
+    connection: &Connection,
+    path: &str,
+    filename: &str,
+    limit: Duration,
+) -> Result<Option<FileHeader>> {
+    let mut stream: StreamPair = connection.open_bi().await?.into();
+    trace!("send stat");
+    stream
+        .send
+        .write_all(&Command::new_stat(path, filename).serialize())
+        .await?;
+    stream.send.flush().await?;
+    let response = with_timeout(limit, "response", Response::read(&mut stream.recv)).await?;
+    if response.status == Status::FileNotFound {
+        return Ok(None);
+    }
+    response.into_result()?;
+    Ok(Some(
+        with_timeout(limit, "file header", FileHeader::read(&mut stream.recv)).await?,
+    ))
+}
+
+/// Asks the server how much of the destination of a PUT is already present, and so where to resume.
+///
+/// Returns `None` if the destination is already complete.
+async fn put_resume_point(
+    connection: &Connection,
+    job: &CopyJobSpec,
+    limit: Duration,
+) -> Result<Option<u64>> {
+    if job.source.is_url() || job.source.is_stdio() {
+        // We can't seek in an HTTP response or a pipe
+        return Ok(Some(0));
+    }
+    let source_len = tokio::fs::metadata(&job.source.filename).await?.len();
+    let filename = put_protocol_filename(job)?;
+    let destination = job.remote_destination_display(&filename);
+    let dest_len = stat_remote(connection, &job.destination.filename, &filename, limit)
+        .await
+        .map_err(|e| {
+            let message = format!("Checking {destination} failed: {e}");
+            e.context(message)
+        })?
+        .map(|header| header.size);
+    let offset =
+        resume_point(source_len, dest_len).map_err(|e| anyhow::anyhow!("{destination}: {e}"))?;
+    if let Some(offset) = offset.filter(|o| *o > 0) {
+        info!(
+            "Resuming {destination} after {}",
+            offset.human_count_bytes()
+        );
+    }
+    Ok(offset)
+}
+
+/// Reads and hashes the source of a PUT, checking it against the expected hash
+async fn verify_source(job: &CopyJobSpec, expected: &blake3::Hash) -> Result<()> {
+    let src_filename = &job.source.filename;
+    if job.source.is_url() {
+        anyhow::bail!("Cannot verify a URL source ({src_filename})");
+    }
+    if job.source.is_stdio() {
+        anyhow::bail!("Cannot verify standard input");
+    }
+    let file = tokio::fs::File::open(src_filename).await?;
+    let mut reader = BufReader::with_capacity(Configuration::send_buffer().try_into()?, file);
+    let computed = util::hash::hash_reader(&mut reader).await?;
+    if computed != *expected {
+        anyhow::bail!(
+            "Source file {src_filename} failed verification: computed hash {computed}, expected {expected}"
+        );
+    }
+    debug!("Source file {src_filename} verified OK");
+    Ok(())
+}
+
+/// Actions a MKDIR command, which is part of a recursive PUT.
+///
+/// Returns the payload size, which is always 0.
+async fn do_mkdir(sp: RawStreamPair, job: &CopyJobSpec, limit: Duration) -> Result<u64> {
+    let mut stream: StreamPair = sp.into();
+    trace!("send command");
+    stream
+        .send
+        .write_all(
+            &crate::protocol::session::Command::new_mkdir(&job.destination.filename).serialize(),
+        )
+        .await?;
+    stream.send.flush().await?;
+
+    trace!("await response");
+    check_response(
+        with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+        format_args!("Creating directory {} failed", job.destination),
+    )?;
+    trace!("complete");
+    Ok(0)
+}
+
+/// Creates a symbolic link on the remote, as part of a recursive copy
+async fn do_symlink(sp: RawStreamPair, job: &CopyJobSpec, limit: Duration) -> Result<u64> {
+    let mut stream: StreamPair = sp.into();
+    let target = job.symlink_target().unwrap_or_default();
+    trace!("send command");
+    let cmd = crate::protocol::session::Command::new_symlink(&job.destination.filename, target);
+    stream.send.write_all(&cmd.serialize()).await?;
+    stream.send.flush().await?;
+
+    trace!("await response");
+    check_response(
+        with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+        format_args!("Creating symbolic link {} failed", job.destination),
+    )?;
+    trace!("complete");
+    Ok(0)
+}
+
+/// Builds the command for a PUT
+fn put_command(job: &CopyJobSpec, resume_offset: u64, options: &JobOptions) -> Command {
+    let dest_filename = &job.destination.filename;
+    if options.append {
+        Command::new_appending_put(dest_filename)
+    } else {
+        Command::new_resumed_put(dest_filename, resume_offset)
+    }
+    .with_mkpath(options.mkpath)
+    .with_fsync(options.fsync)
+    .with_recursive(job.tree.is_some())
+}
+
+/// Actions a PUT command
+///
+/// If `resume_offset` is non-zero, that much of the destination is assumed to be present already.
+/// Returns the number of bytes sent.
+async fn do_put(
+    sp: RawStreamPair,
+    job: &CopyJobSpec,
+    display: MultiProgress,
+    spinner: ProgressBar,
+    config: &Configuration,
+    options: JobOptions,
+    resume_offset: u64,
+) -> Result<u64> {
+    let JobOptions {
+        quiet,
+        print_hash: compute_hash,
+        ..
+    } = options;
+    let mut stream: StreamPair = sp.into();
+    let src_filename = &job.source.filename;
+
+    let PutSource {
+        reader: file,
+        len: payload_len,
+        filename: protocol_filename,
+        meta,
+    } = open_put_source(job, resume_offset, config.mmap).await?;
+    let to_send = payload_len.map(|l| l.saturating_sub(resume_offset));
+    if compute_hash && resume_offset > 0 {
+        warn!("Cannot output the hash of a resumed transfer ({src_filename})");
+    }
+    options.check_put_size(payload_len, job)?;
+    let destination = job.remote_destination_display(&protocol_filename);
+    let no_clobber = options.put_no_clobber(&destination)?;
 
     // Now we can compute how much we're going to send, update the chrome.
-    // Marshalled commands are currently 48 bytes + filename length
-    // File headers are currently 36 + filename length; Trailers are 16 bytes.
-    let steps = payload_len + 48 + 36 + 16 + 2 * dest_filename.len() as u64;
-    let progress_bar = progress_bar_for(&display, job, steps, quiet)?;
-    let mut outbound = progress_bar.wrap_async_write(stream.send);
-    let mut meter =
-        crate::client::meter::InstaMeterRunner::new(&progress_bar, spinner, config.tx());
+    // The progress bar counts payload bytes consumed from the source, not bytes on the wire,
+    // so it reflects what the user cares about regardless of protocol overheads.
+    let progress_bar = progress_bar_for(&display, job, payload_len.unwrap_or_default(), quiet)?;
+    if payload_len.is_none() {
+        progress_bar.unset_length();
+    }
+    let progress = options.progress_sink(&progress_bar, job, payload_len);
+    if resume_offset > 0 {
+        progress.inc(resume_offset);
+        progress_bar.reset_eta();
+    }
+    let mut outbound = stream.send;
+    let mut meter = options.meter(&progress_bar, spinner, config.effective_tx());
     meter.start().await;
 
     trace!("sending command");
-    let mut file = BufReader::with_capacity(Configuration::send_buffer().try_into()?, file);
+    let mut file = ProgressReader::new(file, progress);
 
-    outbound
-        .write_all(&crate::protocol::session::Command::new_put(dest_filename).serialize())
-        .await?;
+    let cmd = put_command(job, resume_offset, &options).with_no_clobber(no_clobber);
+    outbound.write_all(&cmd.serialize()).await?;
     outbound.flush().await?;
 
-    // TODO protocol timeout?
     trace!("await response");
-    let response = Response::read(&mut stream.recv).await?;
-    if response.status != Status::Ok {
-        anyhow::bail!(format!("PUT ({src_filename}) failed: {response}"));
-    }
+    let limit = config.protocol_timeout_duration();
+    check_response(
+        with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+        format_args!("PUT ({src_filename}) failed"),
+    )?;
 
-    // The filename in the protocol is the file part only of src_filename
     trace!("send header");
-    let protocol_filename = path.file_name().unwrap().to_str().unwrap().to_string(); // can't fail with the preceding checks
-    let header = FileHeader::serialize_direct(payload_len, &protocol_filename);
+    let compressed = config.compress.applies_to(&protocol_filename);
+    let header = put_header(
+        &protocol_filename,
+        payload_len,
+        meta.as_ref(),
+        options.preserve,
+        compressed,
+    );
     outbound.write_all(&header).await?;
+    if no_clobber {
+        outbound.flush().await?;
+        await_destination_check(&mut stream.recv, limit, destination.clone())
+            .await
+            .inspect_err(|_| progress_bar.finish_and_clear())?;
+    }
 
     // A server-side abort might happen part-way through a large transfer.
     trace!("send payload");
-    let result = tokio::io::copy_buf(&mut file, &mut outbound).await;
-
-    match result {
-        Ok(sent) if sent == meta.len() => (),
-        Ok(sent) => {
-            anyhow::bail!(
-                "File sent size {sent} doesn't match its metadata {}",
-                meta.len()
-            );
-        }
-        Err(e) => {
-            if e.kind() == tokio::io::ErrorKind::ConnectionReset {
-                // Maybe the connection was cut, maybe the server sent something to help us inform the user.
-                let response = match Response::read(&mut stream.recv).await {
-                    Err(_) => anyhow::bail!("connection closed unexpectedly"),
-                    Ok(r) => r,
-                };
-                anyhow::bail!(
-                    "remote closed connection: {:?}: {}",
-                    response.status,
-                    response.message.unwrap_or("(no message)".into())
-                );
-            }
-            anyhow::bail!(
-                "Unknown I/O error during PUT: {e}/{:?}/{:?}",
-                e.kind(),
-                e.raw_os_error()
-            );
-        }
+    let mut throttled = util::io::RateLimitedWriter::new(&mut outbound, config.bwlimit());
+    let sent = match send_put_payload(&mut file, &mut throttled, compressed, to_send).await {
+        Ok(sent) => sent,
+        Err(e) => return Err(put_payload_error(e, &mut stream.recv).await),
+    };
+    if let Some(expected) = to_send {
+        check_source_size(
+            src_filename,
+            expected,
+            sent.bytes,
+            &mut file,
+            options.allow_growing,
+        )
+        .await?;
     }
 
-    trace!("send trailer");
-    let trailer = FileTrailer::serialize_direct();
-    outbound.write_all(&trailer).await?;
-    outbound.flush().await?;
+    send_put_trailer(&mut outbound, &sent, to_send.is_none()).await?;
     meter.stop().await;
 
-    let response = Response::read(&mut stream.recv).await?;
-    if response.status != Status::Ok {
-        anyhow::bail!(format!(
-            "PUT ({src_filename}) failed on completion check: {response}"
-        ));
+    check_response(
+        with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+        format_args!("PUT ({src_filename}) failed on completion check"),
+    )?;
+    if let Some(meta) = meta.as_ref().filter(|_| options.remove_source) {
+        util::io::remove_if_unchanged(Path::new(src_filename), meta)
+            .await
+            .map_err(|e| anyhow::anyhow!("Could not remove {src_filename}: {e}"))?;
     }
 
     // Note that the Quinn sendstream calls finish() on drop.
     trace!("complete");
     progress_bar.finish_and_clear();
-    Ok(payload_len)
+    print_hash(
+        &display,
+        Some(sent.hash).filter(|_| compute_hash && resume_offset == 0),
+        &destination,
+    );
+    Ok(sent.bytes)
+}
+
+/// Sends the [`FileTrailer`] for a PUT.
+///
+/// If the data was streamed (its size was not known in advance), this also finishes the stream.
+async fn send_put_trailer(
+    outbound: &mut quinn::SendStream,
+    sent: &compress::Sent,
+    streamed: bool,
+) -> Result<()> {
+    trace!("send trailer");
+    if streamed {
+        // Finishing the stream tells the server where the data ends
+        let trailer = FileTrailer::serialize_padded(Some(&sent.hash), sent.compressed_size);
+        outbound.write_all(&trailer).await?;
+        outbound.finish()?;
+    } else {
+        let trailer = FileTrailer::serialize_direct(Some(&sent.hash), sent.compressed_size);
+        outbound.write_all(&trailer).await?;
+        outbound.flush().await?;
+    }
+    Ok(())
+}
+
+/// Creates the serialized [`FileHeader`] for a PUT.
+///
+/// The server applies the source's metadata to the destination if we send it, so we only do that if preserving.
+fn put_header(
+    protocol_filename: &str,
+    payload_len: Option<u64>,
+    meta: Option<&std::fs::Metadata>,
+    preserve: Option<Preserve>,
+    compressed: bool,
+) -> Vec<u8> {
+    let preserved = meta.filter(|_| preserve.is_some());
+    FileHeader {
+        size: payload_len.unwrap_or(FileHeader::UNKNOWN_SIZE),
+        filename: protocol_filename.to_string(),
+        mtime: preserved.map(util::io::mtime_nanos).unwrap_or_default(),
+        mode: preserved.map(util::io::mode_bits).unwrap_or_default(),
+        exact_mode: preserve == Some(Preserve::Mode),
+        compressed,
+    }
+    .serialize()
+}
+
+/// Waits for the server to say whether the destination of a PUT exists, having asked it not to overwrite it.
+///
+/// Fails with [`DestinationExists`] if it does.
+async fn await_destination_check(
+    recv: &mut quinn::RecvStream,
+    limit: Duration,
+    destination: String,
+) -> Result<()> {
+    trace!("await destination check");
+    let response = with_timeout(limit, "response", Response::read(recv)).await?;
+    if response.status == Status::DestinationExists {
+        return Err(DestinationExists(destination).into());
+    }
+    check_response(response, format_args!("PUT to {destination} failed"))
+}
+
+/// Interprets an error sending the payload of a PUT
+async fn put_payload_error(e: std::io::Error, recv: &mut quinn::RecvStream) -> anyhow::Error {
+    if e.kind() == tokio::io::ErrorKind::ConnectionReset {
+        // Maybe the connection was cut, maybe the server sent something to help us inform the user.
+        if let Ok(response) = Response::read(recv).await {
+            if let Err(e) = check_response(response, "remote closed connection") {
+                return e;
+            }
+        }
+        return anyhow::anyhow!("connection closed unexpectedly");
+    }
+    anyhow::anyhow!(
+        "Unknown I/O error during PUT: {e}/{:?}/{:?}",
+        e.kind(),
+        e.raw_os_error()
+    )
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        check_source_size, resume_point, send_put_payload, throughput_mode_for, Transferred,
+    };
+    use crate::{
+        client::{job::job, CopyJobSpec, FileSpec, Parameters},
+        protocol::session::CommandType,
+        transport::ThroughputMode,
+    };
+    use std::str::FromStr as _;
+    #[test]
+    fn throughput_mode_per_host() {
+        let jobs = [
+            job("host1:a", "a"),
+            job("host1:b", "b"),
+            job("c", "host2:c"),
+            job("host3:d", "d"),
+            job("e", "host3:e"),
+        ];
+        assert_eq!(throughput_mode_for(&jobs, "host1"), ThroughputMode::Rx);
+        assert_eq!(throughput_mode_for(&jobs, "host2"), ThroughputMode::Tx);
+        assert_eq!(throughput_mode_for(&jobs, "host3"), ThroughputMode::Both);
+    }
+
+    #[test]
+    fn partial_paths() {
+        use super::JobOptions;
+        use std::path::{Path, PathBuf};
+        let options = |dir: Option<&str>| {
+            JobOptions::new(&Parameters {
+                partial_dir: dir.map(String::from),
+                ..Default::default()
+            })
+        };
+        let dest = Path::new("/data/out/file");
+        assert_eq!(options(None).partial_path(dest), None);
+        let relative = options(Some(".part"));
+        assert!(relative.keep_partial);
+        assert_eq!(
+            relative.partial_path(dest),
+            Some(PathBuf::from("/data/out/.part/file"))
+        );
+        assert_eq!(
+            relative.partial_path(Path::new("file")),
+            Some(PathBuf::from(".part/file"))
+        );
+        assert_eq!(
+            options(Some("/tmp/partial")).partial_path(dest),
+            Some(PathBuf::from("/tmp/partial/file"))
+        );
+    }
+
+    #[test]
+    fn legacy_remote() {
+        use super::JobOptions;
+        let options = |parameters: Parameters| JobOptions::new(&parameters).for_remote_version(1);
+        let (get, put) = (job("host:a", "a"), job("a", "host:a"));
+        let plain = options(Parameters::default());
+        assert!(plain.check_remote(&get).is_ok());
+        assert!(plain.check_remote(&put).is_ok());
+        assert!(plain.check_put_size(Some(1), &put).is_ok());
+        let err = plain.check_put_size(None, &put).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a: the remote qcp is too old to receive data of unknown size"
+        );
+
+        let append = options(Parameters {
+            append: true,
+            ..Default::default()
+        });
+        // The client appends locally
+        assert!(append.check_remote(&get).is_ok());
+        let err = append.check_remote(&put).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            "a: the remote qcp is too old to append to a file (--append)"
+        );
+        for parameters in [
+            Parameters {
+                resume: true,
+                ..Default::default()
+            },
+            Parameters {
+                mkpath: true,
+                ..Default::default()
+            },
+            Parameters {
+                fsync: true,
+                ..Default::default()
+            },
+        ] {
+            assert!(options(parameters).check_remote(&put).is_err());
+        }
+        let remove_source = options(Parameters {
+            remove_source_files: true,
+            ..Default::default()
+        });
+        assert!(remove_source.check_remote(&get).is_err());
+        assert!(remove_source.check_remote(&put).is_ok());
+
+        let mut tree = put.clone();
+        tree.tree = Some(crate::client::job::TreeEntry::Directory);
+        assert!(plain.check_remote(&tree).is_err());
+        assert!(plain.for_remote_version(2).check_remote(&tree).is_ok());
+    }
+
+    #[test]
+    fn transferred_by_direction() {
+        let mut t = Transferred::default();
+        t.add(CommandType::Get, 100);
+        t.add(CommandType::Put, 20);
+        t.add(CommandType::Get, 3);
+        assert_eq!(t.received, 103);
+        assert_eq!(t.sent, 20);
+        assert_eq!(t.total(), 123);
+    }
+
+    #[test]
+    fn mixed_direction_jobs() {
+        let fs = |s: &str| FileSpec::from_str(s).unwrap();
+        let mut p = Parameters {
+            source: Some(fs("host:a")),
+            destination: Some(fs("/tmp/")),
+            also: vec![fs("/tmp/b"), fs("host:b"), fs("host:c"), fs("/tmp/")],
+            ..Default::default()
+        };
+        let jobs = p.jobs().unwrap();
+        assert_eq!(
+            jobs.iter()
+                .map(CopyJobSpec::command_type)
+                .collect::<Vec<_>>(),
+            [CommandType::Get, CommandType::Put, CommandType::Get]
+        );
+        assert_eq!(throughput_mode_for(&jobs, "host"), ThroughputMode::Both);
+
+        p.also = vec![fs("/tmp/b"), fs("other:b")];
+        assert!(p.jobs().is_err());
+    }
+
+    #[test]
+    fn resume_points() {
+        assert_eq!(resume_point(100, None).unwrap(), Some(0));
+        assert_eq!(resume_point(100, Some(0)).unwrap(), Some(0));
+        assert_eq!(resume_point(100, Some(40)).unwrap(), Some(40));
+        assert_eq!(resume_point(100, Some(100)).unwrap(), None);
+        assert!(resume_point(100, Some(101)).is_err());
+    }
+
+    #[tokio::test]
+    async fn source_size_changes() {
+        let data = [7u8; 100];
+        let send = |declared, allow_growing| async move {
+            let mut source = &data[..];
+            let mut sink = Vec::new();
+            let sent = send_put_payload(&mut source, &mut sink, false, Some(declared))
+                .await
+                .unwrap();
+            assert_eq!(sink.len() as u64, sent.bytes);
+            check_source_size("f", declared, sent.bytes, &mut source, allow_growing).await
+        };
+        assert!(send(100, false).await.is_ok());
+        let shrank = send(120, true).await.unwrap_err().to_string();
+        assert!(shrank.contains("shrank"), "{shrank}");
+        let grew = send(80, false).await.unwrap_err().to_string();
+        assert!(
+            grew.contains("grew") && grew.contains("--allow-growing"),
+            "{grew}"
+        );
+        assert!(send(80, true).await.is_ok());
+    }
+
+    #[test]
+    fn verify_source_needs_single_put() {
+        let fs = |s: &str| FileSpec::from_str(s).unwrap();
+        let mut p = Parameters {
+            source: Some(fs("/tmp/a")),
+            destination: Some(fs("host:")),
+            verify_source: true,
+            ..Default::default()
+        };
+        assert!(p.jobs().is_ok());
+        p.also = vec![fs("/tmp/b"), fs("host:")];
+        assert!(p.jobs().is_err());
+        p.also.clear();
+        p.source = Some(fs("host:a"));
+        p.destination = Some(fs("/tmp/"));
+        assert!(p.jobs().is_err());
+    }
+
+    #[test]
+    fn follow_needs_single_get() {
+        let fs = |s: &str| FileSpec::from_str(s).unwrap();
+        let mut p = Parameters {
+            source: Some(fs("host:app.log")),
+            destination: Some(fs("/tmp/")),
+            follow: true,
+            ..Default::default()
+        };
+        assert!(p.jobs().is_ok());
+        p.also = vec![fs("host:other.log"), fs("/tmp/")];
+        assert!(p.jobs().is_err());
+        p.also.clear();
+        p.source = Some(fs("/tmp/app.log"));
+        p.destination = Some(fs("host:"));
+        assert!(p.jobs().is_err());
+    }
+
+    #[test]
+    fn files_from() {
+        let fs = |s: &str| FileSpec::from_str(s).unwrap();
+        let (list, _tempdir) =
+            crate::util::make_test_tempfile("# comment\na\n\n  \nsub/b\n/abs/c\n", "list");
+        let mut p = Parameters {
+            source: Some(fs("host:dest/")),
+            files_from: Some(list.to_string_lossy().into_owned()),
+            files_from_base: Some("/base".into()),
+            ..Default::default()
+        };
+        let jobs = p.jobs().unwrap();
+        assert_eq!(
+            jobs.iter()
+                .map(|j| j.source.filename.as_str())
+                .collect::<Vec<_>>(),
+            ["/base/a", "/base/sub/b", "/abs/c"]
+        );
+        assert!(jobs
+            .iter()
+            .all(|j| j.destination.to_string() == "host:dest/"));
+
+        // several files need a directory destination
+        p.source = Some(fs("host:dest"));
+        assert!(p.jobs().is_err());
+        // only the destination may be given
+        p.source = Some(fs("/tmp/x"));
+        p.destination = Some(fs("host:dest/"));
+        assert!(p.jobs().is_err());
+    }
 }
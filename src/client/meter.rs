@@ -6,17 +6,116 @@
 //! It is good for estimating the ETA, but conceals the full picture when bandwidth is spiky.
 //! This struct computes the near-instant progress rate and updates the message on another progress bar.
 //! Sorry (not sorry)...
+//!
+//! With `--stats-fd`, the same figures are also written, once a second, as a line of JSON
+//! to the given file descriptor, for consumption by a dashboard or similar.
+//! Each line looks like this (the `rate` is in bytes per second):
+//! ```text
+//! {"timestamp":1733000000.25,"file":"data.bin","bytes":1048576,"rate":1040000.0,"rtt_ms":301.5,"cwnd":3000000}
+//! ```
 
 use std::{
+    fs::File,
+    io::Write as _,
     sync::{Arc, Mutex},
     time::{Duration, SystemTime},
 };
 
+use anyhow::Result;
 use human_repr::HumanThroughput as _;
 use indicatif::ProgressBar;
+use quinn::Connection;
+use serde::Serialize;
 use tokio::{sync::oneshot, task::JoinHandle};
 use tracing::{debug, warn};
 
+/// Where `--stats-fd` writes its statistics lines.
+///
+/// This is shared by all jobs; each line is written in a single call, so lines from concurrent jobs do not interleave.
+#[derive(Clone, Debug)]
+pub(crate) struct StatsOutput {
+    file: Arc<Mutex<File>>,
+    /// The connection and filename of the job being reported on
+    job: Option<(Connection, String)>,
+}
+
+/// A line of `--stats-fd` output
+#[derive(Debug, Serialize)]
+struct StatsLine<'a> {
+    /// Seconds since the Unix epoch
+    timestamp: f64,
+    file: &'a str,
+    /// Payload bytes transferred so far
+    bytes: u64,
+    /// Payload bytes per second, over the last second
+    rate: f64,
+    rtt_ms: f64,
+    /// Congestion window, in bytes
+    cwnd: u64,
+}
+
+impl StatsOutput {
+    /// Opens an inherited file descriptor for writing
+    #[cfg(unix)]
+    pub(crate) fn open(fd: u32) -> Result<Self> {
+        let file = std::fs::OpenOptions::new()
+            .append(true)
+            .open(format!("/dev/fd/{fd}"))
+            .map_err(|e| anyhow::anyhow!("cannot write statistics to file descriptor {fd}: {e}"))?;
+        Ok(Self::new(file))
+    }
+    /// Opens an inherited file descriptor for writing
+    #[cfg(not(unix))]
+    pub(crate) fn open(_fd: u32) -> Result<Self> {
+        anyhow::bail!("--stats-fd is not supported on this platform")
+    }
+
+    fn new(file: File) -> Self {
+        Self {
+            file: Arc::new(Mutex::new(file)),
+            job: None,
+        }
+    }
+
+    /// Returns a copy of this output which reports on the given job
+    pub(crate) fn for_job(&self, connection: &Connection, filename: String) -> Self {
+        Self {
+            file: self.file.clone(),
+            job: Some((connection.clone(), filename)),
+        }
+    }
+
+    fn report(&self, bytes: u64, rate: f64) {
+        let Some((connection, filename)) = &self.job else {
+            return;
+        };
+        let line = StatsLine {
+            timestamp: SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs_f64(),
+            file: filename,
+            bytes,
+            rate,
+            rtt_ms: connection.rtt().as_secs_f64() * 1000.,
+            cwnd: connection.stats().path.cwnd,
+        };
+        self.write(&line);
+    }
+
+    fn write(&self, line: &StatsLine<'_>) {
+        let Ok(mut text) = serde_json::to_string(line) else {
+            return;
+        };
+        text.push('\n');
+        let mut file = self.file.lock().unwrap();
+        // An unbuffered file, so each line goes out at once
+        if let Err(e) = file.write_all(text.as_bytes()) {
+            debug!("failed to write statistics: {e}");
+        }
+    }
+}
+
 /// Convenience wrapper for `InstaMeter` that takes care of starting & stopping
 #[derive(Debug)]
 pub(crate) struct InstaMeterRunner {
@@ -26,13 +125,16 @@ pub(crate) struct InstaMeterRunner {
 }
 
 impl InstaMeterRunner {
-    pub(crate) fn new(source: &ProgressBar, destination: ProgressBar, max_throughput: u64) -> Self {
+    pub(crate) fn new(
+        source: &ProgressBar,
+        destination: ProgressBar,
+        max_throughput: u64,
+        stats: Option<StatsOutput>,
+    ) -> Self {
+        let mut inner = InstaMeterInner::new(source, destination, max_throughput);
+        inner.stats = stats;
         Self {
-            inner: Arc::new(Mutex::new(InstaMeterInner::new(
-                source,
-                destination,
-                max_throughput,
-            ))),
+            inner: Arc::new(Mutex::new(inner)),
             task: None,
             stopper: None,
         }
@@ -91,12 +193,16 @@ impl Drop for InstaMeterRunner {
 
 /// Near-instant progress meter wrapper for `ProgressBar`.
 /// This struct holds the inner persistent data that is updated for the life of the struct.
+///
+/// The `source` progress bar is expected to count payload bytes (read from the source file, or written to
+/// the destination file), not bytes on the wire. The reported rate is therefore the rate of useful progress.
 #[derive(Clone, Debug)]
 pub(crate) struct InstaMeterInner {
     previous_position: u64,
     source: ProgressBar,
     destination: ProgressBar,
     tick_calc: TickRateCalculator,
+    stats: Option<StatsOutput>,
 }
 
 impl InstaMeterInner {
@@ -107,6 +213,7 @@ impl InstaMeterInner {
             source: source.clone(),
             destination,
             tick_calc: TickRateCalculator::new(max_throughput as f64),
+            stats: None,
         }
     }
 
@@ -118,6 +225,9 @@ impl InstaMeterInner {
         let elapsed = elapsed.as_secs_f64();
         let rate = progress / elapsed;
         self.previous_position = current;
+        if let Some(stats) = &self.stats {
+            stats.report(current, rate);
+        }
         let msg = format!("{} (last 1s)", rate.human_throughput_bytes());
         self.destination.set_prefix(msg.clone());
         self.destination
@@ -168,7 +278,7 @@ impl TickRateCalculator {
 
 #[cfg(test)]
 mod test {
-    use super::TickRateCalculator;
+    use super::{StatsLine, StatsOutput, TickRateCalculator};
 
     fn rate(tput: f64) {
         let trc = TickRateCalculator::new(5. * 37_500_000.0);
@@ -189,4 +299,31 @@ mod test {
         rate(10_000_000.);
         rate(37_500_000.);
     }
+
+    #[cfg(unix)]
+    #[test]
+    fn stats_lines() {
+        use std::os::fd::AsRawFd as _;
+
+        let tempfile = tempfile::NamedTempFile::new().unwrap();
+        let fd = u32::try_from(tempfile.as_file().as_raw_fd()).unwrap();
+        let output = StatsOutput::open(fd).unwrap();
+        for bytes in [1000, 2000] {
+            output.write(&StatsLine {
+                timestamp: 1.5,
+                file: "f",
+                bytes,
+                rate: 1000.,
+                rtt_ms: 300.,
+                cwnd: 12000,
+            });
+        }
+        let text = std::fs::read_to_string(tempfile.path()).unwrap();
+        let lines = text.lines().collect::<Vec<_>>();
+        assert_eq!(lines.len(), 2);
+        let line = json::parse(lines[1]).unwrap();
+        assert_eq!(line["bytes"], 2000);
+        assert_eq!(line["file"], "f");
+        assert_eq!(line["cwnd"], 12000);
+    }
 }
@@ -0,0 +1,337 @@
+//! Removal of remote files which are not in the source, for `--delete`
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! For backup-style usage, a recursive copy should leave the destination tree matching the source.
+//! After the copy has completed successfully, `--delete` lists each destination tree on the remote
+//! and deletes anything in it that was not sent.
+//!
+//! Entries matching an exclude pattern are left alone, as are the directories containing them.
+//! Nothing is deleted unless all the jobs succeeded.
+
+use std::{collections::HashSet, path::Path, time::Duration};
+
+use anyhow::Result;
+use indicatif::MultiProgress;
+use quinn::Connection;
+use tokio::io::{AsyncRead, AsyncWriteExt as _};
+use tracing::{error, info, trace, warn};
+
+use super::{
+    exclude::Exclusions,
+    job::{join_remote, CopyJobSpec, FileSpec},
+    main_loop::{check_response, HostConnection},
+    Parameters,
+};
+use crate::{
+    protocol::{
+        session::{with_timeout, Command, FileHeader, Response, Status},
+        StreamPair,
+    },
+    util,
+};
+
+/// Mask and value of the file type bits in a Unix mode (`st_mode`) which identify a directory
+const MODE_TYPE_MASK: u32 = 0o170_000;
+const MODE_DIRECTORY: u32 = 0o040_000;
+
+/// A directory tree sent to the remote, which is to be left matching the source
+#[derive(Debug)]
+struct Tree {
+    /// The remote root directory of the tree
+    root: FileSpec,
+    /// The paths within the tree that the source contains, relative to the root
+    expected: HashSet<String>,
+}
+
+/// The destination trees of a recursive copy, which `--delete` prunes
+#[derive(Debug)]
+pub(crate) struct Mirror {
+    trees: Vec<Tree>,
+    exclude: Exclusions,
+}
+
+/// If `path` is within the remote directory `root`, returns its path relative to `root`
+fn relative_to<'a>(path: &'a str, root: &str) -> Option<&'a str> {
+    path.strip_prefix(root)?
+        .strip_prefix('/')
+        .filter(|r| !r.is_empty())
+}
+
+impl Mirror {
+    /// Works out the destination trees from the jobs of a recursive copy.
+    ///
+    /// This must be given _all_ the jobs, including any that a state file says are already complete,
+    /// or their destinations would be deleted.
+    pub(crate) fn new(jobs: &[CopyJobSpec], parameters: &Parameters) -> Result<Self> {
+        let paths = jobs
+            .iter()
+            .filter_map(CopyJobSpec::remote_tree_path)
+            .collect::<Vec<_>>();
+        let directories = jobs.iter().filter(|j| j.is_directory()).collect::<Vec<_>>();
+        let trees = directories
+            .iter()
+            .filter(|d| {
+                // A root is not within any other directory we are sending
+                !directories.iter().any(|other| {
+                    relative_to(&d.destination.filename, &other.destination.filename).is_some()
+                })
+            })
+            .map(|root| Tree {
+                root: root.destination.clone(),
+                expected: paths
+                    .iter()
+                    .filter_map(|p| relative_to(p, &root.destination.filename))
+                    .map(str::to_string)
+                    .collect(),
+            })
+            .collect();
+        Ok(Self {
+            trees,
+            exclude: Exclusions::new(&parameters.exclude, &parameters.exclude_from)?,
+        })
+    }
+
+    /// Deletes whatever should not be in the trees on the given remote host, or lists it if `dry_run` is set.
+    ///
+    /// Returns true if this was successful.
+    pub(crate) async fn delete_extraneous(
+        &self,
+        host: &HostConnection,
+        display: &MultiProgress,
+        dry_run: bool,
+        limit: Duration,
+    ) -> bool {
+        let connection = &host.connection;
+        let mut success = true;
+        for tree in &self.trees {
+            if tree.root.host.as_deref() != Some(host.user_hostname.as_str()) {
+                continue;
+            }
+            if host.protocol_version < 2 {
+                error!(
+                    "The remote qcp is too old to delete extraneous files from {}",
+                    tree.root
+                );
+                success = false;
+                continue;
+            }
+            let listing = match list(connection, &tree.root, limit).await {
+                Ok(listing) => listing,
+                Err(e) => {
+                    error!("{e}");
+                    success = false;
+                    continue;
+                }
+            };
+            for relative in extraneous(&tree.expected, &listing, &self.exclude) {
+                let target = FileSpec {
+                    host: tree.root.host.clone(),
+                    filename: join_remote(&tree.root.filename, &relative),
+                };
+                if dry_run {
+                    display.suspend(|| println!("Would delete {target}"));
+                    continue;
+                }
+                match delete(connection, &target, limit).await {
+                    Ok(()) => info!("Deleted {target}"),
+                    Err(e) => {
+                        error!("{e}");
+                        success = false;
+                    }
+                }
+            }
+        }
+        success
+    }
+}
+
+/// Works out which entries in a remote tree should be deleted.
+///
+/// `listing` contains the path of each entry relative to the root, and whether it is a directory.
+/// The output is ordered so that the contents of a directory come before the directory itself.
+fn extraneous(
+    expected: &HashSet<String>,
+    listing: &[(String, bool)],
+    exclude: &Exclusions,
+) -> Vec<String> {
+    let mut keep: HashSet<&Path> = expected.iter().map(Path::new).collect();
+    for (path, is_dir) in listing {
+        let path = Path::new(path);
+        let protected = exclude.is_excluded(path, *is_dir)
+            || path
+                .ancestors()
+                .skip(1)
+                .filter(|a| !a.as_os_str().is_empty())
+                .any(|a| exclude.is_excluded(a, true));
+        if protected {
+            // Keep the directories containing it, too
+            keep.extend(path.ancestors().filter(|a| !a.as_os_str().is_empty()));
+        }
+    }
+    let mut result = listing
+        .iter()
+        .map(|(path, _)| path)
+        .filter(|path| !keep.contains(Path::new(path)))
+        .filter(|path| {
+            // Don't let the remote trick us into deleting anything outside the tree
+            let ok = util::io::relative_path(path).is_some();
+            if !ok {
+                warn!("Ignoring invalid path {path} in remote listing");
+            }
+            ok
+        })
+        .cloned()
+        .collect::<Vec<_>>();
+    // Reverse order puts everything within a directory before the directory itself
+    result.sort_unstable_by(|a, b| b.cmp(a));
+    result
+}
+
+/// Lists a remote directory tree.
+///
+/// If the directory does not exist (which may happen in a dry run), the listing is empty.
+async fn list(
+    connection: &Connection,
+    root: &FileSpec,
+    limit: Duration,
+) -> Result<Vec<(String, bool)>> {
+    let mut stream: StreamPair = connection.open_bi().await?.into();
+    trace!("send list");
+    stream
+        .send
+        .write_all(&Command::new_list(&root.filename).serialize())
+        .await?;
+    stream.send.flush().await?;
+    let response = with_timeout(limit, "response", Response::read(&mut stream.recv)).await?;
+    if response.status == Status::FileNotFound {
+        return Ok(Vec::new());
+    }
+    check_response(response, format_args!("Listing {root} failed"))?;
+    let listing = receive_listing(&mut stream.recv, limit)
+        .await
+        .map_err(|e| anyhow::anyhow!("Listing {root} failed: {e}"))?;
+    let _ = stream.send.finish();
+    trace!("listed {} entries", listing.len());
+    Ok(listing)
+}
+
+/// Reads the entries sent in response to a List command, up to the end marker
+async fn receive_listing<R>(recv: &mut R, limit: Duration) -> Result<Vec<(String, bool)>>
+where
+    R: AsyncRead + Unpin,
+{
+    let mut listing = Vec::new();
+    loop {
+        let header = with_timeout(limit, "file header", FileHeader::try_read(recv)).await?;
+        match header {
+            Some(header) if header.is_list_end() => return Ok(listing),
+            Some(header) => {
+                let is_dir = header.mode & MODE_TYPE_MASK == MODE_DIRECTORY;
+                listing.push((header.filename, is_dir));
+            }
+            None => anyhow::bail!("the listing was cut short"),
+        }
+    }
+}
+
+/// Deletes a remote file, symbolic link or empty directory
+async fn delete(connection: &Connection, target: &FileSpec, limit: Duration) -> Result<()> {
+    let mut stream: StreamPair = connection.open_bi().await?.into();
+    trace!("send delete");
+    stream
+        .send
+        .write_all(&Command::new_delete(&target.filename).serialize())
+        .await?;
+    stream.send.flush().await?;
+    check_response(
+        with_timeout(limit, "response", Response::read(&mut stream.recv)).await?,
+        format_args!("Deleting {target} failed"),
+    )?;
+    let _ = stream.send.finish();
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use super::{extraneous, receive_listing, relative_to, MODE_DIRECTORY};
+    use crate::{client::exclude::Exclusions, protocol::session::FileHeader};
+
+    #[test]
+    fn relative() {
+        assert_eq!(relative_to("dest/d/x", "dest/d"), Some("x"));
+        assert_eq!(relative_to("dest/dd/x", "dest/d"), None);
+        assert_eq!(relative_to("dest/d", "dest/d"), None);
+    }
+
+    fn listing(entries: &[(&str, bool)]) -> Vec<(String, bool)> {
+        entries
+            .iter()
+            .map(|(p, d)| ((*p).to_string(), *d))
+            .collect()
+    }
+
+    #[test]
+    fn finds_extraneous() {
+        let expected: HashSet<String> = ["a", "sub", "sub/b"].iter().map(|s| (*s).into()).collect();
+        let remote = listing(&[
+            ("a", false),
+            ("old", true),
+            ("old/x", false),
+            ("old/deeper", true),
+            ("old/deeper/y", false),
+            ("sub", true),
+            ("sub/b", false),
+            ("sub/c", false),
+        ]);
+        let result = extraneous(&expected, &remote, &Exclusions::default());
+        assert_eq!(
+            result,
+            ["sub/c", "old/x", "old/deeper/y", "old/deeper", "old"]
+        );
+    }
+
+    #[test]
+    fn excluded_entries_are_kept() {
+        let expected: HashSet<String> = ["a"].iter().map(|s| (*s).into()).collect();
+        let remote = listing(&[
+            ("a", false),
+            ("old", true),
+            ("old/keep.tmp", false),
+            ("old/other", false),
+            ("target", true),
+            ("target/x.o", false),
+        ]);
+        let exclude = Exclusions::new(&["*.tmp".into(), "target/".into()], &[]).unwrap();
+        let result = extraneous(&expected, &remote, &exclude);
+        assert_eq!(result, ["old/other"]);
+    }
+
+    #[tokio::test]
+    async fn listing_end_marker() {
+        let entry = |filename: &str, mode| FileHeader {
+            filename: filename.into(),
+            mode,
+            ..Default::default()
+        };
+        let mut wire = entry("sub", MODE_DIRECTORY).serialize();
+        wire.extend(entry("sub/file", 0o100_644).serialize());
+        let limit = std::time::Duration::ZERO;
+
+        // Without the end marker, the listing is incomplete
+        assert!(receive_listing(&mut wire.as_slice(), limit).await.is_err());
+        wire.extend(FileHeader::list_end().serialize());
+        assert_eq!(
+            receive_listing(&mut wire.as_slice(), limit).await.unwrap(),
+            listing(&[("sub", true), ("sub/file", false)])
+        );
+    }
+
+    #[test]
+    fn ignores_escapes() {
+        let remote = listing(&[("../outside", false), ("/etc/passwd", false)]);
+        assert!(extraneous(&HashSet::new(), &remote, &Exclusions::default()).is_empty());
+    }
+}
@@ -1,21 +1,42 @@
 //! client-side (_initiator_) main loop and supporting structures
 
 mod options;
-pub use options::Parameters;
+pub use options::{LinkMode, Parameters, Preserve};
 
 mod control;
+#[cfg(unix)]
+mod daemon;
+mod exclude;
+mod exec;
+mod follow;
+#[cfg(feature = "http-source")]
+mod http;
 pub use control::Channel;
 
 mod job;
+mod library;
 pub use job::CopyJobSpec;
 pub use job::FileSpec;
+pub use library::{copy, copy_with_progress, TransferReport};
 
 mod main_loop;
 mod meter;
+mod mirror;
+mod notify;
+mod overwrite;
+mod partial;
+mod probe;
 mod progress;
+mod skip;
 pub mod ssh;
+mod state;
+mod total;
+mod window;
 
 #[allow(clippy::module_name_repetitions)]
 pub use main_loop::client_main;
 
-pub use progress::MAX_UPDATE_FPS;
+#[cfg(unix)]
+pub(crate) use daemon::daemon_main;
+
+pub use progress::{ProgressSink, MAX_UPDATE_FPS};
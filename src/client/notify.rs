@@ -0,0 +1,96 @@
+//! Notifying the user when a long transfer finishes
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! Whoever starts a transfer that takes hours is likely to go and do something else in the meantime.
+//! With `notify`, once a run which took longer than `notify_after` finishes, qcp lets them know in two ways:
+//!
+//! * An OSC 9 escape sequence, which many terminal emulators turn into a desktop notification.
+//!   This is only sent if standard error is a terminal; terminals which don't support it ignore it.
+//! * `notify_command`, if set, so users can wire up their own alerts.
+//!   The outcome is passed to it in environment variables, as described in its documentation.
+//!
+//! A failure to notify is reported, but does not affect the outcome of the run.
+
+use std::{process::Stdio, time::Duration};
+
+use human_repr::{HumanCount as _, HumanDuration as _};
+use tracing::{debug, warn};
+
+use super::exec::shell;
+use crate::config::Configuration;
+
+/// Lets the user know that a run has finished, if it took long enough for `notify` to apply.
+///
+/// `bytes` is the payload transferred.
+pub(super) async fn notify(config: &Configuration, success: bool, bytes: u64, elapsed: Duration) {
+    if !config.notify || elapsed < config.notify_after_duration() {
+        return;
+    }
+    let message = if success {
+        format!(
+            "qcp: transferred {} in {}",
+            bytes.human_count_bytes(),
+            elapsed.human_duration()
+        )
+    } else {
+        format!("qcp: failed after {}", elapsed.human_duration())
+    };
+    if console::Term::stderr().is_term() {
+        eprint!("\x1b]9;{message}\x07");
+    }
+    if !config.notify_command.is_empty() {
+        run_command(&config.notify_command, success, bytes, elapsed).await;
+    }
+}
+
+/// Runs the user's `notify_command`, telling it the outcome in its environment
+async fn run_command(command: &str, success: bool, bytes: u64, elapsed: Duration) {
+    debug!("running notify command: {command}");
+    let status = shell(command)
+        .env("QCP_RESULT", if success { "success" } else { "failure" })
+        .env("QCP_BYTES", bytes.to_string())
+        .env("QCP_ELAPSED", elapsed.as_secs().to_string())
+        // Our standard output may be carrying data
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .status()
+        .await;
+    match status {
+        Ok(s) if s.success() => (),
+        Ok(s) => warn!("notify command failed ({s})"),
+        Err(e) => warn!("could not run notify command: {e}"),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::notify;
+    use crate::config::Configuration;
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn command_environment() {
+        let tmp = tempfile::tempdir().unwrap();
+        let out = tmp.path().join("out");
+        let config = Configuration {
+            notify: true,
+            notify_after: 10,
+            notify_command: format!(
+                "echo $QCP_RESULT $QCP_BYTES $QCP_ELAPSED > {}",
+                out.display()
+            ),
+            ..Default::default()
+        };
+        // Too quick to bother
+        notify(&config, true, 1234, Duration::from_secs(5)).await;
+        assert!(!out.exists());
+
+        notify(&config, true, 1234, Duration::from_secs(12)).await;
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "success 1234 12\n");
+        notify(&config, false, 0, Duration::from_secs(30)).await;
+        assert_eq!(std::fs::read_to_string(&out).unwrap(), "failure 0 30\n");
+    }
+}
@@ -1,8 +1,12 @@
 //! Options specific to qcp client-mode
 // (c) 2024 Ross Younger
 
-use super::{CopyJobSpec, FileSpec};
+use super::{exclude::Exclusions, CopyJobSpec, FileSpec};
+use crate::protocol::session::CommandType;
+use anyhow::Context as _;
 use clap::Parser;
+use serde::{Deserialize, Serialize};
+use std::path::Path;
 
 #[derive(Debug, Parser, Clone, Default)]
 #[allow(clippy::struct_excessive_bools)]
@@ -48,18 +52,426 @@ pub struct Parameters {
     )]
     pub statistics: bool,
 
+    /// Treats a failure to set UDP buffer sizes, at either end, as an error
+    ///
+    /// By default qcp warns and continues, though performance may suffer.
+    /// This option is for those who would rather fix the system configuration (see `--help-buffers`).
+    #[arg(long, action, help_heading("Network tuning"), display_order(100))]
+    pub strict_buffers: bool,
+
+    /// If the kernel UDP buffer limits are too small, raises them for the duration of the transfer
+    ///
+    /// This writes to `/proc/sys/net/core/{rmem,wmem}_max`, so only works on Linux and requires root.
+    /// The previous limits are restored on exit.
+    /// If not run as root, qcp prints the commands to run instead.
+    #[arg(long, action, help_heading("Network tuning"), display_order(100))]
+    pub auto_sysctl: bool,
+
     /// Enables detailed debug output from the remote endpoint
     /// (this may interfere with transfer speeds)
     #[arg(long, action, help_heading("Debug"), display_order(0))]
     pub remote_debug: bool,
 
+    /// Writes output from the remote endpoint to a file, instead of to the terminal
+    ///
+    /// This keeps local and remote logs separate, and keeps remote output (which may include
+    /// file and path names) out of your terminal. It is most useful with `--remote-debug`.
+    ///
+    /// N.B. Everything the remote process writes to stderr goes to this file, including any errors and messages from ssh itself.
+    #[arg(
+        long,
+        action,
+        value_name("FILE"),
+        help_heading("Debug"),
+        display_order(0)
+    )]
+    pub remote_log: Option<String>,
+
+    /// Writes live statistics to this file descriptor, as a line of JSON every second
+    ///
+    /// Each line has the time, the file being transferred, the bytes transferred so far,
+    /// the rate over the last second, and the connection's current round-trip time and congestion window.
+    /// The file descriptor must be open for writing when qcp starts, e.g. `qcp --stats-fd 3 ... 3>stats.json`.
+    #[arg(
+        long,
+        action,
+        value_name("N"),
+        help_heading("Output"),
+        display_order(0)
+    )]
+    pub stats_fd: Option<u32>,
+
+    /// Doesn't show the total progress of a batch of jobs
+    ///
+    /// By default, when there is more than one job, an extra progress bar shows their total payload.
+    /// Working this out means looking up the size of every file first, which for remote files takes a round trip.
+    #[arg(long, action, help_heading("Output"), display_order(0))]
+    pub no_total: bool,
+
     /// Output timing profile data after completion
     #[arg(long, action, help_heading("Output"), display_order(0))]
     pub profile: bool,
 
+    /// Outputs the BLAKE3 hash of each file transferred
+    ///
+    /// For each file, prints a line of the form `<hash>  <destination>` to stdout.
+    /// The hash is computed locally from the data as it is sent or received.
+    #[arg(long, action, help_heading("Output"), display_order(0))]
+    pub print_hash: bool,
+
+    /// Verifies the source file before sending it
+    ///
+    /// The source file is read and hashed before the transfer starts.
+    /// If its BLAKE3 hash does not match `--expected-hash`, the transfer is aborted.
+    /// This catches local corruption before any bandwidth is spent.
+    #[arg(
+        long,
+        action,
+        requires("expected_hash"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub verify_source: bool,
+
+    /// The expected BLAKE3 hash of the source file, in hex, for `--verify-source`
+    #[arg(
+        long,
+        value_name("HASH"),
+        requires("verify_source"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub expected_hash: Option<blake3::Hash>,
+
+    /// Records completed jobs in a state file, and skips any jobs it says are already complete
+    ///
+    /// If a batch of jobs is interrupted, re-running the same command with the same state file
+    /// resumes where it left off, at the level of whole files.
+    /// Jobs are identified by their source and destination, including the remote host.
+    /// The file is created if it does not exist.
+    #[arg(long, value_name("FILE"), help_heading("Jobs"), display_order(0))]
+    pub state_file: Option<String>,
+
+    /// Overwrites existing destination files without asking
+    ///
+    /// By default, if a destination file already exists, qcp asks whether to overwrite it.
+    /// If standard input is not a terminal, there is nobody to ask, so the file is not overwritten
+    /// and the job fails.
+    /// This does not apply to `--resume` or `--append`, which expect the destination to exist.
+    #[arg(short('f'), long, action, help_heading("Jobs"), display_order(0))]
+    pub force: bool,
+
+    /// Skips files whose destination already exists
+    ///
+    /// Before sending or receiving each file, qcp checks whether the destination exists
+    /// (asking the remote, when sending). If it does, the file is skipped.
+    /// Skipped files are reported as such; they do not count as failures, nor towards the data transferred.
+    #[arg(
+        long,
+        action,
+        conflicts_with_all(["size_only", "update", "append"]),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub ignore_existing: bool,
+
+    /// Skips files whose destination is already the same size as the source
+    ///
+    /// This works like `--ignore-existing`, except that a destination which differs in size is replaced.
+    /// N.B. The contents of the files are not compared.
+    #[arg(
+        long,
+        action,
+        conflicts_with_all(["update", "append"]),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub size_only: bool,
+
+    /// Skips files whose destination is at least as new as the source
+    ///
+    /// This works like `--ignore-existing`, except that a destination which is older than the source is replaced.
+    /// Modification times within 2 seconds of each other are considered the same, as some filesystems
+    /// record them coarsely. If either time is unknown, the file is transferred.
+    /// Use `--preserve` so that the destinations of transferred files have the same times as their sources.
+    #[arg(
+        short('u'),
+        long,
+        action,
+        conflicts_with("append"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub update: bool,
+
+    /// Resumes interrupted transfers
+    ///
+    /// When sending a file, qcp first asks the remote how much of the destination is already present,
+    /// and sends only the remainder. If the destination is already the same size as the source, nothing is sent.
+    ///
+    /// When receiving a file, qcp records details of the remote file in a `.qcp-partial` file next to the destination
+    /// until the transfer completes. If that is present, only the rest of the file is requested;
+    /// but if the remote file has changed in the meantime, the transfer starts again from the beginning.
+    ///
+    /// N.B. The existing data is assumed to be correct; it is not checked.
+    /// Partially received files are kept if a transfer fails or is interrupted.
+    #[arg(long, action, help_heading("Jobs"), display_order(0))]
+    pub resume: bool,
+
+    /// Appends to the destination file, instead of replacing it
+    ///
+    /// The destination is created if it does not exist. It must be a file, not a directory.
+    /// Only sending a single file is supported.
+    /// If the transfer fails, the remote file is truncated back to its original length.
+    #[arg(
+        long,
+        action,
+        conflicts_with_all(["resume", "recursive"]),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub append: bool,
+
+    /// Creates any missing parent directories of the remote destination
+    ///
+    /// By default, sending to a destination whose directory does not exist is an error.
+    /// With this option, a destination ending in `/` is taken to be a directory, which is created if necessary.
+    #[arg(long, action, help_heading("Jobs"), display_order(0))]
+    pub mkpath: bool,
+
+    /// Keeps partially received files if a transfer fails or is interrupted
+    ///
+    /// By default, a file that was being received when something went wrong (or when you pressed Ctrl-C) is removed.
+    /// This option does not affect files being sent; the remote keeps whatever it has received of those,
+    /// so that `--resume` can complete them.
+    #[arg(long, action, help_heading("Jobs"), display_order(0))]
+    pub partial: bool,
+
+    /// Puts received files in DIR until they are complete
+    ///
+    /// Each file is moved into place once it has been received and its checksum verified.
+    /// If a transfer fails or is interrupted, the file is kept in DIR, so that `--resume` can complete it;
+    /// this implies `--partial`.
+    ///
+    /// A relative DIR is taken relative to each destination's directory. It is created as needed,
+    /// and removed again once it is empty. DIR must be on the same filesystem as the destination.
+    /// This option does not affect files being sent.
+    #[arg(
+        long,
+        value_name("DIR"),
+        conflicts_with("inplace"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub partial_dir: Option<String>,
+
+    /// Deletes each source file once it has been transferred
+    ///
+    /// A source file is deleted only after the receiver has confirmed that the whole file arrived intact.
+    /// Nothing is deleted if a transfer fails, or if the source changed while it was being sent.
+    ///
+    /// Directories and symbolic links are left alone, as are files skipped by `--ignore-existing`,
+    /// `--size-only` or `--update`. Deleting files on the remote needs a remote qcp which supports it.
+    #[arg(long, action, help_heading("Jobs"), display_order(0))]
+    pub remove_source_files: bool,
+
+    /// Sends a local file which grows while it is being sent, up to the size it had when the transfer started
+    ///
+    /// This is for files which are being appended to, such as logs. Without this option, a file which
+    /// grows while it is being sent is an error, as the destination would not match it.
+    /// A file which shrinks is always an error.
+    #[arg(long, action, help_heading("Jobs"), display_order(0))]
+    pub allow_growing: bool,
+
+    /// Keeps receiving a remote file as it grows, like `tail -F`, until interrupted
+    ///
+    /// The file is copied, then whatever is appended to it is appended to the destination as it arrives.
+    /// The destination is written in place, and may be `-` for standard output.
+    /// If the remote file is rotated (replaced by a new file) or truncated, qcp says so and carries on
+    /// from the beginning of the new file, appending that to the destination too.
+    ///
+    /// With `--resume`, only what the destination does not already have is requested.
+    /// Only receiving a single file is supported. This needs a remote qcp which supports it.
+    #[arg(
+        long,
+        action,
+        conflicts_with_all(["recursive", "remove_source_files", "partial_dir"]),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub follow: bool,
+
+    /// Runs a command after each file is transferred successfully
+    ///
+    /// The command is run by the shell. `{}` in it is replaced by the destination, already quoted for the shell:
+    /// a local path for a GET, or `HOST:PATH` for a PUT.
+    /// It is not run for jobs which are skipped, for directories or symbolic links,
+    /// or for transfers to or from standard input or output.
+    /// If it fails, a warning is shown (see also `--exec-strict`).
+    #[arg(long, value_name("COMMAND"), help_heading("Jobs"), display_order(0))]
+    pub exec_after: Option<String>,
+
+    /// Treats a failure of the `--exec-after` command as a failure of the job
+    #[arg(
+        long,
+        action,
+        requires("exec_after"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub exec_strict: bool,
+
+    /// Writes received files directly to their destination
+    ///
+    /// By default, a file being received is written to a hidden temporary file alongside the destination,
+    /// which is renamed into place once its checksum has been verified. This means that an incomplete file never
+    /// appears at the destination. This option writes to the destination directly, which may be needed if the
+    /// directory is not writeable.
+    ///
+    /// Destinations which are symbolic links or special files are always written in place,
+    /// as are files kept by `--partial` or `--resume` (unless `--partial-dir` is given).
+    #[arg(long, action, help_heading("Jobs"), display_order(0))]
+    pub inplace: bool,
+
+    /// Makes sure each file has reached the disk before reporting success
+    ///
+    /// The destination file, and then its directory, are synced to disk before the transfer is considered complete.
+    /// Without this option, data may still be in the operating system's cache when qcp exits.
+    /// This is slower, but useful for backups.
+    #[arg(long, action, help_heading("Jobs"), display_order(0))]
+    pub fsync: bool,
+
+    /// Checks what would happen, without transferring any data
+    ///
+    /// qcp connects to the remote as usual, and asks it to check each destination, but sends no file data.
+    /// This reports problems such as a missing destination directory or insufficient permissions
+    /// before a long transfer starts.
+    /// Directories and symbolic links that a recursive copy would create are listed, but not created.
+    #[arg(short('n'), long, action, help_heading("Jobs"), display_order(0))]
+    pub dry_run: bool,
+
+    /// Connects directly to the remote host, even if the `daemon` option is set
+    #[arg(long, action, help_heading("Connection"), display_order(0))]
+    pub no_daemon: bool,
+
+    /// Preserves the modification time and permissions of each file
+    ///
+    /// By default, permission bits set in the receiver's umask are cleared, as they would be for a newly created file.
+    /// `--preserve=mode` applies the permissions exactly.
+    /// Permissions are only preserved between Unix systems.
+    ///
+    /// N.B. `-p` is short for `--port`, so this option has no short form.
+    #[arg(
+        long,
+        value_name("WHAT"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("default"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub preserve: Option<Preserve>,
+
+    /// Copies directories recursively
+    ///
+    /// A source directory is copied into the destination directory, which is created if necessary.
+    /// Empty directories are copied too. Symbolic links are treated according to `--links`.
+    /// Each file is sent on its own stream over the same connection.
+    ///
+    /// Only sending (PUT) is supported at present.
+    /// N.B. `-r` is short for `--rtt`, so the short form of this option is `-R`.
+    #[arg(short('R'), long, action, help_heading("Jobs"), display_order(0))]
+    pub recursive: bool,
+
+    /// Deletes anything in the destination directory tree that is not in the source, when copying recursively
+    ///
+    /// This happens after all the files have been sent, and only if they were all sent successfully.
+    /// Entries matching `--exclude` patterns are not deleted.
+    /// Use with `--dry-run` to see what would be deleted.
+    #[arg(
+        long,
+        action,
+        requires("recursive"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub delete: bool,
+
+    /// How to treat symbolic links found within directories, when copying recursively
+    ///
+    /// A symbolic link named directly as a source is always followed.
+    #[arg(
+        long,
+        value_name("MODE"),
+        default_value("follow"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub links: LinkMode,
+
+    /// Skips files and directories matching this pattern, when copying recursively
+    ///
+    /// The pattern is a shell-style wildcard, such as `'*.tmp'`.
+    /// Without a `/`, it matches the name of a file or directory at any depth;
+    /// otherwise it matches the path relative to the source directory.
+    /// A trailing `/` matches only directories. Nothing inside an excluded directory is copied.
+    ///
+    /// This option may be repeated.
+    #[arg(
+        long,
+        value_name("PATTERN"),
+        requires("recursive"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub exclude: Vec<String>,
+
+    /// Reads exclude patterns from a file, one per line
+    ///
+    /// Blank lines and lines beginning with `#` are ignored.
+    /// This option may be repeated.
+    #[arg(
+        long,
+        value_name("FILE"),
+        requires("recursive"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub exclude_from: Vec<String>,
+
+    /// Reads the list of source files from a file, one per line (`-` reads from standard input)
+    ///
+    /// Each listed file is sent to the remote destination over the same connection.
+    /// With this option, the only positional argument is the destination, which should be a directory
+    /// ending in `/` (or simply HOST:).
+    /// Blank lines and lines beginning with `#` are ignored.
+    ///
+    /// This avoids the shell's limits on the length of a command line when copying many files.
+    #[arg(long, value_name("FILE"), help_heading("Jobs"), display_order(0))]
+    pub files_from: Option<String>,
+
+    /// The directory that relative paths read by `--files-from` are relative to
+    ///
+    /// By default they are relative to the current directory.
+    #[arg(
+        long,
+        value_name("DIR"),
+        requires("files_from"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub files_from_base: Option<String>,
+
     // JOB SPECIFICAION ====================================================================
     // (POSITIONAL ARGUMENTS!)
     /// The source file. This may be a local filename, or remote specified as HOST:FILE or USER@HOST:FILE.
+    ///
+    /// If qcp was built with the `http-source` feature, this may also be an HTTP or HTTPS URL.
+    /// The client fetches it and sends it to the remote destination.
+    ///
+    /// A local source may be a wildcard pattern (e.g. `'*.iso'`), which is expanded into one job per matching file.
+    ///
+    /// A source of `-` sends standard input. If the destination is a directory, the file created there is named `stdin`.
     #[arg(
         required_unless_present_any(crate::cli::MODE_OPTIONS),
         value_name = "SOURCE"
@@ -69,11 +481,50 @@ pub struct Parameters {
     /// Destination. This may be a file or directory. It may be local or remote.
     ///
     /// If remote, specify as HOST:DESTINATION or USER@HOST:DESTINATION; or simply HOST: or USER@HOST: to copy to your home directory there.
+    ///
+    /// A local destination of `-` writes the file to standard output.
     #[arg(
         required_unless_present_any(crate::cli::MODE_OPTIONS),
+        required_unless_present("files_from"),
         value_name = "DESTINATION"
     )]
     pub destination: Option<FileSpec>,
+
+    /// An additional copy job, which is run concurrently over the same connection
+    ///
+    /// The source and destination are specified in the same way as the main job, and must involve the same remote host.
+    /// They may be in either direction, so a single invocation can both send and receive.
+    /// This option may be repeated.
+    #[arg(
+        long,
+        num_args(2),
+        value_names(["SOURCE", "DESTINATION"]),
+        action(clap::ArgAction::Append),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub also: Vec<FileSpec>,
+}
+
+/// What `--preserve` applies to the destination
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum, Deserialize, Serialize)]
+pub enum Preserve {
+    /// Modification time, and permissions clamped by the receiver's umask
+    Default,
+    /// Modification time, and permissions exactly as they are at the source
+    Mode,
+}
+
+/// How `--recursive` treats symbolic links
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum LinkMode {
+    /// Copy the file or directory the link points to. Loops are skipped with a warning.
+    #[default]
+    Follow,
+    /// Recreate the link at the destination, with the same target
+    Copy,
+    /// Ignore the link
+    Skip,
 }
 
 impl TryFrom<&Parameters> for CopyJobSpec {
@@ -90,19 +541,103 @@ impl TryFrom<&Parameters> for CopyJobSpec {
             .as_ref()
             .ok_or_else(|| anyhow::anyhow!("source and destination are required"))?
             .clone();
+        Self::try_new(source, destination)
+    }
+}
 
-        if !(source.host.is_none() ^ destination.host.is_none()) {
-            anyhow::bail!("One file argument must be remote");
+impl Parameters {
+    /// All the copy jobs requested: the main job (or those listed by `--files-from`),
+    /// followed by any specified with `--also`.
+    ///
+    /// Wildcard patterns in local sources are expanded into one job per matching file.
+    /// With `--recursive`, directory sources are expanded into one job per file and directory.
+    pub(crate) fn jobs(&self) -> anyhow::Result<Vec<CopyJobSpec>> {
+        let mut requested = match &self.files_from {
+            Some(list) => self.files_from_jobs(list)?,
+            None => vec![CopyJobSpec::try_from(self)?],
+        };
+        for pair in self.also.chunks(2) {
+            let [source, destination] = pair else {
+                anyhow::bail!("--also requires a source and a destination");
+            };
+            requested.push(CopyJobSpec::try_new(source.clone(), destination.clone())?);
+        }
+        let mut jobs = Vec::new();
+        for job in requested {
+            jobs.append(&mut job.expand_glob(self.recursive)?);
+        }
+        if self.recursive {
+            let exclude = Exclusions::new(&self.exclude, &self.exclude_from)?;
+            if jobs.iter().any(|j| j.command_type() == CommandType::Get) {
+                anyhow::bail!("--recursive is only supported when sending files");
+            }
+            let mut expanded = Vec::new();
+            for job in jobs {
+                expanded.append(&mut job.expand_tree(self.links, &exclude)?);
+            }
+            jobs = expanded;
+        }
+        let from_stdin = jobs.iter().filter(|j| j.source.is_stdio()).count();
+        if from_stdin > 1 || (from_stdin > 0 && self.files_from.as_deref() == Some("-")) {
+            anyhow::bail!("Standard input can only be read once");
+        }
+        if jobs.iter().filter(|j| j.destination.is_stdio()).count() > 1 {
+            anyhow::bail!("Only one job can write to standard output");
+        }
+        let single_put = jobs.len() == 1 && jobs[0].command_type() == CommandType::Put;
+        if self.verify_source && !single_put {
+            anyhow::bail!("--verify-source requires a single job that sends a file");
+        }
+        if self.append && !single_put {
+            anyhow::bail!("--append requires a single job that sends a file");
         }
+        if self.follow && (jobs.len() != 1 || jobs[0].command_type() != CommandType::Get) {
+            anyhow::bail!("--follow requires a single job that receives a file");
+        }
+        let host = jobs[0].remote_host();
+        if jobs.iter().any(|j| j.remote_host() != host) {
+            anyhow::bail!("All jobs must involve the same remote host");
+        }
+        Ok(jobs)
+    }
 
-        Ok(Self {
-            source,
-            destination,
-        })
+    /// Reads the jobs listed by `--files-from`.
+    ///
+    /// The only positional argument is the destination, which clap places in `source`.
+    fn files_from_jobs(&self, list: &str) -> anyhow::Result<Vec<CopyJobSpec>> {
+        let (Some(destination), None) = (&self.source, &self.destination) else {
+            anyhow::bail!("With --files-from, give only the destination");
+        };
+        let text = if list == "-" {
+            std::io::read_to_string(std::io::stdin()).context("reading file list from stdin")?
+        } else {
+            std::fs::read_to_string(list)
+                .with_context(|| format!("reading file list from {list}"))?
+        };
+        let base = Path::new(self.files_from_base.as_deref().unwrap_or_default());
+        let mut jobs = Vec::new();
+        for line in text.lines() {
+            if line.trim().is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let source = FileSpec {
+                host: None,
+                filename: base.join(line).to_string_lossy().into_owned(),
+            };
+            jobs.push(CopyJobSpec::try_new(source, destination.clone())?);
+        }
+        if jobs.is_empty() {
+            anyhow::bail!("{list}: no files listed");
+        }
+        let dest = &destination.filename;
+        if jobs.len() > 1 && !dest.is_empty() && !dest.ends_with('/') {
+            anyhow::bail!(
+                "{list} lists several files, so the destination must be a directory ending in `/`"
+            );
+        }
+        Ok(jobs)
     }
-}
 
-impl Parameters {
     /// A best-effort attempt to extract a single remote host string from the parameters.
     ///
     /// # Output
@@ -118,7 +653,7 @@ impl Parameters {
             if dst_host.is_some() {
                 anyhow::bail!("Only one remote file argument is supported");
             }
-            Some(src_host.to_string())
+            Some(src_host.clone())
         } else {
             // Destination without source would be an exotic situation, but do our best anyway:
             dst_host.map(std::string::ToString::to_string)
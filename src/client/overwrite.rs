@@ -0,0 +1,124 @@
+//! Protection against overwriting existing files
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! scp silently replaces any file which is already at the destination. qcp instead asks first,
+//! unless `--force` was given. If there is nobody to ask (standard input is not a terminal),
+//! it refuses to overwrite the file, and the job fails.
+//!
+//! A local destination (in a GET) is checked before the transfer starts.
+//! A remote destination (in a PUT) is checked by the server, which tells us whether the file exists before
+//! we send any data; see the session protocol.
+
+use std::{io::IsTerminal as _, sync::Arc};
+
+use anyhow::Result;
+use indicatif::MultiProgress;
+
+/// What to do when the destination of a job already exists
+#[derive(Clone, Debug)]
+pub(super) enum Overwrite {
+    /// Overwrite it (`--force`)
+    Always,
+    /// Ask the user. The lock makes sure that concurrent jobs ask one at a time.
+    Ask(Arc<tokio::sync::Mutex<()>>),
+    /// Don't overwrite it; there is nobody to ask
+    Never,
+}
+
+impl Overwrite {
+    /// Decides the policy for this run
+    pub(super) fn new(force: bool) -> Self {
+        if force {
+            Self::Always
+        } else if std::io::stdin().is_terminal() && console::Term::stderr().is_term() {
+            Self::Ask(Arc::default())
+        } else {
+            Self::Never
+        }
+    }
+
+    /// Whether destinations need to be checked before they are written
+    pub(super) fn checks(&self) -> bool {
+        !matches!(self, Self::Always)
+    }
+
+    /// Decides whether to overwrite `destination`, which already exists, asking the user if necessary.
+    ///
+    /// Returns false if the user declined. Fails with [`DestinationExists`] if there is nobody to ask.
+    pub(super) async fn confirm(&self, destination: &str, display: &MultiProgress) -> Result<bool> {
+        let lock = match self {
+            Self::Always => return Ok(true),
+            Self::Never => return Err(DestinationExists(destination.to_string()).into()),
+            Self::Ask(lock) => lock,
+        };
+        let _guard = lock.lock().await;
+        let prompt = format!("overwrite '{destination}'? [y/N] ");
+        let display = display.clone();
+        // Reading from the terminal blocks, but the connection must carry on meanwhile
+        let answer = tokio::task::spawn_blocking(move || {
+            display.suspend(|| {
+                let term = console::Term::stderr();
+                term.write_str(&prompt)?;
+                term.read_line()
+            })
+        })
+        .await??;
+        Ok(is_yes(&answer))
+    }
+}
+
+/// Interprets the user's answer to the prompt. Anything other than yes means no.
+fn is_yes(answer: &str) -> bool {
+    matches!(answer.trim().to_ascii_lowercase().as_str(), "y" | "yes")
+}
+
+/// The destination of a job already exists, and is not to be overwritten
+#[derive(Debug)]
+pub(super) struct DestinationExists(pub(super) String);
+
+impl std::fmt::Display for DestinationExists {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(
+            f,
+            "{} already exists; not overwriting it (use --force to do so)",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for DestinationExists {}
+
+#[cfg(test)]
+mod test {
+    use indicatif::MultiProgress;
+
+    use super::{is_yes, DestinationExists, Overwrite};
+
+    #[test]
+    fn answers() {
+        for yes in ["y", "Y", "yes", " YES\n"] {
+            assert!(is_yes(yes), "{yes:?}");
+        }
+        for no in ["", "n", "no", "yep", "maybe"] {
+            assert!(!is_yes(no), "{no:?}");
+        }
+    }
+
+    #[tokio::test]
+    async fn policies() {
+        let display = MultiProgress::new();
+        let force = Overwrite::new(true);
+        assert!(!force.checks());
+        assert!(force.confirm("file", &display).await.unwrap());
+
+        let never = Overwrite::Never;
+        assert!(never.checks());
+        let err = never.confirm("file", &display).await.unwrap_err();
+        assert!(err.is::<DestinationExists>());
+        assert_eq!(
+            err.to_string(),
+            "file already exists; not overwriting it (use --force to do so)"
+        );
+    }
+}
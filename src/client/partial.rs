@@ -0,0 +1,139 @@
+//! Sidecar files for partially received files
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! An interrupted GET can be resumed by asking the remote for only the data we don't yet have.
+//! That is only safe if the remote file hasn't changed in the meantime.
+//!
+//! So while a file is being received, we record the size and modification time reported by the remote
+//! in a sidecar file next to the destination (`<destination>.qcp-partial`).
+//! When resuming, we compare them against what the remote reports now.
+//! The sidecar is removed when the transfer completes.
+//!
+//! If a transfer fails or is interrupted, the partial file and its sidecar are removed by [`PartialGuard`],
+//! unless the user asked to keep them (`--partial` or `--resume`).
+
+use std::path::{Path, PathBuf};
+
+use tracing::debug;
+
+/// Suffix of sidecar files
+const SUFFIX: &str = ".qcp-partial";
+
+/// What the remote told us about a file, when we started receiving it
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Partial {
+    /// Full size of the file
+    pub(crate) size: u64,
+    /// Modification time of the file (nanoseconds since the Unix epoch, or 0 if unknown)
+    pub(crate) mtime: u64,
+}
+
+impl Partial {
+    /// The sidecar file for a given destination
+    fn path_for(dest: &Path) -> PathBuf {
+        let mut path = dest.as_os_str().to_owned();
+        path.push(SUFFIX);
+        path.into()
+    }
+
+    /// Reads the sidecar for a destination, if there is one and it makes sense
+    pub(crate) async fn read(dest: &Path) -> Option<Self> {
+        let text = tokio::fs::read_to_string(Self::path_for(dest)).await.ok()?;
+        let (size, mtime) = text.trim().split_once(' ')?;
+        Some(Self {
+            size: size.parse().ok()?,
+            mtime: mtime.parse().ok()?,
+        })
+    }
+
+    /// Writes the sidecar for a destination
+    pub(crate) async fn write(self, dest: &Path) -> std::io::Result<()> {
+        tokio::fs::write(
+            Self::path_for(dest),
+            format!("{} {}\n", self.size, self.mtime),
+        )
+        .await
+    }
+
+    /// Removes the sidecar for a destination, if there is one
+    pub(crate) async fn remove(dest: &Path) {
+        let _ = tokio::fs::remove_file(Self::path_for(dest)).await;
+    }
+}
+
+/// Removes a partially received file, and its sidecar, when dropped.
+///
+/// This happens when a job fails, or when it is cancelled because the user interrupted qcp.
+/// Call [`PartialGuard::disarm`] once the file is complete.
+#[derive(Debug)]
+pub(crate) struct PartialGuard {
+    /// The destination to remove; None if it is to be kept
+    dest: Option<PathBuf>,
+}
+
+impl PartialGuard {
+    /// Constructor. If `keep` is set, the guard does nothing.
+    pub(crate) fn new(dest: &Path, keep: bool) -> Self {
+        Self {
+            dest: (!keep).then(|| dest.to_owned()),
+        }
+    }
+
+    /// Keeps the file
+    pub(crate) fn disarm(mut self) {
+        self.dest = None;
+    }
+}
+
+impl Drop for PartialGuard {
+    fn drop(&mut self) {
+        // This may run as a task is cancelled, so it can't be async
+        if let Some(dest) = self.dest.take() {
+            debug!("removing partial file {}", dest.display());
+            let _ = std::fs::remove_file(&dest);
+            let _ = std::fs::remove_file(Partial::path_for(&dest));
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{Partial, PartialGuard};
+
+    #[tokio::test]
+    async fn round_trip() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("file");
+        assert_eq!(Partial::read(&dest).await, None);
+        let p = Partial {
+            size: 1234,
+            mtime: 5678,
+        };
+        p.write(&dest).await.unwrap();
+        assert!(tmp.path().join("file.qcp-partial").exists());
+        assert_eq!(Partial::read(&dest).await, Some(p));
+        Partial::remove(&dest).await;
+        assert_eq!(Partial::read(&dest).await, None);
+    }
+
+    #[tokio::test]
+    async fn guard() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("file");
+        let p = Partial { size: 1, mtime: 2 };
+        for (keep, disarm) in [(false, false), (true, false), (false, true)] {
+            std::fs::write(&dest, "x").unwrap();
+            p.write(&dest).await.unwrap();
+            let uut = PartialGuard::new(&dest, keep);
+            if disarm {
+                uut.disarm();
+            } else {
+                drop(uut);
+            }
+            let removed = !keep && !disarm;
+            assert_eq!(dest.exists(), !removed);
+            assert_eq!(Partial::read(&dest).await.is_some(), !removed);
+        }
+    }
+}
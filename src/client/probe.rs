@@ -0,0 +1,190 @@
+//! UDP connectivity probe, for diagnosing a QUIC connection that times out
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! When the control channel works but the QUIC handshake times out, the cause is almost always
+//! a firewall dropping UDP. To tell the user something more useful than "timed out", we send the
+//! remote endpoint a QUIC packet with a reserved version number. Any QUIC server must answer this
+//! with a Version Negotiation packet (RFC 9000 section 6), so we can tell whether packets are
+//! getting through, being refused, or vanishing.
+
+use std::{
+    fmt,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+
+use tokio::{net::UdpSocket, time::timeout};
+use tracing::debug;
+
+use crate::util::{socket::bind_range_for_peer, PortRange};
+
+/// A version of the form `0x?a?a?a?a`, which RFC 9000 reserves to force version negotiation
+const RESERVED_VERSION: u32 = 0x1a2a_3a4a;
+/// Servers need not respond to datagrams smaller than this (RFC 9000 section 14.1)
+const PROBE_SIZE: usize = 1200;
+/// How many probes to send before giving up
+const ATTEMPTS: u32 = 3;
+
+/// The outcome of a probe
+#[derive(Debug, PartialEq, Eq)]
+pub(crate) enum ProbeResult {
+    /// The remote endpoint replied
+    Reply,
+    /// An ICMP error came back: the port is closed, or something on the path rejected the packet
+    Refused,
+    /// Nothing came back
+    Silence,
+    /// The probe could not be sent
+    Failed(String),
+}
+
+impl fmt::Display for ProbeResult {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ProbeResult::Reply => write!(
+                f,
+                "A UDP probe to the remote endpoint was answered, so UDP is not blocked outright; \
+                perhaps the network is dropping larger packets, or only some ports are allowed."
+            ),
+            ProbeResult::Refused => write!(
+                f,
+                "A UDP probe to the remote endpoint was actively rejected (ICMP unreachable): \
+                the port is closed, or a firewall is rejecting the packets."
+            ),
+            ProbeResult::Silence => write!(
+                f,
+                "A UDP probe to the remote endpoint got no reply: packets are being dropped silently, \
+                most likely by a firewall."
+            ),
+            ProbeResult::Failed(e) => write!(f, "(Could not send a UDP probe: {e})"),
+        }
+    }
+}
+
+/// Constructs a probe packet: a QUIC long header with a reserved version, padded to the minimum size
+fn probe_packet() -> Vec<u8> {
+    let mut packet = Vec::with_capacity(PROBE_SIZE);
+    packet.push(0xc0); // long header, fixed bit
+    packet.extend_from_slice(&RESERVED_VERSION.to_be_bytes());
+    for _ in 0..2 {
+        // destination and source connection IDs; the contents are arbitrary
+        packet.push(8);
+        packet.extend_from_slice(b"qcpprobe");
+    }
+    packet.resize(PROBE_SIZE, 0);
+    packet
+}
+
+/// Is this datagram a QUIC Version Negotiation packet?
+fn is_version_negotiation(datagram: &[u8]) -> bool {
+    datagram.len() >= 5 && datagram[0] & 0x80 != 0 && datagram[1..5] == [0, 0, 0, 0]
+}
+
+/// Sends probes to a remote QUIC endpoint and reports what came back.
+///
+/// The probe is sent from `local` (if given) and a port in `range` if one is free, otherwise any port.
+/// Each attempt waits for `wait`.
+pub(crate) async fn udp_probe(
+    remote: SocketAddr,
+    local: Option<IpAddr>,
+    range: PortRange,
+    wait: Duration,
+) -> ProbeResult {
+    let socket = bind_range_for_peer(&remote, range, local)
+        .or_else(|_| bind_range_for_peer(&remote, PortRange::default(), local))
+        .and_then(|s| {
+            s.set_nonblocking(true)?;
+            Ok(UdpSocket::from_std(s)?)
+        });
+    let socket = match socket {
+        Ok(s) => s,
+        Err(e) => return ProbeResult::Failed(e.to_string()),
+    };
+    // A connected socket reports ICMP errors to us
+    if let Err(e) = socket.connect(remote).await {
+        return ProbeResult::Failed(e.to_string());
+    }
+    let packet = probe_packet();
+    let mut buf = vec![0u8; 2048];
+    for attempt in 1..=ATTEMPTS {
+        debug!("sending UDP probe {attempt} to {remote}");
+        if let Err(e) = socket.send(&packet).await {
+            if e.kind() == std::io::ErrorKind::ConnectionRefused {
+                return ProbeResult::Refused;
+            }
+            return ProbeResult::Failed(e.to_string());
+        }
+        match timeout(wait, socket.recv(&mut buf)).await {
+            Ok(Ok(n)) if is_version_negotiation(&buf[..n]) => return ProbeResult::Reply,
+            Ok(Ok(n)) => debug!("ignoring unexpected {n} byte reply to UDP probe"),
+            Ok(Err(e)) if e.kind() == std::io::ErrorKind::ConnectionRefused => {
+                return ProbeResult::Refused
+            }
+            Ok(Err(e)) => return ProbeResult::Failed(e.to_string()),
+            Err(_) => (), // timed out; try again
+        }
+    }
+    ProbeResult::Silence
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{is_version_negotiation, probe_packet, udp_probe, ProbeResult, PROBE_SIZE};
+    use crate::util::{Credentials, PortRange};
+
+    const WAIT: Duration = Duration::from_millis(200);
+
+    #[test]
+    fn packet() {
+        let p = probe_packet();
+        assert_eq!(p.len(), PROBE_SIZE);
+        assert!(!is_version_negotiation(&p));
+        assert!(is_version_negotiation(&[0xc0, 0, 0, 0, 0, 8]));
+    }
+
+    #[tokio::test]
+    async fn silence() {
+        let quiet = std::net::UdpSocket::bind("127.0.0.1:0").unwrap();
+        let result = udp_probe(
+            quiet.local_addr().unwrap(),
+            None,
+            PortRange::default(),
+            WAIT,
+        )
+        .await;
+        assert_eq!(result, ProbeResult::Silence);
+    }
+
+    #[tokio::test]
+    async fn refused() {
+        let closed = std::net::UdpSocket::bind("127.0.0.1:0")
+            .unwrap()
+            .local_addr()
+            .unwrap();
+        // the socket has now been dropped, so nothing is listening
+        let result = udp_probe(closed, None, PortRange::default(), WAIT).await;
+        assert_eq!(result, ProbeResult::Refused);
+    }
+
+    #[tokio::test]
+    async fn quic_server_replies() {
+        let credentials = Credentials::generate().unwrap();
+        let config = quinn::ServerConfig::with_single_cert(
+            credentials.cert_chain(),
+            credentials.keypair.clone_key(),
+        )
+        .unwrap();
+        let endpoint = quinn::Endpoint::server(config, "127.0.0.1:0".parse().unwrap()).unwrap();
+        let result = udp_probe(
+            endpoint.local_addr().unwrap(),
+            None,
+            PortRange::default(),
+            WAIT,
+        )
+        .await;
+        assert_eq!(result, ProbeResult::Reply);
+    }
+}
@@ -1,11 +1,120 @@
-//! Progress bar styling
+//! Progress reporting and progress bar styling
 // (c) 2024 Ross Younger
 
+//! # Rationale
+//! On the console, each job has an [`indicatif`] progress bar.
+//! A program which embeds qcp may want to report progress some other way, so the payload of each job
+//! is reported through the [`ProgressSink`] trait. By default, the sink is the job's progress bar.
+
 /// Maximum update frequency we will use for the progress display
 pub const MAX_UPDATE_FPS: u8 = 20;
 
+use std::{
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
 use console::Term;
-use indicatif::ProgressStyle;
+use indicatif::{ProgressBar, ProgressStyle};
+use tokio::io::{AsyncBufRead, AsyncRead, AsyncWrite, ReadBuf};
+
+/// Receives progress updates for a job
+pub trait ProgressSink: Send + Sync {
+    /// Sets the total number of payload bytes, if known
+    fn set_length(&self, length: u64);
+    /// Records that some more payload bytes have been transferred
+    fn inc(&self, delta: u64);
+    /// Sets a message describing the job (typically the file name)
+    fn message(&self, message: &str);
+}
+
+impl ProgressSink for ProgressBar {
+    fn set_length(&self, length: u64) {
+        ProgressBar::set_length(self, length);
+    }
+    fn inc(&self, delta: u64) {
+        ProgressBar::inc(self, delta);
+    }
+    fn message(&self, message: &str) {
+        self.set_message(message.to_string());
+    }
+}
+
+/// An [`AsyncRead`] adapter which reports the data read through it to a [`ProgressSink`]
+pub(crate) struct ProgressReader<R> {
+    inner: R,
+    sink: Arc<dyn ProgressSink>,
+}
+
+impl<R: AsyncRead + Unpin> ProgressReader<R> {
+    pub(crate) fn new(inner: R, sink: Arc<dyn ProgressSink>) -> Self {
+        Self { inner, sink }
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for ProgressReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            this.sink.inc((buf.filled().len() - before) as u64);
+        }
+        result
+    }
+}
+
+impl<R: AsyncBufRead + Unpin> AsyncBufRead for ProgressReader<R> {
+    fn poll_fill_buf(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        Pin::new(&mut self.get_mut().inner).poll_fill_buf(cx)
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        Pin::new(&mut this.inner).consume(amt);
+        this.sink.inc(amt as u64);
+    }
+}
+
+/// An [`AsyncWrite`] adapter which reports the data written through it to a [`ProgressSink`]
+pub(crate) struct ProgressWriter<W> {
+    inner: W,
+    sink: Arc<dyn ProgressSink>,
+}
+
+impl<W: AsyncWrite + Unpin> ProgressWriter<W> {
+    pub(crate) fn new(inner: W, sink: Arc<dyn ProgressSink>) -> Self {
+        Self { inner, sink }
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for ProgressWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(n)) = result {
+            this.sink.inc(n as u64);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
 
 /// A single-line style format for Indicatif which should cover most situations.
 ///
@@ -59,3 +168,45 @@ pub(crate) const SPINNER_TEMPLATE: &str = "{spinner} {wide_msg} {prefix}";
 pub(crate) fn spinner_style() -> anyhow::Result<ProgressStyle> {
     Ok(ProgressStyle::with_template(SPINNER_TEMPLATE)?)
 }
+
+#[cfg(test)]
+mod test {
+    use std::sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    };
+
+    use tokio::io::{AsyncBufReadExt as _, AsyncReadExt as _, AsyncWriteExt as _};
+
+    use super::{ProgressReader, ProgressSink, ProgressWriter};
+
+    #[derive(Default)]
+    struct Counter(AtomicU64);
+
+    impl ProgressSink for Counter {
+        fn set_length(&self, _: u64) {}
+        fn inc(&self, delta: u64) {
+            let _ = self.0.fetch_add(delta, Ordering::Relaxed);
+        }
+        fn message(&self, _: &str) {}
+    }
+
+    #[tokio::test]
+    async fn counts() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let counter = Arc::new(Counter::default());
+        let mut reader = ProgressReader::new(&data[..], counter.clone());
+        let mut out = Vec::new();
+        let _ = reader.read_to_end(&mut out).await.unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), data.len() as u64);
+
+        let mut reader = ProgressReader::new(&data[..], counter.clone());
+        let mut line = String::new();
+        let _ = reader.read_line(&mut line).await.unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 2 * data.len() as u64);
+
+        let mut writer = ProgressWriter::new(Vec::new(), counter.clone());
+        writer.write_all(&out).await.unwrap();
+        assert_eq!(counter.0.load(Ordering::Relaxed), 3 * data.len() as u64);
+    }
+}
@@ -0,0 +1,201 @@
+//! Skipping jobs whose destinations are already present
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! When re-running a batch of jobs, there is no point sending files which are already at the destination.
+//! `--ignore-existing` skips any file whose destination exists; `--size-only` skips any file whose destination
+//! is the same size as the source; `--update` skips any file whose destination is at least as new as the source.
+//! (Like rsync, the contents are not compared.)
+//!
+//! Some filesystems record modification times coarsely (FAT, to the nearest 2 seconds), so a file copied with
+//! `--preserve` may appear slightly older than its source. `--update` therefore allows [`MTIME_TOLERANCE`].
+//!
+//! A local file is checked directly. A remote file is checked with a Stat command, which is cheap:
+//! it reports only the size and modification time of the file.
+//!
+//! Only files are skipped. Directories and symbolic links in a recursive copy are always created,
+//! and standard input, standard output and URLs are always transferred.
+
+use std::time::Duration;
+
+use anyhow::Result;
+use quinn::Connection;
+
+use super::{
+    main_loop::{put_protocol_filename, stat_remote},
+    CopyJobSpec, Parameters,
+};
+use crate::{
+    protocol::session::{CommandType, FileHeader},
+    util::io::{local_destination, mtime_nanos},
+};
+
+/// How much older than the source a destination may appear, and still be considered up to date by `--update`
+pub(super) const MTIME_TOLERANCE: Duration = Duration::from_secs(2);
+
+/// Which jobs to skip because their destinations are already present
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub(super) enum SkipMode {
+    /// Transfer everything
+    #[default]
+    Never,
+    /// Skip a file if its destination exists (`--ignore-existing`)
+    Existing,
+    /// Skip a file if its destination is the same size as the source (`--size-only`)
+    SameSize,
+    /// Skip a file if its destination is at least as new as the source (`--update`)
+    NotNewer,
+}
+
+/// What we know about a file, for the purposes of deciding whether to skip it
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+struct Stamp {
+    size: u64,
+    /// Modification time in nanoseconds since the Unix epoch, or 0 if unknown
+    mtime: u64,
+}
+
+impl From<&std::fs::Metadata> for Stamp {
+    fn from(meta: &std::fs::Metadata) -> Self {
+        Self {
+            size: meta.len(),
+            mtime: mtime_nanos(meta),
+        }
+    }
+}
+
+impl From<FileHeader> for Stamp {
+    fn from(header: FileHeader) -> Self {
+        Self {
+            size: header.size,
+            mtime: header.mtime,
+        }
+    }
+}
+
+impl SkipMode {
+    pub(super) fn new(parameters: &Parameters) -> Self {
+        if parameters.ignore_existing {
+            Self::Existing
+        } else if parameters.size_only {
+            Self::SameSize
+        } else if parameters.update {
+            Self::NotNewer
+        } else {
+            Self::Never
+        }
+    }
+
+    /// Decides whether to skip a job.
+    ///
+    /// `limit` is the protocol timeout, for asking the remote about the file.
+    pub(super) async fn applies(
+        self,
+        connection: &Connection,
+        job: &CopyJobSpec,
+        limit: Duration,
+    ) -> Result<bool> {
+        if self == Self::Never
+            || job.is_directory()
+            || job.symlink_target().is_some()
+            || job.source.is_stdio()
+            || job.source.is_url()
+            || job.destination.is_stdio()
+        {
+            return Ok(false);
+        }
+        let (source, dest) = match job.command_type() {
+            CommandType::Get => {
+                let dest = local_destination(&job.destination.filename, &job.source.filename);
+                let Ok(meta) = tokio::fs::metadata(&dest).await else {
+                    return Ok(false);
+                };
+                if self == Self::Existing {
+                    return Ok(true);
+                }
+                // A Stat with no filename reports on the path itself
+                let source = stat_remote(connection, &job.source.filename, "", limit).await?;
+                (source.map(Stamp::from), Stamp::from(&meta))
+            }
+            CommandType::Put => {
+                let filename = put_protocol_filename(job)?;
+                let dest =
+                    stat_remote(connection, &job.destination.filename, &filename, limit).await?;
+                let Some(dest) = dest else {
+                    return Ok(false);
+                };
+                if self == Self::Existing {
+                    return Ok(true);
+                }
+                let source = tokio::fs::metadata(&job.source.filename).await?;
+                (Some(Stamp::from(&source)), Stamp::from(dest))
+            }
+            CommandType::Delete => unreachable!("a copy job is never a Delete"),
+        };
+        Ok(source.is_some_and(|source| self.skips(source, dest)))
+    }
+
+    /// Compares a source with its existing destination
+    fn skips(self, source: Stamp, dest: Stamp) -> bool {
+        match self {
+            Self::Never => false,
+            Self::Existing => true,
+            Self::SameSize => source.size == dest.size,
+            // If either time is unknown, we can't tell
+            Self::NotNewer => {
+                let tolerance = u64::try_from(MTIME_TOLERANCE.as_nanos()).unwrap_or_default();
+                source.mtime != 0
+                    && dest.mtime != 0
+                    && dest.mtime.saturating_add(tolerance) >= source.mtime
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{SkipMode, Stamp, MTIME_TOLERANCE};
+    use crate::client::Parameters;
+
+    #[test]
+    fn modes() {
+        let mode = |ignore_existing, size_only, update| {
+            SkipMode::new(&Parameters {
+                ignore_existing,
+                size_only,
+                update,
+                ..Default::default()
+            })
+        };
+        assert_eq!(mode(false, false, false), SkipMode::Never);
+        assert_eq!(mode(true, false, false), SkipMode::Existing);
+        assert_eq!(mode(false, true, false), SkipMode::SameSize);
+        assert_eq!(mode(false, false, true), SkipMode::NotNewer);
+    }
+
+    #[test]
+    fn comparisons() {
+        const SECOND: u64 = 1_000_000_000;
+        let stamp = |size, secs| Stamp {
+            size,
+            mtime: secs * SECOND,
+        };
+        let source = stamp(100, 1000);
+        assert!(!SkipMode::Never.skips(source, source));
+        assert!(SkipMode::Existing.skips(source, stamp(1, 1)));
+        assert!(SkipMode::SameSize.skips(source, stamp(100, 1)));
+        assert!(!SkipMode::SameSize.skips(source, stamp(99, 1000)));
+
+        let update = SkipMode::NotNewer;
+        assert!(update.skips(source, stamp(1, 1000)));
+        assert!(update.skips(source, stamp(1, 2000)));
+        assert!(!update.skips(source, stamp(100, 900)));
+        // Coarse timestamps are tolerated
+        let tolerance = MTIME_TOLERANCE.as_secs();
+        assert!(update.skips(source, stamp(100, 1000 - tolerance)));
+        assert!(!update.skips(source, stamp(100, 1000 - tolerance - 1)));
+        // Unknown times are never up to date
+        assert!(!update.skips(source, stamp(100, 0)));
+        assert!(!update.skips(stamp(100, 0), source));
+    }
+}
@@ -3,7 +3,7 @@
 
 use std::{path::PathBuf, str::FromStr};
 
-use crate::config::ssh::Parser;
+use crate::config::ssh::{HostConfiguration, Parser, Setting};
 use anyhow::{Context, Result};
 use tracing::{debug, warn};
 
@@ -35,8 +35,29 @@ impl ConfigFile {
         })
     }
 
-    /// Attempts to resolve a hostname from a single OpenSSH-style config file
-    fn resolve_one(&self, host: &str) -> Option<String> {
+    /// The ssh config files to read, in priority order.
+    ///
+    /// If the list given is empty, the user's and system's ssh config files are used.
+    fn list(config_files: &[String]) -> Vec<Self> {
+        if config_files.is_empty() {
+            let mut v = Vec::new();
+            if let Ok(f) = Platform::user_ssh_config() {
+                v.push(ConfigFile::for_path(f, true));
+            }
+            if let Ok(f) = ConfigFile::for_str(Platform::system_ssh_config(), false, false) {
+                v.push(f);
+            }
+            v
+        } else {
+            config_files
+                .iter()
+                .flat_map(|s| ConfigFile::for_str(s, true, true))
+                .collect()
+        }
+    }
+
+    /// Parses a single OpenSSH-style config file with a given host in mind
+    fn parse(&self, host: &str) -> Option<HostConfiguration> {
         let path = &self.path;
         if !std::fs::exists(path).is_ok_and(|b| b) {
             // file could not be verified to exist.
@@ -55,24 +76,116 @@ impl ConfigFile {
                 return None;
             }
         };
-        let data = match parser
+        match parser
             .parse_file_for(Some(host))
-            .with_context(|| format!("reading configuration file {path:?}"))
+            .with_context(|| format!("reading configuration file {}", path.display()))
         {
-            Ok(data) => data,
+            Ok(data) => Some(data),
             Err(e) => {
                 warn!("{e}");
-                return None;
+                None
+            }
+        }
+    }
+
+    /// Attempts to resolve a hostname from a single OpenSSH-style config file
+    #[cfg(test)]
+    fn resolve_one(&self, host: &str) -> Option<String> {
+        hostname_setting(&self.parse(host).into_iter().collect::<Vec<_>>(), host)
+    }
+}
+
+/// Looks up the first setting for a keyword in a list of parsed config files
+fn first_setting<'a>(configs: &'a [HostConfiguration], keyword: &str) -> Option<&'a Setting> {
+    configs.iter().find_map(|c| c.get(keyword))
+}
+
+/// Looks up the `HostName` for a host in a list of parsed config files
+fn hostname_setting(configs: &[HostConfiguration], host: &str) -> Option<String> {
+    let s = first_setting(configs, "hostname")?;
+    let result = s.first_arg();
+    debug!("Using hostname '{result}' for '{host}' (from {})", s.source);
+    Some(result)
+}
+
+/// Hostname canonicalization settings (`CanonicalizeHostname` and friends)
+#[derive(Debug, PartialEq)]
+struct Canonicalization {
+    /// Whether canonicalization is enabled
+    enabled: bool,
+    /// Domain suffixes to try, in order (`CanonicalDomains`)
+    domains: Vec<String>,
+    /// Names with more than this many dots are left alone (`CanonicalizeMaxDots`)
+    max_dots: usize,
+}
+
+impl Canonicalization {
+    /// OpenSSH's default `CanonicalizeMaxDots`
+    const DEFAULT_MAX_DOTS: usize = 1;
+
+    /// Reads the settings from a list of parsed config files
+    fn new(configs: &[HostConfiguration]) -> Self {
+        let enabled = match first_setting(configs, "canonicalizehostname")
+            .map(|s| s.first_arg().to_ascii_lowercase())
+            .as_deref()
+        {
+            None | Some("no") => false,
+            Some("always") => true,
+            // `yes` doesn't apply to proxied connections
+            Some("yes") => {
+                first_setting(configs, "proxyjump").is_none()
+                    && first_setting(configs, "proxycommand").is_none()
+            }
+            Some(other) => {
+                warn!("ignoring unrecognised CanonicalizeHostname value `{other}`");
+                false
             }
         };
-        if let Some(s) = data.get("hostname") {
-            let result = s.first_arg();
-            debug!("Using hostname '{result}' for '{host}' (from {})", s.source);
-            Some(result)
-        } else {
-            None
+        let domains = first_setting(configs, "canonicaldomains")
+            .map(|s| s.args.clone())
+            .unwrap_or_default();
+        let max_dots =
+            first_setting(configs, "canonicalizemaxdots").map_or(Self::DEFAULT_MAX_DOTS, |s| {
+                s.first_arg().parse().unwrap_or_else(|_| {
+                    warn!(
+                        "ignoring invalid CanonicalizeMaxDots value (at {} line {})",
+                        s.source, s.line_number
+                    );
+                    Self::DEFAULT_MAX_DOTS
+                })
+            });
+        Self {
+            enabled,
+            domains,
+            max_dots,
         }
     }
+
+    /// Applies the settings to a host name.
+    ///
+    /// Each domain is appended in turn; the first resulting name for which `resolves` returns true is the answer.
+    /// Returns None if the host is not to be canonicalized, or if no domain worked.
+    fn canonicalize(&self, host: &str, resolves: impl Fn(&str) -> bool) -> Option<String> {
+        if !self.enabled
+            || host.ends_with('.') // already fully qualified
+            || host.parse::<std::net::IpAddr>().is_ok()
+            || host.matches('.').count() > self.max_dots
+        {
+            return None;
+        }
+        self.domains
+            .iter()
+            .map(|domain| format!("{host}.{domain}"))
+            .find(|candidate| resolves(candidate))
+    }
+}
+
+/// Does this name resolve in the DNS?
+fn dns_resolves(name: &str) -> bool {
+    use std::net::ToSocketAddrs as _;
+    (name, 0)
+        .to_socket_addrs()
+        .is_ok_and(|mut a| a.next().is_some())
 }
 
 /// Attempts to resolve hostname aliasing from ssh config files.
@@ -83,44 +196,155 @@ impl ConfigFile {
 ///
 /// If the list is empty, the user's and system's ssh config files will be used.
 ///
+/// If `CanonicalizeHostname` is enabled, the host is first canonicalized using `CanonicalDomains`
+/// and `CanonicalizeMaxDots`, then the config files are read again with the canonical name in mind.
+///
 /// ## Returns
-/// Some(hostname) if any config file matched.
+/// Some(hostname) if any config file matched, or the host was canonicalized.
 /// None if no config files matched.
 ///
 /// ## ssh_config features not currently supported
-/// * Match patterns
-/// * CanonicalizeHostname and friends
+/// * `CanonicalizeFallbackLocal no` (we always fall back to the name as given)
+/// * `CanonicalizePermittedCNAMEs`
 #[must_use]
 pub fn resolve_host_alias(host: &str, config_files: &[String]) -> Option<String> {
-    let files = if config_files.is_empty() {
-        let mut v = Vec::new();
-        if let Ok(f) = Platform::user_ssh_config() {
-            v.push(ConfigFile::for_path(f, true));
-        }
-        if let Ok(f) = ConfigFile::for_str(Platform::system_ssh_config(), false, false) {
-            v.push(f);
-        }
-        v
-    } else {
-        config_files
+    let files = ConfigFile::list(config_files);
+    let parse_all = |host| {
+        files
             .iter()
-            .flat_map(|s| ConfigFile::for_str(s, true, true))
-            .collect()
+            .filter_map(|f| f.parse(host))
+            .collect::<Vec<_>>()
     };
-    for cfg in files {
-        let result = cfg.resolve_one(host);
-        if result.is_some() {
-            return result;
+    let configs = parse_all(host);
+    match Canonicalization::new(&configs).canonicalize(host, dns_resolves) {
+        Some(canonical) => {
+            debug!("Canonicalized '{host}' to '{canonical}'");
+            hostname_setting(&parse_all(&canonical), &canonical).or(Some(canonical))
+        }
+        None => hostname_setting(&configs, host),
+    }
+}
+
+/// Looks for a setting in ssh command-line options, given either as its short option (e.g. `-J`)
+/// or as its config keyword with `-o` (e.g. `-o ProxyJump=x`).
+///
+/// Returns `Some(value)` if one was found.
+fn option_value(ssh_options: &[String], short: &str, keyword: &str) -> Option<String> {
+    let mut options = ssh_options.iter();
+    while let Some(opt) = options.next() {
+        if let Some(value) = opt.strip_prefix(short) {
+            return if value.is_empty() {
+                options.next().cloned()
+            } else {
+                Some(value.to_string())
+            };
+        }
+        if let Some(value) = opt.strip_prefix("-o") {
+            let value = if value.is_empty() {
+                options.next().map_or("", String::as_str)
+            } else {
+                value
+            };
+            let Some((key, value)) = value.split_once(['=', ' ']) else {
+                continue;
+            };
+            if key.trim().eq_ignore_ascii_case(keyword) {
+                return Some(value.trim().to_string());
+            }
         }
     }
     None
 }
 
+/// Looks for a `ProxyJump` setting in ssh command-line options.
+///
+/// Returns `Some(value)` if one was found, which may be `none`.
+fn proxy_jump_option(ssh_options: &[String]) -> Option<String> {
+    option_value(ssh_options, "-J", "proxyjump")
+}
+
+/// Determines whether ssh would connect to a host via a jump host (`ProxyJump`, or `-J`).
+///
+/// Command-line options take precedence over the config files, as they do for ssh.
+/// Returns the jump host, if there is one.
+pub(crate) fn jump_host(
+    host: &str,
+    ssh_options: &[String],
+    config_files: &[String],
+) -> Option<String> {
+    let value = proxy_jump_option(ssh_options).or_else(|| {
+        let configs = ConfigFile::list(config_files)
+            .iter()
+            .filter_map(|f| f.parse(host))
+            .collect::<Vec<_>>();
+        first_setting(&configs, "proxyjump").map(Setting::first_arg)
+    })?;
+    (!value.eq_ignore_ascii_case("none")).then_some(value)
+}
+
+/// Connection settings from the ssh config files, which qcp passes on to ssh.
+///
+/// As qcp gives ssh the resolved host name (see [`resolve_host_alias`]), ssh would not otherwise
+/// apply the settings from a `Host` block which matches only the alias.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct HostSettings {
+    /// The user to log in as (`User`)
+    pub user: Option<String>,
+    /// The ssh port (`Port`)
+    pub port: Option<u16>,
+    /// The private key to use (`IdentityFile`)
+    pub identity_file: Option<String>,
+}
+
+/// Reads the connection settings for a host from the ssh config files.
+///
+/// Settings given explicitly in `ssh_options` take precedence, so are left out of the result.
+pub(crate) fn host_settings(
+    host: &str,
+    ssh_options: &[String],
+    config_files: &[String],
+) -> HostSettings {
+    let configs = ConfigFile::list(config_files)
+        .iter()
+        .filter_map(|f| f.parse(host))
+        .collect::<Vec<_>>();
+    let setting = |short, keyword| {
+        if option_value(ssh_options, short, keyword).is_some() {
+            return None;
+        }
+        let s = first_setting(&configs, keyword)?;
+        debug!(
+            "Using {keyword} '{}' for '{host}' (from {})",
+            s.first_arg(),
+            s.source
+        );
+        Some(s)
+    };
+    let port = setting("-p", "port").and_then(|s| {
+        s.first_arg()
+            .parse()
+            .inspect_err(|_| {
+                warn!(
+                    "ignoring invalid Port value (at {} line {})",
+                    s.source, s.line_number
+                );
+            })
+            .ok()
+    });
+    HostSettings {
+        user: setting("-l", "user").map(Setting::first_arg),
+        port,
+        identity_file: setting("-i", "identityfile").map(Setting::first_arg),
+    }
+}
+
 #[cfg(test)]
 mod test {
     use std::path::Path;
 
-    use super::ConfigFile;
+    use super::{
+        host_settings, jump_host, proxy_jump_option, Canonicalization, ConfigFile, HostSettings,
+    };
     use crate::util::make_test_tempfile;
 
     fn resolve_one(path: &Path, user: bool, host: &str) -> Option<String> {
@@ -171,4 +395,146 @@ mod test {
         assert!(resolve_one(&path, false, "freed").is_none());
         assert!(resolve_one(&path, false, "fredd").is_none());
     }
+
+    fn canonicalization(path: &Path, host: &str) -> Canonicalization {
+        let configs = ConfigFile::for_path(path.to_path_buf(), false)
+            .parse(host)
+            .into_iter()
+            .collect::<Vec<_>>();
+        Canonicalization::new(&configs)
+    }
+
+    #[test]
+    fn canonicalize() {
+        let (path, _dir) = make_test_tempfile(
+            r"
+        Host proxied
+            ProxyJump gateway
+        Host *
+            CanonicalizeHostname yes
+            CanonicalDomains example.com example.net
+            CanonicalizeMaxDots 1
+        ",
+            "test_ssh_config",
+        );
+        let resolves = |name: &str| name.ends_with(".example.net");
+        let uut = canonicalization(&path, "foo");
+        assert_eq!(
+            uut,
+            Canonicalization {
+                enabled: true,
+                domains: vec!["example.com".into(), "example.net".into()],
+                max_dots: 1,
+            }
+        );
+        assert_eq!(
+            uut.canonicalize("foo", resolves).unwrap(),
+            "foo.example.net"
+        );
+        assert_eq!(
+            uut.canonicalize("foo.bar", resolves).unwrap(),
+            "foo.bar.example.net"
+        );
+        // Too many dots
+        assert!(uut.canonicalize("foo.bar.baz", resolves).is_none());
+        // Fully qualified, or an address
+        assert!(uut.canonicalize("foo.", resolves).is_none());
+        assert!(uut.canonicalize("10.0.0.1", resolves).is_none());
+        assert!(uut.canonicalize("::1", resolves).is_none());
+        // Nothing resolves
+        assert!(uut.canonicalize("foo", |_| false).is_none());
+        // `yes` does not apply to proxied connections
+        assert!(!canonicalization(&path, "proxied").enabled);
+    }
+
+    #[test]
+    fn canonical_name_matches_host_block() {
+        let (path, _dir) = make_test_tempfile(
+            r"
+        Host foo.example.com
+            HostName bar
+        Host *
+            CanonicalizeHostname always
+            CanonicalDomains example.com
+            CanonicalizeMaxDots 0
+        ",
+            "test_ssh_config",
+        );
+        let uut = canonicalization(&path, "foo");
+        assert_eq!(uut.max_dots, 0);
+        let canonical = uut.canonicalize("foo", |_| true).unwrap();
+        assert_eq!(resolve_one(&path, false, &canonical).unwrap(), "bar");
+        assert!(uut.canonicalize("foo.bar", |_| true).is_none());
+    }
+
+    #[test]
+    fn proxy_jump_options() {
+        let f = |opts: &[&str]| {
+            proxy_jump_option(&opts.iter().map(ToString::to_string).collect::<Vec<_>>())
+        };
+        assert_eq!(f(&[]), None);
+        assert_eq!(f(&["-o", "Compression=yes"]), None);
+        assert_eq!(f(&["-J", "bastion"]).unwrap(), "bastion");
+        assert_eq!(f(&["-Jbastion"]).unwrap(), "bastion");
+        assert_eq!(f(&["-o", "ProxyJump=bastion"]).unwrap(), "bastion");
+        assert_eq!(f(&["-oproxyjump bastion"]).unwrap(), "bastion");
+        assert_eq!(f(&["-o", "ProxyJump=none"]).unwrap(), "none");
+    }
+
+    #[test]
+    fn jump_hosts() {
+        let (path, _dir) = make_test_tempfile(
+            r"
+        Host inner
+            ProxyJump bastion
+        Host *
+            Compression yes
+        ",
+            "test_ssh_config",
+        );
+        let files = [path.to_string_lossy().to_string()];
+        assert_eq!(jump_host("inner", &[], &files).unwrap(), "bastion");
+        assert!(jump_host("outer", &[], &files).is_none());
+        // Command-line options take precedence
+        let none = ["-oProxyJump=none".to_string()];
+        assert!(jump_host("inner", &none, &files).is_none());
+        let other = ["-J".to_string(), "other".to_string()];
+        assert_eq!(jump_host("outer", &other, &files).unwrap(), "other");
+    }
+
+    #[test]
+    fn settings_for_host() {
+        let (path, _dir) = make_test_tempfile(
+            r"
+        Host alias
+            HostName real.example.com
+            User bob
+            Port 2222
+            IdentityFile ~/.ssh/special_key
+        Host broken
+            Port many
+        ",
+            "test_ssh_config",
+        );
+        let files = [path.to_string_lossy().to_string()];
+        let f = |host: &str, opts: &[&str]| {
+            let opts = opts.iter().map(ToString::to_string).collect::<Vec<_>>();
+            host_settings(host, &opts, &files)
+        };
+        assert_eq!(
+            f("alias", &[]),
+            HostSettings {
+                user: Some("bob".into()),
+                port: Some(2222),
+                identity_file: Some("~/.ssh/special_key".into()),
+            }
+        );
+        assert_eq!(f("other", &[]), HostSettings::default());
+        assert_eq!(f("broken", &[]), HostSettings::default());
+        // Command-line options take precedence
+        assert_eq!(
+            f("alias", &["-p", "22", "-oUser=alice", "-i/dev/null"]),
+            HostSettings::default()
+        );
+    }
 }
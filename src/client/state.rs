@@ -0,0 +1,92 @@
+//! Batch state file
+// (c) 2024 Ross Younger
+
+//! The state file records which jobs have completed, one per line, so an interrupted batch
+//! may be resumed by re-running it. Each line is the job's source and destination,
+//! as given on the command line, separated by a tab.
+
+use std::{
+    collections::HashSet,
+    fs::File,
+    io::{BufRead as _, BufReader, Write as _},
+    path::PathBuf,
+};
+
+use anyhow::{Context as _, Result};
+
+use super::CopyJobSpec;
+
+/// A file recording completed jobs
+#[derive(Debug)]
+pub(crate) struct StateFile {
+    path: PathBuf,
+    completed: HashSet<String>,
+    file: File,
+}
+
+impl StateFile {
+    /// Opens a state file, creating it if necessary, and reads the jobs recorded in it
+    pub(crate) fn open<P: Into<PathBuf>>(path: P) -> Result<Self> {
+        let path = path.into();
+        let file = File::options()
+            .create(true)
+            .append(true)
+            .read(true)
+            .open(&path)
+            .with_context(|| format!("opening state file {}", path.display()))?;
+        let completed = BufReader::new(&file)
+            .lines()
+            .collect::<std::io::Result<HashSet<_>>>()
+            .with_context(|| format!("reading state file {}", path.display()))?;
+        Ok(Self {
+            path,
+            completed,
+            file,
+        })
+    }
+
+    fn key(job: &CopyJobSpec) -> String {
+        format!("{}\t{}", job.source, job.destination)
+    }
+
+    /// Has this job already been completed?
+    pub(crate) fn is_complete(&self, job: &CopyJobSpec) -> bool {
+        self.completed.contains(&Self::key(job))
+    }
+
+    /// Records that a job has completed
+    pub(crate) fn mark_complete(&mut self, job: &CopyJobSpec) -> Result<()> {
+        let key = Self::key(job);
+        writeln!(self.file, "{key}")
+            .and_then(|()| self.file.sync_data())
+            .with_context(|| format!("writing state file {}", self.path.display()))?;
+        let _ = self.completed.insert(key);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::StateFile;
+    use crate::client::job::job;
+
+    #[test]
+    fn resume() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("state");
+        let a = job("host:a", "/tmp/");
+        let b = job("/tmp/b", "host:");
+        let other_host = job("other:a", "/tmp/");
+
+        let mut uut = StateFile::open(&path).unwrap();
+        assert!(!uut.is_complete(&a));
+        uut.mark_complete(&a).unwrap();
+        assert!(uut.is_complete(&a));
+        drop(uut);
+
+        let uut = StateFile::open(&path).unwrap();
+        assert!(uut.is_complete(&a));
+        assert!(!uut.is_complete(&b));
+        assert!(!uut.is_complete(&other_host));
+    }
+}
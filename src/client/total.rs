@@ -0,0 +1,201 @@
+//! Overall progress of a batch of jobs
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! Each job has its own progress bar, which says nothing about how far through the batch we are.
+//! When there is more than one job, an extra bar shows the total payload of all of them (unless `--no-total`).
+//!
+//! This needs the sizes of all the files before any are sent: local files are looked up directly,
+//! remote files with a Stat command, all at once.
+//! If any size can't be found out (standard input, a URL, or an error), there is no total bar.
+//!
+//! When a job finishes, or is skipped, whatever it did not report is added to the total, so that the bar reaches the end.
+//! When a job fails, what it did not send is taken off the total instead.
+
+use std::{
+    collections::HashMap,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use anyhow::Result;
+use futures_util::future::join_all;
+use indicatif::{MultiProgress, ProgressBar, ProgressFinish};
+use quinn::Connection;
+
+use super::{main_loop::stat_remote, progress::progress_style_for, CopyJobSpec, ProgressSink};
+use crate::protocol::session::CommandType;
+
+/// The label of the total bar
+const LABEL: &str = "Total";
+
+/// The total progress bar, and what each job is expected to contribute to it
+pub(super) struct TotalProgress {
+    bar: ProgressBar,
+    /// Expected payload size, and payload reported so far, of each job
+    jobs: HashMap<String, (u64, AtomicU64)>,
+}
+
+/// How jobs are identified
+fn key(job: &CopyJobSpec) -> String {
+    format!("{}\0{}", job.source, job.destination)
+}
+
+/// The payload size of a job, if it can be found out.
+///
+/// `connection` is the connection to the remote host of the job.
+async fn job_size(
+    job: &CopyJobSpec,
+    connection: Option<&Connection>,
+    limit: Duration,
+) -> Option<u64> {
+    if job.is_directory() || job.symlink_target().is_some() {
+        return Some(0);
+    }
+    if job.source.is_stdio() || job.source.is_url() {
+        return None;
+    }
+    match job.command_type() {
+        CommandType::Put => tokio::fs::metadata(&job.source.filename)
+            .await
+            .ok()
+            .map(|m| m.len()),
+        // A Stat with no filename reports on the path itself
+        CommandType::Get => stat_remote(connection?, &job.source.filename, "", limit)
+            .await
+            .ok()
+            .flatten()
+            .map(|h| h.size),
+        CommandType::Delete => unreachable!("a copy job is never a Delete"),
+    }
+}
+
+impl TotalProgress {
+    /// Works out the total size of a batch of jobs, and adds the total bar to the display.
+    ///
+    /// `connection_for` gives the connection to the remote host of a job.
+    /// Returns None if the size of any of the jobs can't be found out.
+    pub(super) async fn new<'a>(
+        jobs: &[CopyJobSpec],
+        connection_for: impl Fn(&CopyJobSpec) -> Option<&'a Connection>,
+        display: &MultiProgress,
+        limit: Duration,
+    ) -> Result<Option<Self>> {
+        let sizes = join_all(
+            jobs.iter()
+                .map(|job| job_size(job, connection_for(job), limit)),
+        )
+        .await;
+        let mut total = 0;
+        let mut map = HashMap::new();
+        for (job, size) in jobs.iter().zip(sizes) {
+            let Some(size) = size else {
+                tracing::debug!("Not showing a total: the size of {} is unknown", job.source);
+                return Ok(None);
+            };
+            total += size;
+            let _ = map.insert(key(job), (size, AtomicU64::new(0)));
+        }
+        let bar = display.add(
+            ProgressBar::new(total)
+                .with_style(indicatif::ProgressStyle::with_template(
+                    progress_style_for(&console::Term::stderr(), LABEL.len()),
+                )?)
+                .with_message(LABEL)
+                .with_finish(ProgressFinish::AndClear),
+        );
+        Ok(Some(Self { bar, jobs: map }))
+    }
+
+    /// Records some progress on a job
+    fn inc(&self, job: &str, delta: u64) {
+        if let Some((_, reported)) = self.jobs.get(job) {
+            let _ = reported.fetch_add(delta, Ordering::Relaxed);
+        }
+        self.bar.inc(delta);
+    }
+
+    /// Accounts for the part of a job that it did not report, once it has finished
+    pub(super) fn finish(&self, job: &CopyJobSpec, success: bool) {
+        let Some((expected, reported)) = self.jobs.get(&key(job)) else {
+            return;
+        };
+        let rest = expected.saturating_sub(reported.swap(*expected, Ordering::Relaxed));
+        if success {
+            self.bar.inc(rest);
+        } else {
+            self.bar
+                .set_length(self.bar.length().unwrap_or_default().saturating_sub(rest));
+        }
+    }
+}
+
+/// Reports a job's progress to both its own bar and the total bar
+pub(super) struct WithTotal {
+    bar: ProgressBar,
+    total: Arc<TotalProgress>,
+    job: String,
+}
+
+impl WithTotal {
+    pub(super) fn new(bar: &ProgressBar, total: &Arc<TotalProgress>, job: &CopyJobSpec) -> Self {
+        Self {
+            bar: bar.clone(),
+            total: total.clone(),
+            job: key(job),
+        }
+    }
+}
+
+impl ProgressSink for WithTotal {
+    fn set_length(&self, length: u64) {
+        self.bar.set_length(length);
+    }
+    fn inc(&self, delta: u64) {
+        self.bar.inc(delta);
+        self.total.inc(&self.job, delta);
+    }
+    fn message(&self, message: &str) {
+        self.bar.set_message(message.to_string());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{collections::HashMap, sync::atomic::AtomicU64, sync::Arc};
+
+    use indicatif::ProgressBar;
+
+    use super::{key, TotalProgress, WithTotal};
+    use crate::client::{CopyJobSpec, ProgressSink as _};
+
+    #[test]
+    fn accounting() {
+        let job = |name: &str| {
+            CopyJobSpec::try_new(name.parse().unwrap(), "host:dir/".parse().unwrap()).unwrap()
+        };
+        let (a, b, c) = (job("a"), job("b"), job("c"));
+        let total = Arc::new(TotalProgress {
+            bar: ProgressBar::with_draw_target(Some(600), indicatif::ProgressDrawTarget::hidden()),
+            jobs: HashMap::from(
+                [(&a, 100), (&b, 200), (&c, 300)].map(|(j, n)| (key(j), (n, AtomicU64::new(0)))),
+            ),
+        });
+        let sink = WithTotal::new(&ProgressBar::hidden(), &total, &a);
+        sink.inc(40);
+        assert_eq!(total.bar.position(), 40);
+        // Finished, but only reported some of its payload (say it was resumed)
+        total.finish(&a, true);
+        assert_eq!(total.bar.position(), 100);
+        // Failed part-way
+        WithTotal::new(&ProgressBar::hidden(), &total, &b).inc(50);
+        total.finish(&b, false);
+        assert_eq!((total.bar.position(), total.bar.length()), (150, Some(450)));
+        // Skipped
+        total.finish(&c, true);
+        assert_eq!((total.bar.position(), total.bar.length()), (450, Some(450)));
+    }
+}
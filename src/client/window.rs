@@ -0,0 +1,325 @@
+//! _(Experimental)_ Flow control window monitoring
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! The QUIC flow control windows are sized from the configured bandwidth and RTT.
+//! If the real RTT is longer than configured, the window may be too small to keep the pipe full,
+//! and throughput stalls well below the configured bandwidth even though there is little loss.
+//!
+//! This module periodically samples the connection statistics to detect that situation.
+//! It logs a suggestion and, if so configured, grows the receive window within safe bounds.
+
+use std::time::Duration;
+
+use human_repr::{HumanCount as _, HumanThroughput as _};
+use quinn::{Connection, ConnectionStats, VarInt};
+use tokio::{sync::oneshot, task::JoinHandle, time::Instant};
+use tracing::{debug, info, warn};
+
+use crate::{
+    config::Configuration,
+    transport::{AutoWindow, ThroughputMode},
+};
+
+/// Sampling interval
+const INTERVAL: Duration = Duration::from_secs(1);
+
+/// Throughput below this fraction of the configured bandwidth is considered to have stalled
+const STALL_FRACTION: f64 = 0.5;
+
+/// Throughput above this fraction of the window-limited rate (window ÷ RTT) is considered to be window-bound
+const WINDOW_BOUND_FRACTION: f64 = 0.8;
+
+/// The information we need from a single sample of connection statistics
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+struct Sample {
+    /// UDP bytes in the direction of interest
+    bytes: u64,
+    /// Congestion events detected so far
+    congestion_events: u64,
+    /// Current RTT estimate
+    rtt: Duration,
+}
+
+impl Sample {
+    fn new(stats: &ConnectionStats, direction: ThroughputMode) -> Self {
+        let bytes = match direction {
+            ThroughputMode::Rx => stats.udp_rx.bytes,
+            ThroughputMode::Tx | ThroughputMode::Both => stats.udp_tx.bytes,
+        };
+        Self {
+            bytes,
+            congestion_events: stats.path.congestion_events,
+            rtt: stats.path.rtt,
+        }
+    }
+}
+
+/// What the advisor thinks should happen
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Advice {
+    /// Grow the receive window to this size
+    GrowReceiveWindow(u64),
+    /// The window appears to be the bottleneck, but we can't (or won't) do anything about it
+    WindowLimited {
+        /// Observed throughput (bytes per second)
+        rate: f64,
+        /// The current window size
+        window: u64,
+    },
+}
+
+/// Pure computation half of the window monitor
+#[derive(Clone, Debug)]
+struct WindowAdvisor {
+    direction: ThroughputMode,
+    /// Configured bandwidth in the direction of interest
+    target_rate: f64,
+    /// Current window size in the direction of interest
+    window: u64,
+    /// Maximum window size we may grow to; if equal to `window`, we don't grow
+    max_window: u64,
+    previous: Option<Sample>,
+}
+
+impl WindowAdvisor {
+    fn new(config: &Configuration, direction: ThroughputMode) -> Self {
+        #[allow(clippy::cast_precision_loss)]
+        let (target_rate, window, max_window) = match direction {
+            ThroughputMode::Rx => {
+                let max = if config.auto_window == AutoWindow::On {
+                    config.max_recv_window()
+                } else {
+                    config.recv_window()
+                };
+                (config.rx() as f64, config.recv_window(), max)
+            }
+            ThroughputMode::Tx | ThroughputMode::Both => (
+                config.tx() as f64,
+                config.send_window(),
+                config.send_window(),
+            ),
+        };
+        Self {
+            direction,
+            target_rate,
+            window,
+            max_window,
+            previous: None,
+        }
+    }
+
+    /// Considers a new sample. `elapsed` is the time since the previous sample.
+    fn assess(&mut self, sample: Sample, elapsed: Duration) -> Option<Advice> {
+        let previous = self.previous.replace(sample)?;
+        if elapsed.is_zero() || sample.rtt.is_zero() {
+            return None;
+        }
+        #[allow(clippy::cast_precision_loss)]
+        let rate = sample.bytes.saturating_sub(previous.bytes) as f64 / elapsed.as_secs_f64();
+        #[allow(clippy::cast_precision_loss)]
+        let window_rate = self.window as f64 / sample.rtt.as_secs_f64();
+        let congested = sample.congestion_events > previous.congestion_events;
+
+        if congested
+            || rate >= self.target_rate * STALL_FRACTION
+            || rate < window_rate * WINDOW_BOUND_FRACTION
+        {
+            return None;
+        }
+        if self.window < self.max_window {
+            self.window = std::cmp::min(self.window.saturating_mul(2), self.max_window);
+            Some(Advice::GrowReceiveWindow(self.window))
+        } else {
+            Some(Advice::WindowLimited {
+                rate,
+                window: self.window,
+            })
+        }
+    }
+}
+
+/// Runs a [`WindowAdvisor`] on a connection for the duration of a transfer
+#[derive(Debug)]
+pub(crate) struct WindowMonitor {
+    task: Option<JoinHandle<()>>,
+    stopper: Option<oneshot::Sender<()>>,
+}
+
+impl WindowMonitor {
+    /// Starts monitoring, if enabled by the configuration.
+    pub(crate) fn start(
+        connection: &Connection,
+        config: &Configuration,
+        direction: ThroughputMode,
+    ) -> Self {
+        if config.auto_window == AutoWindow::Off {
+            return Self {
+                task: None,
+                stopper: None,
+            };
+        }
+        let (tx, mut rx) = oneshot::channel();
+        let mut advisor = WindowAdvisor::new(config, direction);
+        let connection = connection.clone();
+        let configured_rtt = config.rtt;
+        let task = tokio::spawn(async move {
+            let mut earlier = Instant::now();
+            let mut advised = false;
+            loop {
+                let sleep = tokio::time::sleep(INTERVAL);
+                tokio::pin!(sleep);
+                tokio::select! {
+                    () = &mut sleep => (), // we woke up, continue
+                    _ = &mut rx => break, // we've been signalled to stop
+                }
+                let now = Instant::now();
+                let stats = connection.stats();
+                let advice = advisor.assess(Sample::new(&stats, direction), now - earlier);
+                earlier = now;
+                match advice {
+                    None => (),
+                    Some(Advice::GrowReceiveWindow(size)) => {
+                        debug!("growing receive window to {}", size.human_count_bytes());
+                        match VarInt::from_u64(size) {
+                            Ok(v) => connection.set_receive_window(v),
+                            Err(e) => warn!("could not set receive window: {e}"),
+                        }
+                    }
+                    Some(Advice::WindowLimited { rate, window }) => {
+                        // Only say this once per transfer, it's not going to change.
+                        if advised {
+                            continue;
+                        }
+                        advised = true;
+                        let suggested_rtt = stats.path.rtt.as_millis() + 1; // round up
+                        info!(
+                            "Throughput {rate} appears to be limited by the {dir} window ({window}); for better performance, next time try --rtt {rtt}",
+                            rate = rate.human_throughput_bytes(),
+                            dir = if advisor.direction == ThroughputMode::Rx { "receive" } else { "send" },
+                            window = window.human_count_bytes(),
+                            rtt = std::cmp::max(suggested_rtt, u128::from(configured_rtt) * 2),
+                        );
+                    }
+                }
+            }
+        });
+        Self {
+            task: Some(task),
+            stopper: Some(tx),
+        }
+    }
+
+    /// Stops monitoring
+    pub(crate) async fn stop(mut self) {
+        if let Some(tx) = self.stopper.take() {
+            let _ = tx.send(());
+        }
+        if let Some(task) = self.task.take() {
+            let _ = task
+                .await
+                .inspect_err(|e| warn!("window monitor task panicked: {e}"));
+        }
+    }
+}
+
+impl Drop for WindowMonitor {
+    fn drop(&mut self) {
+        if let Some(t) = self.task.take() {
+            t.abort();
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{Advice, Sample, WindowAdvisor};
+    use crate::{
+        config::Configuration,
+        transport::{AutoWindow, ThroughputMode},
+    };
+
+    fn config(auto_window: AutoWindow) -> Configuration {
+        Configuration {
+            rx: 10_000_000.into(),
+            rtt: 100,
+            auto_window,
+            ..Default::default()
+        }
+    }
+
+    fn sample(bytes: u64, rtt_ms: u64) -> Sample {
+        Sample {
+            bytes,
+            congestion_events: 0,
+            rtt: Duration::from_millis(rtt_ms),
+        }
+    }
+
+    const SEC: Duration = Duration::from_secs(1);
+
+    #[test]
+    fn first_sample_is_baseline() {
+        let mut uut = WindowAdvisor::new(&config(AutoWindow::On), ThroughputMode::Rx);
+        assert_eq!(uut.assess(sample(0, 100), SEC), None);
+    }
+
+    #[test]
+    fn full_speed_is_fine() {
+        let mut uut = WindowAdvisor::new(&config(AutoWindow::On), ThroughputMode::Rx);
+        let _ = uut.assess(sample(0, 100), SEC);
+        assert_eq!(uut.assess(sample(9_000_000, 100), SEC), None);
+    }
+
+    #[test]
+    fn window_bound_grows() {
+        // Configured window is 1MB (10MB/s * 100ms). At an actual RTT of 400ms that limits us to 2.5MB/s.
+        let mut uut = WindowAdvisor::new(&config(AutoWindow::On), ThroughputMode::Rx);
+        let _ = uut.assess(sample(0, 400), SEC);
+        assert_eq!(
+            uut.assess(sample(2_500_000, 400), SEC),
+            Some(Advice::GrowReceiveWindow(2_000_000))
+        );
+        assert_eq!(
+            uut.assess(sample(7_000_000, 400), SEC),
+            Some(Advice::GrowReceiveWindow(4_000_000))
+        );
+        // That's the limit
+        assert!(matches!(
+            uut.assess(sample(10_000_000, 400), SEC),
+            None | Some(Advice::WindowLimited { .. })
+        ));
+    }
+
+    #[test]
+    fn advise_only_does_not_grow() {
+        let mut uut = WindowAdvisor::new(&config(AutoWindow::Advise), ThroughputMode::Rx);
+        let _ = uut.assess(sample(0, 400), SEC);
+        assert!(matches!(
+            uut.assess(sample(2_500_000, 400), SEC),
+            Some(Advice::WindowLimited {
+                window: 1_000_000,
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn slow_but_not_window_bound() {
+        // Throughput well below the window-limited rate: something else is the bottleneck.
+        let mut uut = WindowAdvisor::new(&config(AutoWindow::On), ThroughputMode::Rx);
+        let _ = uut.assess(sample(0, 100), SEC);
+        assert_eq!(uut.assess(sample(1_000_000, 100), SEC), None);
+    }
+
+    #[test]
+    fn congestion_is_not_window() {
+        let mut uut = WindowAdvisor::new(&config(AutoWindow::On), ThroughputMode::Rx);
+        let _ = uut.assess(sample(0, 400), SEC);
+        let mut s = sample(2_500_000, 400);
+        s.congestion_events = 1;
+        assert_eq!(uut.assess(s, SEC), None);
+    }
+}
@@ -3,11 +3,19 @@
 
 use crate::os::{AbstractPlatform as _, Platform};
 
-use super::{ssh::SshConfigError, Configuration};
+use super::{
+    ssh::SshConfigError,
+    structured::{Format, StructuredConfig},
+    Configuration,
+};
 
-use figment::{providers::Serialized, value::Value, Figment, Metadata, Provider};
+use figment::{
+    providers::Serialized,
+    value::{Dict, Value},
+    Figment, Metadata, Provider,
+};
 use heck::ToUpperCamelCase;
-use serde::Deserialize;
+use serde::{Deserialize, Serialize};
 use std::{
     collections::HashSet,
     fmt::{Debug, Display},
@@ -59,6 +67,10 @@ pub struct Manager {
     data: Figment,
     /// The host argument this data was read for, if applicable
     host: Option<String>,
+    /// Problems found in configuration files which were not fatal
+    warnings: Vec<String>,
+    /// The host patterns found in configuration files
+    host_patterns: Vec<String>,
 }
 
 impl Default for Manager {
@@ -67,6 +79,8 @@ impl Default for Manager {
         Self {
             data: Figment::default(),
             host: None,
+            warnings: Vec::new(),
+            host_patterns: Vec::new(),
         }
     }
 }
@@ -79,6 +93,8 @@ impl Manager {
         let mut new1 = Self {
             data: Figment::new(),
             host: for_host.map(std::borrow::ToOwned::to_owned),
+            warnings: Vec::new(),
+            host_patterns: Vec::new(),
         };
         new1.merge_provider(SystemDefault::default());
         // N.B. This may leave data in a fused-error state, if a config file isn't parseable.
@@ -104,6 +120,12 @@ impl Manager {
             warn!("could not determine {what} configuration file path");
             return;
         };
+        // Alternative formats are read first, so the ssh-style file takes precedence
+        for alternative in Self::alternative_paths(&path) {
+            if alternative.exists() {
+                self.merge_config_file(alternative, for_host, is_user);
+            }
+        }
         if !path.exists() {
             debug!("{what} configuration file {path:?} not present");
             return;
@@ -111,6 +133,13 @@ impl Manager {
         self.merge_ssh_config(path, for_host, is_user);
     }
 
+    /// Alternative-format (TOML, YAML) equivalents of a configuration file path
+    fn alternative_paths(path: &Path) -> impl Iterator<Item = PathBuf> + '_ {
+        Format::EXTENSIONS
+            .iter()
+            .map(|ext| path.with_extension(ext))
+    }
+
     /// Returns the list of configuration files we read.
     ///
     /// This is a function of platform and the current user id.
@@ -120,7 +149,15 @@ impl Manager {
 
         inputs
             .into_iter()
-            .filter_map(|p| Some(p?.into_os_string().to_string_lossy().to_string()))
+            .flatten()
+            .flat_map(|p| {
+                // Alternative formats are only listed if present
+                let alternatives = Self::alternative_paths(&p)
+                    .filter(|a| a.exists())
+                    .collect::<Vec<_>>();
+                alternatives.into_iter().chain(std::iter::once(p))
+            })
+            .map(|p| p.into_os_string().to_string_lossy().to_string())
             .collect()
     }
 
@@ -130,7 +167,12 @@ impl Manager {
     pub(crate) fn without_files(host: Option<&str>) -> Self {
         let data = Figment::new().merge(SystemDefault::default());
         let host = host.map(std::string::ToString::to_string);
-        Self { data, host }
+        Self {
+            data,
+            host,
+            warnings: Vec::new(),
+            host_patterns: Vec::new(),
+        }
     }
 
     /// Merges in a data set, which is some sort of [figment::Provider](https://docs.rs/figment/latest/figment/trait.Provider.html).
@@ -155,29 +197,129 @@ impl Manager {
         let path = file.as_ref();
         let p = super::ssh::Parser::for_path(file.as_ref(), is_user)
             .and_then(|p| p.parse_file_for(host))
-            .map(|hc| self.merge_provider(hc.as_figment()));
+            .map(|hc| {
+                self.warnings.extend_from_slice(hc.warnings());
+                self.note_host_patterns(hc.host_patterns());
+                self.merge_provider(hc.as_figment());
+            });
         if let Err(e) = p {
-            warn!("parsing {ff}: {e}", ff = path.to_string_lossy());
+            self.warnings
+                .push(format!("parsing {ff}: {e}", ff = path.to_string_lossy()));
         }
     }
 
-    /// Attempts to extract a particular struct from the data.
+    /// Merges in a data set from a configuration file, in a format determined by its extension.
     ///
-    /// Within qcp, `T` is usually [Configuration], but it isn't intrinsically required to be.
-    /// (This is useful for unit testing.)
-    pub(crate) fn get<'de, T>(&self) -> anyhow::Result<T, SshConfigError>
+    /// Files ending `.toml`, `.yaml` or `.yml` are read as structured data; anything else is read as ssh-style.
+    pub fn merge_config_file<F>(&mut self, file: F, host: Option<&str>, is_user: bool)
     where
-        T: Deserialize<'de>,
+        F: AsRef<Path>,
     {
+        let path = file.as_ref();
+        let Some(format) = Format::for_path(path) else {
+            return self.merge_ssh_config(path, host, is_user);
+        };
+        match StructuredConfig::read(path, format, host) {
+            Ok(sc) => {
+                self.note_host_patterns(sc.host_patterns());
+                self.merge_provider(sc);
+            }
+            Err(e) => self
+                .warnings
+                .push(format!("parsing {ff}: {e}", ff = path.to_string_lossy())),
+        }
+    }
+
+    fn note_host_patterns(&mut self, patterns: &[String]) {
+        for pattern in patterns {
+            if !self.host_patterns.contains(pattern) {
+                self.host_patterns.push(pattern.clone());
+            }
+        }
+    }
+
+    /// Problems found in configuration files which were not fatal.
+    ///
+    /// These are kept so that the caller can report them once logging has been set up.
+    #[must_use]
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Checks the configuration files for problems, without doing anything else.
+    ///
+    /// The configuration is read for no host in particular, then for each host pattern found in the files,
+    /// so that every `Host` block is checked.
+    /// Returns a description of each problem found: parse errors, invalid values and unknown keywords.
+    #[must_use]
+    pub fn check_files() -> Vec<String> {
+        let global = Self::standard(None);
+        let hosts = std::iter::once(None).chain(
+            global
+                .host_patterns
+                .iter()
+                .filter(|p| *p != "*" && !p.starts_with('!'))
+                .map(|p| Some(p.as_str())),
+        );
+        let mut problems = Vec::new();
+        for host in hosts {
+            let mgr = Self::standard(host);
+            let mut found = mgr.warnings.clone();
+            found.extend(mgr.unknown_keywords());
+            if let Err(e) = mgr.get::<Configuration>() {
+                // These errors say which host they relate to
+                found.extend(e.into_iter().map(|e| e.to_string()));
+            }
+            for problem in found {
+                if !problems.contains(&problem) {
+                    problems.push(problem);
+                }
+            }
+        }
+        problems
+    }
+
+    /// Describes the keywords in the data which are not fields of [`Configuration`], and where each was set
+    fn unknown_keywords(&self) -> Vec<String> {
+        let data = self.selected();
+        let Ok(dict) = data.extract::<Dict>() else {
+            return Vec::new(); // get() will report the problem
+        };
+        dict.keys()
+            .filter(|k| !Configuration::FIELD_NAMES_AS_SLICE.contains(&k.as_str()))
+            .map(|k| {
+                let meta = data
+                    .find_value(k)
+                    .ok()
+                    .and_then(|v| data.get_metadata(v.tag()));
+                format!(
+                    "unknown keyword {k} at {}",
+                    PrettyConfig::render_source(meta)
+                )
+            })
+            .collect()
+    }
+
+    /// The data, with the profile for our host selected
+    fn selected(&self) -> Figment {
         let profile = if let Some(host) = &self.host {
             figment::Profile::new(host)
         } else {
             figment::Profile::Default
         };
+        self.data.clone().select(profile)
+    }
 
-        self.data
-            .clone()
-            .select(profile)
+    /// Attempts to extract a particular struct from the data.
+    ///
+    /// Within qcp, `T` is usually [Configuration], but it isn't intrinsically required to be.
+    /// (This is useful for unit testing.)
+    #[allow(clippy::result_large_err)]
+    pub(crate) fn get<'de, T>(&self) -> anyhow::Result<T, SshConfigError>
+    where
+        T: Deserialize<'de>,
+    {
+        self.selected()
             .extract_lossy::<T>()
             .map_err(SshConfigError::from)
     }
@@ -206,7 +348,7 @@ impl PrettyConfig {
 
     fn render_value(value: &Value) -> String {
         match value {
-            Value::String(_tag, s) => s.to_string(),
+            Value::String(_tag, s) => s.clone(),
             Value::Char(_tag, c) => c.to_string(),
             Value::Bool(_tag, b) => b.to_string(),
             Value::Num(_tag, num) => {
@@ -244,6 +386,18 @@ impl PrettyConfig {
     }
 }
 
+/// Output formats for `--show-config`
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum ShowFormat {
+    /// A table, showing where each value came from
+    #[default]
+    Table,
+    /// JSON, for automation
+    Json,
+    /// Configuration file lines, which may be pasted into a qcp configuration file
+    Conf,
+}
+
 /// Pretty-printing type wrapper to Manager
 #[derive(Debug)]
 pub struct DisplayAdapter<'a> {
@@ -251,6 +405,8 @@ pub struct DisplayAdapter<'a> {
     source: &'a Manager,
     /// The fields we want to output. (If empty, outputs everything.)
     fields: HashSet<String>,
+    /// The output, if it is not to be a table
+    rendered: Option<String>,
 }
 
 impl Manager {
@@ -258,25 +414,90 @@ impl Manager {
     ///
     /// # Returns
     /// An ephemeral structure implementing `Display`.
-    #[must_use]
-    pub fn to_display_adapter<'de, T>(&self) -> DisplayAdapter<'_>
+    ///
+    /// # Errors
+    /// For formats other than [`ShowFormat::Table`], if the struct cannot be extracted from the data
+    pub fn to_display_adapter<'de, T>(
+        &self,
+        format: ShowFormat,
+    ) -> anyhow::Result<DisplayAdapter<'_>>
     where
-        T: Deserialize<'de> + FieldNamesAsSlice,
+        T: Deserialize<'de> + Serialize + FieldNamesAsSlice,
     {
         let mut fields = HashSet::<String>::new();
         fields.extend(T::FIELD_NAMES_AS_SLICE.iter().map(|s| String::from(*s)));
-        DisplayAdapter {
+        let resolved = || self.get::<T>().map_err(|e| anyhow::anyhow!("{e}"));
+        let rendered = match format {
+            ShowFormat::Table => None,
+            ShowFormat::Json => Some(serde_json::to_string_pretty(&resolved()?)?),
+            ShowFormat::Conf => {
+                Some(self.render_conf(&serde_json::to_value(resolved()?)?, T::FIELD_NAMES_AS_SLICE))
+            }
+        };
+        Ok(DisplayAdapter {
             source: self,
             fields,
+            rendered,
+        })
+    }
+
+    /// Renders resolved data as configuration file lines.
+    /// Empty values are commented out, as they cannot be expressed.
+    fn render_conf(&self, data: &serde_json::Value, fields: &[&str]) -> String {
+        let mut lines = vec![match &self.host {
+            Some(host) => format!("# Effective configuration for host {host}"),
+            None => "# Effective configuration (globals)".into(),
+        }];
+        for field in fields {
+            let key = field.to_upper_camel_case();
+            let args = conf_args(&data[field]);
+            lines.push(if args.is_empty() {
+                format!("# {key}")
+            } else {
+                format!("{key} {args}")
+            });
         }
+        lines.join("\n")
+    }
+}
+
+/// Renders a value as the arguments of a configuration file line.
+/// Returns an empty string if the value is empty.
+fn conf_args(value: &serde_json::Value) -> String {
+    use serde_json::Value as V;
+    match value {
+        V::Null => String::new(),
+        V::String(s) if s.is_empty() => String::new(),
+        V::String(s) => conf_quote(s),
+        V::Array(a) => a
+            .iter()
+            .map(|v| match v {
+                V::String(s) => conf_quote(s),
+                other => conf_args(other),
+            })
+            .collect::<Vec<_>>()
+            .join(" "),
+        other => other.to_string(),
+    }
+}
+
+/// Quotes a configuration file argument, if necessary
+fn conf_quote(arg: &str) -> String {
+    if arg.is_empty() || arg.contains([' ', '\t', '#', '"', '\'', '\\']) {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    } else {
+        arg.into()
     }
 }
 
 impl Display for DisplayAdapter<'_> {
     /// Formats the contents of this structure which are relevant to a given output type.
     ///
-    /// N.B. This function uses CLI styling.
+    /// N.B. The table format uses CLI styling.
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(rendered) = &self.rendered {
+            return f.write_str(rendered);
+        }
         let mut data = self.source.data.clone();
 
         let mut output = Vec::<PrettyConfig>::new();
@@ -315,8 +536,10 @@ impl Display for DisplayAdapter<'_> {
 
 #[cfg(test)]
 mod test {
-    use crate::config::ssh::SshConfigError;
-    use crate::config::{Configuration, Configuration_Optional, Manager};
+    use assertables::assert_contains;
+
+    use crate::config::{Configuration, Configuration_Optional, Manager, ShowFormat};
+    use crate::transport::CongestionControllerType;
     use crate::util::{make_test_tempfile, PortRange};
     use serde::Deserialize;
 
@@ -374,6 +597,62 @@ mod test {
         assert_eq!(other_struct.magic, 42);
     }
 
+    #[test]
+    fn show_formats() {
+        let config = Configuration {
+            rtt: 123,
+            congestion: CongestionControllerType::Bbr,
+            ssh_options: vec!["-o".into(), "ProxyCommand=nc \"%h\" 22".into()],
+            ssh_config: vec!["/x/y".into()],
+            ..Default::default()
+        };
+        let mut mgr = Manager::without_files(None);
+        mgr.merge_provider(figment::providers::Serialized::defaults(&config));
+
+        let json = mgr
+            .to_display_adapter::<Configuration>(ShowFormat::Json)
+            .unwrap()
+            .to_string();
+        assert_eq!(
+            serde_json::from_str::<Configuration>(&json).unwrap(),
+            config
+        );
+
+        // The conf output reads back in to the same configuration
+        let conf = mgr
+            .to_display_adapter::<Configuration>(ShowFormat::Conf)
+            .unwrap()
+            .to_string();
+        let (path, _tempdir) = make_test_tempfile(&conf, "test.conf");
+        let mut reread = Manager::without_files(None);
+        reread.merge_ssh_config(path, None, false);
+        assert_eq!(reread.get::<Configuration>().unwrap(), config);
+    }
+
+    #[test]
+    fn unknown_keywords() {
+        let (path, _tempdir) = make_test_tempfile(
+            r"
+            rtt 100
+            congstion bbr
+            Host foo *.bar !baz
+            frobnicate yes
+        ",
+            "test.conf",
+        );
+        let mut mgr = Manager::without_files(None);
+        mgr.merge_ssh_config(&path, None, false);
+        assert_eq!(mgr.host_patterns, ["foo", "*.bar", "!baz"]);
+        let unknown = mgr.unknown_keywords();
+        assert_eq!(unknown.len(), 1);
+        assert_contains!(unknown[0], "congstion");
+        assert_contains!(unknown[0], "line 3");
+
+        let mut mgr = Manager::without_files(Some("foo"));
+        mgr.merge_ssh_config(&path, Some("foo"), false);
+        assert_eq!(mgr.unknown_keywords().len(), 2);
+    }
+
     #[test]
     fn field_parse_failure() {
         #[derive(Debug, Deserialize)]
@@ -506,6 +785,35 @@ mod test {
         }
     }
 
+    #[test]
+    fn congestion_types() {
+        use crate::transport::CongestionControllerType;
+
+        #[derive(Debug, Deserialize)]
+        struct Test {
+            en: CongestionControllerType,
+        }
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("testfile");
+
+        for (s, expected) in [
+            ("cubic", CongestionControllerType::Cubic),
+            ("bbr", CongestionControllerType::Bbr),
+            ("newreno", CongestionControllerType::NewReno),
+            ("NewReno", CongestionControllerType::NewReno),
+        ] {
+            std::fs::write(&path, format!("en {s}\n")).expect("Unable to write tempfile");
+            let mut mgr = Manager::without_files(Some("foo"));
+            mgr.merge_ssh_config(&path, Some("foo"), false);
+            let result = mgr
+                .get::<Test>()
+                .inspect_err(|e| println!("ERROR: {e}"))
+                .unwrap();
+            assert_eq!(result.en, expected);
+        }
+    }
+
     #[test]
     fn invalid_data() {
         use crate::transport::CongestionControllerType;
@@ -530,7 +838,7 @@ mod test {
         let mut mgr = Manager::default();
         mgr.merge_ssh_config(&path, Some("foo"), false);
         //println!("{mgr:?}");
-        let err = mgr.get::<Test>().map_err(SshConfigError::from).unwrap_err();
+        let err = mgr.get::<Test>().unwrap_err();
         println!("{err}");
     }
 
@@ -27,7 +27,7 @@
 //!
 //! Empty lines are ignored.
 //!
-//! **qcp supports Host and Include directives in way that is intended to be compatible with OpenSSH.**
+//! **qcp supports Host, Match and Include directives in way that is intended to be compatible with OpenSSH.**
 //! This allows you to tune your configuration for a range of network hosts.
 //!
 //! #### Host
@@ -35,7 +35,7 @@
 //! `Host host [host2 host3...]`
 //!
 //! This directive introduces a _host block_.
-//! All following options - up to the next `Host` - only apply to hosts matching any of the patterns given.
+//! All following options - up to the next `Host` or `Match` - only apply to hosts matching any of the patterns given.
 //!
 //! * Pattern matching uses `*` and `?` as wildcards in the usual way.
 //! * A single asterisk `*` matches all hosts; this is used to provide defaults.
@@ -43,6 +43,19 @@
 //! * Pattern matching is applied directly to the remote host given on the QCP command line, before DNS or alias resolution.
 //!   If you connect to hosts by IP address, a pattern of `10.11.12.*` works in the obvious way.
 //!
+//! #### Match
+//!
+//! `Match criterion [argument] [criterion2 [argument2]...]`
+//!
+//! This directive introduces a _match block_, which applies if all the criteria are satisfied.
+//!
+//! * `all` always matches.
+//! * `host pattern[,pattern2...]` matches the remote host in the same way as a `Host` directive.
+//!   `originalhost` is the same, as qcp does not canonicalise hostnames.
+//! * `final` always matches, and `canonical` never does, as qcp reads its configuration in a single pass.
+//! * Any criterion may be negated by prefixing it with `!`.
+//! * The other OpenSSH criteria (`exec`, `user`, `localuser`, `localnetwork`, `tagged`) are ignored, i.e. treated as matching.
+//!
 //! #### Include
 //!
 //! `Include file [file2 file3...]`
@@ -64,9 +77,16 @@
 //! In configuration files, option keywords are case insensitive and ignore hyphens and underscores.
 //! (On the command line, they must be specified in kebab-case.)
 //!
+//! In the values of options which take free-form strings (such as `SshOptions` or `TlsCert`),
+//! `${VAR}` is replaced by the value of the environment variable `VAR`.
+//! If it is not set, qcp warns and leaves the reference as it is.
+//! In options which take a path to a local file (`Ssh`, `SshConfig`, `TlsCert`, `TlsKey` and `AllowPath`),
+//! a leading `~` is expanded to the user's home directory.
+//!
 //! * `qcp --show-config` outputs a list of supported fields, their current values, and where each value came from.
 //! * For an explanation of each field, refer to `qcp --help` .
 //! * `qcp --config-files` outputs the list of configuration files for the current user and platform.
+//! * `qcp --check-config` checks the configuration files for errors and unknown keywords.
 //!
 //! ## Example
 //!
@@ -104,14 +124,53 @@
 //! * `Host` blocks; if you use wildcards, from most-specific to least-specific
 //! * A `Host *` block to provide default settings to apply where no more specific value has been given
 //!
+//! ## Alternative formats: TOML and YAML
+//!
+//! If you prefer, you may write configuration files in [TOML](https://toml.io/) or [YAML](https://yaml.org/).
+//! These are read from the same locations as the ssh-style files, with the extension changed:
+//! for example `~/.qcp.toml` or `/etc/qcp.yaml` (`.yml` also works).
+//!
+//! Precedence is as above, by location: user configuration overrides system configuration.
+//! If there is more than one file in the same location, the ssh-style file takes precedence,
+//! then TOML, then YAML.
+//!
+//! Keywords are the same as in ssh-style files, and are matched in the same case-insensitive way.
+//! Values are interpreted in the same way too, so `rx = "5M"` and `rx = 5000000` are equivalent.
+//! `Include` is not supported.
+//!
+//! Per-host settings go in a `host` table, keyed by host patterns (space-separated, if more than one).
+//! Settings in a matching `host` table override top-level settings.
+//! If more than one table matches, the first one in the file wins.
+//!
+//! This is equivalent to the example above:
+//!
+//! ```toml
+//! [host.old-faithful]
+//! rx = "125k"
+//! tx = 0
+//! RemotePort = "65400-65500"
+//!
+//! [host."*.internal.corp"]
+//! rx = "125M"
+//! tx = 0
+//! rtt = 10
+//!
+//! [host."*"]
+//! rx = "5M"
+//! tx = "1M"
+//! rtt = 150
+//! congestion = "bbr"
+//! ```
+//!
 
 mod structure;
 pub use structure::Configuration;
 pub(crate) use structure::Configuration_Optional;
 
 mod manager;
-pub use manager::Manager;
+pub use manager::{Manager, ShowFormat};
 
 pub(crate) const BASE_CONFIG_FILENAME: &str = "qcp.conf";
 
 pub(crate) mod ssh;
+mod structured;
@@ -4,16 +4,18 @@
 mod errors;
 pub(crate) use errors::SshConfigError;
 
+mod expansion;
 mod files;
 mod includes;
 mod lines;
 mod matching;
 mod values;
 
-pub(crate) use files::Parser;
+pub(crate) use files::{configuration_field_name, HostConfiguration, Parser};
 pub(crate) use values::Setting;
 
 use includes::find_include_files;
 use lines::{split_args, Line};
-use matching::evaluate_host_match;
+pub(crate) use matching::evaluate_host_match;
+use matching::evaluate_match;
 use values::ValueProvider;
@@ -0,0 +1,81 @@
+//! Environment variable and ~ expansion for setting values
+// (c) 2024 Ross Younger
+
+/// Expands `${VAR}` references in a string.
+///
+/// `lookup` resolves a variable name to its value.
+/// References to variables which are not set are left as they are, and reported to `on_unset`.
+/// An unterminated `${` is left as it is.
+pub(super) fn expand_env_vars<L, U>(input: &str, lookup: L, mut on_unset: U) -> String
+where
+    L: Fn(&str) -> Option<String>,
+    U: FnMut(&str),
+{
+    let mut output = String::with_capacity(input.len());
+    let mut rest = input;
+    while let Some(start) = rest.find("${") {
+        output.push_str(&rest[..start]);
+        let reference = &rest[start..];
+        let Some(end) = reference.find('}') else {
+            break;
+        };
+        let name = &reference[2..end];
+        if let Some(value) = lookup(name) {
+            output.push_str(&value);
+        } else {
+            on_unset(name);
+            output.push_str(&reference[..=end]);
+        }
+        rest = &reference[end + 1..];
+    }
+    output.push_str(rest);
+    output
+}
+
+/// Expands a leading `~` or `~user` in a path to the relevant home directory.
+///
+/// Paths which do not start with `~` are returned unchanged.
+/// Returns an error message if the home directory cannot be determined.
+pub(super) fn expand_tilde(path: &str) -> Result<String, String> {
+    if !path.starts_with('~') {
+        return Ok(path.to_string());
+    }
+    expanduser::expanduser(path)
+        .map(|p| p.to_string_lossy().to_string())
+        .map_err(|e| format!("could not expand {path}: {e}"))
+}
+
+#[cfg(test)]
+mod test {
+    use super::{expand_env_vars, expand_tilde};
+
+    fn lookup(name: &str) -> Option<String> {
+        match name {
+            "DIR" => Some("/srv/qcp".into()),
+            "EMPTY" => Some(String::new()),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn env_vars() {
+        let mut unset = Vec::new();
+        let mut expand = |s| expand_env_vars(s, lookup, |v| unset.push(v.to_string()));
+        assert_eq!(expand("${DIR}/cert.pem"), "/srv/qcp/cert.pem");
+        assert_eq!(expand("a${EMPTY}b${DIR}"), "ab/srv/qcp");
+        assert_eq!(expand("no references, $DIR"), "no references, $DIR");
+        assert_eq!(expand("${NOPE}/x"), "${NOPE}/x");
+        assert_eq!(expand("${DIR}${unterminated"), "/srv/qcp${unterminated");
+        assert_eq!(unset, ["NOPE"]);
+    }
+
+    #[test]
+    fn tilde() {
+        assert_eq!(expand_tilde("/abs/path").unwrap(), "/abs/path");
+        assert_eq!(expand_tilde("rel~ative").unwrap(), "rel~ative");
+        if let Some(home) = dirs::home_dir() {
+            let expected = home.join("x").to_string_lossy().to_string();
+            assert_eq!(expand_tilde("~/x").unwrap(), expected);
+        }
+    }
+}
@@ -52,7 +52,7 @@ mod test {
     use super::find_include_files;
 
     #[test]
-    #[ignore] // this test is dependent on the current user filespace
+    #[ignore = "dependent on the current user filespace"]
     fn tilde_expansion_current_user() {
         let a = find_include_files("~/*.conf", true).expect("~ should expand to home directory");
         assert!(!a.is_empty());
@@ -61,7 +61,7 @@ mod test {
     }
 
     #[test]
-    #[ignore] // obviously this won't run on CI. TODO: figure out a way to make it CIable.
+    #[ignore = "won't run on CI"] // TODO: figure out a way to make it CIable.
     fn tilde_expansion_arbitrary_user() {
         let a =
             find_include_files("~wry/*.conf", true).expect("~ should expand to a home directory");
@@ -72,7 +72,7 @@ mod test {
     }
 
     #[test]
-    #[ignore] // TODO: Make this runnable on CI
+    #[ignore = "not runnable on CI"] // TODO: Make this runnable on CI
     fn relative_path_expansion() {
         let a = find_include_files("config", true).unwrap();
         println!("{a:?}");
@@ -1,6 +1,9 @@
 //! Host matching
 // (c) 2024 Ross Younger
 
+use anyhow::{Context as _, Result};
+use tracing::debug;
+
 fn match_one_pattern(host: &str, pattern: &str) -> bool {
     if let Some(negative_pattern) = pattern.strip_prefix('!') {
         !wildmatch::WildMatch::new(negative_pattern).matches(host)
@@ -9,7 +12,7 @@ fn match_one_pattern(host: &str, pattern: &str) -> bool {
     }
 }
 
-pub(super) fn evaluate_host_match(host: Option<&str>, args: &[String]) -> bool {
+pub(crate) fn evaluate_host_match(host: Option<&str>, args: &[String]) -> bool {
     if let Some(host) = host {
         args.iter().any(|arg| match_one_pattern(host, arg))
     } else {
@@ -18,11 +21,55 @@ pub(super) fn evaluate_host_match(host: Option<&str>, args: &[String]) -> bool {
     }
 }
 
+/// Evaluates the criteria of a `Match` line. All the criteria must match.
+///
+/// We support:
+/// * `all`
+/// * `host` and `originalhost`, which are the same thing as we don't canonicalise hostnames
+/// * `canonical` and `final`. As qcp reads its configuration in a single pass, `final` always matches and `canonical` never does.
+///
+/// Any criterion may be negated by prefixing it with `!`.
+/// Other criteria (`exec`, `user`, `localuser`, `localnetwork`, `tagged`) are ignored, i.e. treated as matching.
+pub(crate) fn evaluate_match(host: Option<&str>, args: &[String]) -> Result<bool> {
+    let mut result = true;
+    let mut args = args.iter();
+    while let Some(criterion) = args.next() {
+        let (negated, criterion) = match criterion.strip_prefix('!') {
+            Some(c) => (true, c.to_ascii_lowercase()),
+            None => (false, criterion.to_ascii_lowercase()),
+        };
+        let matched = match criterion.as_str() {
+            "all" => true,
+            "final" => !negated,
+            "canonical" => negated,
+            "host" | "originalhost" => {
+                let patterns = args
+                    .next()
+                    .with_context(|| format!("missing argument to Match {criterion}"))?
+                    .split(',')
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                evaluate_host_match(host, &patterns) != negated
+            }
+            "exec" | "user" | "localuser" | "localnetwork" | "tagged" => {
+                let _ = args
+                    .next()
+                    .with_context(|| format!("missing argument to Match {criterion}"))?;
+                debug!("ignoring unsupported Match criterion `{criterion}`");
+                true
+            }
+            _ => anyhow::bail!("unknown Match criterion `{criterion}`"),
+        };
+        result &= matched;
+    }
+    Ok(result)
+}
+
 ///////////////////////////////////////////////////////////////////////////////////////
 
 #[cfg(test)]
 mod test {
-    use super::evaluate_host_match;
+    use super::{evaluate_host_match, evaluate_match};
     use anyhow::{anyhow, Context, Result};
     use assertables::assert_eq_as_result;
 
@@ -74,4 +121,29 @@ mod test {
         }
         Ok(())
     }
+
+    #[test]
+    fn match_criteria() -> Result<()> {
+        for (host, args, result) in [
+            (Some("foo"), sv!["all"], true),
+            (None, sv!["all"], true),
+            (Some("foo"), sv!["host", "foo"], true),
+            (Some("foo"), sv!["Host", "bar,f*"], true),
+            (Some("foo"), sv!["host", "bar"], false),
+            (Some("foo"), sv!["!host", "bar"], true),
+            (Some("foo"), sv!["originalhost", "foo"], true),
+            (None, sv!["host", "foo"], false),
+            (Some("foo"), sv!["final", "all"], true),
+            (Some("foo"), sv!["canonical", "all"], false),
+            (Some("foo"), sv!["host", "foo", "exec", "false"], true),
+            (Some("foo"), sv!["exec", "true", "host", "bar"], false),
+        ] {
+            assert_eq_as_result!(evaluate_match(host, &args)?, result)
+                .map_err(|e| anyhow!(e))
+                .with_context(|| format!("host {host:?}, args {args:?}"))?;
+        }
+        assert!(evaluate_match(Some("foo"), &["host".to_string()]).is_err());
+        assert!(evaluate_match(Some("foo"), &["frobnicate".to_string()]).is_err());
+        Ok(())
+    }
 }
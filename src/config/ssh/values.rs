@@ -3,6 +3,8 @@
 
 use figment::{Metadata, Profile, Source};
 
+use crate::config::Configuration;
+
 #[derive(Debug, Clone, PartialEq)]
 /// A setting we read from a config file
 pub(crate) struct Setting {
@@ -65,7 +67,9 @@ impl figment::Provider for ValueProvider<'_> {
         let mut dict = Dict::new();
         let value: Value = match self.value.args.len() {
             0 => Empty::Unit.into(),
-            1 => self.value.args.first().unwrap().clone().into(),
+            1 if !Configuration::LIST_FIELDS.contains(&self.key.as_str()) => {
+                self.value.args.first().unwrap().clone().into()
+            }
             _ => self.value.args.clone().into(),
         };
         let _ = dict.insert(self.key.clone(), value);
@@ -1,18 +1,23 @@
 //! Configuration structure
 // (c) 2024 Ross Younger
 
-use std::time::Duration;
+use std::{
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use clap::Parser;
 use human_repr::{HumanCount as _, HumanDuration as _};
+use num_format::Locale;
 use serde::{Deserialize, Serialize};
 use struct_field_names_as_array::FieldNamesAsSlice;
 
 use crate::{
-    transport::CongestionControllerType,
+    protocol::session::CommandType,
+    transport::{AutoWindow, CongestionControllerType, MAX_UDP_PAYLOAD_SIZE, MIN_UDP_PAYLOAD_SIZE},
     util::{
-        derive_deftly_template_Optionalify, humanu64::HumanU64, AddressFamily, PortRange,
-        TimeFormat,
+        compress::Compress, derive_deftly_template_Optionalify, humanu64::HumanU64,
+        socket::MAX_DSCP, AddressFamily, ColorMode, CommandSet, PortRange, Resolver, TimeFormat,
     },
 };
 
@@ -34,6 +39,7 @@ use derive_deftly::Deftly;
 #[derive_deftly(Optionalify)]
 #[deftly(visibility = "pub(crate)")]
 #[derive(Debug, Clone, PartialEq, Eq, Parser, Deserialize, Serialize, FieldNamesAsSlice)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct Configuration {
     // TRANSPORT PARAMETERS ============================================================================
     // System bandwidth, UDP ports, timeout.
@@ -44,6 +50,9 @@ pub struct Configuration {
     /// like `10M` or `256k`. **Note that this is described in BYTES, not bits**;
     /// if (for example) you expect to fill a 1Gbit ethernet connection,
     /// 125M might be a suitable setting.
+    ///
+    /// It may also be given as a percentage of a link capacity, e.g. `80%@1Gbit`
+    /// or `80%@125M`. A capacity ending in `bit` is in bits; otherwise it is in bytes.
     #[arg(short('b'), long, alias("rx-bw"), help_heading("Network tuning"), display_order(1), value_name="bytes", value_parser=clap::value_parser!(HumanU64))]
     pub rx: HumanU64,
     /// The maximum network bandwidth we expect sending data TO the remote system,
@@ -51,10 +60,20 @@ pub struct Configuration {
     ///
     /// (For example, when you are connected via an asymmetric last-mile DSL or fibre profile.)
     ///
+    /// This is specified in the same way as `rx`.
+    ///
     /// If not specified or 0, uses the value of `rx`.
     #[arg(short('B'), long, alias("tx-bw"), help_heading("Network tuning"), display_order(1), value_name="bytes", value_parser=clap::value_parser!(HumanU64))]
     pub tx: HumanU64,
 
+    /// Limits the rate at which file data is sent, regardless of the available bandwidth.
+    /// [default: 0, which means no limit]
+    ///
+    /// This is specified in the same way as `rx`. It applies in both directions:
+    /// the remote observes the same limit when sending files to us.
+    #[arg(long, help_heading("Network tuning"), display_order(2), value_name="bytes", value_parser=clap::value_parser!(HumanU64))]
+    pub bwlimit: HumanU64,
+
     /// The expected network Round Trip time to the target system, in milliseconds.
     /// [default: 300]
     #[arg(
@@ -91,6 +110,116 @@ pub struct Configuration {
     )]
     pub initial_congestion_window: u64,
 
+    /// _(Network wizards only!)_
+    /// The number of consecutive probe timeouts after which the path is considered to be in persistent congestion.
+    /// [default: 0, meaning QUIC's standard value of 3]
+    ///
+    /// Persistent congestion collapses the congestion window to its minimum, from which it must grow again.
+    /// On a path with occasional long stalls (such as a satellite link) a higher value avoids
+    /// starting over after every stall; a lower value backs off sooner when the path is overloaded.
+    /// The same value is used at the remote end.
+    #[arg(
+        long,
+        help_heading("Advanced network tuning"),
+        value_name = "count",
+        display_order(0)
+    )]
+    pub persistent_congestion_threshold: u32,
+
+    /// _(Network wizards only!)_
+    /// The largest UDP payload to send or receive, in bytes.
+    /// [default: 0, meaning 1452, which suits the standard 1500-byte Ethernet MTU]
+    ///
+    /// On a path that supports jumbo frames, a larger value reduces per-packet overheads.
+    /// For a 9000-byte MTU, try 8952 (this allows for IPv6 and UDP headers).
+    /// The value must be between 1200 and 65527. Path MTU discovery still applies,
+    /// so qcp will not send packets the path cannot carry; but it cannot find a larger MTU than this.
+    #[arg(
+        long("mtu"),
+        alias("udp-payload-size"),
+        help_heading("Advanced network tuning"),
+        value_name = "bytes",
+        display_order(0),
+        value_parser=clap::value_parser!(u16).range(i64::from(MIN_UDP_PAYLOAD_SIZE)..=i64::from(MAX_UDP_PAYLOAD_SIZE))
+    )]
+    pub udp_payload_size: u16,
+
+    /// Marks outgoing packets with the given DSCP (Differentiated Services Code Point), for QoS.
+    /// [default: 0, which leaves packets unmarked]
+    ///
+    /// This allows managed networks to prioritise (or deprioritise) qcp's traffic.
+    /// For example, 8 (CS1) is commonly used for bulk traffic. The value must be between 0 and 63.
+    /// If the operating system does not allow it, qcp warns and carries on.
+    /// The same value is used at the remote end.
+    ///
+    /// **Caution:** This sets the socket option, but the QUIC library currently writes the whole
+    /// TOS / Traffic Class byte on every packet it sends, to carry its ECN marking.
+    /// On most platforms (including Linux and macOS) this replaces the DSCP, so packets leave unmarked.
+    #[arg(
+        long,
+        help_heading("Advanced network tuning"),
+        value_name = "code",
+        display_order(0),
+        value_parser=clap::value_parser!(u8).range(0..=i64::from(MAX_DSCP))
+    )]
+    pub dscp: u8,
+
+    /// _(Network wizards only!)_
+    /// Disables UDP generic segmentation offload (GSO). [default: false]
+    ///
+    /// Where the kernel and network driver support it (on Linux), qcp hands batches of outgoing packets to the
+    /// kernel in a single system call, which greatly reduces CPU usage when sending at high rates.
+    /// (In a loopback test on Linux, disabling it cut throughput by about 20%.)
+    /// (Receive offload, GRO, is used automatically where available.)
+    /// Some drivers, tunnels and packet inspection tools mishandle these batches; if sending is unexpectedly slow
+    /// or lossy, try this option.
+    /// The same setting is applied at the remote end.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Advanced network tuning"),
+        display_order(0)
+    )]
+    pub no_gso: bool,
+
+    /// _(Network wizards only!)_
+    /// Overrides the QUIC receive window, in bytes.
+    /// [default: 0, meaning it is computed from `rx` and `rtt`]
+    ///
+    /// This is specified in the same way as `rx`. It applies to data we receive; the remote sizes its own.
+    /// With `auto_window on`, the window may grow to 4x this.
+    #[arg(long, help_heading("Advanced network tuning"), value_name = "bytes", display_order(0), value_parser=clap::value_parser!(HumanU64))]
+    pub recv_window_override: HumanU64,
+
+    /// _(Network wizards only!)_
+    /// Overrides the QUIC stream receive window, in bytes.
+    /// [default: 0, meaning it is computed from the receive window]
+    ///
+    /// This is specified in the same way as `rx`. It applies to data we receive; the remote sizes its own.
+    /// As qcp sends one file per stream, this limits the data in flight for each file.
+    #[arg(long, help_heading("Advanced network tuning"), value_name = "bytes", display_order(0), value_parser=clap::value_parser!(HumanU64))]
+    pub stream_recv_window_override: HumanU64,
+
+    /// _(Experimental!)_
+    /// Monitors throughput during a transfer, to detect when the flow control window appears to be limiting it.
+    /// [default: off]
+    ///
+    /// With `advise`, qcp logs a suggestion when this happens.
+    /// With `on`, qcp additionally grows the receive window (up to 4x its configured size) when receiving data.
+    /// (The send window cannot be changed once the connection is established.)
+    #[arg(
+        long,
+        action,
+        value_name = "mode",
+        help_heading("Advanced network tuning"),
+        display_order(0)
+    )]
+    #[clap(value_enum)]
+    pub auto_window: AutoWindow,
+
     /// Uses the given UDP port or range on the local endpoint.
     /// This can be useful when there is a firewall between the endpoints.
     ///
@@ -119,7 +248,133 @@ pub struct Configuration {
     )]
     pub timeout: u16,
 
+    /// How many times to retry a failed connection to a remote host [default 0]
+    ///
+    /// Only connection failures are retried: for example, ssh failing to reach the host,
+    /// or the QUIC handshake timing out. Authentication failures are not retried.
+    /// The delay before each retry starts at 1 second and doubles each time, subject to
+    /// `retry_deadline`. This is not passed to the remote.
+    #[arg(long, value_name("N"), help_heading("Connection"), display_order(0))]
+    pub retries: u8,
+
+    /// The time limit for retrying a failed connection [seconds; default 60]
+    ///
+    /// This is measured from the start of the first connection attempt.
+    /// No retry is started after this time, and a retry which is still in progress is abandoned.
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub retry_deadline: u16,
+
+    /// Interval between QUIC keep-alive packets [seconds; default 5; 0 disables them]
+    ///
+    /// Keep-alives stop an idle connection from timing out, and keep NAT and firewall
+    /// state alive while (for example) a large file is being opened.
+    /// Shorten this if a NAT on the path forgets connections quickly; lengthen or disable it
+    /// on a metered link. The same interval is used at the remote end.
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub keepalive: u16,
+
+    /// How long the QUIC connection may go without hearing from the remote before it is abandoned
+    /// [seconds; default 0, which works it out from `timeout`, `keepalive` and `rtt`]
+    ///
+    /// This is how quickly a severed link is detected.
+    /// The automatic value is the largest of `timeout`, three `keepalive` intervals and ten round trips.
+    /// If you set it, it should be longer than `keepalive`, or an idle connection may time out.
+    /// The same timeout is used at the remote end.
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub idle_timeout: u16,
+
+    /// How long the server waits for the client's QUIC connection to arrive
+    /// [seconds; default 0, which works it out from `timeout` and `rtt`]
+    ///
+    /// The server starts waiting before the client has even heard where to connect, so it needs to allow
+    /// for that as well as the client's own `timeout`. The automatic value is `timeout` plus four round trips.
+    /// Lengthen this if the server gives up (timed out waiting for QUIC connection) on a high-latency link.
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub accept_timeout: u16,
+
+    /// Timeout for closing down the connection at the end of a transfer [seconds; default 10]
+    ///
+    /// This is separate from `timeout`, as after a large transfer there may be a lot of
+    /// data in flight to be acknowledged before the endpoints can exchange their final statistics.
+    /// If this expires, qcp warns and exits anyway; the statistics report may be incomplete.
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub closedown_timeout: u16,
+
+    /// Timeout for each protocol message on a stream [seconds; default 60; 0 disables it]
+    ///
+    /// This is separate from `timeout`. It protects against a remote which keeps the connection alive,
+    /// but stops responding on one of its streams. It must be long enough for the remote to finish writing
+    /// (and, with `--fsync`, syncing) a file before it responds.
+    /// The same timeout is used at the remote end.
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub protocol_timeout: u16,
+
+    /// Limits the number of files concurrently doing disk I/O [default: 0 = no limit]
+    ///
+    /// When several files are transferred at once, reading or writing them all simultaneously
+    /// can cause a spinning disk to thrash, so they may be quicker one (or a few) at a time.
+    /// Solid-state storage usually does not need a limit.
+    ///
+    /// This is separate from the number of network streams; jobs that are waiting for disk
+    /// access do not start their transfer until they have it.
+    /// The same limit is applied at the remote end.
+    #[arg(long, value_name("N"), help_heading("Jobs"), display_order(0))]
+    pub io_concurrency: u16,
+
+    /// Reserves disk space for the whole of each destination file before writing it [default: false]
+    ///
+    /// On filesystems such as ext4 and xfs, this reduces fragmentation and can improve write throughput for large files.
+    /// This is only supported on Linux. Where it is not supported, qcp carries on without it.
+    /// The same setting is applied at the remote end.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub preallocate: bool,
+
+    /// Reads large files being sent by mapping them into memory [default: false]
+    ///
+    /// This saves a copy of the data, and can reduce CPU usage when sending large files quickly.
+    /// It applies only to files of at least 1MiB; smaller files, and files which cannot be mapped, are read as usual.
+    /// A mapped file must not be truncated while it is being sent: if that happens, qcp will be killed by SIGBUS.
+    /// The same setting is applied at the remote end.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Jobs"),
+        display_order(0)
+    )]
+    pub mmap: bool,
+
     // CLIENT OPTIONS ==================================================================================
+    /// Compresses file data (with zstd) in transit. [default: off]
+    ///
+    /// This may speed up transfers of compressible data over slow links, at the cost of CPU time at both ends.
+    /// With `auto`, files whose names suggest they are already compressed (`.gz`, `.jpg`, `.zip` and so on)
+    /// are sent as they are.
+    ///
+    /// On the command line, `--compress` on its own means `--compress=on`.
+    /// Compression requires support from the remote; if it is too old, files are sent uncompressed.
+    #[arg(
+        long,
+        value_name("when"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("on"),
+        help_heading("Network tuning"),
+        display_order(20)
+    )]
+    #[clap(value_enum)]
+    pub compress: Compress,
+
     /// Forces use of a particular IP version when connecting to the remote. [default: any]
     ///
     // (see also [CliArgs::ipv4_alias__] and [CliArgs::ipv6_alias__])
@@ -164,6 +419,27 @@ pub struct Configuration {
     )]
     pub remote_port: PortRange,
 
+    /// Binds the local UDP endpoint to the given IP address. [default: none, meaning any address]
+    ///
+    /// On a multihomed host, this selects the address (and so the network) QUIC traffic uses.
+    /// It must be of the same address family as the remote address.
+    /// On a server, the client is told to connect to this address, unless `advertise_address` is set.
+    /// This is not passed to the remote.
+    #[arg(
+        long,
+        value_name("address"),
+        help_heading("Connection"),
+        display_order(0)
+    )]
+    pub bind_address: String,
+
+    /// Binds the local UDP endpoint to the given network interface (Linux only). [default: none]
+    ///
+    /// This uses `SO_BINDTODEVICE`, so QUIC traffic only uses that interface, whatever the routing table says.
+    /// This is not passed to the remote.
+    #[arg(long, value_name("name"), help_heading("Connection"), display_order(0))]
+    pub bind_interface: String,
+
     /// Specifies the time format to use when printing messages to the console or to file
     /// [default: local]
     #[arg(
@@ -176,9 +452,79 @@ pub struct Configuration {
     )]
     pub time_format: TimeFormat,
 
+    /// Whether to colour console output [default: auto]
+    ///
+    /// `auto` colours output if it is going to a terminal, unless the `NO_COLOR` environment variable is set.
+    #[arg(
+        long,
+        value_name("WHEN"),
+        help_heading("Output"),
+        next_line_help(true),
+        display_order(0)
+    )]
+    pub color: ColorMode,
+
+    /// The locale to use when formatting large numbers in statistics, e.g. `en`, `de`, `fr-CH`
+    /// [default: from the environment]
+    ///
+    /// If not set, this is taken from the `LC_ALL`, `LC_NUMERIC` or `LANG` environment variable,
+    /// falling back to `en`.
+    #[arg(long, value_name("name"), help_heading("Output"), display_order(0))]
+    pub locale: String,
+
+    /// Notifies you when a long transfer finishes. [default: false]
+    ///
+    /// If the run took longer than `notify_after`, qcp sends a terminal notification (OSC 9),
+    /// which many terminal emulators show as a desktop notification; and runs `notify_command`, if set.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Output"),
+        display_order(0)
+    )]
+    pub notify: bool,
+
+    /// How long a run must take for `notify` to apply, in seconds [default: 60]
+    #[arg(long, value_name("sec"), help_heading("Output"), display_order(0))]
+    pub notify_after: u16,
+
+    /// A command to run when `notify` applies, for example to send yourself a message. [default: none]
+    ///
+    /// The command is run by the shell. The environment variable `QCP_RESULT` is set to `success` or `failure`,
+    /// `QCP_BYTES` to the number of bytes transferred, and `QCP_ELAPSED` to the time taken in seconds.
+    #[arg(long, value_name("command"), help_heading("Output"), display_order(0))]
+    pub notify_command: String,
+
+    /// Also sends log messages to the system log (syslog, or the systemd journal) [default: false]
+    ///
+    /// This is most useful in the configuration file on a server, so that remote qcp activity
+    /// is recorded even though ssh does not capture its output.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Output"),
+        display_order(0)
+    )]
+    pub log_syslog: bool,
+
+    /// The facility to use when logging to the system log, e.g. `user`, `daemon`, `local0` [default: user]
+    #[arg(long, value_name("facility"), help_heading("Output"), display_order(0))]
+    pub syslog_facility: String,
+
+    /// The program name to use when logging to the system log [default: qcp]
+    #[arg(long, value_name("name"), help_heading("Output"), display_order(0))]
+    pub syslog_ident: String,
+
     /// Alternative ssh config file(s)
     ///
-    /// By default, qcp reads your user and system ssh config files to look for Hostname aliases.
+    /// By default, qcp reads your user and system ssh config files to look for Hostname aliases,
+    /// and the `User`, `Port` and `IdentityFile` settings which go with them.
     /// In some cases the logic in qcp may not read them successfully; this is an escape hatch,
     /// allowing you to specify one or more alternative files to read instead (which may be empty,
     /// nonexistent or /dev/null).
@@ -187,9 +533,197 @@ pub struct Configuration {
     /// On the command line, you can repeat `--ssh-config file` as many times as needed.
     #[arg(long, value_name("FILE"), help_heading("Connection"), display_order(0))]
     pub ssh_config: Vec<String>,
+
+    /// Reuses the connection to the remote host across runs of qcp, via a background process. [default: false]
+    ///
+    /// The first run starts a background qcp process which connects to the remote host, then listens
+    /// for further runs on a local socket. Later runs to the same host (with the same configuration)
+    /// hand their jobs to it, saving the time taken by ssh and the QUIC handshake.
+    /// The background process exits when it has been idle for `daemon_idle_timeout`.
+    ///
+    /// The background process cannot prompt for a password, so ssh must be able to log in without one
+    /// (for example, with an ssh agent). Runs which use standard input or output, or options such as
+    /// `--dry-run`, `--delete` or `--state-file`, connect directly as usual.
+    /// This is only supported on Unix platforms. Use `--no-daemon` to override it for one run.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Connection"),
+        display_order(0)
+    )]
+    pub daemon: bool,
+
+    /// How long the background process started by `daemon` waits for another run before exiting, in seconds
+    /// [default: 300]
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub daemon_idle_timeout: u16,
+
+    /// Looks up the remote host in DNS SRV records to find where to connect. [default: false]
+    ///
+    /// For a host `example.com`, this looks for a `_qcp._udp.example.com` SRV record. If there is one,
+    /// qcp connects (by ssh as well as by QUIC) to the host it names, and the remote qcp uses its port,
+    /// unless `remote_port` is set. Of several records, the one with the lowest priority value
+    /// (and of those, the greatest weight) is used.
+    /// If there is no such record, the host is looked up as usual.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Connection"),
+        display_order(0)
+    )]
+    pub srv: bool,
+
+    /// Looks up the remote host by asking this DNS server, instead of using the system resolver. [default: none]
+    ///
+    /// This is an IP address, optionally with a port (for example `192.0.2.53`, `192.0.2.53:5353`, `[2001:db8::53]:53`).
+    /// This is not passed to the remote.
+    #[arg(
+        long,
+        value_name("address"),
+        help_heading("Connection"),
+        display_order(0)
+    )]
+    pub dns_server: String,
+
+    /// How long to wait for the remote host to be looked up in DNS, in seconds.
+    /// [default: 0, which means as long as the resolver takes]
+    ///
+    /// The system resolver may take a long time to give up on a slow or unreachable DNS server,
+    /// during which qcp appears to hang. Setting this makes it fail promptly instead.
+    #[arg(long, value_name("sec"), help_heading("Connection"), display_order(0))]
+    pub dns_timeout: u16,
+
+    // SERVER OPTIONS ==================================================================================
+    /// The UDP port the client should connect to, if different from the port the server is bound to.
+    /// [default: 0, which means use the bound port]
+    ///
+    /// This is for when the server is behind NAT with a port forwarding rule
+    /// whose external port differs from the internal one.
+    /// It is usually set together with `remote_port`.
+    #[arg(long, value_name("port"), help_heading("Connection"), display_order(0))]
+    pub advertise_port: u16,
+
+    /// The address (or hostname) the client should connect to, if different from the address it used for ssh.
+    /// [default: none]
+    ///
+    /// This is for when the server is reachable via ssh at one address (for example, over a VPN or
+    /// a jump host) but its QUIC endpoint is reachable at another (for example, a NAT gateway).
+    #[arg(
+        long,
+        value_name("address"),
+        help_heading("Connection"),
+        display_order(0)
+    )]
+    pub advertise_address: String,
+
+    /// A TLS certificate to use for the QUIC connection, in PEM format [default: none]
+    ///
+    /// By default, qcp generates a new certificate and key for every session, and this is the secure choice.
+    /// This option is for testing and interoperability experiments.
+    /// It must be set together with `tls_key`, and is not passed to the remote.
+    /// When used by a server, the certificate must be valid for the server's hostname.
+    #[arg(long, value_name("FILE"), help_heading("Connection"), display_order(0))]
+    pub tls_cert: String,
+
+    /// The private key for `tls_cert`, in PEM format [default: none]
+    #[arg(long, value_name("FILE"), help_heading("Connection"), display_order(0))]
+    pub tls_key: String,
+
+    /// The session commands the server will permit. [default: all]
+    ///
+    /// This allows an operator to restrict what clients may do, for example to make a server
+    /// download-only with `allow get`.
+    /// Requests for any other command are rejected.
+    ///
+    /// The commands are:
+    /// * `get`: retrieve files.
+    /// * `put`: send files, including creating directories and symbolic links when sending a directory tree.
+    /// * `delete`: delete files, for `--delete` and `--remove-source-files`.
+    ///   This is separate from `put` so that a server can accept files without letting clients remove any.
+    ///
+    /// This setting applies on the server side, so it is normally set in the server's system configuration file.
+    /// On the command line, separate multiple values with commas: `--allow get,put`
+    #[arg(
+        long,
+        value_name("commands"),
+        value_parser=clap::value_parser!(CommandSet),
+        help_heading("Server"),
+        display_order(0)
+    )]
+    pub allow: CommandSet,
+
+    /// Makes the server download-only: clients may retrieve files, but not send them. [default: false]
+    ///
+    /// This is the same as `allow get`, whatever `allow` says.
+    /// In particular, clients may not have files removed once they have been retrieved (`--remove-source-files`).
+    /// It is normally set in the server's system configuration file.
+    #[arg(
+        long,
+        value_name("bool"),
+        num_args(0..=1),
+        require_equals(true),
+        default_missing_value("true"),
+        help_heading("Server"),
+        display_order(0)
+    )]
+    pub read_only: bool,
+
+    /// The largest file the server will send or receive, in bytes. [default: 0, which means no limit]
+    ///
+    /// This may be specified directly as a number, or as an SI quantity like `10G`.
+    /// Requests for larger files are refused before any data is transferred.
+    /// When a client appends to a file, the limit applies to the size of the file afterwards.
+    /// A file sent from standard input is refused, as its size is not known in advance.
+    ///
+    /// This setting applies on the server side, so it is normally set in the server's system configuration file.
+    #[arg(long, help_heading("Server"), display_order(0), value_name="bytes", value_parser=clap::value_parser!(HumanU64))]
+    pub max_file_size: HumanU64,
+
+    /// Restricts the files the server will read or write to those within these directories. [default: no restriction]
+    ///
+    /// Requests for anything outside them are rejected as permission denied.
+    /// Symbolic links and `..` are resolved before checking, so a link to somewhere else does not escape the restriction.
+    /// Each directory must exist when the server starts.
+    ///
+    /// This setting applies on the server side, so it is normally set in the server's system configuration file.
+    /// On the command line, you can repeat `--allow-path DIR` as many times as needed.
+    #[arg(long, value_name("DIR"), help_heading("Server"), display_order(0))]
+    pub allow_path: Vec<String>,
 }
 
 impl Configuration {
+    /// Fields which take free-form strings.
+    /// In configuration files, `${VAR}` in these is replaced by the value of the environment variable.
+    pub(crate) const STRING_FIELDS: &[&str] = &[
+        "ssh",
+        "ssh_options",
+        "ssh_config",
+        "bind_address",
+        "bind_interface",
+        "dns_server",
+        "locale",
+        "notify_command",
+        "syslog_facility",
+        "syslog_ident",
+        "advertise_address",
+        "tls_cert",
+        "tls_key",
+        "allow_path",
+    ];
+    /// Fields which take a list of values.
+    /// In configuration files, these are lists even when only one value is given.
+    pub(crate) const LIST_FIELDS: &[&str] = &["ssh_options", "ssh_config", "allow_path"];
+    /// Fields which take paths to local files.
+    /// In configuration files, a leading `~` in these is replaced by the user's home directory.
+    pub(crate) const PATH_FIELDS: &[&str] =
+        &["ssh", "ssh_config", "tls_cert", "tls_key", "allow_path"];
+
     /// Computes the theoretical bandwidth-delay product for outbound data
     #[must_use]
     #[allow(clippy::cast_possible_truncation)]
@@ -215,6 +749,37 @@ impl Configuration {
             tx => tx,
         }
     }
+    /// Accessor for `bwlimit`; 0 means no limit
+    #[must_use]
+    pub fn bwlimit(&self) -> u64 {
+        *self.bwlimit
+    }
+    /// The rate at which we expect to send data, taking account of `bwlimit`
+    #[must_use]
+    pub fn effective_tx(&self) -> u64 {
+        match self.bwlimit() {
+            0 => self.tx(),
+            limit => std::cmp::min(limit, self.tx()),
+        }
+    }
+    /// The rate at which we expect to receive data, taking account of the remote observing our `bwlimit`
+    #[must_use]
+    pub fn effective_rx(&self) -> u64 {
+        match self.bwlimit() {
+            0 => self.rx(),
+            limit => std::cmp::min(limit, self.rx()),
+        }
+    }
+    /// The session commands the server permits, taking account of `read_only`
+    #[must_use]
+    pub fn allowed_commands(&self) -> Vec<CommandType> {
+        self.allow
+            .as_slice()
+            .iter()
+            .copied()
+            .filter(|c| !self.read_only || *c == CommandType::Get)
+            .collect()
+    }
     /// RTT accessor as Duration
     #[must_use]
     pub fn rtt_duration(&self) -> Duration {
@@ -237,8 +802,29 @@ impl Configuration {
     /// QUIC receive window
     #[must_use]
     pub fn recv_window(&self) -> u64 {
-        // The theoretical in-flight limit appears to be sufficient
-        self.bandwidth_delay_product_rx()
+        match *self.recv_window_override {
+            // The theoretical in-flight limit appears to be sufficient
+            0 => self.bandwidth_delay_product_rx(),
+            w => w,
+        }
+    }
+
+    /// QUIC stream receive window
+    #[must_use]
+    pub fn stream_recv_window(&self) -> u64 {
+        match *self.stream_recv_window_override {
+            // The stream receive window cannot be changed on the fly, but the connection receive window can.
+            // So if we might grow the connection window, we give the stream window room to grow.
+            0 if self.auto_window == AutoWindow::On => self.max_recv_window(),
+            0 => self.recv_window(),
+            w => w,
+        }
+    }
+
+    /// The largest QUIC receive window we will grow to, if `auto_window` is on
+    #[must_use]
+    pub fn max_recv_window(&self) -> u64 {
+        4 * self.recv_window()
     }
 
     /// QUIC send window
@@ -254,6 +840,102 @@ impl Configuration {
         Duration::from_secs(self.timeout.into())
     }
 
+    /// Accessor for `retry_deadline`, as a Duration
+    #[must_use]
+    pub fn retry_deadline_duration(&self) -> Duration {
+        Duration::from_secs(self.retry_deadline.into())
+    }
+
+    /// Accessor for `protocol_timeout`, as a Duration (zero means no timeout)
+    #[must_use]
+    pub fn protocol_timeout_duration(&self) -> Duration {
+        Duration::from_secs(self.protocol_timeout.into())
+    }
+
+    /// The QUIC idle timeout, taking account of the automatic setting
+    #[must_use]
+    pub fn idle_timeout_duration(&self) -> Duration {
+        if self.idle_timeout != 0 {
+            return Duration::from_secs(self.idle_timeout.into());
+        }
+        let keepalive = self.keepalive_interval().unwrap_or_default();
+        self.timeout_duration()
+            .max(keepalive * 3)
+            .max(self.rtt_duration() * 10)
+    }
+
+    /// How long the server waits for the QUIC connection, taking account of the automatic setting
+    #[must_use]
+    pub fn accept_timeout_duration(&self) -> Duration {
+        if self.accept_timeout != 0 {
+            return Duration::from_secs(self.accept_timeout.into());
+        }
+        self.timeout_duration() + self.rtt_duration() * 4
+    }
+
+    /// Accessor for `bind_address`, parsed; None if it is not set
+    /// # Errors
+    /// If `bind_address` is not a valid IP address
+    pub fn bind_ip(&self) -> anyhow::Result<Option<IpAddr>> {
+        if self.bind_address.is_empty() {
+            return Ok(None);
+        }
+        self.bind_address
+            .parse()
+            .map(Some)
+            .map_err(|e| anyhow::anyhow!("invalid bind_address {}: {e}", self.bind_address))
+    }
+
+    /// How to look up host names, according to `dns_server` and `dns_timeout`
+    /// # Errors
+    /// If `dns_server` is not a valid IP address, with or without a port
+    pub fn resolver(&self) -> anyhow::Result<Resolver> {
+        let timeout = Duration::from_secs(self.dns_timeout.into());
+        if self.dns_server.is_empty() {
+            return Ok(Resolver::new(None, timeout));
+        }
+        let server = self
+            .dns_server
+            .parse()
+            .or_else(|_| self.dns_server.parse().map(|ip| SocketAddr::new(ip, 53)))
+            .map_err(|e| anyhow::anyhow!("invalid dns_server {}: {e}", self.dns_server))?;
+        Ok(Resolver::new(Some(server), timeout))
+    }
+
+    /// Accessor for `locale`, parsed, or detected from the environment if it is not set
+    /// # Errors
+    /// If `locale` is not a locale name that we know
+    pub fn number_locale(&self) -> anyhow::Result<Locale> {
+        if self.locale.is_empty() {
+            return Ok(["LC_ALL", "LC_NUMERIC", "LANG"]
+                .into_iter()
+                .filter_map(|v| std::env::var(v).ok())
+                .find(|v| !v.is_empty())
+                .and_then(|v| posix_locale(&v))
+                .unwrap_or(Locale::en));
+        }
+        Locale::from_name(&self.locale)
+            .map_err(|_| anyhow::anyhow!("unknown locale {}", self.locale))
+    }
+
+    /// Accessor for `notify_after`, as a Duration
+    #[must_use]
+    pub fn notify_after_duration(&self) -> Duration {
+        Duration::from_secs(self.notify_after.into())
+    }
+
+    /// Accessor for `closedown_timeout`, as a Duration
+    #[must_use]
+    pub fn closedown_timeout_duration(&self) -> Duration {
+        Duration::from_secs(self.closedown_timeout.into())
+    }
+
+    /// Accessor for `keepalive`, as a Duration; None if keep-alives are disabled
+    #[must_use]
+    pub fn keepalive_interval(&self) -> Option<Duration> {
+        Some(Duration::from_secs(self.keepalive.into())).filter(|d| !d.is_zero())
+    }
+
     /// Formats the transport-related options for display
     #[must_use]
     pub fn format_transport_config(&self) -> String {
@@ -274,6 +956,14 @@ impl Configuration {
     }
 }
 
+/// Converts a POSIX locale string such as `de_CH.UTF-8@euro` to the nearest locale we know
+fn posix_locale(value: &str) -> Option<Locale> {
+    let name = value.split(['.', '@']).next().unwrap_or_default();
+    Locale::from_name(name)
+        .or_else(|_| Locale::from_name(name.split('_').next().unwrap_or_default()))
+        .ok()
+}
+
 impl Default for Configuration {
     /// **(Unusual!)**
     /// Returns qcp's hard-wired configuration defaults.
@@ -282,26 +972,77 @@ impl Default for Configuration {
             // Transport
             rx: 12_500_000.into(),
             tx: 0.into(),
+            bwlimit: 0.into(),
             rtt: 300,
             congestion: CongestionControllerType::Cubic,
             initial_congestion_window: 0,
+            persistent_congestion_threshold: 0,
+            recv_window_override: 0.into(),
+            stream_recv_window_override: 0.into(),
+            udp_payload_size: 0,
+            dscp: 0,
+            no_gso: false,
+            auto_window: AutoWindow::Off,
             port: PortRange::default(),
             timeout: 5,
+            retries: 0,
+            retry_deadline: 60,
+            closedown_timeout: 10,
+            protocol_timeout: 60,
+            keepalive: 5,
+            idle_timeout: 0,
+            accept_timeout: 0,
+            io_concurrency: 0,
+            preallocate: false,
+            mmap: false,
 
             // Client
+            compress: Compress::Off,
             address_family: AddressFamily::Any,
             ssh: "ssh".into(),
             ssh_options: vec![],
             remote_port: PortRange::default(),
+            bind_address: String::new(),
+            bind_interface: String::new(),
             time_format: TimeFormat::Local,
+            color: ColorMode::Auto,
+            locale: String::new(),
+            notify: false,
+            notify_after: 60,
+            notify_command: String::new(),
+            log_syslog: false,
+            syslog_facility: "user".into(),
+            syslog_ident: "qcp".into(),
             ssh_config: Vec::new(),
+            daemon: false,
+            daemon_idle_timeout: 300,
+            srv: false,
+            dns_server: String::new(),
+            dns_timeout: 0,
+
+            // Server
+            advertise_port: 0,
+            advertise_address: String::new(),
+            tls_cert: String::new(),
+            tls_key: String::new(),
+            allow: CommandSet::all(),
+            read_only: false,
+            max_file_size: 0.into(),
+            allow_path: Vec::new(),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    use super::Configuration;
+    use std::time::Duration;
+
+    use num_format::Locale;
+
+    use struct_field_names_as_array::FieldNamesAsSlice as _;
+
+    use super::{posix_locale, Configuration};
+    use crate::{protocol::session::CommandType, transport::AutoWindow, util::Resolver};
 
     #[test]
     fn flattened() {
@@ -311,4 +1052,152 @@ mod test {
         assert!(!d.has_key("bw"));
         assert!(d.has_key("rtt"));
     }
+
+    #[test]
+    fn expandable_fields() {
+        for field in Configuration::STRING_FIELDS {
+            assert!(
+                Configuration::FIELD_NAMES_AS_SLICE.contains(field),
+                "{field}"
+            );
+        }
+        for field in Configuration::PATH_FIELDS {
+            assert!(Configuration::STRING_FIELDS.contains(field), "{field}");
+        }
+        let defaults = serde_json::to_value(Configuration::default()).unwrap();
+        for field in Configuration::LIST_FIELDS {
+            assert!(defaults[field].is_array(), "{field}");
+        }
+    }
+
+    #[test]
+    fn window_overrides() {
+        let computed = Configuration::default();
+        assert_eq!(computed.stream_recv_window(), computed.recv_window());
+
+        let config = Configuration {
+            recv_window_override: 1_000_000.into(),
+            ..Default::default()
+        };
+        assert_eq!(config.recv_window(), 1_000_000);
+        assert_eq!(config.stream_recv_window(), 1_000_000);
+
+        let config = Configuration {
+            stream_recv_window_override: 2_000_000.into(),
+            auto_window: AutoWindow::On,
+            ..Default::default()
+        };
+        assert_eq!(config.recv_window(), computed.recv_window());
+        assert_eq!(config.stream_recv_window(), 2_000_000);
+    }
+
+    #[test]
+    fn idle_timeout() {
+        let config = |idle_timeout, keepalive, rtt| Configuration {
+            rtt,
+            keepalive,
+            idle_timeout,
+            ..Default::default()
+        };
+        assert_eq!(
+            Configuration::default().idle_timeout_duration(),
+            Duration::from_secs(15)
+        );
+        assert_eq!(
+            config(0, 0, 300).idle_timeout_duration(),
+            Duration::from_secs(5)
+        );
+        assert_eq!(
+            config(0, 1, 2000).idle_timeout_duration(),
+            Duration::from_secs(20)
+        );
+        assert_eq!(
+            config(30, 5, 300).idle_timeout_duration(),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn accept_timeout() {
+        let config = |accept_timeout, timeout, rtt| Configuration {
+            rtt,
+            timeout,
+            accept_timeout,
+            ..Default::default()
+        };
+        assert_eq!(
+            config(0, 5, 300).accept_timeout_duration(),
+            Duration::from_millis(6200)
+        );
+        assert_eq!(
+            config(0, 5, 3000).accept_timeout_duration(),
+            Duration::from_secs(17)
+        );
+        assert_eq!(
+            config(30, 5, 3000).accept_timeout_duration(),
+            Duration::from_secs(30)
+        );
+    }
+
+    #[test]
+    fn read_only() {
+        let config = |allow: &str, read_only| Configuration {
+            allow: allow.parse().unwrap(),
+            read_only,
+            ..Default::default()
+        };
+        assert_eq!(
+            config("get,put", false).allowed_commands(),
+            [CommandType::Get, CommandType::Put]
+        );
+        assert_eq!(
+            config("get,put", true).allowed_commands(),
+            [CommandType::Get]
+        );
+        assert!(config("put", true).allowed_commands().is_empty());
+        assert_eq!(
+            config("get,put,delete", false).allowed_commands(),
+            CommandType::ALL
+        );
+        assert_eq!(
+            config("get,put,delete", true).allowed_commands(),
+            [CommandType::Get]
+        );
+    }
+
+    #[test]
+    fn locale() {
+        let config = |locale: &str| Configuration {
+            locale: locale.into(),
+            ..Default::default()
+        };
+        assert_eq!(config("de").number_locale().unwrap(), Locale::de);
+        assert_eq!(config("fr-CH").number_locale().unwrap(), Locale::fr_CH);
+        assert!(config("xx-nonsense").number_locale().is_err());
+
+        assert_eq!(posix_locale("de_CH.UTF-8"), Some(Locale::de_CH));
+        assert_eq!(posix_locale("fr_FR@euro"), Some(Locale::fr));
+        assert_eq!(posix_locale("C"), None);
+    }
+
+    #[test]
+    fn resolver() {
+        let config = |server: &str| Configuration {
+            dns_server: server.into(),
+            dns_timeout: 5,
+            ..Default::default()
+        };
+        let timeout = Duration::from_secs(5);
+        assert_eq!(config("").resolver().unwrap(), Resolver::new(None, timeout));
+        for (server, expected) in [
+            ("192.0.2.53", "192.0.2.53:53"),
+            ("192.0.2.53:5353", "192.0.2.53:5353"),
+            ("2001:db8::53", "[2001:db8::53]:53"),
+            ("[2001:db8::53]:5353", "[2001:db8::53]:5353"),
+        ] {
+            let expected = Resolver::new(Some(expected.parse().unwrap()), timeout);
+            assert_eq!(config(server).resolver().unwrap(), expected, "{server}");
+        }
+        assert!(config("dns.example.com").resolver().is_err());
+    }
 }
@@ -0,0 +1,247 @@
+//! Config file parsing, TOML and YAML
+// (c) 2024 Ross Younger
+
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context as _, Result};
+use figment::{
+    value::{Dict, Map, Value},
+    Metadata, Profile, Provider,
+};
+
+use super::ssh::{configuration_field_name, evaluate_host_match};
+
+/// The key of the table containing per-host settings
+const HOST_KEY: &str = "host";
+
+/// The structured configuration file formats we support
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Format {
+    /// [TOML](https://toml.io/)
+    Toml,
+    /// [YAML](https://yaml.org/)
+    Yaml,
+}
+
+impl Format {
+    /// Determines the format of a configuration file from its extension.
+    ///
+    /// Returns `None` if this is not a structured file, i.e. it should be treated as ssh-style.
+    pub(crate) fn for_path(path: &Path) -> Option<Self> {
+        match path.extension()?.to_str()? {
+            "toml" => Some(Self::Toml),
+            "yaml" | "yml" => Some(Self::Yaml),
+            _ => None,
+        }
+    }
+
+    /// The extensions we recognise, in the order we read alternative files (i.e. lowest precedence first)
+    pub(crate) const EXTENSIONS: &[&str] = &["yml", "yaml", "toml"];
+}
+
+/// The result of parsing a structured configuration file, with a particular host in mind.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct StructuredConfig {
+    /// The host we were interested in. If None, only the top-level settings and `*` tables apply.
+    host: Option<String>,
+    /// The file we read, if any
+    source: Option<PathBuf>,
+    /// Output data, keyed by field names in [`super::Configuration`] where they match
+    data: Dict,
+    /// All the patterns in `host` tables, whether or not they matched
+    host_patterns: Vec<String>,
+}
+
+impl StructuredConfig {
+    /// Reads and interprets a file with a given hostname in mind.
+    pub(crate) fn read(path: &Path, format: Format, host: Option<&str>) -> Result<Self> {
+        let text =
+            std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+        let mut result = Self::parse(&text, format, host)?;
+        result.source = Some(path.to_owned());
+        Ok(result)
+    }
+
+    /// Interprets the contents of a file with a given hostname in mind.
+    ///
+    /// Settings in the first matching `host` table take precedence, then any later matching tables,
+    /// then the top-level settings.
+    fn parse(text: &str, format: Format, host: Option<&str>) -> Result<Self> {
+        let table: toml::Table = match format {
+            Format::Toml => toml::from_str(text)?,
+            Format::Yaml => serde_yaml::from_str(text)?,
+        };
+        let mut data = Dict::new();
+        let mut host_patterns = Vec::new();
+        let mut host_tables = None;
+        for (key, value) in table {
+            if key == HOST_KEY {
+                host_tables = Some(value);
+                continue;
+            }
+            let _ = data.insert(configuration_field_name(&key), convert(&key, &value)?);
+        }
+
+        if let Some(host_tables) = host_tables {
+            let toml::Value::Table(host_tables) = host_tables else {
+                anyhow::bail!("`{HOST_KEY}` must be a table of host patterns");
+            };
+            for pattern in host_tables.keys().flat_map(|k| k.split_whitespace()) {
+                if !host_patterns.iter().any(|p| p == pattern) {
+                    host_patterns.push(pattern.to_string());
+                }
+            }
+            // Apply matching tables in reverse order, so the first match wins
+            for (patterns, settings) in host_tables.iter().rev() {
+                let patterns = patterns
+                    .split_whitespace()
+                    .map(str::to_string)
+                    .collect::<Vec<_>>();
+                if !evaluate_host_match(host, &patterns) {
+                    continue;
+                }
+                let toml::Value::Table(settings) = settings else {
+                    anyhow::bail!("`{HOST_KEY}.{}` must be a table", patterns.join(" "));
+                };
+                for (key, value) in settings {
+                    let _ = data.insert(configuration_field_name(key), convert(key, value)?);
+                }
+            }
+        }
+        Ok(Self {
+            host: host.map(std::borrow::ToOwned::to_owned),
+            source: None,
+            data,
+            host_patterns,
+        })
+    }
+
+    pub(crate) fn host_patterns(&self) -> &[String] {
+        &self.host_patterns
+    }
+}
+
+/// Converts a value into the form produced by the ssh-style parser, so both are interpreted identically.
+/// That is, scalars become strings and arrays become arrays of strings.
+fn convert(key: &str, value: &toml::Value) -> Result<Value> {
+    use toml::Value as V;
+    Ok(match value {
+        V::String(s) => s.as_str().into(),
+        V::Integer(i) => i.to_string().into(),
+        V::Float(f) => f.to_string().into(),
+        V::Boolean(b) => b.to_string().into(),
+        V::Datetime(d) => d.to_string().into(),
+        V::Array(a) => a
+            .iter()
+            .map(|v| convert(key, v))
+            .collect::<Result<Vec<_>>>()?
+            .into(),
+        V::Table(_) => anyhow::bail!("`{key}`: nested tables are not supported here"),
+    })
+}
+
+impl Provider for StructuredConfig {
+    fn metadata(&self) -> Metadata {
+        match &self.source {
+            Some(path) => Metadata::from("configuration file", path.as_path()),
+            None => Metadata::named("configuration file"),
+        }
+    }
+
+    fn data(&self) -> std::result::Result<Map<Profile, Dict>, figment::Error> {
+        let profile = self.host.as_deref().map_or(Profile::Default, Profile::new);
+        Ok(Map::from([(profile, self.data.clone())]))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{Format, StructuredConfig};
+    use crate::config::{Configuration, Manager};
+    use crate::util::{make_test_tempfile, PortRange};
+
+    const TOML: &str = r#"
+        rx = "10M"
+        rtt = 300
+        ssh-options = ["-o", "Foo=bar"]
+
+        [host."*.example.com other.example.org"]
+        rx = 1000000
+        RemotePort = "50000-50100"
+
+        [host."*"]
+        rtt = 150
+        rx = "5M"
+    "#;
+
+    const YAML: &str = r#"
+        rx: 10M
+        rtt: 300
+        ssh_options: ["-o", "Foo=bar"]
+        host:
+          "*.example.com other.example.org":
+            rx: 1000000
+            remote_port: 50000-50100
+          "*":
+            rtt: 150
+            rx: 5M
+    "#;
+
+    #[test]
+    fn formats() {
+        assert_eq!(Format::for_path(Path::new("a/b.toml")), Some(Format::Toml));
+        assert_eq!(Format::for_path(Path::new(".qcp.yml")), Some(Format::Yaml));
+        assert_eq!(Format::for_path(Path::new("/etc/qcp.conf")), None);
+        assert_eq!(Format::for_path(Path::new("qcp")), None);
+    }
+
+    fn get(text: &str, format: Format, host: Option<&str>) -> Configuration {
+        let mut mgr = Manager::without_files(host);
+        mgr.merge_provider(StructuredConfig::parse(text, format, host).unwrap());
+        mgr.get().unwrap()
+    }
+
+    #[test]
+    fn per_host() {
+        for (text, format) in [(TOML, Format::Toml), (YAML, Format::Yaml)] {
+            let c = get(text, format, Some("foo.example.com"));
+            assert_eq!(c.rx(), 1_000_000);
+            assert_eq!(c.rtt, 150);
+            assert_eq!(
+                c.remote_port,
+                PortRange {
+                    begin: 50000,
+                    end: 50100
+                }
+            );
+            assert_eq!(c.ssh_options, ["-o", "Foo=bar"]);
+
+            let c = get(text, format, Some("example.net"));
+            assert_eq!(c.rx(), 5_000_000);
+
+            // `*` matches even when there is no host
+            let c = get(text, format, None);
+            assert_eq!(c.rx(), 5_000_000);
+            let c = get("rx = 42\n[host.foo]\nrx = 43", Format::Toml, None);
+            assert_eq!(c.rx(), 42);
+        }
+    }
+
+    #[test]
+    fn errors() {
+        assert!(StructuredConfig::parse("rx = { a = 1 }", Format::Toml, None).is_err());
+        assert!(StructuredConfig::parse("host = 3", Format::Toml, Some("h")).is_err());
+        assert!(StructuredConfig::parse("rx = ", Format::Toml, None).is_err());
+    }
+
+    #[test]
+    fn manager_reads_file() {
+        let (path, _tempdir) = make_test_tempfile(TOML, "test.toml");
+        let mut mgr = Manager::without_files(Some("x.example.com"));
+        mgr.merge_config_file(&path, Some("x.example.com"), false);
+        let c = mgr.get::<Configuration>().unwrap();
+        assert_eq!(c.rx(), 1_000_000);
+    }
+}
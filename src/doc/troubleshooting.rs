@@ -14,6 +14,16 @@
 //!
 //! Note that this variable setting applies to the local machine, not the remote. If you arrange to set it on the remote, the output will come back over the ssh channel; **this may impact performance**.
 //!
+//! ### Decrypting captured packets
+//!
+//! If the `SSLKEYLOGFILE` environment variable is set, qcp appends the TLS session keys to the file it names.
+//! Wireshark can use this file to decrypt a packet capture of the QUIC connection, which helps when
+//! investigating handshake problems or packet loss.
+//! As with `RUST_LOG`, this applies to the machine where the variable is set.
+//!
+//! **Anybody who can read the key log file can decrypt the session.**
+//! Only use this for debugging, and never in production.
+//!
 //! ### You can't ssh to the remote machine
 //!
 //! Sorry, that's a prerequisite. Get that working first, then come back to qcp.
@@ -28,6 +38,13 @@
 //! * Is the remote host behind NAT? Sorry, NAT traversal is not currently supported.
 //!   At best, you might be able to open up a small range of UDP ports on the NAT gateway which are directly forwarded to the target machine.
 //!   Use the `--remote-port` option to tell it which.
+//!   * If the gateway forwards a different external port to the one the server binds to, set `AdvertisePort` to
+//!     the external port. For example, if the gateway forwards UDP port 40000 to port 50000 on the server,
+//!     set `RemotePort 50000` and `AdvertisePort 40000`.
+//!   * If the control channel reaches the server by a different route (for example, a VPN or jump host),
+//!     set `AdvertiseAddress` to the gateway's external address or hostname.
+//!   * These may be set in the server's configuration file, or in a `Host` block in the client's configuration file
+//!     (in which case the client passes them to the server).
 //! * Are outbound UDP packets from the initiator firewalled?
 //!   You will need to open up some outbound ports; use the `--port` option to tell qcp which.
 //!
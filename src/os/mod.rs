@@ -12,6 +12,7 @@ pub trait SocketOptions {
     /// This takes account of kernel behaviour: the internal buffer
     /// allocation is _double_ the size you set with setsockopt,
     /// and getsockopt returns the doubled value.
+    /// macOS and the BSDs do not do this, so the value is returned as is.
     fn get_sendbuf(&self) -> Result<usize>;
     /// Wrapper for setsockopt `SO_SNDBUF`
     fn set_sendbuf(&mut self, size: usize) -> Result<()>;
@@ -23,6 +24,7 @@ pub trait SocketOptions {
     /// This takes account of kernel behaviour: the internal buffer
     /// allocation is _double_ the size you set with setsockopt,
     /// and getsockopt returns the doubled value.
+    /// macOS and the BSDs do not do this, so the value is returned as is.
     fn get_recvbuf(&self) -> Result<usize>;
     /// Wrapper for setsockopt `SO_RCVBUF`
     fn set_recvbuf(&mut self, size: usize) -> Result<()>;
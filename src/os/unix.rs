@@ -4,9 +4,13 @@
 use crate::config::BASE_CONFIG_FILENAME;
 
 use super::SocketOptions;
-use anyhow::Result;
+use anyhow::{Context as _, Result};
 use nix::sys::socket::{self, sockopt};
-use std::{net::UdpSocket, path::PathBuf};
+use std::{
+    net::UdpSocket,
+    path::{Path, PathBuf},
+};
+use tracing::{debug, info, warn};
 
 /// Is this platform BSDish?
 fn bsdish() -> bool {
@@ -18,13 +22,19 @@ fn bsdish() -> bool {
     ))
 }
 
+/// How much the kernel inflates socket buffer sizes.
+///
+/// Linux (and so Android) allocates double the size set with setsockopt, and getsockopt reports the doubled value.
+/// macOS and the BSDs allocate and report the size as set.
+const KERNEL_BUFFER_FACTOR: usize = if cfg!(any(target_os = "linux", target_os = "android")) {
+    2
+} else {
+    1
+};
+
 impl SocketOptions for UdpSocket {
     fn get_sendbuf(&self) -> Result<usize> {
-        #[cfg(target_os = "linux")]
-        let divisor = 2;
-        #[cfg(not(target_os = "linux"))]
-        let divisor = 1;
-        Ok(socket::getsockopt(self, sockopt::SndBuf)? / divisor)
+        Ok(socket::getsockopt(self, sockopt::SndBuf)? / KERNEL_BUFFER_FACTOR)
     }
 
     fn set_sendbuf(&mut self, size: usize) -> Result<()> {
@@ -38,11 +48,7 @@ impl SocketOptions for UdpSocket {
     }
 
     fn get_recvbuf(&self) -> Result<usize> {
-        #[cfg(target_os = "linux")]
-        let divisor = 2;
-        #[cfg(not(target_os = "linux"))]
-        let divisor = 1;
-        Ok(socket::getsockopt(self, sockopt::RcvBuf)? / divisor)
+        Ok(socket::getsockopt(self, sockopt::RcvBuf)? / KERNEL_BUFFER_FACTOR)
     }
 
     fn set_recvbuf(&mut self, size: usize) -> Result<()> {
@@ -59,25 +65,25 @@ impl SocketOptions for UdpSocket {
 /// Outputs helpful information for the sysadmin
 pub(crate) fn print_udp_buffer_size_help_message(rmem: u64, wmem: u64) {
     println!(
-        r#"For best performance, it is necessary to set the kernel UDP buffer size limits.
+        r"For best performance, it is necessary to set the kernel UDP buffer size limits.
 This program attempts to automatically set buffer sizes for itself,
-but doing so requires elevated privileges."#
+but doing so requires elevated privileges."
     );
 
     if bsdish() {
         // Received wisdom about BSD kernels leads me to recommend 115% of the max. I'm not sure this is necessary.
         let size = std::cmp::max(rmem, wmem) * 115 / 100;
         println!(
-            r#"
+            r"
 To set the kernel limits immediately, run the following command as root:
     sysctl -w kern.ipc.maxsockbuf={size}
 To have this setting apply at boot, add this line to /etc/sysctl.conf:
     kern.ipc.maxsockbuf={size}
-            "#
+            "
         );
     } else {
         println!(
-            r#"
+            r"
 To set the kernel limits immediately, run the following command as root:
     sysctl -w net.core.rmem_max={rmem} -w net.core.wmem_max={wmem}
 
@@ -85,12 +91,85 @@ To have this setting apply at boot, on most Linux distributions you
 can create a file /etc/sysctl.d/20-qcp.conf containing:
     net.core.rmem_max={rmem}
     net.core.wmem_max={wmem}
-"#
+"
         );
     }
     // TODO add other OS-specific notes here
 }
 
+/// The Linux sysctl files holding the maximum UDP receive and send buffer sizes
+const RMEM_MAX: &str = "/proc/sys/net/core/rmem_max";
+const WMEM_MAX: &str = "/proc/sys/net/core/wmem_max";
+
+/// Kernel settings changed by [`raise_udp_buffer_limits`].
+/// The previous values are restored when this is dropped.
+#[derive(Debug, Default)]
+#[must_use]
+pub struct SysctlGuard {
+    restore: Vec<(PathBuf, u64)>,
+}
+
+impl Drop for SysctlGuard {
+    fn drop(&mut self) {
+        for (path, value) in self.restore.iter().rev() {
+            match write_sysctl(path, *value) {
+                Ok(()) => info!("Restored {} to {value}", path.display()),
+                Err(e) => warn!("Failed to restore {} to {value}: {e}", path.display()),
+            }
+        }
+    }
+}
+
+fn read_sysctl(path: &Path) -> Result<u64> {
+    let text =
+        std::fs::read_to_string(path).with_context(|| format!("reading {}", path.display()))?;
+    text.trim()
+        .parse()
+        .with_context(|| format!("parsing {}", path.display()))
+}
+
+fn write_sysctl(path: &Path, value: u64) -> Result<()> {
+    std::fs::write(path, format!("{value}\n"))
+        .with_context(|| format!("writing {}", path.display()))
+}
+
+/// Raises the settings in the given files to at least the wanted values
+fn raise_sysctls(settings: &[(&Path, u64)]) -> Result<SysctlGuard> {
+    let mut guard = SysctlGuard::default();
+    for (path, wanted) in settings {
+        let current = read_sysctl(path)?;
+        if current >= *wanted {
+            debug!("{} is {current}, no change needed", path.display());
+            continue;
+        }
+        // If this fails, dropping the guard restores anything we already changed
+        write_sysctl(path, *wanted)?;
+        info!("Raised {} from {current} to {wanted}", path.display());
+        guard.restore.push(((*path).to_owned(), current));
+    }
+    Ok(guard)
+}
+
+/// Raises the kernel UDP buffer size limits, if necessary, to allow buffers of the given sizes.
+///
+/// This requires root, and is only supported on Linux.
+/// The previous limits are restored when the returned guard is dropped.
+///
+/// # Errors
+/// If not running as root (the error message contains the commands to run instead),
+/// on other platforms, or if the kernel settings could not be read or written.
+pub fn raise_udp_buffer_limits(rmem: u64, wmem: u64) -> Result<SysctlGuard> {
+    anyhow::ensure!(
+        cfg!(any(target_os = "linux", target_os = "android")),
+        "--auto-sysctl is only supported on Linux; for help setting kernel buffer limits, run `qcp --help-buffers`"
+    );
+    anyhow::ensure!(
+        nix::unistd::geteuid().is_root(),
+        "--auto-sysctl requires root. To raise the kernel UDP buffer limits yourself, run:\n    sudo sysctl -w net.core.rmem_max={rmem} -w net.core.wmem_max={wmem}"
+    );
+    raise_sysctls(&[(Path::new(RMEM_MAX), rmem), (Path::new(WMEM_MAX), wmem)])
+}
+
 /// Concretions for Unix platforms
 #[derive(Debug, Clone, Copy)]
 pub struct Platform {}
@@ -128,3 +207,41 @@ impl super::AbstractPlatform for Platform {
         Some(p)
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::{raise_sysctls, read_sysctl, SocketOptions as _};
+    use crate::util::make_test_tempfile;
+    use std::net::UdpSocket;
+
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "openbsd"
+    ))]
+    #[test]
+    fn buffer_accounting() {
+        // Small enough to be within the default kernel limits
+        const SIZE: usize = 65536;
+        let mut sock = UdpSocket::bind("127.0.0.1:0").unwrap();
+        sock.set_sendbuf(SIZE).unwrap();
+        sock.set_recvbuf(SIZE).unwrap();
+        assert_eq!(sock.get_sendbuf().unwrap(), SIZE);
+        assert_eq!(sock.get_recvbuf().unwrap(), SIZE);
+    }
+
+    #[test]
+    fn sysctl_restored() {
+        let (rmem, _dir1) = make_test_tempfile("1000\n", "rmem_max");
+        let (wmem, _dir2) = make_test_tempfile("5000\n", "wmem_max");
+        let guard = raise_sysctls(&[(&rmem, 2000), (&wmem, 2000)]).unwrap();
+        assert_eq!(read_sysctl(&rmem).unwrap(), 2000);
+        assert_eq!(read_sysctl(&wmem).unwrap(), 5000); // already big enough
+        drop(guard);
+        assert_eq!(read_sysctl(&rmem).unwrap(), 1000);
+        assert_eq!(read_sysctl(&wmem).unwrap(), 5000);
+    }
+}
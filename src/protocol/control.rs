@@ -11,7 +11,7 @@
 //! * S ➡️ C: [`ServerMessage`]
 //! * Client establishes a QUIC connection to the server, on the port given in the [`ServerMessage`].
 //! * Client then opens one or more bidirectional QUIC streams ('sessions') on that connection.
-//!    (See the session protocol for what happens there.)
+//!   (See the session protocol for what happens there.)
 //!
 //! When transfer is complete and all QUIC streams are closed:
 //! * S ➡️ C: [`ClosedownReport`]
@@ -19,12 +19,25 @@
 //!
 //! On the wire these are [CapnProto] messages, sent using standard framing.
 //!
+//! ## Versioning
+//! The [`ClientMessage`] carries the newest protocol version the client supports ([`PROTOCOL_VERSION`]).
+//! The server selects the newest version that both sides support, and reports it in the [`ServerMessage`].
+//! If there is none, the server reports its oldest supported version and exits; the client then reports the mismatch.
+//! Peers which predate version negotiation send 0, which means version 1.
+//!
+//! | Version | Change |
+//! | ------- | ------ |
+//! | 1 | Initial version |
+//! | 2 | Everything since the initial version. Newer clients refuse to ask a version 1 server to do any of it, as it would ignore the request and do something else:<br/>• Commands MKDIR, STAT, SYMLINK, LIST, DELETE and FOLLOW<br/>• GET `resumeOffset` and `removeSource`<br/>• PUT `resumeOffset`, `append`, `mkpath`, `fsync`, `recursive` and `noClobber`, and data of unknown size<br/>• File metadata, compression and hashes in `FileHeader` and `FileTrailer` |
+//!
 //! [quic]: https://quicwg.github.io/
 //! [capnproto]: https://capnproto.org/
 
 pub use super::control_capnp::client_message::ConnectionType;
 
 use super::control_capnp;
+use super::session::CommandType;
+use crate::util::compress::Compress;
 use anyhow::Result;
 use capnp::message::ReaderOptions;
 use quinn::ConnectionStats;
@@ -33,25 +46,70 @@ use tokio_util::compat::{TokioAsyncReadCompatExt as _, TokioAsyncWriteCompatExt
 /// Server banner message, sent on stdout and checked by the client
 pub const BANNER: &str = "qcp-server-1\n";
 
+/// The newest protocol version this build supports
+pub const PROTOCOL_VERSION: u16 = 2;
+
+/// The oldest protocol version this build supports
+pub const OLDEST_PROTOCOL_VERSION: u16 = 1;
+
+/// Interprets a protocol version received from the peer. A peer which predates version negotiation sends 0.
+fn received_version(version: u16) -> u16 {
+    version.max(1)
+}
+
+/// Selects the protocol version to use (server side), given the newest version the client supports.
+///
+/// Returns None if there is no version that both sides support.
+#[must_use]
+pub fn select_version(client_version: u16) -> Option<u16> {
+    let version = client_version.min(PROTOCOL_VERSION);
+    (version >= OLDEST_PROTOCOL_VERSION).then_some(version)
+}
+
+/// Checks that we support the protocol version selected by the server (client side)
+pub fn check_server_version(server_version: u16) -> Result<()> {
+    anyhow::ensure!(
+        (OLDEST_PROTOCOL_VERSION..=PROTOCOL_VERSION).contains(&server_version),
+        "incompatible protocol: client v{PROTOCOL_VERSION}, server v{server_version}"
+    );
+    Ok(())
+}
+
 /// Helper type for [`control_capnp::client_message`]
 #[derive(Debug)]
 #[allow(missing_docs)]
 pub struct ClientMessage {
     pub cert: Vec<u8>,
     pub connection_type: ConnectionType,
+    /// Whether the server should compress the files it sends
+    pub compression: Compress,
+    /// The newest protocol version the client supports
+    pub protocol_version: u16,
 }
 
 impl ClientMessage {
     // This is weirdly asymmetric to avoid needless allocs.
     /// One-stop serializer
-    pub async fn write<W>(write: &mut W, cert: &[u8], conn_type: ConnectionType) -> Result<()>
+    pub async fn write<W>(
+        write: &mut W,
+        cert: &[u8],
+        conn_type: ConnectionType,
+        compression: Compress,
+    ) -> Result<()>
     where
         W: tokio::io::AsyncWrite + Unpin,
     {
+        use control_capnp::client_message::Compression;
         let mut msg = ::capnp::message::Builder::new_default();
         let mut builder = msg.init_root::<control_capnp::client_message::Builder<'_>>();
         builder.set_cert(cert);
         builder.set_connection_type(conn_type);
+        builder.set_compression(match compression {
+            Compress::Off => Compression::Off,
+            Compress::On => Compression::On,
+            Compress::Auto => Compression::Auto,
+        });
+        builder.set_protocol_version(PROTOCOL_VERSION);
         capnp_futures::serialize::write_message(write.compat_write(), &msg).await?;
         Ok(())
     }
@@ -67,9 +125,17 @@ impl ClientMessage {
         let connection_type: ConnectionType = msg_reader
             .get_connection_type()
             .map_err(|_| anyhow::anyhow!("incompatible ClientMessage"))?;
+        // A mode we don't know about is of no use to us
+        let compression = match msg_reader.get_compression() {
+            Ok(control_capnp::client_message::Compression::On) => Compress::On,
+            Ok(control_capnp::client_message::Compression::Auto) => Compress::Auto,
+            Ok(control_capnp::client_message::Compression::Off) | Err(_) => Compress::Off,
+        };
         Ok(Self {
             cert,
             connection_type,
+            compression,
+            protocol_version: received_version(msg_reader.get_protocol_version()),
         })
     }
 }
@@ -86,6 +152,14 @@ pub struct ServerMessage {
     pub warning: Option<String>,
     /// Server bandwidth information message
     pub bandwidth_info: String,
+    /// Address the client should connect to, if it differs from the one used for the control channel
+    pub advertised_address: Option<String>,
+    /// Session commands the server permits
+    pub allowed_commands: Vec<CommandType>,
+    /// Whether the server accepts compressed file data
+    pub compression: bool,
+    /// The protocol version selected by the server
+    pub protocol_version: u16,
 }
 
 impl std::fmt::Debug for ServerMessage {
@@ -96,6 +170,10 @@ impl std::fmt::Debug for ServerMessage {
             .field("name", &self.name)
             .field("warning", &self.warning)
             .field("bandwidth_info", &self.bandwidth_info)
+            .field("advertised_address", &self.advertised_address)
+            .field("allowed_commands", &self.allowed_commands)
+            .field("compression", &self.compression)
+            .field("protocol_version", &self.protocol_version)
             .finish()
     }
 }
@@ -103,6 +181,7 @@ impl std::fmt::Debug for ServerMessage {
 impl ServerMessage {
     /// Serializer
     // This is weirdly asymmetric to avoid needless allocs.
+    #[allow(clippy::too_many_arguments)]
     pub async fn write<W>(
         write: &mut W,
         port: u16,
@@ -110,6 +189,10 @@ impl ServerMessage {
         name: &str,
         warning: Option<&str>,
         bandwidth_info: &str,
+        advertised_address: Option<&str>,
+        allowed_commands: &[CommandType],
+        compression: bool,
+        protocol_version: u16,
     ) -> Result<()>
     where
         W: tokio::io::AsyncWrite + Unpin,
@@ -123,6 +206,15 @@ impl ServerMessage {
             builder.set_warning(w);
         }
         builder.set_bandwidth_info(bandwidth_info);
+        if let Some(a) = advertised_address {
+            builder.set_advertised_address(a);
+        }
+        builder.set_compression(compression);
+        builder.set_protocol_version(protocol_version);
+        let mut allowed = builder.init_allowed_commands(u32::try_from(allowed_commands.len())?);
+        for (i, c) in allowed_commands.iter().enumerate() {
+            allowed.set(u32::try_from(i)?, c.as_ref());
+        }
         capnp_futures::serialize::write_message(write.compat_write(), &msg).await?;
         Ok(())
     }
@@ -145,12 +237,35 @@ impl ServerMessage {
             Some(warning.to_string())
         };
         let bandwidth_info = msg_reader.get_bandwidth_info()?.to_str()?.to_string();
+        let advertised_address = msg_reader.get_advertised_address()?.to_str()?;
+        let advertised_address = if advertised_address.is_empty() {
+            None
+        } else {
+            Some(advertised_address.to_string())
+        };
+        let allowed_commands = {
+            let list = msg_reader.get_allowed_commands()?;
+            if list.is_empty() {
+                // Server predates this field
+                CommandType::ALL.to_vec()
+            } else {
+                // Ignore any commands we don't know about; they would be of no use to us
+                list.iter()
+                    .filter_map(|t| t.ok()?.to_str().ok()?.parse().ok())
+                    .collect()
+            }
+        };
         Ok(Self {
             port,
             cert,
             name,
             warning,
             bandwidth_info,
+            advertised_address,
+            allowed_commands,
+            // A server that predates this field leaves it false
+            compression: msg_reader.get_compression(),
+            protocol_version: received_version(msg_reader.get_protocol_version()),
         })
     }
 }
@@ -227,9 +342,14 @@ mod tests {
 
     // These tests are really only exercising capnp, proving that we know how to drive it correctly.
 
-    use super::{control_capnp, ClientMessage, ServerMessage};
+    use super::{
+        check_server_version, control_capnp, select_version, ClientMessage, CommandType,
+        ConnectionType, ServerMessage, PROTOCOL_VERSION,
+    };
+    use crate::util::{compress::Compress, Credentials};
     use anyhow::Result;
     use capnp::{message::ReaderOptions, serialize};
+    use std::path::PathBuf;
 
     fn encode_client(cert: &[u8]) -> Vec<u8> {
         let mut msg = ::capnp::message::Builder::new_default();
@@ -245,6 +365,8 @@ mod tests {
         Ok(ClientMessage {
             cert: Vec::<u8>::from(cert_reader.get_cert()?),
             connection_type: cert_reader.get_connection_type()?,
+            compression: Compress::Off,
+            protocol_version: 1,
         })
     }
     fn encode_server(port: u16, cert: &[u8]) -> Vec<u8> {
@@ -266,6 +388,10 @@ mod tests {
             name: "localhost".to_string(),
             warning: Some("foo".to_string()),
             bandwidth_info: "bar".into(),
+            advertised_address: None,
+            allowed_commands: Vec::new(),
+            compression: false,
+            protocol_version: 1,
         })
     }
 
@@ -306,4 +432,138 @@ mod tests {
         assert_eq!(port, decoded.port);
         Ok(())
     }
+
+    /// Compares serialized data against a golden file in `src/protocol/testdata`.
+    ///
+    /// If a schema change is intentional, set `QCP_UPDATE_GOLDEN=1` and rerun the tests to regenerate the files.
+    fn check_golden(name: &str, data: &[u8]) {
+        let path: PathBuf = [env!("CARGO_MANIFEST_DIR"), "src/protocol/testdata", name]
+            .iter()
+            .collect();
+        if std::env::var_os("QCP_UPDATE_GOLDEN").is_some() {
+            std::fs::write(&path, data).unwrap();
+            return;
+        }
+        let expected = std::fs::read(&path).unwrap();
+        assert_eq!(
+            data,
+            expected,
+            "serialized data does not match golden file {}",
+            path.display()
+        );
+    }
+
+    #[tokio::test]
+    async fn client_message_golden() -> Result<()> {
+        let creds = Credentials::generate_from_seed(1)?;
+        let mut wire = Vec::new();
+        ClientMessage::write(
+            &mut wire,
+            &creds.certificate,
+            ConnectionType::Ipv4,
+            Compress::Off,
+        )
+        .await?;
+        check_golden("client_message.bin", &wire);
+
+        let decoded = ClientMessage::read(&mut wire.as_slice()).await?;
+        assert_eq!(decoded.cert, creds.certificate.as_ref());
+        assert_eq!(decoded.connection_type, ConnectionType::Ipv4);
+        assert_eq!(decoded.compression, Compress::Off);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn compression_negotiation() -> Result<()> {
+        for mode in [Compress::Off, Compress::On, Compress::Auto] {
+            let mut wire = Vec::new();
+            ClientMessage::write(&mut wire, &[1, 2, 3], ConnectionType::Ipv6, mode).await?;
+            let decoded = ClientMessage::read(&mut wire.as_slice()).await?;
+            assert_eq!(decoded.compression, mode);
+        }
+
+        let mut wire = Vec::new();
+        ServerMessage::write(&mut wire, 1, &[1], "n", None, "", None, &[], true, 1).await?;
+        assert!(ServerMessage::read(&mut wire.as_slice()).await?.compression);
+        // A server that predates compression support doesn't accept it
+        let wire = encode_server(1234, &[1, 2, 3]);
+        assert!(!ServerMessage::read(&mut wire.as_slice()).await?.compression);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_message_golden() -> Result<()> {
+        let creds = Credentials::generate_from_seed(2)?;
+        let mut wire = Vec::new();
+        ServerMessage::write(
+            &mut wire,
+            12345,
+            &creds.certificate,
+            &creds.hostname,
+            Some("a warning"),
+            "some bandwidth info",
+            Some("192.0.2.1"),
+            &[CommandType::Get],
+            false,
+            1,
+        )
+        .await?;
+        check_golden("server_message.bin", &wire);
+
+        let decoded = ServerMessage::read(&mut wire.as_slice()).await?;
+        assert_eq!(decoded.port, 12345);
+        assert_eq!(decoded.cert, creds.certificate.as_ref());
+        assert_eq!(decoded.name, creds.hostname);
+        assert_eq!(decoded.warning.as_deref(), Some("a warning"));
+        assert_eq!(decoded.bandwidth_info, "some bandwidth info");
+        assert_eq!(decoded.advertised_address.as_deref(), Some("192.0.2.1"));
+        assert_eq!(decoded.allowed_commands, [CommandType::Get]);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn server_message_without_allowed_commands() -> Result<()> {
+        // A server that predates the allowed commands field is assumed to permit everything
+        let wire = encode_server(1234, &[1, 2, 3]);
+        let decoded = ServerMessage::read(&mut wire.as_slice()).await?;
+        assert_eq!(decoded.allowed_commands, CommandType::ALL);
+        Ok(())
+    }
+
+    #[tokio::test]
+    async fn version_negotiation() -> Result<()> {
+        let mut wire = Vec::new();
+        ClientMessage::write(&mut wire, &[1], ConnectionType::Ipv4, Compress::Off).await?;
+        let decoded = ClientMessage::read(&mut wire.as_slice()).await?;
+        assert_eq!(decoded.protocol_version, PROTOCOL_VERSION);
+        assert_eq!(
+            select_version(decoded.protocol_version),
+            Some(PROTOCOL_VERSION)
+        );
+        // A newer client gets our newest version
+        assert_eq!(select_version(u16::MAX), Some(PROTOCOL_VERSION));
+
+        // Peers which predate negotiation speak version 1
+        let wire = encode_client(&[1]);
+        assert_eq!(
+            ClientMessage::read(&mut wire.as_slice())
+                .await?
+                .protocol_version,
+            1
+        );
+        let wire = encode_server(1234, &[1]);
+        let decoded = ServerMessage::read(&mut wire.as_slice()).await?;
+        assert_eq!(decoded.protocol_version, 1);
+        check_server_version(decoded.protocol_version)?;
+
+        let err = check_server_version(PROTOCOL_VERSION + 1).unwrap_err();
+        assert_eq!(
+            err.to_string(),
+            format!(
+                "incompatible protocol: client v{PROTOCOL_VERSION}, server v{}",
+                PROTOCOL_VERSION + 1
+            )
+        );
+        Ok(())
+    }
 }
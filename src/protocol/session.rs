@@ -11,6 +11,9 @@
 //! * S ➡️ C : [Response] packet
 //! * Then they do whatever is appropriate for the command.
 //!
+//! Servers using control protocol version 1 only support a Get or Put of a single file, without any of the options
+//! below, and ignore the [FileHeader] fields other than `size` and `filename`. The client does not ask them for anything more.
+//!
 //! The following commands are defined:
 //! ### Get
 //!
@@ -23,6 +26,17 @@
 //!
 //! Either side may close the stream mid-flow if it needs to abort the transfer.
 //!
+//! To resume an interrupted transfer, the client sets a non-zero `resume_offset` in [GetArgs].
+//! The [FileHeader] carries the full size and modification time of the file, but only the data after the offset is sent.
+//!
+//! If the client sets `remove_source` in [GetArgs], the server keeps the stream open after the [FileTrailer]:
+//! * C ➡️ S: [Response] . OK if the client received and verified the file.
+//! * S ➡️ C: [Response] indicating whether the file was deleted.
+//!
+//! The server deletes the file only if the client's Response was OK.
+//! If the client closes the stream instead, or the file changed while it was being sent, the file is kept.
+//! As this deletes the server's file, a Get with `remove_source` is only permitted if [Delete](CommandType::Delete) is.
+//!
 //! ### Put
 //!
 //! Sends a file to the remote.
@@ -35,6 +49,130 @@
 //!
 //! If the server needs to abort the transfer mid-flow, it may send a Response explaining why, then close the stream.
 //!
+//! When sending a directory tree, the client sets `recursive` in [PutArgs], and the filename in the [FileHeader]
+//! may be a relative path; the server creates any missing intermediate directories below the destination.
+//! Otherwise, the server ignores any directory components of the filename.
+//! Either way, the server rejects a filename which is absolute or contains `..`.
+//! (Clients using control protocol version 1 do not set `recursive`, so the server allows them relative paths.)
+//!
+//! To resume an interrupted transfer, the client sets a non-zero `resume_offset` in [PutArgs].
+//! The [FileHeader] carries the full size of the file, but only the data after the offset is sent.
+//!
+//! To append to an existing file, the client sets `append` in [PutArgs].
+//! The destination must then be a file, not a directory.
+//! The [FileHeader] carries the size of the data to be appended, and the [FileTrailer] hash covers only that data.
+//!
+//! If the client sets `mkpath` in [PutArgs], the server creates any missing parent directories of the destination
+//! instead of responding with [`Status::DirectoryDoesNotExist`].
+//! A destination ending with `/` is then taken to be a directory, which is created if necessary.
+//!
+//! If the client sets `no_clobber`, the server checks whether the destination file already exists once it has
+//! read the [FileHeader] (which names the file, if the destination is a directory).
+//! It then sends an additional [Response] before the client sends the file data: OK, or
+//! [`Status::DestinationExists`], in which case the command does not proceed.
+//! The client may then ask its user whether to overwrite the file, and if so send the Put again without `no_clobber`.
+//!
+//! If the client sets `fsync`, the server makes sure the file and its directory entry have reached the disk
+//! before sending the final [Response].
+//!
+//! If the client does not know the size of the data in advance (for example, when reading from standard input),
+//! it streams it; see [below](#streaming).
+//!
+//! ### Mkdir
+//!
+//! Creates a directory on the remote, along with any missing parents.
+//! For access control purposes this is considered a [Put](CommandType::Put).
+//! * C ➡️ S: [MkdirArgs] _(within [Command])_
+//! * S ➡️ C: [Response]
+//!
+//! Then close the stream.
+//!
+//! ### Stat
+//!
+//! Reports the size and modification time of a file on the remote, so the client can work out where to resume a Put,
+//! or whether a job can be skipped because its destination is already present.
+//! The server resolves the path in the same way as for a Put.
+//! For access control purposes this is considered a [Put](CommandType::Put); but a server which only allows
+//! Get commands permits it too, as a Get would reveal the same information.
+//! * C ➡️ S: [StatArgs] _(within [Command])_
+//! * S ➡️ C: [Response] . If the status within was OK, this is followed by a [FileHeader].
+//!
+//! Then close the stream.
+//!
+//! ### Symlink
+//!
+//! Creates a symbolic link on the remote, when sending a directory tree with `--links=copy`.
+//! An existing symbolic link at the same path is replaced.
+//! For access control purposes this is considered a [Put](CommandType::Put).
+//! * C ➡️ S: [SymlinkArgs] _(within [Command])_
+//! * S ➡️ C: [Response]
+//!
+//! Then close the stream.
+//!
+//! ### List
+//!
+//! Lists the contents of a directory tree on the remote, so that `--delete` can find entries which are not in the source.
+//! For access control purposes this is considered a [Put](CommandType::Put).
+//! * C ➡️ S: [ListArgs] _(within [Command])_
+//! * S ➡️ C: [Response] . If the status within was OK, this is followed by a [FileHeader] for each entry in the tree,
+//!   then an [end marker](FileHeader::list_end).
+//!
+//! The filename in each [FileHeader] is the path of the entry relative to the listed directory, with `/` separators;
+//! its `mode` identifies the type of entry. The directory itself is not listed, and symbolic links are not followed.
+//! Entries are sent in no particular order, as the server finds them, so a large tree does not have to be held in memory.
+//!
+//! The end marker is a [FileHeader] with an empty filename. The server closes the stream after it.
+//! If the server cannot read part of the tree, it closes the stream without sending the end marker,
+//! so the client knows that the listing is incomplete.
+//!
+//! ### Delete
+//!
+//! Deletes a file, symbolic link or empty directory on the remote, for `--delete`.
+//! For access control purposes this is considered a [Delete](CommandType::Delete),
+//! so a server may accept files without allowing clients to remove anything.
+//! * C ➡️ S: [DeleteArgs] _(within [Command])_
+//! * S ➡️ C: [Response]
+//!
+//! Then close the stream.
+//!
+//! ### Follow
+//!
+//! Follows a file on the remote which is being appended to, such as a log, as `tail -F` does.
+//! For access control purposes this is considered a [Get](CommandType::Get).
+//! * C ➡️ S: [FollowArgs] _(within [Command])_
+//! * S ➡️ C: [Response] . If the status within was OK, this is followed by a [FollowEvent] whenever something
+//!   happens to the file.
+//!
+//! The server sends the file from `offset`, then a [`FollowEvent::Data`] each time it grows, followed by the new data.
+//! This carries on until the client closes the stream.
+//!
+//! If a different file appears at the same path (the file was rotated), the server sends the rest of the old file,
+//! then [`FollowEvent::Rotated`]. If the file becomes shorter than what has been sent, it sends [`FollowEvent::Truncated`].
+//! Either way, it then closes the stream; the client may send a new Follow, with offset 0, to carry on with the
+//! file now at the path.
+//!
+//! ### Compression
+//!
+//! In a Get or Put, the sender may compress the file data if the receiver has said it will accept that
+//! (see the control protocol). If so, it sets `compressed` in the [FileHeader] and sends the data as a single zstd frame.
+//! The [FileHeader] still carries the size of the file itself; the [FileTrailer] reports the compressed size.
+//!
+//! ### Streaming
+//!
+//! In a Put, the client may send data whose size is not known in advance.
+//! It sets the size in the [FileHeader] to [`FileHeader::UNKNOWN_SIZE`], sends the file data,
+//! then sends the [FileTrailer] padded with zeroes to [`FileTrailer::PADDED_LEN`] bytes.
+//! Finally it finishes its side of the stream, which is how the server knows where the data ends:
+//! the last [`FileTrailer::PADDED_LEN`] bytes of the stream are the trailer.
+//! The server then sends its [Response] indicating transfer status as usual.
+//!
+//! A streamed transfer cannot be resumed.
+//!
+//! ### Timeouts
+//!
+//! Each side applies a timeout (the `protocol_timeout` setting) while waiting for a [Command], [Response] or
+//! [FileHeader] from the other, so that a peer which stops responding on a stream does not hold it open forever.
+//!
 //! [quic]: https://quicwg.github.io/
 //! [capnproto]: https://capnproto.org/
 
@@ -43,7 +181,9 @@ pub use super::session_capnp::Status;
 use super::session_capnp;
 use anyhow::Result;
 use capnp::message::ReaderOptions;
-use std::fmt::Display;
+use serde::{de, Deserialize, Serialize};
+use std::{fmt::Display, future::Future, str::FromStr, time::Duration};
+use strum::VariantNames as _;
 use tokio_util::compat::TokioAsyncReadCompatExt as _;
 
 /// Command packet
@@ -52,50 +192,341 @@ use tokio_util::compat::TokioAsyncReadCompatExt as _;
 pub enum Command {
     Get(GetArgs),
     Put(PutArgs),
+    Mkdir(MkdirArgs),
+    Stat(StatArgs),
+    Symlink(SymlinkArgs),
+    List(ListArgs),
+    Delete(DeleteArgs),
+    Follow(FollowArgs),
+}
+/// Identifies a type of [Command], for the purposes of access control
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    PartialEq,
+    Eq,
+    strum::AsRefStr,
+    strum::Display,
+    strum::EnumString,
+    strum::VariantNames,
+    clap::ValueEnum,
+    Serialize,
+)]
+#[strum(serialize_all = "lowercase")] // N.B. this applies to EnumString and AsRefStr, not Display
+pub enum CommandType {
+    /// Retrieve a file from the server
+    Get,
+    /// Send a file to the server
+    Put,
+    /// Delete files on the server
+    Delete,
+}
+
+impl CommandType {
+    /// All the command types known to this build
+    pub const ALL: &[CommandType] = &[CommandType::Get, CommandType::Put, CommandType::Delete];
+}
+
+impl<'de> Deserialize<'de> for CommandType {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let lower = s.to_ascii_lowercase();
+        // requires strum::EnumString && strum::VariantNames && #[strum(serialize_all = "lowercase")]
+        FromStr::from_str(&lower).map_err(|_| de::Error::unknown_variant(&s, CommandType::VARIANTS))
+    }
 }
+
 #[derive(Debug)]
 /// Arguments for [Command::Get]
 #[allow(missing_docs)]
 pub struct GetArgs {
     pub filename: String,
+    pub resume_offset: u64,
+    pub remove_source: bool,
 }
 #[derive(Debug)]
 /// Arguments for [Command::Put]
 #[allow(missing_docs)]
+#[allow(clippy::struct_excessive_bools)]
 pub struct PutArgs {
     pub filename: String,
+    pub resume_offset: u64,
+    pub append: bool,
+    pub mkpath: bool,
+    pub fsync: bool,
+    pub recursive: bool,
+    pub no_clobber: bool,
+}
+#[derive(Debug)]
+/// Arguments for [Command::Mkdir]
+#[allow(missing_docs)]
+pub struct MkdirArgs {
+    pub dirname: String,
+}
+#[derive(Debug)]
+/// Arguments for [Command::Stat]
+#[allow(missing_docs)]
+pub struct StatArgs {
+    pub path: String,
+    pub filename: String,
+}
+#[derive(Debug)]
+/// Arguments for [Command::Symlink]
+#[allow(missing_docs)]
+pub struct SymlinkArgs {
+    pub linkpath: String,
+    pub target: String,
+}
+#[derive(Debug)]
+/// Arguments for [Command::List]
+#[allow(missing_docs)]
+pub struct ListArgs {
+    pub path: String,
+}
+#[derive(Debug)]
+/// Arguments for [Command::Delete]
+#[allow(missing_docs)]
+pub struct DeleteArgs {
+    pub path: String,
+}
+#[derive(Debug)]
+/// Arguments for [Command::Follow]
+#[allow(missing_docs)]
+pub struct FollowArgs {
+    pub filename: String,
+    /// Number of bytes of the file the client already has
+    pub offset: u64,
 }
 
 impl Command {
+    /// Whether a server which allows the given types of command permits this command
+    #[must_use]
+    pub fn is_permitted(&self, allowed: &[CommandType]) -> bool {
+        match self {
+            Command::Stat(_) => allowed
+                .iter()
+                .any(|t| matches!(t, CommandType::Get | CommandType::Put)),
+            Command::Get(args) if args.remove_source => {
+                allowed.contains(&CommandType::Get) && allowed.contains(&CommandType::Delete)
+            }
+            _ => allowed.contains(&self.command_type()),
+        }
+    }
+
+    /// Whether this command modifies the server's files
+    #[must_use]
+    pub fn modifies(&self) -> bool {
+        match self {
+            Command::Get(args) => args.remove_source,
+            _ => self.command_type() != CommandType::Get,
+        }
+    }
+
+    /// The type of this command
+    #[must_use]
+    pub fn command_type(&self) -> CommandType {
+        match self {
+            Command::Get(_) | Command::Follow(_) => CommandType::Get,
+            Command::Put(_)
+            | Command::Mkdir(_)
+            | Command::Stat(_)
+            | Command::Symlink(_)
+            | Command::List(_) => CommandType::Put,
+            Command::Delete(_) => CommandType::Delete,
+        }
+    }
+
     /// Specialised constructor for Get
     #[must_use]
     pub fn new_get(filename: &str) -> Self {
+        Self::new_resumed_get(filename, 0)
+    }
+    /// Specialised constructor for Get, resuming an interrupted transfer
+    #[must_use]
+    pub fn new_resumed_get(filename: &str, resume_offset: u64) -> Self {
         Self::Get(GetArgs {
             filename: filename.to_string(),
+            resume_offset,
+            remove_source: false,
         })
     }
     /// Specialised constructor for Put
     #[must_use]
     pub fn new_put(filename: &str) -> Self {
+        Self::new_resumed_put(filename, 0)
+    }
+    /// Specialised constructor for Put, resuming an interrupted transfer
+    #[must_use]
+    pub fn new_resumed_put(filename: &str, resume_offset: u64) -> Self {
         Self::Put(PutArgs {
             filename: filename.to_string(),
+            resume_offset,
+            append: false,
+            mkpath: false,
+            fsync: false,
+            recursive: false,
+            no_clobber: false,
+        })
+    }
+    /// Specialised constructor for Put, appending to the destination
+    #[must_use]
+    pub fn new_appending_put(filename: &str) -> Self {
+        Self::Put(PutArgs {
+            filename: filename.to_string(),
+            resume_offset: 0,
+            append: true,
+            mkpath: false,
+            fsync: false,
+            recursive: false,
+            no_clobber: false,
+        })
+    }
+    /// For a Put, sets whether the server should create missing parent directories of the destination.
+    /// Other commands are unchanged.
+    #[must_use]
+    pub fn with_mkpath(mut self, mkpath: bool) -> Self {
+        if let Self::Put(args) = &mut self {
+            args.mkpath = mkpath;
+        }
+        self
+    }
+    /// For a Put, sets whether the server should sync the file to disk before reporting success.
+    /// Other commands are unchanged.
+    #[must_use]
+    pub fn with_fsync(mut self, fsync: bool) -> Self {
+        if let Self::Put(args) = &mut self {
+            args.fsync = fsync;
+        }
+        self
+    }
+    /// For a Put, sets whether it is part of a recursive copy, so the filename in the [FileHeader] may be a path.
+    /// Other commands are unchanged.
+    #[must_use]
+    pub fn with_recursive(mut self, recursive: bool) -> Self {
+        if let Self::Put(args) = &mut self {
+            args.recursive = recursive;
+        }
+        self
+    }
+    /// For a Put, sets whether the server should refuse to overwrite an existing file.
+    /// Other commands are unchanged.
+    #[must_use]
+    pub fn with_no_clobber(mut self, no_clobber: bool) -> Self {
+        if let Self::Put(args) = &mut self {
+            args.no_clobber = no_clobber;
+        }
+        self
+    }
+    /// For a Get, sets whether the server should delete the file once the client has confirmed receipt.
+    /// Other commands are unchanged.
+    #[must_use]
+    pub fn with_remove_source(mut self, remove_source: bool) -> Self {
+        if let Self::Get(args) = &mut self {
+            args.remove_source = remove_source;
+        }
+        self
+    }
+    /// Specialised constructor for Mkdir
+    #[must_use]
+    pub fn new_mkdir(dirname: &str) -> Self {
+        Self::Mkdir(MkdirArgs {
+            dirname: dirname.to_string(),
+        })
+    }
+    /// Specialised constructor for Stat
+    #[must_use]
+    pub fn new_stat(path: &str, filename: &str) -> Self {
+        Self::Stat(StatArgs {
+            path: path.to_string(),
+            filename: filename.to_string(),
+        })
+    }
+    /// Specialised constructor for Symlink
+    #[must_use]
+    pub fn new_symlink(linkpath: &str, target: &str) -> Self {
+        Self::Symlink(SymlinkArgs {
+            linkpath: linkpath.to_string(),
+            target: target.to_string(),
+        })
+    }
+    /// Specialised constructor for List
+    #[must_use]
+    pub fn new_list(path: &str) -> Self {
+        Self::List(ListArgs {
+            path: path.to_string(),
+        })
+    }
+    /// Specialised constructor for Delete
+    #[must_use]
+    pub fn new_delete(path: &str) -> Self {
+        Self::Delete(DeleteArgs {
+            path: path.to_string(),
+        })
+    }
+    /// Specialised constructor for Follow
+    #[must_use]
+    pub fn new_follow(filename: &str, offset: u64) -> Self {
+        Self::Follow(FollowArgs {
+            filename: filename.to_string(),
+            offset,
         })
     }
 
     /// One-stop serializer
     #[must_use]
     pub fn serialize(&self) -> Vec<u8> {
-        use crate::protocol::session::Command::{Get, Put};
+        use crate::protocol::session::Command::{
+            Delete, Follow, Get, List, Mkdir, Put, Stat, Symlink,
+        };
         let mut msg = ::capnp::message::Builder::new_default();
         let builder = msg.init_root::<session_capnp::command::Builder<'_>>();
         match self {
             Get(args) => {
                 let mut build_args = builder.init_args().init_get();
                 build_args.set_filename(&args.filename);
+                build_args.set_resume_offset(args.resume_offset);
+                build_args.set_remove_source(args.remove_source);
             }
             Put(args) => {
                 let mut build_args = builder.init_args().init_put();
                 build_args.set_filename(&args.filename);
+                build_args.set_resume_offset(args.resume_offset);
+                build_args.set_append(args.append);
+                build_args.set_mkpath(args.mkpath);
+                build_args.set_fsync(args.fsync);
+                build_args.set_recursive(args.recursive);
+                build_args.set_no_clobber(args.no_clobber);
+            }
+            Mkdir(args) => {
+                let mut build_args = builder.init_args().init_mkdir();
+                build_args.set_dirname(&args.dirname);
+            }
+            Stat(args) => {
+                let mut build_args = builder.init_args().init_stat();
+                build_args.set_path(&args.path);
+                build_args.set_filename(&args.filename);
+            }
+            Symlink(args) => {
+                let mut build_args = builder.init_args().init_symlink();
+                build_args.set_linkpath(&args.linkpath);
+                build_args.set_target(&args.target);
+            }
+            List(args) => {
+                let mut build_args = builder.init_args().init_list();
+                build_args.set_path(&args.path);
+            }
+            Delete(args) => {
+                let mut build_args = builder.init_args().init_delete();
+                build_args.set_path(&args.path);
+            }
+            Follow(args) => {
+                let mut build_args = builder.init_args().init_follow();
+                build_args.set_filename(&args.filename);
+                build_args.set_offset(args.offset);
             }
         }
         capnp::serialize::write_message_to_words(&msg)
@@ -108,19 +539,63 @@ impl Command {
     {
         use session_capnp::command::{
             self,
-            args::{Get, Put},
+            args::{Delete, Follow, Get, List, Mkdir, Put, Stat, Symlink},
         };
         let reader =
             capnp_futures::serialize::read_message(read.compat(), ReaderOptions::new()).await?;
         let msg: command::Reader<'_> = reader.get_root()?;
 
         Ok(match msg.get_args().which() {
-            Ok(Get(get)) => Command::Get(GetArgs {
-                filename: get?.get_filename()?.to_string()?,
+            Ok(Get(get)) => {
+                let get = get?;
+                Command::Get(GetArgs {
+                    filename: get.get_filename()?.to_string()?,
+                    resume_offset: get.get_resume_offset(),
+                    remove_source: get.get_remove_source(),
+                })
+            }
+            Ok(Put(put)) => {
+                let put = put?;
+                Command::Put(PutArgs {
+                    filename: put.get_filename()?.to_string()?,
+                    resume_offset: put.get_resume_offset(),
+                    append: put.get_append(),
+                    mkpath: put.get_mkpath(),
+                    fsync: put.get_fsync(),
+                    recursive: put.get_recursive(),
+                    no_clobber: put.get_no_clobber(),
+                })
+            }
+            Ok(Mkdir(mkdir)) => Command::Mkdir(MkdirArgs {
+                dirname: mkdir?.get_dirname()?.to_string()?,
             }),
-            Ok(Put(put)) => Command::Put(PutArgs {
-                filename: put?.get_filename()?.to_string()?,
+            Ok(Stat(stat)) => {
+                let stat = stat?;
+                Command::Stat(StatArgs {
+                    path: stat.get_path()?.to_string()?,
+                    filename: stat.get_filename()?.to_string()?,
+                })
+            }
+            Ok(Symlink(symlink)) => {
+                let symlink = symlink?;
+                Command::Symlink(SymlinkArgs {
+                    linkpath: symlink.get_linkpath()?.to_string()?,
+                    target: symlink.get_target()?.to_string()?,
+                })
+            }
+            Ok(List(list)) => Command::List(ListArgs {
+                path: list?.get_path()?.to_string()?,
+            }),
+            Ok(Delete(delete)) => Command::Delete(DeleteArgs {
+                path: delete?.get_path()?.to_string()?,
             }),
+            Ok(Follow(follow)) => {
+                let follow = follow?;
+                Command::Follow(FollowArgs {
+                    filename: follow.get_filename()?.to_string()?,
+                    offset: follow.get_offset(),
+                })
+            }
             Err(e) => {
                 anyhow::bail!("unrecognised command id {}", e.0);
             }
@@ -183,23 +658,129 @@ impl Display for Response {
     }
 }
 
-#[derive(Debug)]
+impl Response {
+    /// Converts this response into a `Result`, so unsuccessful statuses can be propagated as errors
+    pub fn into_result(self) -> Result<(), StatusError> {
+        if self.status == Status::Ok {
+            Ok(())
+        } else {
+            Err(StatusError {
+                status: self.status,
+                message: self.message.filter(|m| !m.is_empty()),
+            })
+        }
+    }
+}
+
+/// Waits for a message from the peer, failing if it does not arrive within `limit`.
+///
+/// `what` describes the message, for the error. A zero `limit` waits indefinitely.
+pub async fn with_timeout<T>(
+    limit: Duration,
+    what: &str,
+    read: impl Future<Output = Result<T>>,
+) -> Result<T> {
+    if limit.is_zero() {
+        return read.await;
+    }
+    tokio::time::timeout(limit, read).await.map_err(|_| {
+        anyhow::anyhow!(
+            "protocol timeout: no {what} from the remote within {}s",
+            limit.as_secs()
+        )
+    })?
+}
+
+/// A user-friendly description of a [`Status`]
+#[must_use]
+pub fn status_description(status: Status) -> &'static str {
+    match status {
+        Status::Ok => "success",
+        Status::FileNotFound => "file not found",
+        Status::IncorrectPermissions => "permission denied",
+        Status::DirectoryDoesNotExist => "destination directory does not exist",
+        Status::IoError => "I/O error",
+        Status::DiskFull => "disk full",
+        Status::NotYetImplemented => "not supported by the remote qcp (version mismatch?)",
+        Status::ItIsADirectory => "it is a directory",
+        Status::CommandNotPermitted => "not permitted by the remote qcp configuration",
+        Status::ChecksumMismatch => "data integrity check failed (checksum mismatch)",
+        Status::DestinationExists => "destination already exists",
+    }
+}
+
+/// An unsuccessful [`Response`], as an error type.
+///
+/// Callers may recover the status from an `anyhow::Error` with `downcast_ref`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct StatusError {
+    /// The status reported by the remote
+    pub status: Status,
+    /// Any further explanation reported by the remote
+    pub message: Option<String>,
+}
+
+impl Display for StatusError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let description = status_description(self.status);
+        match &self.message {
+            Some(msg) => write!(f, "{description} ({msg})"),
+            None => write!(f, "{description}"),
+        }
+    }
+}
+
+impl std::error::Error for StatusError {}
+
+#[derive(Debug, Default)]
 #[allow(missing_docs)]
 /// File Header packet
 pub struct FileHeader {
     pub size: u64,
     pub filename: String,
+    /// Modification time in nanoseconds since the Unix epoch, or 0 if unknown
+    pub mtime: u64,
+    /// Unix mode (`st_mode`), or 0 if unknown
+    pub mode: u32,
+    /// Whether to apply `mode` without clamping it by the receiver's umask
+    pub exact_mode: bool,
+    /// Whether the file data is zstd compressed
+    pub compressed: bool,
 }
 
 impl FileHeader {
-    /// One-stop serializer
+    /// Value of `size` when the size of the file is not known in advance (see [Streaming](self#streaming))
+    pub const UNKNOWN_SIZE: u64 = u64::MAX;
+
+    /// The size of the file, if it is known in advance
+    #[must_use]
+    pub fn known_size(&self) -> Option<u64> {
+        (self.size != Self::UNKNOWN_SIZE).then_some(self.size)
+    }
+
+    /// The marker sent after the last entry in response to [Command::List]
+    #[must_use]
+    pub fn list_end() -> Self {
+        Self::default()
+    }
+    /// Is this the [end marker](Self::list_end) of a listing?
     #[must_use]
-    pub fn serialize_direct(size: u64, filename: &str) -> Vec<u8> {
+    pub fn is_list_end(&self) -> bool {
+        self.filename.is_empty()
+    }
+
+    /// Serializer
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
         let mut msg = ::capnp::message::Builder::new_default();
 
         let mut response_msg = msg.init_root::<session_capnp::file_header::Builder<'_>>();
-        response_msg.set_size(size);
-        response_msg.set_filename(filename);
+        response_msg.set_size(self.size);
+        response_msg.set_filename(&self.filename);
+        response_msg.set_mtime(self.mtime);
+        response_msg.set_mode(self.mode);
+        response_msg.set_exact_mode(self.exact_mode);
+        response_msg.set_compressed(self.compressed);
         capnp::serialize::write_message_to_words(&msg)
     }
     /// Deserializer
@@ -207,29 +788,113 @@ impl FileHeader {
     where
         R: tokio::io::AsyncRead + Unpin,
     {
-        let reader =
-            capnp_futures::serialize::read_message(read.compat(), ReaderOptions::new()).await?;
+        Self::try_read(read)
+            .await?
+            .ok_or_else(|| anyhow::anyhow!("Premature end of file"))
+    }
+    /// Deserializer which returns `None` if the stream ends cleanly before the header
+    pub async fn try_read<R>(read: &mut R) -> anyhow::Result<Option<Self>>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        let Some(reader) =
+            capnp_futures::serialize::try_read_message(read.compat(), ReaderOptions::new()).await?
+        else {
+            return Ok(None);
+        };
         let msg_reader: session_capnp::file_header::Reader<'_> = reader.get_root()?;
-        Ok(Self {
+        Ok(Some(Self {
             size: msg_reader.get_size(),
             filename: msg_reader.get_filename()?.to_string()?,
-        })
+            mtime: msg_reader.get_mtime(),
+            mode: msg_reader.get_mode(),
+            exact_mode: msg_reader.get_exact_mode(),
+            compressed: msg_reader.get_compressed(),
+        }))
+    }
+}
+
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+/// Event packet, sent in response to [Command::Follow]
+pub enum FollowEvent {
+    /// The file grew; this many bytes of its data follow
+    Data(u64),
+    /// A different file now exists at the path
+    Rotated,
+    /// The file is now shorter than what has been sent
+    Truncated,
+}
+
+impl FollowEvent {
+    /// Serializer
+    #[must_use]
+    pub fn serialize(&self) -> Vec<u8> {
+        let mut msg = ::capnp::message::Builder::new_default();
+
+        let mut event_msg = msg
+            .init_root::<session_capnp::follow_event::Builder<'_>>()
+            .init_event();
+        match self {
+            Self::Data(len) => event_msg.set_data(*len),
+            Self::Rotated => event_msg.set_rotated(()),
+            Self::Truncated => event_msg.set_truncated(()),
+        }
+        capnp::serialize::write_message_to_words(&msg)
+    }
+    /// Deserializer which returns `None` if the stream ends cleanly before the event
+    pub async fn try_read<R>(read: &mut R) -> anyhow::Result<Option<Self>>
+    where
+        R: tokio::io::AsyncRead + Unpin,
+    {
+        use session_capnp::follow_event::event::Which;
+        let Some(reader) =
+            capnp_futures::serialize::try_read_message(read.compat(), ReaderOptions::new()).await?
+        else {
+            return Ok(None);
+        };
+        let msg_reader: session_capnp::follow_event::Reader<'_> = reader.get_root()?;
+        Ok(Some(match msg_reader.get_event().which() {
+            Ok(Which::Data(len)) => Self::Data(len),
+            Ok(Which::Rotated(())) => Self::Rotated,
+            Ok(Which::Truncated(())) => Self::Truncated,
+            Err(e) => anyhow::bail!("unrecognised follow event {}", e.0),
+        }))
     }
 }
 
 #[derive(Debug, Copy, Clone)]
 /// File Trailer packet
-pub struct FileTrailer {}
+pub struct FileTrailer {
+    /// BLAKE3 hash of the file data that was sent, if the sender computed one
+    pub hash: Option<blake3::Hash>,
+    /// Size of the compressed file data that was sent, if it was compressed
+    pub compressed_size: Option<u64>,
+}
 
 impl FileTrailer {
+    /// Length of the padded trailer which follows file data of unknown size (see [Streaming](self#streaming))
+    pub const PADDED_LEN: usize = 128;
+
     /// One-stop serializer
     #[must_use]
-    pub fn serialize_direct() -> Vec<u8> {
+    pub fn serialize_direct(hash: Option<&blake3::Hash>, compressed_size: Option<u64>) -> Vec<u8> {
         let mut msg = ::capnp::message::Builder::new_default();
 
-        let mut _response_msg = msg.init_root::<session_capnp::file_trailer::Builder<'_>>();
+        let mut response_msg = msg.init_root::<session_capnp::file_trailer::Builder<'_>>();
+        if let Some(hash) = hash {
+            response_msg.set_hash(hash.as_bytes());
+        }
+        response_msg.set_compressed_size(compressed_size.unwrap_or_default());
         capnp::serialize::write_message_to_words(&msg)
     }
+    /// Serializer for the trailer of file data of unknown size, padded to [`PADDED_LEN`](Self::PADDED_LEN) bytes
+    #[must_use]
+    pub fn serialize_padded(hash: Option<&blake3::Hash>, compressed_size: Option<u64>) -> Vec<u8> {
+        let mut wire = Self::serialize_direct(hash, compressed_size);
+        debug_assert!(wire.len() <= Self::PADDED_LEN);
+        wire.resize(Self::PADDED_LEN, 0);
+        wire
+    }
     /// Deserializer
     pub async fn read<R>(read: &mut R) -> anyhow::Result<Self>
     where
@@ -237,14 +902,38 @@ impl FileTrailer {
     {
         let reader =
             capnp_futures::serialize::read_message(read.compat(), ReaderOptions::new()).await?;
-        let _msg_reader: session_capnp::file_trailer::Reader<'_> = reader.get_root()?;
-        Ok(Self {})
+        let msg_reader: session_capnp::file_trailer::Reader<'_> = reader.get_root()?;
+        let hash = if msg_reader.has_hash() {
+            let bytes: [u8; blake3::OUT_LEN] = msg_reader
+                .get_hash()?
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("invalid hash in FileTrailer"))?;
+            Some(blake3::Hash::from_bytes(bytes))
+        } else {
+            None
+        };
+        let compressed_size = Some(msg_reader.get_compressed_size()).filter(|s| *s != 0);
+        Ok(Self {
+            hash,
+            compressed_size,
+        })
+    }
+
+    /// Checks the hash in this trailer, if there was one, against that of the data received
+    #[must_use]
+    pub fn verify(&self, received: &blake3::Hash) -> bool {
+        self.hash.map_or(true, |h| h == *received)
     }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::{Command, FileHeader, FileTrailer, Response, Status};
+    use std::time::Duration;
+
+    use super::{
+        with_timeout, Command, CommandType, FileHeader, FileTrailer, FollowEvent, Response, Status,
+        StatusError,
+    };
     #[test]
     fn marshal_size() {
         // not really a test - just a sanity check that nothing has broken
@@ -266,11 +955,281 @@ mod tests {
         .serialize();
         assert!(r.len() >= 32);
         println!("Response with msg 5 {}", r.len());
-        let head = FileHeader::serialize_direct(1234, "foo");
+        let head = FileHeader {
+            size: 1234,
+            filename: "foo".into(),
+            ..Default::default()
+        }
+        .serialize();
         println!("File Header {}", head.len());
         assert!(head.len() >= 32);
-        let trail = FileTrailer::serialize_direct();
+        let trail = FileTrailer::serialize_direct(None, None);
         println!("File Trailer {}", trail.len());
         assert!(trail.len() >= 16);
     }
+
+    #[test]
+    fn permitted_commands() {
+        let get_only = [CommandType::Get];
+        assert!(Command::new_get("foo").is_permitted(&get_only));
+        assert!(!Command::new_put("foo").is_permitted(&get_only));
+        assert!(!Command::new_mkdir("foo").is_permitted(&get_only));
+        // Stat reveals no more than a Get would
+        assert!(Command::new_stat("foo", "").is_permitted(&get_only));
+        assert!(Command::new_stat("foo", "").is_permitted(&[CommandType::Put]));
+        assert!(!Command::new_stat("foo", "").is_permitted(&[]));
+        // Removing the source deletes the server's file
+        let remove = Command::new_get("foo").with_remove_source(true);
+        assert!(remove.modifies());
+        assert!(!remove.is_permitted(&get_only));
+        assert!(!remove.is_permitted(&[CommandType::Get, CommandType::Put]));
+        assert!(remove.is_permitted(&[CommandType::Get, CommandType::Delete]));
+        // Sending files does not imply deleting them
+        let delete = Command::new_delete("foo");
+        assert!(delete.modifies());
+        assert!(!delete.is_permitted(&[CommandType::Get, CommandType::Put]));
+        assert!(delete.is_permitted(CommandType::ALL));
+    }
+
+    #[test]
+    fn command_type_names() {
+        use std::str::FromStr as _;
+        assert_eq!(CommandType::Get.as_ref(), "get");
+        assert_eq!(CommandType::from_str("put").unwrap(), CommandType::Put);
+        let t: CommandType = serde_json::from_str("\"GET\"").unwrap();
+        assert_eq!(t, CommandType::Get);
+        assert_eq!(Command::new_put("foo").command_type(), CommandType::Put);
+        assert_eq!(Command::new_mkdir("foo").command_type(), CommandType::Put);
+        assert_eq!(
+            Command::new_stat("foo", "bar").command_type(),
+            CommandType::Put
+        );
+        assert_eq!(
+            Command::new_delete("foo").command_type(),
+            CommandType::Delete
+        );
+    }
+
+    #[tokio::test]
+    async fn command_round_trip() {
+        let wire = Command::new_resumed_get("foo", 12)
+            .with_remove_source(true)
+            .serialize();
+        let Command::Get(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert_eq!((args.filename.as_str(), args.resume_offset), ("foo", 12));
+        assert!(args.remove_source);
+
+        let wire = Command::new_resumed_put("foo", 1234).serialize();
+        let Command::Put(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert_eq!(args.filename, "foo");
+        assert_eq!(args.resume_offset, 1234);
+        assert!(!args.append);
+        assert!(!args.mkpath);
+        assert!(!args.fsync);
+        assert!(!args.no_clobber);
+
+        let wire = Command::new_appending_put("foo").serialize();
+        let Command::Put(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert!(args.append);
+
+        let wire = Command::new_put("foo")
+            .with_mkpath(true)
+            .with_fsync(true)
+            .with_recursive(true)
+            .with_no_clobber(true)
+            .serialize();
+        let Command::Put(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert!(args.mkpath);
+        assert!(args.fsync);
+        assert!(args.recursive);
+        assert!(args.no_clobber);
+
+        let wire = Command::new_stat("dir/", "file").serialize();
+        let Command::Stat(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert_eq!(
+            (args.path.as_str(), args.filename.as_str()),
+            ("dir/", "file")
+        );
+
+        let wire = Command::new_symlink("dest/link", "../target").serialize();
+        let Command::Symlink(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert_eq!(
+            (args.linkpath.as_str(), args.target.as_str()),
+            ("dest/link", "../target")
+        );
+
+        let wire = Command::new_list("dest/dir").serialize();
+        let Command::List(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert_eq!(args.path, "dest/dir");
+
+        let wire = Command::new_delete("dest/dir/file").serialize();
+        let Command::Delete(args) = Command::read(&mut wire.as_slice()).await.unwrap() else {
+            panic!("wrong command type");
+        };
+        assert_eq!(args.path, "dest/dir/file");
+
+        let wire = Command::new_follow("var/log/app.log", 1234).serialize();
+        let cmd = Command::read(&mut wire.as_slice()).await.unwrap();
+        assert_eq!(cmd.command_type(), CommandType::Get);
+        let Command::Follow(args) = cmd else {
+            panic!("wrong command type");
+        };
+        assert_eq!(
+            (args.filename.as_str(), args.offset),
+            ("var/log/app.log", 1234)
+        );
+    }
+
+    #[tokio::test]
+    async fn follow_event_round_trip() {
+        let events = [
+            FollowEvent::Data(4096),
+            FollowEvent::Rotated,
+            FollowEvent::Truncated,
+        ];
+        let wire: Vec<u8> = events.iter().flat_map(FollowEvent::serialize).collect();
+        let mut read = wire.as_slice();
+        for expected in events {
+            let event = FollowEvent::try_read(&mut read).await.unwrap();
+            assert_eq!(event, Some(expected));
+        }
+        assert!(FollowEvent::try_read(&mut read).await.unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn header_round_trip() {
+        let wire = FileHeader {
+            size: 1234,
+            filename: "dir/foo".into(),
+            mtime: 1_234_567_890_123_456_789,
+            mode: 0o100_644,
+            exact_mode: true,
+            compressed: true,
+        }
+        .serialize();
+        let header = FileHeader::read(&mut wire.as_slice()).await.unwrap();
+        assert_eq!(header.size, 1234);
+        assert_eq!(header.filename, "dir/foo");
+        assert_eq!(header.mtime, 1_234_567_890_123_456_789);
+        assert_eq!(header.mode, 0o100_644);
+        assert!(header.exact_mode);
+        assert!(header.compressed);
+        assert_eq!(header.known_size(), Some(1234));
+
+        let header = FileHeader {
+            size: FileHeader::UNKNOWN_SIZE,
+            ..Default::default()
+        };
+        assert_eq!(header.known_size(), None);
+
+        // The stream may end cleanly instead
+        assert!(FileHeader::try_read(&mut [].as_slice())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(FileHeader::read(&mut [].as_slice()).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn trailer_hash() {
+        let hash = blake3::hash(b"hello");
+        let wire = FileTrailer::serialize_direct(Some(&hash), None);
+        let trailer = FileTrailer::read(&mut wire.as_slice()).await.unwrap();
+        assert_eq!(trailer.hash, Some(hash));
+        assert_eq!(trailer.compressed_size, None);
+        assert!(trailer.verify(&hash));
+        assert!(!trailer.verify(&blake3::hash(b"world")));
+
+        // A peer that doesn't send a hash can't be checked
+        let wire = FileTrailer::serialize_direct(None, None);
+        let trailer = FileTrailer::read(&mut wire.as_slice()).await.unwrap();
+        assert!(trailer.hash.is_none());
+        assert!(trailer.verify(&hash));
+    }
+
+    #[tokio::test]
+    async fn trailer_compressed_size() {
+        let wire = FileTrailer::serialize_direct(None, Some(4321));
+        let trailer = FileTrailer::read(&mut wire.as_slice()).await.unwrap();
+        assert_eq!(trailer.compressed_size, Some(4321));
+    }
+
+    #[tokio::test]
+    async fn padded_trailer() {
+        let hash = blake3::hash(b"hello");
+        let wire = FileTrailer::serialize_padded(Some(&hash), Some(u64::MAX));
+        assert_eq!(wire.len(), FileTrailer::PADDED_LEN);
+        let trailer = FileTrailer::read(&mut wire.as_slice()).await.unwrap();
+        assert_eq!(trailer.hash, Some(hash));
+        assert_eq!(trailer.compressed_size, Some(u64::MAX));
+    }
+
+    #[tokio::test]
+    async fn timeout() {
+        let never = std::future::pending::<anyhow::Result<()>>();
+        let err = with_timeout(Duration::from_millis(10), "response", never)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("no response from the remote"));
+        let ready = async { Ok(42) };
+        assert_eq!(
+            with_timeout(Duration::ZERO, "response", ready)
+                .await
+                .unwrap(),
+            42
+        );
+    }
+
+    #[test]
+    fn status_errors() {
+        let ok = Response {
+            status: Status::Ok,
+            message: None,
+        };
+        assert!(ok.into_result().is_ok());
+
+        let err = Response {
+            status: Status::DirectoryDoesNotExist,
+            message: Some(String::new()),
+        }
+        .into_result()
+        .unwrap_err();
+        assert_eq!(
+            err,
+            StatusError {
+                status: Status::DirectoryDoesNotExist,
+                message: None
+            }
+        );
+        assert_eq!(err.to_string(), "destination directory does not exist");
+
+        let err = Response {
+            status: Status::IoError,
+            message: Some("device on fire".into()),
+        }
+        .into_result()
+        .unwrap_err();
+        assert_eq!(err.to_string(), "I/O error (device on fire)");
+
+        // the status survives a trip through anyhow
+        let any = anyhow::Error::new(err).context("PUT (foo) failed");
+        assert_eq!(
+            any.downcast_ref::<StatusError>().unwrap().status,
+            Status::IoError
+        );
+    }
 }
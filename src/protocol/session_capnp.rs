@@ -6,6 +6,7 @@
     single_use_lifetimes,
     unreachable_pub,
     missing_docs,
+    missing_copy_implementations,
     clippy::expl_impl_clone_on_copy,
     clippy::match_same_arms,
     clippy::missing_panics_doc,
@@ -1,26 +1,31 @@
 //! server-side _(remote)_ event loop
 // (c) 2024 Ross Younger
 
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
 
 use crate::config::Configuration;
 use crate::protocol::control::{ClientMessage, ClosedownReport, ServerMessage};
-use crate::protocol::session::{Command, FileHeader, FileTrailer, Response, Status};
+use crate::protocol::session::{
+    with_timeout, Command, CommandType, FileHeader, FileTrailer, FollowArgs, FollowEvent, GetArgs,
+    PutArgs, Response, Status,
+};
 use crate::protocol::{self, StreamPair};
 use crate::transport::ThroughputMode;
-use crate::util::{io, socket, Credentials};
+use crate::util::follow::{self, Follower};
+use crate::util::{compress, compress::Compress, io, mmap, socket, AllowedPaths, Credentials};
 
 use anyhow::Context as _;
+use human_repr::HumanCount as _;
 use quinn::crypto::rustls::QuicServerConfig;
 use quinn::rustls::server::WebPkiClientVerifier;
 use quinn::rustls::{self, RootCertStore};
-use quinn::{ConnectionStats, EndpointConfig};
+use quinn::ConnectionStats;
 use rustls_pki_types::CertificateDer;
-use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _, BufReader};
+use tokio::io::{AsyncSeekExt as _, AsyncWriteExt as _};
 use tokio::sync::oneshot;
 use tokio::task::JoinSet;
-use tokio::time::timeout;
+use tokio::time::{timeout, Duration};
 use tracing::{debug, error, info, trace, trace_span, warn, Instrument};
 
 /// Server event loop
@@ -47,21 +52,53 @@ pub async fn server_main(config: &Configuration) -> anyhow::Result<()> {
         client_message.cert.len(),
         client_message.connection_type,
     );
+    let protocol_version = negotiate_version(&mut stdout, client_message.protocol_version).await?;
 
-    let bandwidth_info = config.format_transport_config().to_string();
-    let file_buffer_size = usize::try_from(Configuration::send_buffer())?;
+    let allowed = config.allowed_commands();
+    anyhow::ensure!(
+        !allowed.is_empty(),
+        "server configuration does not allow any commands"
+    );
+    debug!("allowed commands: {allowed:?}");
+    let allowed_paths = AllowedPaths::new(&config.allow_path)?;
+    if allowed_paths.is_restricted() {
+        debug!("allowed paths: {allowed_paths}");
+    }
 
-    let credentials = Credentials::generate()?;
+    let bandwidth_info = config.format_transport_config();
+    let settings = StreamSettings {
+        file_buffer_size: usize::try_from(Configuration::send_buffer())?,
+        bwlimit: config.bwlimit(),
+        allowed: allowed.into(),
+        read_only: config.read_only,
+        max_file_size: *config.max_file_size,
+        allowed_paths,
+        io_limiter: io::IoLimiter::new(config.io_concurrency),
+        compression: client_message.compression,
+        preallocate: config.preallocate,
+        mmap: config.mmap,
+        protocol_timeout: config.protocol_timeout_duration(),
+        protocol_version,
+    };
+    let credentials = Credentials::load_or_generate(&config.tls_cert, &config.tls_key)?;
     let (endpoint, warning) = create_endpoint(&credentials, client_message, config)?;
     let local_addr = endpoint.local_addr()?;
     debug!("Local address is {local_addr}");
+    let (port, advertised_address) = advertised_endpoint(config, local_addr.port());
+    if port != local_addr.port() || advertised_address.is_some() {
+        debug!("Advertising port {port}, address {advertised_address:?}");
+    }
     ServerMessage::write(
         &mut stdout,
-        local_addr.port(),
+        port,
         &credentials.certificate,
         &credentials.hostname,
         warning.as_deref(),
         &bandwidth_info,
+        advertised_address,
+        &settings.allowed,
+        true, // we can always receive compressed data
+        protocol_version,
     )
     .await?;
     stdout.flush().await?;
@@ -72,14 +109,15 @@ pub async fn server_main(config: &Configuration) -> anyhow::Result<()> {
     // Wait for a successful connection OR timeout OR for stdin to be closed (implicitly handled).
     // We have tight control over what we expect (TLS peer certificate/name) so only need to handle one successful connection,
     // but a timeout is useful to give the user a cue that UDP isn't getting there.
-    trace!("waiting for QUIC");
     let (stats_tx, mut stats_rx) = oneshot::channel();
-    if let Some(conn) = timeout(config.timeout_duration(), endpoint.accept())
+    let accept_timeout = config.accept_timeout_duration();
+    debug!("waiting up to {accept_timeout:?} for QUIC");
+    if let Some(conn) = timeout(accept_timeout, endpoint.accept())
         .await
         .with_context(|| "Timed out waiting for QUIC connection")?
     {
         let _ = tasks.spawn(async move {
-            let result = handle_connection(conn, file_buffer_size).await;
+            let result = handle_connection(conn, settings).await;
             match result {
                 Err(e) => error!("inward stream failed: {reason}", reason = e.to_string()),
                 Ok(conn_stats) => {
@@ -101,10 +139,59 @@ pub async fn server_main(config: &Configuration) -> anyhow::Result<()> {
     endpoint.wait_idle().await;
     let stats = stats_rx.try_recv().unwrap_or_default();
     ClosedownReport::write(&mut stdout, &stats).await?;
+    stdout.flush().await?;
     trace!("finished");
     Ok(())
 }
 
+/// Selects the protocol version to use, given the newest version the client supports.
+///
+/// If there is none in common, tells the client which version we support (so it can report the problem), and fails.
+async fn negotiate_version(
+    stdout: &mut tokio::io::Stdout,
+    client_version: u16,
+) -> anyhow::Result<u16> {
+    use protocol::control::OLDEST_PROTOCOL_VERSION;
+    if let Some(version) = protocol::control::select_version(client_version) {
+        debug!("using protocol version {version}");
+        return Ok(version);
+    }
+    let message = format!(
+        "incompatible protocol: client v{client_version}, server v{OLDEST_PROTOCOL_VERSION}"
+    );
+    ServerMessage::write(
+        stdout,
+        0,
+        &[],
+        "",
+        Some(&message),
+        "",
+        None,
+        &[],
+        false,
+        OLDEST_PROTOCOL_VERSION,
+    )
+    .await?;
+    stdout.flush().await?;
+    anyhow::bail!(message);
+}
+
+/// Determines the port and address to advertise to the client in the [`ServerMessage`].
+///
+/// The configured `advertise_port` and `advertise_address`, if set, override the values from the local socket.
+/// Otherwise, if the socket was bound to a specific `bind_address`, that is the address to advertise.
+fn advertised_endpoint(config: &Configuration, bound_port: u16) -> (u16, Option<&str>) {
+    let port = match config.advertise_port {
+        0 => bound_port,
+        p => p,
+    };
+    let address = [&config.advertise_address, &config.bind_address]
+        .into_iter()
+        .find(|a| !a.is_empty())
+        .map(String::as_str);
+    (port, address)
+}
+
 fn create_endpoint(
     credentials: &Credentials,
     client_message: ClientMessage,
@@ -119,6 +206,9 @@ fn create_endpoint(
         .with_client_cert_verifier(verifier)
         .with_single_cert(credentials.cert_chain(), credentials.keypair.clone_key())?;
     tls_config.max_early_data_size = u32::MAX;
+    if let Some(key_log) = crate::util::key_log() {
+        tls_config.key_log = key_log;
+    }
 
     let qsc = QuicServerConfig::try_from(tls_config)?;
     let mut server = quinn::ServerConfig::with_crypto(Arc::new(qsc));
@@ -127,25 +217,60 @@ fn create_endpoint(
         ThroughputMode::Both,
     )?);
 
-    let mut socket = socket::bind_range_for_family(client_message.connection_type, transport.port)?;
+    let mut socket = socket::bind_range_for_family(
+        client_message.connection_type,
+        transport.port,
+        transport.bind_ip()?,
+    )?;
+    socket::bind_to_interface(&socket, &transport.bind_interface)?;
     // We don't know whether client will send or receive, so configure for both.
     let wanted_send = Some(usize::try_from(Configuration::send_buffer())?);
     let wanted_recv = Some(usize::try_from(Configuration::recv_buffer())?);
     let warning = socket::set_udp_buffer_sizes(&mut socket, wanted_send, wanted_recv)?
         .inspect(|s| warn!("{s}"));
+    socket::set_dscp(&socket, transport.dscp)?;
 
-    // SOMEDAY: allow user to specify max_udp_payload_size in endpoint config, to support jumbo frames
     let runtime =
         quinn::default_runtime().ok_or_else(|| anyhow::anyhow!("no async runtime found"))?;
     Ok((
-        quinn::Endpoint::new(EndpointConfig::default(), Some(server), socket, runtime)?,
+        quinn::Endpoint::new(
+            crate::transport::endpoint_config(transport)?,
+            Some(server),
+            socket,
+            runtime,
+        )?,
         warning,
     ))
 }
 
+/// Settings which apply to every stream on the connection
+#[derive(Clone, Debug)]
+struct StreamSettings {
+    file_buffer_size: usize,
+    /// Rate limit for file data we send (0 = no limit)
+    bwlimit: u64,
+    allowed: Arc<[CommandType]>,
+    /// The largest file we will send or receive (0 = no limit)
+    max_file_size: u64,
+    /// Whether the server is download-only (`read_only`), for explaining rejections
+    read_only: bool,
+    /// The directories clients may access (`allow_path`)
+    allowed_paths: AllowedPaths,
+    io_limiter: io::IoLimiter,
+    /// Whether to compress file data we send; the client tells us
+    compression: Compress,
+    preallocate: bool,
+    /// Whether to map large files we send into memory
+    mmap: bool,
+    /// How long to wait for each protocol message from the client (zero = no limit)
+    protocol_timeout: Duration,
+    /// The control protocol version in use
+    protocol_version: u16,
+}
+
 async fn handle_connection(
     conn: quinn::Incoming,
-    file_buffer_size: usize,
+    settings: StreamSettings,
 ) -> anyhow::Result<ConnectionStats> {
     let connection = conn.await?;
     debug!("accepted connection from {}", connection.remote_address());
@@ -170,8 +295,9 @@ async fn handle_connection(
                 Ok(s) => StreamPair::from(s),
             };
             trace!("opened stream");
+            let settings = settings.clone();
             let _j = tokio::spawn(async move {
-                if let Err(e) = handle_stream(stream, file_buffer_size).await {
+                if let Err(e) = handle_stream(stream, &settings).await {
                     error!("stream failed: {e}",);
                 }
             });
@@ -181,32 +307,119 @@ async fn handle_connection(
     Ok(connection.stats())
 }
 
-async fn handle_stream(mut sp: StreamPair, file_buffer_size: usize) -> anyhow::Result<()> {
+/// Explains why a command is not permitted, if it isn't
+fn refusal(cmd: &Command, allowed: &[CommandType], read_only: bool) -> Option<&'static str> {
+    if cmd.is_permitted(allowed) {
+        None
+    } else if read_only && cmd.modifies() {
+        Some("the server is read-only")
+    } else {
+        Some("the server does not allow this command")
+    }
+}
+
+async fn handle_stream(mut sp: StreamPair, settings: &StreamSettings) -> anyhow::Result<()> {
     trace!("reading command");
-    let cmd = Command::read(&mut sp.recv).await?;
+    let cmd = with_timeout(
+        settings.protocol_timeout,
+        "command",
+        Command::read(&mut sp.recv),
+    )
+    .await?;
+    if let Some(message) = refusal(&cmd, &settings.allowed, settings.read_only) {
+        warn!("rejecting {} command ({message})", cmd.command_type());
+        return send_response(&mut sp.send, Status::CommandNotPermitted, Some(message)).await;
+    }
+    if !path_permitted(&cmd, &settings.allowed_paths) {
+        warn!("rejecting {} command (outside the allowed paths)", cmd);
+        let (status, message) = OUTSIDE_ALLOWED_PATHS;
+        return send_response(&mut sp.send, status, message).await;
+    }
+    let _permit = settings.io_limiter.acquire().await;
     match cmd {
         Command::Get(get) => {
-            handle_get(sp, get.filename.clone(), file_buffer_size)
+            handle_get(sp, &get, settings)
                 .instrument(trace_span!("SERVER:GET", filename = get.filename))
                 .await
         }
         Command::Put(put) => {
-            handle_put(sp, put.filename.clone())
+            handle_put(sp, &put, settings)
                 .instrument(trace_span!("SERVER:PUT", destination = put.filename))
                 .await
         }
+        Command::Mkdir(mkdir) => {
+            handle_mkdir(sp, mkdir.dirname.clone())
+                .instrument(trace_span!("SERVER:MKDIR", dirname = mkdir.dirname))
+                .await
+        }
+        Command::Stat(stat) => {
+            handle_stat(sp, &stat.path, &stat.filename, &settings.allowed_paths)
+                .instrument(trace_span!("SERVER:STAT", path = stat.path))
+                .await
+        }
+        Command::Symlink(symlink) => {
+            handle_symlink(sp, &symlink.linkpath, &symlink.target)
+                .instrument(trace_span!("SERVER:SYMLINK", linkpath = symlink.linkpath))
+                .await
+        }
+        Command::List(list) => {
+            handle_list(sp, PathBuf::from(&list.path))
+                .instrument(trace_span!("SERVER:LIST", path = list.path))
+                .await
+        }
+        Command::Delete(delete) => {
+            handle_delete(sp, Path::new(&delete.path))
+                .instrument(trace_span!("SERVER:DELETE", path = delete.path))
+                .await
+        }
+        Command::Follow(follow) => {
+            handle_follow(sp, &follow, settings.file_buffer_size)
+                .instrument(trace_span!("SERVER:FOLLOW", filename = follow.filename))
+                .await
+        }
+    }
+}
+
+/// The response to a request for something outside the server's allowed paths
+const OUTSIDE_ALLOWED_PATHS: (Status, Option<&str>) = (
+    Status::IncorrectPermissions,
+    Some("outside the paths this server allows"),
+);
+
+/// Checks the path a command refers to against the server's allowed paths.
+///
+/// Where a Put or Stat ends up depends on the filename as well, so that is checked again once it is known.
+fn path_permitted(cmd: &Command, allowed: &AllowedPaths) -> bool {
+    match cmd {
+        Command::Get(get) => allowed.permits(Path::new(&get.filename)),
+        Command::Put(put) => allowed.permits(&put_destination_path(&put.filename)),
+        Command::Mkdir(mkdir) => allowed.permits(Path::new(&mkdir.dirname)),
+        Command::Stat(stat) => allowed.permits(Path::new(&stat.path)),
+        Command::List(list) => allowed.permits(Path::new(&list.path)),
+        Command::Follow(follow) => allowed.permits(Path::new(&follow.filename)),
+        // These act on the directory entry itself, not what a symbolic link there points to
+        Command::Symlink(symlink) => allowed.permits_entry(Path::new(&symlink.linkpath)),
+        Command::Delete(delete) => allowed.permits_entry(Path::new(&delete.path)),
     }
 }
 
 async fn handle_get(
     mut stream: StreamPair,
-    filename: String,
-    file_buffer_size: usize,
+    args: &GetArgs,
+    settings: &StreamSettings,
 ) -> anyhow::Result<()> {
     trace!("begin");
+    let StreamSettings {
+        file_buffer_size,
+        bwlimit,
+        compression,
+        max_file_size,
+        mmap,
+        ..
+    } = *settings;
 
-    let path = PathBuf::from(&filename);
-    let (file, meta) = match io::open_file(&filename).await {
+    let path = PathBuf::from(&args.filename);
+    let (mut file, meta) = match io::open_file(&args.filename).await {
         Ok(res) => res,
         Err((status, message, _)) => {
             return send_response(&mut stream.send, status, message.as_deref()).await;
@@ -215,136 +428,755 @@ async fn handle_get(
     if meta.is_dir() {
         return send_response(&mut stream.send, Status::ItIsADirectory, None).await;
     }
-    let mut file = BufReader::with_capacity(file_buffer_size, file);
+    if max_file_size != 0 && meta.len() > max_file_size {
+        let message = too_large(max_file_size);
+        warn!("refusing to send {} ({message})", path.display());
+        return send_response(
+            &mut stream.send,
+            Status::CommandNotPermitted,
+            Some(&message),
+        )
+        .await;
+    }
+    // If the file has shrunk, the client will notice from the header and start again
+    let resume_offset = std::cmp::min(args.resume_offset, meta.len());
+    if resume_offset > 0 {
+        debug!("resuming at offset {resume_offset}");
+        if let Err(e) = file.seek(std::io::SeekFrom::Start(resume_offset)).await {
+            return send_response(&mut stream.send, Status::IoError, Some(&e.to_string())).await;
+        }
+    }
+    let mut file = mmap::source_reader(file, meta.len(), resume_offset, file_buffer_size, mmap);
 
     // We believe we can fulfil this request.
     trace!("responding OK");
     send_response(&mut stream.send, Status::Ok, None).await?;
 
     let protocol_filename = path.file_name().unwrap().to_str().unwrap(); // can't fail with the preceding checks
+    let compressed = compression.applies_to(protocol_filename);
 
-    let header = FileHeader::serialize_direct(meta.len(), protocol_filename);
+    let header = FileHeader {
+        size: meta.len(),
+        filename: protocol_filename.to_string(),
+        mtime: io::mtime_nanos(&meta),
+        mode: io::mode_bits(&meta),
+        exact_mode: false, // the client decides how to apply it
+        compressed,
+    }
+    .serialize();
     stream.send.write_all(&header).await?;
 
     trace!("sending file payload");
-    let result = tokio::io::copy_buf(&mut file, &mut stream.send).await;
-    match result {
-        Ok(sent) if sent == meta.len() => (),
+    let to_send = meta.len() - resume_offset;
+    let mut outbound = io::RateLimitedWriter::new(&mut stream.send, bwlimit);
+    let sent = match compress::send_payload(&mut file, &mut outbound, compressed).await {
+        Ok(sent) if sent.bytes == to_send => sent,
         Ok(sent) => {
             error!(
-                "File sent size {sent} doesn't match its metadata {}",
-                meta.len()
+                "File sent size {} doesn't match its metadata {to_send}",
+                sent.bytes
             );
             return Ok(());
         }
+        Err(e)
+            if matches!(
+                e.kind(),
+                std::io::ErrorKind::ConnectionReset | std::io::ErrorKind::NotConnected
+            ) =>
+        {
+            // This is how a dry run ends, or the client may have been interrupted (and closed the connection)
+            debug!("client stopped the transfer: {e}");
+            return Ok(());
+        }
         Err(e) => {
             error!("Error during io::copy: {e}");
             return Ok(());
         }
-    }
+    };
 
     trace!("sending trailer");
-    let trailer = FileTrailer::serialize_direct();
+    let trailer = FileTrailer::serialize_direct(Some(&sent.hash), sent.compressed_size);
     stream.send.write_all(&trailer).await?;
     stream.send.flush().await?;
+    if args.remove_source {
+        drop(file);
+        remove_sent_file(&mut stream, &path, &meta, settings.protocol_timeout).await?;
+    }
     trace!("complete");
     Ok(())
 }
 
-async fn handle_put(mut stream: StreamPair, destination: String) -> anyhow::Result<()> {
+async fn handle_follow(
+    mut stream: StreamPair,
+    args: &FollowArgs,
+    file_buffer_size: usize,
+) -> anyhow::Result<()> {
     trace!("begin");
+    let path = PathBuf::from(&args.filename);
+    let (file, meta) = match io::open_file(&args.filename).await {
+        Ok(res) => res,
+        Err((status, message, _)) => {
+            return send_response(&mut stream.send, status, message.as_deref()).await;
+        }
+    };
+    if meta.is_dir() {
+        return send_response(&mut stream.send, Status::ItIsADirectory, None).await;
+    }
+    let mut follower = match Follower::new(&path, file, args.offset).await {
+        Ok(f) => f,
+        Err(e) => {
+            return send_response(&mut stream.send, Status::IoError, Some(&e.to_string())).await;
+        }
+    };
+    send_response(&mut stream.send, Status::Ok, None).await?;
 
-    // Initial checks. Is the destination valid?
-    let mut path = PathBuf::from(destination);
-    // This is moderately tricky. It might validly be empty, a directory, a file, it might be a nonexistent file in an extant directory.
+    let mut buf = vec![0; file_buffer_size];
+    loop {
+        let Some(event) = follower.next(&mut buf).await? else {
+            tokio::select! {
+                () = tokio::time::sleep(follow::POLL_INTERVAL) => continue,
+                _ = stream.send.stopped() => {
+                    debug!("client stopped following");
+                    return Ok(());
+                }
+            }
+        };
+        let data = match event {
+            FollowEvent::Data(n) => &buf[..usize::try_from(n)?],
+            FollowEvent::Rotated | FollowEvent::Truncated => &[][..],
+        };
+        let sent = async {
+            stream.send.write_all(&event.serialize()).await?;
+            stream.send.write_all(data).await
+        };
+        if let Err(e) = sent.await {
+            debug!("client stopped following: {e}");
+            return Ok(());
+        }
+        if !matches!(event, FollowEvent::Data(_)) {
+            // The client starts again with whatever is now at the path
+            debug!("{}: {event:?}", path.display());
+            stream.send.finish()?;
+            trace!("complete");
+            return Ok(());
+        }
+    }
+}
 
-    if path.as_os_str().is_empty() {
-        // This is the case "qcp some-file host:"
-        // Copy to the current working directory
-        path.push(".");
+/// Deletes the source of a Get with `remove_source`, once the client has confirmed that it received the file.
+///
+/// The file is kept if the client reports a problem or closes the stream, or if it changed while it was being sent.
+async fn remove_sent_file(
+    stream: &mut StreamPair,
+    path: &Path,
+    sent: &std::fs::Metadata,
+    limit: Duration,
+) -> anyhow::Result<()> {
+    match with_timeout(limit, "response", Response::read(&mut stream.recv)).await {
+        Ok(Response {
+            status: Status::Ok, ..
+        }) => (),
+        Ok(response) => {
+            debug!("client reported {response}; keeping {}", path.display());
+            return Ok(());
+        }
+        Err(e) => {
+            debug!(
+                "client did not confirm receipt; keeping {}: {e}",
+                path.display()
+            );
+            return Ok(());
+        }
     }
-    let append_filename = if path.is_dir() || path.is_file() {
-        // Destination exists
-        if !io::dest_is_writeable(&path).await {
-            return send_response(
-                &mut stream.send,
-                Status::IncorrectPermissions,
-                Some("cannot write to destination"),
-            )
-            .await;
+    let (status, message) = match io::remove_if_unchanged(path, sent).await {
+        Ok(()) => {
+            debug!("removed {}", path.display());
+            (Status::Ok, None)
         }
-        // append filename only if it is a directory
-        path.is_dir()
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            (Status::IncorrectPermissions, Some(e.to_string()))
+        }
+        Err(e) => (Status::IoError, Some(e.to_string())),
+    };
+    send_response(&mut stream.send, status, message.as_deref()).await?;
+    stream.send.flush().await?;
+    Ok(())
+}
+
+/// The path to which a PUT writes, before any filename from the client's header is appended
+fn put_destination_path(filename: &str) -> PathBuf {
+    if filename.is_empty() {
+        // This is the case "qcp some-file host:"
+        // Copy to the current working directory
+        PathBuf::from(".")
     } else {
-        // Is it a nonexistent file in a valid directory?
-        let mut path_test = path.clone();
-        let _ = path_test.pop();
-        if path_test.as_os_str().is_empty() {
-            // We're writing a file to the current working directory, so apply the is_dir writability check
-            path_test.push(".");
-        }
-        if path_test.is_dir() {
-            if !io::dest_is_writeable(&path_test).await {
-                return send_response(
-                    &mut stream.send,
-                    Status::IncorrectPermissions,
-                    Some("cannot write to destination"),
-                )
-                .await;
-            }
-            // Yes, we can write there; destination path is fully specified.
-            false
-        } else {
-            // No parent directory
-            return send_response(&mut stream.send, Status::DirectoryDoesNotExist, None).await;
+        PathBuf::from(filename)
+    }
+}
+
+/// Interprets the filename the client sent in the [`FileHeader`] of a PUT to a directory.
+///
+/// The filename must not be absolute, or try to escape the destination.
+/// Unless this is part of a recursive copy, only its final component is used.
+fn put_filename(
+    filename: &str,
+    recursive: bool,
+) -> Result<PathBuf, (Status, Option<&'static str>)> {
+    const UNSAFE: (Status, Option<&str>) = (
+        Status::IncorrectPermissions,
+        Some("filename must be relative to the destination"),
+    );
+    let relative = io::relative_path(filename).ok_or(UNSAFE)?;
+    if recursive {
+        return Ok(relative);
+    }
+    relative.file_name().map(PathBuf::from).ok_or(UNSAFE)
+}
+
+async fn handle_put(
+    mut stream: StreamPair,
+    args: &PutArgs,
+    settings: &StreamSettings,
+) -> anyhow::Result<()> {
+    trace!("begin");
+    let PutArgs {
+        resume_offset,
+        append,
+        mkpath,
+        fsync,
+        no_clobber,
+        ..
+    } = *args;
+    let StreamSettings {
+        preallocate,
+        protocol_timeout,
+        ..
+    } = *settings;
+
+    // Initial checks. Is the destination valid?
+    let path = put_destination_path(&args.filename);
+    let append_filename = match check_put_destination(&path, append, mkpath).await {
+        Ok(a) => a,
+        Err((status, message)) => {
+            return send_response(&mut stream.send, status, message).await;
         }
     };
 
     // So far as we can tell, we believe we can fulfil this request.
-    trace!("responding OK");
-    let ((), header) = tokio::try_join!(
-        send_response(&mut stream.send, Status::Ok, None),
-        FileHeader::read(&mut stream.recv)
-    )?;
+    let Some(header) = accept_put(&mut stream, protocol_timeout).await? else {
+        // This is how a dry run ends
+        return Ok(());
+    };
 
     debug!("PUT {} -> destination", &header.filename);
-    if append_filename {
-        path.push(header.filename);
+    if resume_offset > 0 {
+        debug!("resuming at offset {resume_offset}");
+    }
+    let (version, allowed) = (settings.protocol_version, &settings.allowed_paths);
+    let path = put_file_path(
+        path,
+        append_filename,
+        &header.filename,
+        args,
+        version,
+        allowed,
+    );
+    let path = match path {
+        Ok(p) => p,
+        Err((status, message)) => return send_response(&mut stream.send, status, message).await,
+    };
+    if let Err(message) = check_put_size(&header, &path, append, settings.max_file_size).await {
+        warn!("refusing to write {} ({message})", path.display());
+        return send_response(
+            &mut stream.send,
+            Status::CommandNotPermitted,
+            Some(&message),
+        )
+        .await;
     }
-    let mut file = match tokio::fs::File::create(path).await {
+    if no_clobber {
+        // The client waits to hear whether to proceed before sending the data
+        send_response(&mut stream.send, Status::Ok, None).await?;
+    }
+    let create_parent = append_filename || mkpath;
+    let opened = open_put_destination(&path, &header, args, create_parent, preallocate).await;
+    let (mut file, appended_to) = match opened {
         Ok(f) => f,
         Err(e) => {
-            error!("Could not write to destination: {e}");
-            return Ok(());
+            error!("{e}");
+            return send_response(&mut stream.send, Status::IoError, Some(&e.to_string())).await;
         }
     };
-    if file
-        .set_len(header.size)
-        .await
-        .inspect_err(|e| error!("Could not set destination file length: {e}"))
-        .is_err()
-    {
+
+    trace!("receiving file payload");
+    let to_receive = header.known_size().map_or(FileHeader::UNKNOWN_SIZE, |s| {
+        s.saturating_sub(resume_offset)
+    });
+    let received = compress::receive_payload(
+        &mut stream.recv,
+        &mut file,
+        to_receive,
+        header.compressed,
+        protocol_timeout,
+    )
+    .await;
+    let (trailer, hash) = match received {
+        Ok(r) => r,
+        Err(e) => return receive_failed(&e, &mut stream.send, &file, &path, appended_to).await,
+    };
+    if !trailer.verify(&hash) {
+        discard_corrupt(file, &path, appended_to).await;
+        return send_response(&mut stream.send, Status::ChecksumMismatch, None).await;
+    }
+
+    if fsync {
+        if let Err(e) = sync_received(&mut file, &path).await {
+            error!("{e}");
+            return send_response(&mut stream.send, Status::IoError, Some(&e.to_string())).await;
+        }
+    }
+    send_response(&mut stream.send, Status::Ok, None).await?;
+    stream.send.flush().await?;
+    drop(file);
+    // The client only sends metadata if it wants us to preserve it
+    io::apply_mtime(&path, header.mtime);
+    io::apply_mode(&path, header.mode, header.exact_mode);
+    trace!("complete");
+    Ok(())
+}
+
+/// Works out the full path of the file to write in a Put, once we have its header.
+///
+/// If the destination is a directory (`append_filename`), the filename in the header is appended.
+/// In a recursive copy that may be a relative path, whose directories we create as needed.
+/// The result must be within the server's allowed paths. With `no_clobber`, the file must not already exist.
+fn put_file_path(
+    mut path: PathBuf,
+    append_filename: bool,
+    filename: &str,
+    args: &PutArgs,
+    protocol_version: u16,
+    allowed: &AllowedPaths,
+) -> Result<PathBuf, (Status, Option<&'static str>)> {
+    if append_filename {
+        // Version 1 clients didn't tell us whether they were doing that.
+        let recursive = args.recursive || protocol_version < 2;
+        let relative = put_filename(filename, recursive)
+            .inspect_err(|_| error!("Refusing unsafe filename {filename}"))?;
+        path.push(relative);
+    }
+    if !allowed.permits(&path) {
+        warn!(
+            "refusing to write {} (outside the allowed paths)",
+            path.display()
+        );
+        return Err(OUTSIDE_ALLOWED_PATHS);
+    }
+    if args.no_clobber && path.symlink_metadata().is_ok() {
+        debug!("not overwriting {}", path.display());
+        return Err((Status::DestinationExists, None));
+    }
+    Ok(path)
+}
+
+/// Accepts a Put, responding OK to the command while reading the [`FileHeader`] which follows.
+///
+/// Returns None if the client closed the stream instead of sending a file.
+async fn accept_put(
+    stream: &mut StreamPair,
+    protocol_timeout: Duration,
+) -> anyhow::Result<Option<FileHeader>> {
+    trace!("responding OK");
+    let header = FileHeader::try_read(&mut stream.recv);
+    let header = with_timeout(protocol_timeout, "file header", header);
+    let ((), header) = tokio::try_join!(send_response(&mut stream.send, Status::Ok, None), header)?;
+    if header.is_none() {
+        debug!("client closed the stream without sending a file");
+    }
+    Ok(header)
+}
+
+/// Explains that a file exceeds the server's `max_file_size`
+fn too_large(max_file_size: u64) -> String {
+    format!(
+        "the file is larger than the server allows ({})",
+        max_file_size.human_count_bytes()
+    )
+}
+
+/// Checks the size of a Put against the server's `max_file_size` (0 = no limit).
+///
+/// When appending, it is the size of the file afterwards that matters.
+/// A file whose size is not known in advance can't be checked, so is refused.
+async fn check_put_size(
+    header: &FileHeader,
+    path: &Path,
+    append: bool,
+    max_file_size: u64,
+) -> Result<(), String> {
+    if max_file_size == 0 {
         return Ok(());
+    }
+    let Some(size) = header.known_size() else {
+        return Err("the server needs to know the size of the file in advance".into());
+    };
+    let existing = if append {
+        tokio::fs::metadata(path).await.map_or(0, |m| m.len())
+    } else {
+        0
     };
+    if existing.saturating_add(size) > max_file_size {
+        return Err(too_large(max_file_size));
+    }
+    Ok(())
+}
 
-    trace!("receiving file payload");
-    let mut limited_recv = stream.recv.take(header.size);
-    if tokio::io::copy(&mut limited_recv, &mut file)
+/// Removes what a Put wrote, after it failed its checksum: the appended data, or else the whole file
+async fn discard_corrupt(file: tokio::fs::File, path: &Path, appended_to: Option<u64>) {
+    error!(
+        "Checksum mismatch on {}; removing {}",
+        path.display(),
+        if appended_to.is_some() {
+            "the appended data"
+        } else {
+            "it"
+        }
+    );
+    if let Some(len) = appended_to {
+        discard_appended(&file, path, len).await;
+    } else {
+        drop(file);
+        discard_file(path).await;
+    }
+}
+
+/// Makes sure a received file, and its directory entry, have reached the disk
+async fn sync_received(file: &mut tokio::fs::File, path: &Path) -> anyhow::Result<()> {
+    file.flush().await?;
+    io::sync_file(file, path).await?;
+    io::sync_parent_directory(path).await
+}
+
+/// Opens the destination file of a Put, first creating its parent directory if `create_parent` is set.
+///
+/// When appending, also returns the original length of the file, so it can be restored if something goes wrong.
+async fn open_put_destination(
+    path: &Path,
+    header: &FileHeader,
+    args: &PutArgs,
+    create_parent: bool,
+    preallocate: bool,
+) -> anyhow::Result<(tokio::fs::File, Option<u64>)> {
+    if create_parent {
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent)
+                .await
+                .map_err(|e| anyhow::anyhow!("Could not create destination directory: {e}"))?;
+        }
+    }
+    let PutArgs {
+        resume_offset,
+        append,
+        ..
+    } = *args;
+    if append {
+        io::open_for_append(path)
+            .await
+            .map(|(f, len)| (f, Some(len)))
+    } else {
+        // If the size is not known, the file grows as the data arrives
+        let size = header.known_size().unwrap_or(resume_offset);
+        io::open_destination(path, size, resume_offset, preallocate)
+            .await
+            .map(|f| (f, None))
+    }
+}
+
+/// Checks whether we can write to the destination of a Put.
+///
+/// If `mkpath` is set, a missing parent directory is not an error, as it will be created.
+///
+/// On success, returns whether the filename from the [`FileHeader`] is to be appended to the path
+/// (i.e. the destination is a directory).
+async fn check_put_destination(
+    path: &Path,
+    append: bool,
+    mkpath: bool,
+) -> Result<bool, (Status, Option<&'static str>)> {
+    // This is moderately tricky. It might validly be a directory, a file, it might be a nonexistent file in an extant directory.
+    const CANNOT_WRITE: (Status, Option<&str>) = (
+        Status::IncorrectPermissions,
+        Some("cannot write to destination"),
+    );
+    if append && path.is_dir() {
+        return Err((Status::ItIsADirectory, Some("cannot append to a directory")));
+    }
+    if path.is_dir() || path.is_file() {
+        // Destination exists
+        if !io::dest_is_writeable(&path.to_path_buf()).await {
+            return Err(CANNOT_WRITE);
+        }
+        // append filename only if it is a directory
+        return Ok(path.is_dir());
+    }
+    if mkpath && path.as_os_str().to_string_lossy().ends_with('/') {
+        // A directory, which we will create
+        return if append {
+            Err((Status::ItIsADirectory, Some("cannot append to a directory")))
+        } else {
+            Ok(true)
+        };
+    }
+    // Is it a nonexistent file in a valid directory?
+    let mut path_test = path.to_path_buf();
+    let _ = path_test.pop();
+    if path_test.as_os_str().is_empty() {
+        // We're writing a file to the current working directory, so apply the is_dir writability check
+        path_test.push(".");
+    }
+    if !path_test.is_dir() {
+        // No parent directory
+        return if mkpath {
+            Ok(false)
+        } else {
+            Err((Status::DirectoryDoesNotExist, None))
+        };
+    }
+    if !io::dest_is_writeable(&path_test).await {
+        return Err(CANNOT_WRITE);
+    }
+    // Yes, we can write there; destination path is fully specified.
+    Ok(false)
+}
+
+/// Logs a failure to receive a file
+fn report_receive_error(e: &anyhow::Error) {
+    match e.downcast_ref::<std::io::Error>() {
+        // The client may have been interrupted, and closed the connection
+        Some(io) if io.kind() == std::io::ErrorKind::NotConnected => {
+            debug!("client stopped the transfer: {e}");
+        }
+        _ => error!("Failed to receive file: {e}"),
+    }
+}
+
+/// Cleans up after a failure to receive the data of a Put.
+///
+/// If we ran out of disk space, the client is told so; it reads the response once we stop reading its data.
+async fn receive_failed(
+    e: &anyhow::Error,
+    send: &mut quinn::SendStream,
+    file: &tokio::fs::File,
+    path: &Path,
+    appended_to: Option<u64>,
+) -> anyhow::Result<()> {
+    report_receive_error(e);
+    if let Some(len) = appended_to {
+        discard_appended(file, path, len).await;
+    }
+    if io::is_disk_full(e) {
+        return send_response(send, Status::DiskFull, None).await;
+    }
+    Ok(())
+}
+
+/// Removes data we appended to a file, which turned out to be bad, by truncating it to its original length
+async fn discard_appended(file: &tokio::fs::File, path: &Path, len: u64) {
+    let _ = file
+        .set_len(len)
         .await
-        .inspect_err(|e| error!("Failed to write to destination: {e}"))
-        .is_err()
-    {
-        return Ok(());
+        .inspect_err(|e| error!("Could not truncate {}: {e}", path.display()));
+}
+
+/// Removes a file we received, which turned out to be bad
+async fn discard_file(path: &Path) {
+    let _ = tokio::fs::remove_file(path)
+        .await
+        .inspect_err(|e| error!("Could not remove {}: {e}", path.display()));
+}
+
+async fn handle_stat(
+    mut stream: StreamPair,
+    path: &str,
+    filename: &str,
+    allowed: &AllowedPaths,
+) -> anyhow::Result<()> {
+    trace!("begin");
+    // Resolve the path in the same way as a PUT does
+    let mut path = PathBuf::from(path);
+    if path.as_os_str().is_empty() {
+        path.push(".");
     }
-    // recv_buf has been moved but we can get it back for further operations
-    stream.recv = limited_recv.into_inner();
+    if path.is_dir() {
+        let Some(relative) = io::relative_path(filename) else {
+            return send_response(
+                &mut stream.send,
+                Status::IncorrectPermissions,
+                Some("filename must be relative to the destination"),
+            )
+            .await;
+        };
+        path.push(relative);
+    }
+    if !allowed.permits(&path) {
+        let (status, message) = OUTSIDE_ALLOWED_PATHS;
+        return send_response(&mut stream.send, status, message).await;
+    }
+    let meta = match tokio::fs::metadata(&path).await {
+        Ok(meta) => meta,
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return send_response(&mut stream.send, Status::FileNotFound, None).await;
+        }
+        Err(e) => {
+            return send_response(&mut stream.send, Status::IoError, Some(&e.to_string())).await;
+        }
+    };
+    if meta.is_dir() {
+        return send_response(&mut stream.send, Status::ItIsADirectory, None).await;
+    }
+    send_response(&mut stream.send, Status::Ok, None).await?;
+    let header = FileHeader {
+        size: meta.len(),
+        filename: filename.to_string(),
+        mtime: io::mtime_nanos(&meta),
+        ..Default::default()
+    }
+    .serialize();
+    stream.send.write_all(&header).await?;
+    stream.send.flush().await?;
+    trace!("complete");
+    Ok(())
+}
 
-    trace!("receiving trailer");
-    let _trailer = FileTrailer::read(&mut stream.recv).await?;
+async fn handle_mkdir(mut stream: StreamPair, dirname: String) -> anyhow::Result<()> {
+    trace!("begin");
+    let (status, message) = match tokio::fs::create_dir_all(&dirname).await {
+        Ok(()) => (Status::Ok, None),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            (Status::IncorrectPermissions, Some(e.to_string()))
+        }
+        Err(e) => (Status::IoError, Some(e.to_string())),
+    };
+    send_response(&mut stream.send, status, message.as_deref()).await?;
+    stream.send.flush().await?;
+    trace!("complete");
+    Ok(())
+}
 
-    let f = file.flush();
+async fn handle_symlink(
+    mut stream: StreamPair,
+    linkpath: &str,
+    target: &str,
+) -> anyhow::Result<()> {
+    trace!("begin");
+    let (status, message) = match io::create_symlink(target, Path::new(linkpath)).await {
+        Ok(()) => (Status::Ok, None),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            (Status::IncorrectPermissions, Some(e.to_string()))
+        }
+        Err(e) => (Status::IoError, Some(e.to_string())),
+    };
+    send_response(&mut stream.send, status, message.as_deref()).await?;
+    stream.send.flush().await?;
+    trace!("complete");
+    Ok(())
+}
+
+/// How many listing entries may be waiting to be sent, before the directory walk pauses
+const LIST_QUEUE_DEPTH: usize = 256;
+
+async fn handle_list(mut stream: StreamPair, path: PathBuf) -> anyhow::Result<()> {
+    trace!("begin");
+    match tokio::fs::metadata(&path).await {
+        Ok(meta) if meta.is_dir() => (),
+        Ok(_) => {
+            return send_response(&mut stream.send, Status::IoError, Some("not a directory")).await;
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => {
+            return send_response(&mut stream.send, Status::FileNotFound, None).await;
+        }
+        Err(e) => {
+            return send_response(&mut stream.send, Status::IoError, Some(&e.to_string())).await;
+        }
+    }
+    // Any problem reading the directory itself is reported before we say OK
+    match tokio::fs::read_dir(&path).await {
+        Ok(_) => (),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            return send_response(
+                &mut stream.send,
+                Status::IncorrectPermissions,
+                Some(&e.to_string()),
+            )
+            .await;
+        }
+        Err(e) => {
+            return send_response(&mut stream.send, Status::IoError, Some(&e.to_string())).await;
+        }
+    }
     send_response(&mut stream.send, Status::Ok, None).await?;
-    tokio::try_join!(f, stream.send.flush())?;
+
+    // Entries are sent as the walk finds them
+    let (tx, mut rx) = tokio::sync::mpsc::channel(LIST_QUEUE_DEPTH);
+    let walk = tokio::task::spawn_blocking(move || {
+        for entry in io::list_tree(path) {
+            let failed = entry.is_err();
+            if tx.blocking_send(entry).is_err() || failed {
+                break;
+            }
+        }
+    });
+    let mut count = 0;
+    while let Some(entry) = rx.recv().await {
+        let (filename, meta) = match entry {
+            Ok(entry) => entry,
+            Err(e) => {
+                // Without the end marker, the client knows the listing is incomplete
+                warn!("listing cut short: {e}");
+                stream.send.finish()?;
+                return Ok(());
+            }
+        };
+        let header = FileHeader {
+            size: meta.len(),
+            filename,
+            mtime: io::mtime_nanos(&meta),
+            mode: io::mode_bits(&meta),
+            ..Default::default()
+        };
+        stream.send.write_all(&header.serialize()).await?;
+        count += 1;
+    }
+    walk.await?;
+    stream
+        .send
+        .write_all(&FileHeader::list_end().serialize())
+        .await?;
+    stream.send.finish()?;
+    trace!("complete, {count} entries");
+    Ok(())
+}
+
+async fn handle_delete(mut stream: StreamPair, path: &Path) -> anyhow::Result<()> {
+    trace!("begin");
+    let result = match tokio::fs::symlink_metadata(path).await {
+        Ok(meta) if meta.is_dir() => tokio::fs::remove_dir(path).await,
+        Ok(_) => tokio::fs::remove_file(path).await,
+        Err(e) => Err(e),
+    };
+    let (status, message) = match result {
+        Ok(()) => {
+            debug!("deleted {}", path.display());
+            (Status::Ok, None)
+        }
+        Err(e) if e.kind() == std::io::ErrorKind::NotFound => (Status::FileNotFound, None),
+        Err(e) if e.kind() == std::io::ErrorKind::PermissionDenied => {
+            (Status::IncorrectPermissions, Some(e.to_string()))
+        }
+        Err(e) => (Status::IoError, Some(e.to_string())),
+    };
+    send_response(&mut stream.send, status, message.as_deref()).await?;
+    stream.send.flush().await?;
     trace!("complete");
     Ok(())
 }
@@ -357,3 +1189,232 @@ async fn send_response(
     let buf = Response::serialize_direct(status, message);
     Ok(send.write_all(&buf).await?)
 }
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use super::{
+        advertised_endpoint, check_put_destination, check_put_size, path_permitted, put_file_path,
+        put_filename, refusal,
+    };
+    use crate::{
+        config::Configuration,
+        protocol::{
+            control::PROTOCOL_VERSION,
+            session::{Command, CommandType, FileHeader, PutArgs, Status},
+        },
+        util::AllowedPaths,
+    };
+
+    #[test]
+    fn advertise_defaults_to_socket() {
+        let config = Configuration::default();
+        assert_eq!(advertised_endpoint(&config, 12345), (12345, None));
+    }
+
+    #[test]
+    fn advertise_overrides_socket() {
+        let config = Configuration {
+            advertise_port: 40000,
+            advertise_address: "gateway.example.com".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            advertised_endpoint(&config, 12345),
+            (40000, Some("gateway.example.com"))
+        );
+    }
+
+    #[test]
+    fn advertise_bind_address() {
+        let config = Configuration {
+            bind_address: "192.0.2.7".into(),
+            ..Default::default()
+        };
+        assert_eq!(
+            advertised_endpoint(&config, 12345),
+            (12345, Some("192.0.2.7"))
+        );
+        let config = Configuration {
+            advertise_address: "gateway.example.com".into(),
+            ..config
+        };
+        assert_eq!(
+            advertised_endpoint(&config, 12345),
+            (12345, Some("gateway.example.com"))
+        );
+    }
+
+    #[tokio::test]
+    async fn put_destination_mkpath() {
+        let tmp = tempfile::tempdir().unwrap();
+        let missing = tmp.path().join("new/deeper/file");
+        assert_eq!(
+            check_put_destination(&missing, false, false)
+                .await
+                .unwrap_err()
+                .0,
+            Status::DirectoryDoesNotExist
+        );
+        assert_eq!(
+            check_put_destination(&missing, false, true).await,
+            Ok(false)
+        );
+
+        // A trailing slash means a directory
+        let mut dir = tmp.path().join("new/dir").into_os_string();
+        dir.push("/");
+        let dir = std::path::PathBuf::from(dir);
+        assert_eq!(check_put_destination(&dir, false, true).await, Ok(true));
+        assert!(check_put_destination(&dir, true, true).await.is_err());
+    }
+
+    #[test]
+    fn hostile_put_filenames() {
+        for hostile in ["../../etc/cron.d/x", "/etc/passwd", "sub/../../x", "..", ""] {
+            for recursive in [false, true] {
+                assert_eq!(
+                    put_filename(hostile, recursive).unwrap_err().0,
+                    Status::IncorrectPermissions,
+                    "{hostile} (recursive {recursive})"
+                );
+            }
+        }
+        assert_eq!(put_filename("file", false).unwrap(), Path::new("file"));
+        // Directory components are only honoured in a recursive copy
+        assert_eq!(put_filename("sub/file", false).unwrap(), Path::new("file"));
+        assert_eq!(
+            put_filename("sub/file", true).unwrap(),
+            Path::new("sub/file")
+        );
+    }
+
+    #[test]
+    fn put_no_clobber() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::write(tmp.path().join("exists"), b"hello").unwrap();
+        let args = |no_clobber| PutArgs {
+            filename: String::new(),
+            resume_offset: 0,
+            append: false,
+            mkpath: false,
+            fsync: false,
+            recursive: false,
+            no_clobber,
+        };
+        let path = |dest: &Path, append_filename, no_clobber| {
+            put_file_path(
+                dest.to_path_buf(),
+                append_filename,
+                "exists",
+                &args(no_clobber),
+                PROTOCOL_VERSION,
+                &AllowedPaths::default(),
+            )
+            .map_err(|(status, _)| status)
+        };
+        let existing = tmp.path().join("exists");
+        assert_eq!(path(tmp.path(), true, false), Ok(existing.clone()));
+        assert_eq!(path(tmp.path(), true, true), Err(Status::DestinationExists));
+        // The destination itself may be the file
+        assert_eq!(path(&existing, false, true), Err(Status::DestinationExists));
+        let new = tmp.path().join("new");
+        assert_eq!(path(&new, false, true), Ok(new.clone()));
+    }
+
+    #[test]
+    fn allowed_paths() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("root");
+        std::fs::create_dir(&root).unwrap();
+        let allowed = AllowedPaths::new(&[root.to_string_lossy().into()]).unwrap();
+        let inside = root.join("file").to_string_lossy().to_string();
+        let outside = tmp.path().join("file").to_string_lossy().to_string();
+
+        assert!(path_permitted(&Command::new_get(&inside), &allowed));
+        assert!(!path_permitted(&Command::new_get(&outside), &allowed));
+        assert!(!path_permitted(&Command::new_put(&outside), &allowed));
+        assert!(!path_permitted(&Command::new_delete(&outside), &allowed));
+
+        // A recursive Put may not escape through the filename
+        let args = PutArgs {
+            filename: String::new(),
+            resume_offset: 0,
+            append: false,
+            mkpath: false,
+            fsync: false,
+            recursive: true,
+            no_clobber: false,
+        };
+        let put = |filename| {
+            put_file_path(
+                root.clone(),
+                true,
+                filename,
+                &args,
+                PROTOCOL_VERSION,
+                &allowed,
+            )
+            .map_err(|(status, _)| status)
+        };
+        assert_eq!(put("sub/file"), Ok(root.join("sub/file")));
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(tmp.path(), root.join("link")).unwrap();
+            assert_eq!(put("link/file"), Err(Status::IncorrectPermissions));
+        }
+    }
+
+    #[test]
+    fn read_only_keeps_source() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, b"hello").unwrap();
+        let filename = path.to_string_lossy();
+        let get = Command::new_get(&filename).with_remove_source(true);
+
+        let config = Configuration {
+            read_only: true,
+            ..Default::default()
+        };
+        let allowed = config.allowed_commands();
+        assert_eq!(
+            refusal(&get, &allowed, true),
+            Some("the server is read-only")
+        );
+        // The file may still be fetched, as long as it is left in place
+        assert_eq!(refusal(&Command::new_get(&filename), &allowed, true), None);
+        assert_eq!(
+            refusal(&get, &[CommandType::Get], false),
+            Some("the server does not allow this command")
+        );
+        assert_eq!(refusal(&get, CommandType::ALL, false), None);
+        assert!(path.exists());
+    }
+
+    #[tokio::test]
+    async fn max_file_size() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, b"hello").unwrap();
+        let header = |size| FileHeader {
+            size,
+            ..Default::default()
+        };
+        let check = |size, append, max| {
+            let header = header(size);
+            let path = path.clone();
+            async move { check_put_size(&header, &path, append, max).await.is_ok() }
+        };
+        assert!(check(100, false, 0).await);
+        assert!(check(100, false, 100).await);
+        assert!(!check(101, false, 100).await);
+        // When appending, the existing data counts
+        assert!(check(95, true, 100).await);
+        assert!(!check(96, true, 100).await);
+        // A streamed file can't be checked
+        assert!(!check(FileHeader::UNKNOWN_SIZE, false, 100).await);
+        assert!(check(FileHeader::UNKNOWN_SIZE, false, 0).await);
+    }
+}
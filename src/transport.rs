@@ -1,13 +1,13 @@
 //! QUIC transport configuration
 // (c) 2024 Ross Younger
 
-use std::{str::FromStr, sync::Arc, time::Duration};
+use std::{str::FromStr, sync::Arc};
 
 use anyhow::Result;
 use human_repr::HumanCount as _;
 use quinn::{
-    congestion::{BbrConfig, CubicConfig},
-    TransportConfig,
+    congestion::{BbrConfig, CubicConfig, NewRenoConfig},
+    EndpointConfig, MtuDiscoveryConfig, TransportConfig,
 };
 use serde::{de, Deserialize, Serialize};
 use strum::VariantNames;
@@ -15,12 +15,14 @@ use tracing::debug;
 
 use crate::config::Configuration;
 
-/// Keepalive interval for the QUIC connection
-pub const PROTOCOL_KEEPALIVE: Duration = Duration::from_secs(5);
+/// The smallest UDP payload size QUIC permits (RFC 9000 section 14)
+pub const MIN_UDP_PAYLOAD_SIZE: u16 = 1200;
+/// The largest UDP payload size QUIC permits (RFC 9000 section 18.2)
+pub const MAX_UDP_PAYLOAD_SIZE: u16 = 65527;
 
 /// Specifies whether to configure to maximise transmission throughput, receive throughput, or both.
 /// Specifying `Both` for a one-way data transfer will work, but wastes kernel memory.
-#[derive(Copy, Clone, Debug)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum ThroughputMode {
     /// We expect to send a lot but not receive
     Tx,
@@ -56,6 +58,10 @@ pub enum CongestionControllerType {
     /// `https://blog.apnic.net/2020/01/10/when-to-use-and-not-use-bbr/`
     /// for more discussion.
     Bbr,
+    /// The classic TCP algorithm, which preceded Cubic.
+    /// It grows the congestion window more slowly, which some find more predictable on lossy links.
+    #[value(name = "newreno")]
+    NewReno,
 }
 
 impl<'de> Deserialize<'de> for CongestionControllerType {
@@ -71,14 +77,76 @@ impl<'de> Deserialize<'de> for CongestionControllerType {
     }
 }
 
+/// _(Experimental)_ Selects whether to monitor for, and act on, the flow control window limiting throughput
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    strum::VariantNames,
+    clap::ValueEnum,
+    Serialize,
+)]
+#[strum(serialize_all = "lowercase")] // N.B. this applies to EnumString, not Display
+pub enum AutoWindow {
+    /// Do nothing
+    #[default]
+    Off,
+    /// Log a suggestion when the flow control window appears to be the bottleneck
+    Advise,
+    /// As `advise`, and additionally grow the receive window (within bounds) when receiving data
+    On,
+}
+
+impl<'de> Deserialize<'de> for AutoWindow {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let lower = s.to_ascii_lowercase();
+        // requires strum::EnumString && strum::VariantNames && #[strum(serialize_all = "lowercase")]
+        FromStr::from_str(&lower).map_err(|_| de::Error::unknown_variant(&s, AutoWindow::VARIANTS))
+    }
+}
+
+/// Creates a `quinn::EndpointConfig` for the endpoint setup
+pub fn endpoint_config(params: &Configuration) -> Result<EndpointConfig> {
+    let mut config = EndpointConfig::default();
+    let size = params.udp_payload_size;
+    if size != 0 {
+        anyhow::ensure!(
+            (MIN_UDP_PAYLOAD_SIZE..=MAX_UDP_PAYLOAD_SIZE).contains(&size),
+            "udp_payload_size {size} is out of range (must be {MIN_UDP_PAYLOAD_SIZE} to {MAX_UDP_PAYLOAD_SIZE})"
+        );
+        let _ = config
+            .max_udp_payload_size(size)
+            .map_err(|e| anyhow::anyhow!("udp_payload_size {size}: {e}"))?;
+    }
+    Ok(config)
+}
+
 /// Creates a `quinn::TransportConfig` for the endpoint setup
 pub fn create_config(params: &Configuration, mode: ThroughputMode) -> Result<Arc<TransportConfig>> {
     let mut config = TransportConfig::default();
     let _ = config
         .max_concurrent_bidi_streams(1u8.into())
         .max_concurrent_uni_streams(0u8.into())
-        .keep_alive_interval(Some(PROTOCOL_KEEPALIVE))
-        .allow_spin(true);
+        .keep_alive_interval(params.keepalive_interval())
+        .max_idle_timeout(Some(params.idle_timeout_duration().try_into()?))
+        .allow_spin(true)
+        .enable_segmentation_offload(!params.no_gso);
+    if params.rtt != 0 {
+        // The default initial RTT estimate (333ms) is wrong for long and short paths alike
+        let _ = config.initial_rtt(params.rtt_duration());
+    }
+    if params.persistent_congestion_threshold != 0 {
+        let _ = config.persistent_congestion_threshold(params.persistent_congestion_threshold);
+    }
 
     match mode {
         ThroughputMode::Tx | ThroughputMode::Both => {
@@ -92,13 +160,24 @@ pub fn create_config(params: &Configuration, mode: ThroughputMode) -> Result<Arc
     match mode {
         // TODO: If we later support multiple streams at once, will need to consider receive_window and stream_receive_window.
         ThroughputMode::Rx | ThroughputMode::Both => {
-            let _ = config
-                .stream_receive_window(params.recv_window().try_into()?)
-                .datagram_receive_buffer_size(Some(Configuration::recv_buffer() as usize));
+            let _ =
+                config.datagram_receive_buffer_size(Some(Configuration::recv_buffer() as usize));
+            let _ = config.stream_receive_window(params.stream_recv_window().try_into()?);
+            if params.auto_window == AutoWindow::On || *params.recv_window_override != 0 {
+                // Make the connection window the limiting factor
+                let _ = config.receive_window(params.recv_window().try_into()?);
+            }
         }
         ThroughputMode::Tx => (),
     }
 
+    if params.udp_payload_size != 0 {
+        // Path MTU discovery will not probe beyond its upper bound
+        let mut mtu = MtuDiscoveryConfig::default();
+        let _ = mtu.upper_bound(params.udp_payload_size);
+        let _ = config.mtu_discovery_config(Some(mtu));
+    }
+
     let window = params.initial_congestion_window;
     match params.congestion {
         CongestionControllerType::Cubic => {
@@ -115,6 +194,13 @@ pub fn create_config(params: &Configuration, mode: ThroughputMode) -> Result<Arc
             }
             let _ = config.congestion_controller_factory(Arc::new(bbr));
         }
+        CongestionControllerType::NewReno => {
+            let mut new_reno = NewRenoConfig::default();
+            if window != 0 {
+                let _ = new_reno.initial_window(window);
+            }
+            let _ = config.congestion_controller_factory(Arc::new(new_reno));
+        }
     }
 
     debug!(
@@ -122,12 +208,71 @@ pub fn create_config(params: &Configuration, mode: ThroughputMode) -> Result<Arc
         params.format_transport_config()
     );
     debug!(
-        "Buffer configuration: send window {sw}, buffer {sb}; recv window {rw}, buffer {rb}",
+        "Buffer configuration: send window {sw}, buffer {sb}; recv window {rw}, stream {srw}, buffer {rb}",
         sw = params.send_window().human_count_bytes(),
         sb = Configuration::send_buffer().human_count_bytes(),
         rw = params.recv_window().human_count_bytes(),
+        srw = params.stream_recv_window().human_count_bytes(),
         rb = Configuration::recv_buffer().human_count_bytes()
     );
 
     Ok(config.into())
 }
+
+#[cfg(test)]
+mod test {
+    use super::{create_config, endpoint_config, ThroughputMode};
+    use crate::config::Configuration;
+
+    #[test]
+    fn udp_payload_size() {
+        let config = |udp_payload_size| Configuration {
+            udp_payload_size,
+            ..Default::default()
+        };
+        assert!(endpoint_config(&config(0)).is_ok());
+        assert!(endpoint_config(&config(1200)).is_ok());
+        assert!(endpoint_config(&config(8952)).is_ok());
+        assert!(endpoint_config(&config(65527)).is_ok());
+        assert!(endpoint_config(&config(1199)).is_err());
+        assert!(endpoint_config(&config(65528)).is_err());
+    }
+
+    #[test]
+    fn loss_recovery_tuning() {
+        // TransportConfig has no getters, so inspect its Debug output
+        let config = Configuration {
+            rtt: 600,
+            persistent_congestion_threshold: 5,
+            ..Default::default()
+        };
+        let debug = format!(
+            "{:?}",
+            create_config(&config, ThroughputMode::Both).unwrap()
+        );
+        assert!(debug.contains("initial_rtt: 600ms"));
+        assert!(debug.contains("persistent_congestion_threshold: 5"));
+
+        let debug = format!(
+            "{:?}",
+            create_config(&Configuration::default(), ThroughputMode::Both).unwrap()
+        );
+        assert!(debug.contains("persistent_congestion_threshold: 3"));
+    }
+
+    #[test]
+    fn segmentation_offload() {
+        let debug = |no_gso| {
+            let config = Configuration {
+                no_gso,
+                ..Default::default()
+            };
+            format!(
+                "{:?}",
+                create_config(&config, ThroughputMode::Both).unwrap()
+            )
+        };
+        assert!(debug(false).contains("enable_segmentation_offload: true"));
+        assert!(debug(true).contains("enable_segmentation_offload: false"));
+    }
+}
@@ -0,0 +1,147 @@
+//! Server-side restriction of the paths clients may access
+// (c) 2024 Ross Younger
+
+use std::path::{Component, Path, PathBuf};
+use std::sync::Arc;
+
+use anyhow::Context as _;
+
+/// The directories a server allows its clients to access (`allow_path`).
+///
+/// An empty set places no restriction.
+///
+/// Paths are checked after resolving symbolic links and `..`, so a link inside an allowed directory
+/// does not give access to anything outside it.
+/// A path which does not exist yet (the destination of a Put, say) is checked by resolving its nearest existing ancestor.
+#[derive(Clone, Debug, Default)]
+pub struct AllowedPaths(Arc<[PathBuf]>);
+
+impl AllowedPaths {
+    /// Constructor. Each directory must exist.
+    pub fn new(roots: &[String]) -> anyhow::Result<Self> {
+        let roots = roots
+            .iter()
+            .map(|root| std::fs::canonicalize(root).with_context(|| format!("allowed path {root}")))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        Ok(Self(roots.into()))
+    }
+
+    /// Are the paths clients may access restricted?
+    #[must_use]
+    pub fn is_restricted(&self) -> bool {
+        !self.0.is_empty()
+    }
+
+    /// May a client access `path` (or whatever it refers to, if it is a symbolic link)?
+    #[must_use]
+    pub fn permits(&self, path: &Path) -> bool {
+        !self.is_restricted() || resolve(path).is_some_and(|p| self.contains(&p))
+    }
+
+    /// May a client operate on the directory entry `path` itself (deleting it, or creating a symbolic link there)?
+    ///
+    /// Unlike [`permits`](Self::permits), this does not follow a symbolic link in the final component.
+    #[must_use]
+    pub fn permits_entry(&self, path: &Path) -> bool {
+        if !self.is_restricted() {
+            return true;
+        }
+        let Some(Component::Normal(name)) = path.components().next_back() else {
+            return false;
+        };
+        let parent = path.parent().unwrap_or(Path::new(""));
+        resolve(parent).is_some_and(|p| self.contains(&p.join(name)))
+    }
+
+    fn contains(&self, resolved: &Path) -> bool {
+        self.0.iter().any(|root| resolved.starts_with(root))
+    }
+}
+
+impl std::fmt::Display for AllowedPaths {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let roots: Vec<_> = self.0.iter().map(|p| p.to_string_lossy()).collect();
+        f.write_str(&roots.join(","))
+    }
+}
+
+/// Resolves a path to an absolute path without symbolic links or `..`, as far as it exists.
+///
+/// Returns None if that is not possible, for example a dangling symbolic link, or `..` below something which does not exist.
+fn resolve(path: &Path) -> Option<PathBuf> {
+    let mut existing = if path.as_os_str().is_empty() {
+        Path::new(".")
+    } else {
+        path
+    };
+    let mut missing = Vec::new();
+    loop {
+        if let Ok(mut resolved) = std::fs::canonicalize(existing) {
+            if let Some(first) = missing.last() {
+                // If this exists, it is a dangling symbolic link; we can't tell where it would lead
+                if resolved.join(first).symlink_metadata().is_ok() {
+                    return None;
+                }
+            }
+            resolved.extend(missing.iter().rev());
+            return Some(resolved);
+        }
+        let Some(Component::Normal(name)) = existing.components().next_back() else {
+            return None;
+        };
+        missing.push(name);
+        existing = match existing.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::AllowedPaths;
+
+    #[test]
+    fn jail() {
+        let tmp = tempfile::tempdir().unwrap();
+        let root = tmp.path().join("root");
+        let outside = tmp.path().join("outside");
+        std::fs::create_dir_all(root.join("sub")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::fs::write(root.join("file"), "x").unwrap();
+        std::fs::write(outside.join("secret"), "x").unwrap();
+
+        let uut = AllowedPaths::new(&[root.to_string_lossy().into()]).unwrap();
+        assert!(uut.is_restricted());
+        assert!(uut.permits(&root));
+        assert!(uut.permits(&root.join("file")));
+        assert!(uut.permits(&root.join("sub/../file")));
+        assert!(uut.permits(&root.join("new/deeper/file")));
+        assert!(!uut.permits(&outside.join("secret")));
+        assert!(!uut.permits(&root.join("../outside/secret")));
+        assert!(!uut.permits(&root.join("new/../../outside/secret")));
+        assert!(uut.permits_entry(&root.join("file")));
+        assert!(!uut.permits_entry(&root.join("..")));
+
+        #[cfg(unix)]
+        {
+            std::os::unix::fs::symlink(&outside, root.join("link")).unwrap();
+            std::os::unix::fs::symlink(outside.join("missing"), root.join("dangling")).unwrap();
+            assert!(!uut.permits(&root.join("link/secret")));
+            assert!(!uut.permits(&root.join("link")));
+            assert!(!uut.permits(&root.join("dangling")));
+            // The links themselves may be removed or replaced
+            assert!(uut.permits_entry(&root.join("link")));
+            assert!(uut.permits_entry(&root.join("dangling")));
+        }
+    }
+
+    #[test]
+    fn unrestricted() {
+        let uut = AllowedPaths::default();
+        assert!(!uut.is_restricted());
+        assert!(uut.permits("/etc/passwd".as_ref()));
+        assert!(uut.permits_entry("/".as_ref()));
+        assert!(AllowedPaths::new(&["/does/not/exist".into()]).is_err());
+    }
+}
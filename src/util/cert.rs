@@ -1,8 +1,27 @@
 //! X509 certificate management helper
 // (c) 2024 Ross Younger
 
+use std::{path::Path, sync::Arc};
+
 use anyhow::Result;
-use rustls_pki_types::{CertificateDer, PrivateKeyDer};
+use quinn::rustls::{KeyLog, KeyLogFile};
+use rustls_pki_types::{pem::PemObject as _, CertificateDer, PrivateKeyDer};
+
+/// The environment variable which, if set, names a file to log TLS session keys to
+const KEY_LOG_ENV_VAR: &str = "SSLKEYLOGFILE";
+
+/// Returns a TLS key logger, if the `SSLKEYLOGFILE` environment variable is set.
+///
+/// This allows tools such as Wireshark to decrypt captured packets.
+/// **Anybody who can read the file can decrypt the session**, so this is for debugging only.
+pub(crate) fn key_log() -> Option<Arc<dyn KeyLog>> {
+    let path = std::env::var_os(KEY_LOG_ENV_VAR)?;
+    tracing::warn!(
+        "Logging TLS session keys to {} ({KEY_LOG_ENV_VAR} is set); this session is not confidential",
+        path.to_string_lossy()
+    );
+    Some(Arc::new(KeyLogFile::new()))
+}
 
 /// In-memory representation of X509 credentials (for TLS)
 #[derive(Debug)]
@@ -21,12 +40,16 @@ fn dump(creds: &rcgen::CertifiedKey) {
 }
 */
 
+fn local_hostname() -> String {
+    gethostname::gethostname()
+        .into_string()
+        .unwrap_or("unknown.host.invalid".to_string())
+}
+
 impl Credentials {
     /// Factory method
     pub fn generate() -> Result<Self> {
-        let hostname = gethostname::gethostname()
-            .into_string()
-            .unwrap_or("unknown.host.invalid".to_string());
+        let hostname = local_hostname();
         tracing::trace!("Creating certificate with hostname {hostname}");
         let raw = rcgen::generate_simple_self_signed([hostname.clone()])?;
         Ok(Credentials {
@@ -36,6 +59,68 @@ impl Credentials {
         })
     }
 
+    /// Loads a certificate and its private key from PEM files.
+    ///
+    /// Only the first certificate in `cert` is used.
+    /// A server sends this machine's hostname to the client, so its certificate must be valid for that name.
+    pub fn from_files(cert: impl AsRef<Path>, key: impl AsRef<Path>) -> Result<Self> {
+        let (cert, key) = (cert.as_ref(), key.as_ref());
+        let certificate = std::fs::read(cert)
+            .map_err(anyhow::Error::new)
+            .and_then(|pem| Ok(CertificateDer::from_pem_slice(&pem)?))
+            .map_err(|e| anyhow::anyhow!("reading TLS certificate from {}: {e}", cert.display()))?;
+        let keypair = std::fs::read(key)
+            .map_err(anyhow::Error::new)
+            .and_then(|pem| Ok(PrivateKeyDer::from_pem_slice(&pem)?))
+            .map_err(|e| anyhow::anyhow!("reading TLS private key from {}: {e}", key.display()))?;
+        Ok(Credentials {
+            certificate,
+            keypair,
+            hostname: local_hostname(),
+        })
+    }
+
+    /// Loads credentials from the given PEM files if they are set, otherwise generates new ones.
+    ///
+    /// Either both or neither of the filenames must be set (non-empty).
+    pub fn load_or_generate(cert: &str, key: &str) -> Result<Self> {
+        match (cert.is_empty(), key.is_empty()) {
+            (true, true) => Self::generate(),
+            (false, false) => {
+                tracing::debug!("Using TLS certificate {cert}");
+                Self::from_files(cert, key)
+            }
+            _ => anyhow::bail!("TlsCert and TlsKey must be set together"),
+        }
+    }
+
+    /// Deterministic factory method, for tests.
+    ///
+    /// This creates an Ed25519 keypair from a seeded RNG and a certificate with a fixed hostname.
+    /// Ed25519 signatures are deterministic, so the same seed always produces byte-identical credentials.
+    #[cfg(test)]
+    pub(crate) fn generate_from_seed(seed: u64) -> Result<Self> {
+        use rand::{rngs::StdRng, RngCore as _, SeedableRng as _};
+        // PKCS#8 v1 wrapper for a raw Ed25519 private key (RFC 8410)
+        const PKCS8_PREFIX: [u8; 16] = [
+            0x30, 0x2e, 0x02, 0x01, 0x00, 0x30, 0x05, 0x06, 0x03, 0x2b, 0x65, 0x70, 0x04, 0x22,
+            0x04, 0x20,
+        ];
+        let mut secret = [0u8; 32];
+        StdRng::seed_from_u64(seed).fill_bytes(&mut secret);
+        let mut der = PKCS8_PREFIX.to_vec();
+        der.extend_from_slice(&secret);
+        let key_pair = rcgen::KeyPair::try_from(der.as_slice())?;
+
+        let hostname = "test.host.invalid".to_string();
+        let cert = rcgen::CertificateParams::new([hostname.clone()])?.self_signed(&key_pair)?;
+        Ok(Credentials {
+            certificate: cert.der().clone(),
+            keypair: rustls_pki_types::PrivateKeyDer::Pkcs8(key_pair.serialize_der().into()),
+            hostname,
+        })
+    }
+
     /// Cloning accessor
     #[must_use]
     pub fn cert_chain(&self) -> Vec<CertificateDer<'static>> {
@@ -49,4 +134,32 @@ mod tests {
     fn generate_works() {
         let _ = super::Credentials::generate().unwrap();
     }
+
+    #[test]
+    fn from_files() {
+        use crate::util::make_test_tempfile;
+        let raw = rcgen::generate_simple_self_signed(["test.host.invalid".to_string()]).unwrap();
+        let (cert, _dir1) = make_test_tempfile(&raw.cert.pem(), "cert.pem");
+        let (key, _dir2) = make_test_tempfile(&raw.key_pair.serialize_pem(), "key.pem");
+        let creds = super::Credentials::from_files(&cert, &key).unwrap();
+        assert_eq!(creds.certificate, *raw.cert.der());
+        assert_eq!(creds.keypair.secret_der(), raw.key_pair.serialize_der());
+
+        let (cert, key) = (cert.to_str().unwrap(), key.to_str().unwrap());
+        assert!(super::Credentials::load_or_generate(cert, key).is_ok());
+        assert!(super::Credentials::load_or_generate(cert, "").is_err());
+        // The wrong kind of PEM object
+        assert!(super::Credentials::load_or_generate(key, cert).is_err());
+        assert!(super::Credentials::load_or_generate("/nonexistent", key).is_err());
+    }
+
+    #[test]
+    fn seeded_is_deterministic() {
+        let a = super::Credentials::generate_from_seed(42).unwrap();
+        let b = super::Credentials::generate_from_seed(42).unwrap();
+        let c = super::Credentials::generate_from_seed(43).unwrap();
+        assert_eq!(a.certificate, b.certificate);
+        assert_eq!(a.keypair.secret_der(), b.keypair.secret_der());
+        assert_ne!(a.certificate, c.certificate);
+    }
 }
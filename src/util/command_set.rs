@@ -0,0 +1,140 @@
+//! CLI argument helper type - a set of session commands.
+// (c) 2024 Ross Younger
+use serde::{
+    de::{self, Error as _, Unexpected},
+    Deserialize, Serialize,
+};
+use std::{fmt::Display, str::FromStr};
+
+use crate::protocol::session::CommandType;
+
+/// A set of session command types, used to restrict what the server will do.
+///
+/// In a configuration file, this may be specified as one or more command names.
+/// On the command line, separate the names with commas. For example:
+/// ```text
+/// allow get             # download only
+/// allow get put         # upload and download, but never delete anything
+/// allow get put delete  # everything (this is the default)
+/// ```
+#[derive(Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(into = "String")]
+pub struct CommandSet(Vec<CommandType>);
+
+impl CommandSet {
+    /// A set containing every command known to this build
+    #[must_use]
+    pub fn all() -> Self {
+        Self(CommandType::ALL.to_vec())
+    }
+    /// Does the set contain this command type?
+    #[must_use]
+    pub fn contains(&self, command: CommandType) -> bool {
+        self.0.contains(&command)
+    }
+    /// Is the set empty?
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.0.is_empty()
+    }
+    /// Accessor
+    #[must_use]
+    pub fn as_slice(&self) -> &[CommandType] {
+        &self.0
+    }
+}
+
+impl Default for CommandSet {
+    fn default() -> Self {
+        Self::all()
+    }
+}
+
+impl Display for CommandSet {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let names: Vec<&str> = self.0.iter().map(AsRef::as_ref).collect();
+        f.write_str(&names.join(","))
+    }
+}
+
+impl From<CommandSet> for String {
+    fn from(value: CommandSet) -> Self {
+        value.to_string()
+    }
+}
+
+impl FromStr for CommandSet {
+    type Err = figment::Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        use figment::error::Error as FigmentError;
+        let mut result = Vec::new();
+        for name in s
+            .split(|c: char| c == ',' || c.is_whitespace())
+            .filter(|n| !n.is_empty())
+        {
+            let command = CommandType::from_str(&name.to_ascii_lowercase()).map_err(|_| {
+                FigmentError::invalid_value(
+                    Unexpected::Str(name),
+                    &"a list of session commands (examples: `get`, `get,put`)",
+                )
+            })?;
+            if !result.contains(&command) {
+                result.push(command);
+            }
+        }
+        Ok(Self(result))
+    }
+}
+
+impl<'de> Deserialize<'de> for CommandSet {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        /// A configuration file line with one argument arrives as a string; with more, as a sequence.
+        #[derive(Deserialize)]
+        #[serde(untagged)]
+        enum OneOrMany {
+            One(String),
+            Many(Vec<String>),
+        }
+        let s = match OneOrMany::deserialize(deserializer)? {
+            OneOrMany::One(s) => s,
+            OneOrMany::Many(v) => v.join(","),
+        };
+        FromStr::from_str(&s).map_err(de::Error::custom)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CommandSet;
+    use crate::protocol::session::CommandType;
+    use std::str::FromStr;
+
+    #[test]
+    fn parse() {
+        let uut = CommandSet::from_str("get").unwrap();
+        assert_eq!(uut.as_slice(), [CommandType::Get]);
+        let uut = CommandSet::from_str("PUT,get").unwrap();
+        assert_eq!(uut.as_slice(), [CommandType::Put, CommandType::Get]);
+        let uut = CommandSet::from_str("get,get").unwrap();
+        assert_eq!(uut.as_slice(), [CommandType::Get]);
+    }
+    #[test]
+    fn invalid() {
+        let _ = CommandSet::from_str("get,frobnicate").expect_err("should have failed");
+    }
+    #[test]
+    fn output() {
+        assert_eq!(CommandSet::all().to_string(), "get,put,delete");
+    }
+    #[test]
+    fn deserialize_one_or_many() {
+        let uut: CommandSet = serde_json::from_str("\"get\"").unwrap();
+        assert_eq!(uut.as_slice(), [CommandType::Get]);
+        let uut: CommandSet = serde_json::from_str("[\"get\",\"put\",\"delete\"]").unwrap();
+        assert_eq!(uut, CommandSet::all());
+    }
+}
@@ -0,0 +1,401 @@
+//! Optional compression of file payloads
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! Some data (text, logs, disk images) compresses well. On a slow link it can be quicker to
+//! compress it than to send it as it is. Data that is already compressed gains nothing and costs CPU time,
+//! so compression is opt-in; the `auto` mode skips files whose names suggest they are already compressed.
+//!
+//! Compressed file data is sent as a single zstd frame. As the frame is self-delimiting, the receiver
+//! can find the [`FileTrailer`] that follows it without knowing the compressed size in advance.
+//!
+//! When the size of the file is not known in advance, the sender finishes the stream after the trailer,
+//! which is padded to a fixed length. The receiver holds back that many bytes from the end of the stream,
+//! so the trailer never reaches the file.
+
+use std::{
+    ffi::OsStr,
+    path::Path,
+    pin::Pin,
+    str::FromStr,
+    task::{ready, Context, Poll},
+    time::Duration,
+};
+
+use async_compression::tokio::bufread::{ZstdDecoder, ZstdEncoder};
+use serde::{de, Deserialize, Serialize};
+use strum::VariantNames as _;
+use tokio::io::{
+    AsyncBufRead, AsyncRead, AsyncReadExt as _, AsyncWrite, AsyncWriteExt as _, BufReader, ReadBuf,
+};
+use tracing::debug;
+
+use super::hash::{HashingReader, HashingWriter};
+use crate::protocol::session::{with_timeout, FileHeader, FileTrailer};
+
+/// Selects whether to compress file payloads
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    PartialEq,
+    Eq,
+    strum::Display,
+    strum::EnumString,
+    strum::VariantNames,
+    clap::ValueEnum,
+    Serialize,
+)]
+#[strum(serialize_all = "lowercase")] // N.B. this applies to EnumString, not Display
+pub enum Compress {
+    /// Do not compress
+    #[default]
+    Off,
+    /// Compress all files
+    On,
+    /// Compress files, except those whose names suggest they are already compressed
+    Auto,
+}
+
+impl<'de> Deserialize<'de> for Compress {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let lower = s.to_ascii_lowercase();
+        // requires strum::EnumString && strum::VariantNames && #[strum(serialize_all = "lowercase")]
+        FromStr::from_str(&lower).map_err(|_| de::Error::unknown_variant(&s, Compress::VARIANTS))
+    }
+}
+
+/// Extensions of file formats which are already compressed (lowercase)
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avif", "br", "bz2", "deb", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz", "lz4", "lzma", "m4a", "mkv", "mov", "mp3", "mp4", "odt", "ogg", "png", "pptx",
+    "rar", "rpm", "tbz2", "tgz", "txz", "webm", "webp", "xlsx", "xz", "zip", "zst",
+];
+
+impl Compress {
+    /// Decides whether to compress a file with the given name
+    #[must_use]
+    pub fn applies_to(self, filename: &str) -> bool {
+        match self {
+            Self::Off => false,
+            Self::On => true,
+            Self::Auto => !Path::new(filename)
+                .extension()
+                .and_then(OsStr::to_str)
+                .is_some_and(|ext| {
+                    COMPRESSED_EXTENSIONS.contains(&ext.to_ascii_lowercase().as_str())
+                }),
+        }
+    }
+}
+
+/// The outcome of sending a file payload
+#[derive(Clone, Copy, Debug)]
+pub struct Sent {
+    /// Bytes of file data sent, before any compression
+    pub bytes: u64,
+    /// BLAKE3 hash of the file data, before any compression
+    pub hash: blake3::Hash,
+    /// Bytes sent on the wire, if the data was compressed
+    pub compressed_size: Option<u64>,
+}
+
+/// Sends file data, compressing it if so requested
+pub async fn send_payload<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    compress: bool,
+) -> std::io::Result<Sent>
+where
+    R: AsyncBufRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if compress {
+        let mut hashing = HashingReader::new(reader);
+        let wire =
+            tokio::io::copy(&mut ZstdEncoder::new(BufReader::new(&mut hashing)), writer).await?;
+        debug!("compressed {} bytes to {wire}", hashing.count());
+        Ok(Sent {
+            bytes: hashing.count(),
+            hash: hashing.hash(),
+            compressed_size: Some(wire),
+        })
+    } else {
+        let mut hashing = HashingWriter::new(writer, true);
+        let bytes = tokio::io::copy_buf(reader, &mut hashing).await?;
+        Ok(Sent {
+            bytes,
+            hash: hashing.hash().unwrap_or_else(|| blake3::hash(&[])), // can't fail, we enabled hashing
+            compressed_size: None,
+        })
+    }
+}
+
+/// Receives file data, decompressing it if necessary, followed by the [`FileTrailer`].
+///
+/// If `size` is [`FileHeader::UNKNOWN_SIZE`], the data and padded trailer run to the end of the stream.
+///
+/// The sender has `limit` to send the trailer once the data is complete (zero means no limit).
+///
+/// The writer is flushed before returning.
+/// Returns the trailer and the hash of the data written.
+pub async fn receive_payload<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    size: u64,
+    compressed: bool,
+    limit: Duration,
+) -> anyhow::Result<(FileTrailer, blake3::Hash)>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut hashing = HashingWriter::new(writer, true);
+    let trailer = if size == FileHeader::UNKNOWN_SIZE {
+        receive_streamed(reader, &mut hashing, compressed).await?
+    } else {
+        receive_sized(reader, &mut hashing, size, compressed, limit).await?
+    };
+    // Some writers only report a failure to write the last of the data when flushed
+    hashing.flush().await?;
+    let hash = hashing.hash().unwrap_or_else(|| blake3::hash(&[])); // can't fail, we enabled hashing
+    Ok((trailer, hash))
+}
+
+/// Receives `size` bytes of file data, followed by the [`FileTrailer`]
+async fn receive_sized<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    size: u64,
+    compressed: bool,
+    limit: Duration,
+) -> anyhow::Result<FileTrailer>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    if compressed {
+        // The trailer follows the zstd frame, so must be read from the same buffer
+        let mut buffered = BufReader::new(reader);
+        // Allow one byte too many, so we can tell if the sender overruns
+        let mut decoder = ZstdDecoder::new(&mut buffered).take(size.saturating_add(1));
+        let received = tokio::io::copy(&mut decoder, writer).await?;
+        anyhow::ensure!(
+            received == size,
+            "decompressed data size {received} does not match the expected {size}"
+        );
+        with_timeout(limit, "file trailer", FileTrailer::read(&mut buffered)).await
+    } else {
+        let _ = tokio::io::copy(&mut reader.take(size), writer).await?;
+        with_timeout(limit, "file trailer", FileTrailer::read(reader)).await
+    }
+}
+
+/// Receives file data of unknown size, followed by the padded [`FileTrailer`] which ends the stream
+async fn receive_streamed<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    compressed: bool,
+) -> anyhow::Result<FileTrailer>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let mut held = HoldBack::new(reader, FileTrailer::PADDED_LEN);
+    if compressed {
+        let mut buffered = BufReader::new(&mut held);
+        let _ = tokio::io::copy(&mut ZstdDecoder::new(&mut buffered), writer).await?;
+        let extra = tokio::io::copy(&mut buffered, &mut tokio::io::sink()).await?;
+        anyhow::ensure!(extra == 0, "unexpected data after the compressed data");
+    } else {
+        let _ = tokio::io::copy(&mut held, writer).await?;
+    }
+    let tail = held
+        .into_tail()
+        .ok_or_else(|| anyhow::anyhow!("stream ended before the file trailer"))?;
+    FileTrailer::read(&mut tail.as_slice()).await
+}
+
+/// An [`AsyncRead`] adapter which withholds the last `len` bytes of its input
+#[derive(Debug)]
+struct HoldBack<R> {
+    inner: R,
+    len: usize,
+    held: Vec<u8>,
+    eof: bool,
+}
+
+impl<R: AsyncRead + Unpin> HoldBack<R> {
+    fn new(inner: R, len: usize) -> Self {
+        Self {
+            inner,
+            len,
+            held: Vec::new(),
+            eof: false,
+        }
+    }
+
+    /// Returns the bytes withheld, if the input has ended and was long enough
+    fn into_tail(self) -> Option<Vec<u8>> {
+        (self.eof && self.held.len() == self.len).then_some(self.held)
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HoldBack<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        loop {
+            if this.held.len() > this.len {
+                let n = (this.held.len() - this.len).min(buf.remaining());
+                buf.put_slice(&this.held[..n]);
+                let _ = this.held.drain(..n);
+                return Poll::Ready(Ok(()));
+            }
+            if this.eof {
+                return Poll::Ready(Ok(()));
+            }
+            let mut chunk = [0u8; 8192];
+            let mut chunk = ReadBuf::new(&mut chunk);
+            ready!(Pin::new(&mut this.inner).poll_read(cx, &mut chunk))?;
+            if chunk.filled().is_empty() {
+                this.eof = true;
+            } else {
+                this.held.extend_from_slice(chunk.filled());
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::time::Duration;
+
+    use super::{receive_payload, send_payload, Compress};
+    use crate::protocol::session::{FileHeader, FileTrailer};
+
+    #[test]
+    fn auto_skips_compressed_files() {
+        assert!(Compress::Auto.applies_to("notes.txt"));
+        assert!(Compress::Auto.applies_to("Makefile"));
+        assert!(!Compress::Auto.applies_to("dir/archive.tar.gz"));
+        assert!(!Compress::Auto.applies_to("IMG_0001.JPG"));
+        assert!(Compress::On.applies_to("archive.zst"));
+        assert!(!Compress::Off.applies_to("notes.txt"));
+    }
+
+    async fn round_trip(data: &[u8], compress: bool) -> (Vec<u8>, usize) {
+        let mut wire = Vec::new();
+        let sent = send_payload(&mut &data[..], &mut wire, compress)
+            .await
+            .unwrap();
+        assert_eq!(sent.bytes, data.len() as u64);
+        assert_eq!(sent.hash, blake3::hash(data));
+        let wire_len = wire.len();
+        assert_eq!(sent.compressed_size.is_some(), compress);
+        wire.extend(FileTrailer::serialize_direct(
+            Some(&sent.hash),
+            sent.compressed_size,
+        ));
+
+        let mut output = Vec::new();
+        let (trailer, hash) = receive_payload(
+            &mut wire.as_slice(),
+            &mut output,
+            data.len() as u64,
+            compress,
+            Duration::ZERO,
+        )
+        .await
+        .unwrap();
+        assert!(trailer.verify(&hash));
+        assert_eq!(trailer.compressed_size, sent.compressed_size);
+        (output, wire_len)
+    }
+
+    #[tokio::test]
+    async fn compressed_round_trip() {
+        let data = b"all work and no play makes jack a dull boy\n".repeat(1000);
+        let (output, wire_len) = round_trip(&data, true).await;
+        assert_eq!(output, data);
+        assert!(wire_len < data.len() / 10);
+
+        let (output, wire_len) = round_trip(&data, false).await;
+        assert_eq!(output, data);
+        assert_eq!(wire_len, data.len());
+
+        let (output, _) = round_trip(&[], true).await;
+        assert!(output.is_empty());
+    }
+
+    #[tokio::test]
+    async fn wrong_size_is_rejected() {
+        let data = b"0123456789".repeat(10);
+        let mut wire = Vec::new();
+        let sent = send_payload(&mut &data[..], &mut wire, true).await.unwrap();
+        wire.extend(FileTrailer::serialize_direct(None, sent.compressed_size));
+        for size in [50, 150] {
+            let mut output = Vec::new();
+            assert!(receive_payload(
+                &mut wire.as_slice(),
+                &mut output,
+                size,
+                true,
+                Duration::ZERO
+            )
+            .await
+            .is_err());
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_round_trip() {
+        let data = b"all work and no play makes jack a dull boy\n".repeat(1000);
+        for compress in [false, true] {
+            let mut wire = Vec::new();
+            let sent = send_payload(&mut &data[..], &mut wire, compress)
+                .await
+                .unwrap();
+            wire.extend(FileTrailer::serialize_padded(
+                Some(&sent.hash),
+                sent.compressed_size,
+            ));
+            let mut output = Vec::new();
+            let (trailer, hash) = receive_payload(
+                &mut wire.as_slice(),
+                &mut output,
+                FileHeader::UNKNOWN_SIZE,
+                compress,
+                Duration::ZERO,
+            )
+            .await
+            .unwrap();
+            assert_eq!(output, data);
+            assert!(trailer.verify(&hash));
+            assert_eq!(trailer.compressed_size, sent.compressed_size);
+        }
+    }
+
+    #[tokio::test]
+    async fn streamed_without_trailer() {
+        let mut output = Vec::new();
+        let wire = b"too short to hold a trailer";
+        assert!(receive_payload(
+            &mut &wire[..],
+            &mut output,
+            FileHeader::UNKNOWN_SIZE,
+            false,
+            Duration::ZERO,
+        )
+        .await
+        .is_err());
+    }
+}
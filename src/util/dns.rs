@@ -1,28 +1,227 @@
 //! DNS helpers
 // (c) 2024 Ross Younger
 
-use std::net::IpAddr;
+use std::{
+    future::Future,
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
 
 use anyhow::Context as _;
+use hickory_resolver::{
+    config::{LookupIpStrategy, NameServerConfigGroup, ResolverConfig},
+    name_server::TokioConnectionProvider,
+    proto::rr::rdata::SRV,
+    TokioResolver,
+};
 
 use super::AddressFamily;
 
-/// DNS lookup helper
+/// How host names are looked up.
 ///
-/// Results can be restricted to a given address family.
-/// Only the first matching result is returned.
-/// If there are no matching records of the required type, returns an error.
-pub fn lookup_host_by_family(host: &str, desired: AddressFamily) -> anyhow::Result<IpAddr> {
-    let candidates = dns_lookup::lookup_host(host)
-        .with_context(|| format!("host name lookup for {host} failed"))?;
-    let mut it = candidates.iter();
-
-    let found = match desired {
-        AddressFamily::Any => it.next(),
-        AddressFamily::Inet => it.find(|addr| addr.is_ipv4()),
-        AddressFamily::Inet6 => it.find(|addr| addr.is_ipv6()),
-    };
-    found
-        .map(std::borrow::ToOwned::to_owned)
-        .ok_or(anyhow::anyhow!("host {host} found, but not as {desired:?}"))
+/// By default this is the system resolver, as used by everything else on the system.
+/// Alternatively, a specific DNS server can be asked directly (`dns_server`).
+/// Either way, lookups can be given a time limit (`dns_timeout`), as the system resolver may not have one
+/// that suits us.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Resolver {
+    /// The DNS server to ask, instead of using the system resolver
+    server: Option<SocketAddr>,
+    /// How long to wait for an answer; zero means as long as the resolver takes
+    timeout: Duration,
+}
+
+impl Resolver {
+    /// Constructor
+    #[must_use]
+    pub fn new(server: Option<SocketAddr>, timeout: Duration) -> Self {
+        Self { server, timeout }
+    }
+
+    /// DNS lookup helper
+    ///
+    /// Results can be restricted to a given address family.
+    /// Only the first matching result is returned.
+    /// If there are no matching records of the required type, returns an error.
+    pub async fn lookup_host_by_family(
+        &self,
+        host: &str,
+        desired: AddressFamily,
+    ) -> anyhow::Result<IpAddr> {
+        let candidates = self.lookup_host(host).await?;
+        let mut it = candidates.iter();
+
+        let found = match desired {
+            AddressFamily::Any => it.next(),
+            AddressFamily::Inet => it.find(|addr| addr.is_ipv4()),
+            AddressFamily::Inet6 => it.find(|addr| addr.is_ipv6()),
+        };
+        found
+            .map(std::borrow::ToOwned::to_owned)
+            .ok_or(anyhow::anyhow!("host {host} found, but not as {desired:?}"))
+    }
+
+    /// DNS lookup helper for dual-stack connection attempts
+    ///
+    /// If `desired` is [`AddressFamily::Any`], returns the first address found of each family, in the order
+    /// the resolver gave them; so the first is the preferred address.
+    /// Otherwise, returns the single address that [`lookup_host_by_family`](Self::lookup_host_by_family) would.
+    pub async fn lookup_host_candidates(
+        &self,
+        host: &str,
+        desired: AddressFamily,
+    ) -> anyhow::Result<Vec<IpAddr>> {
+        if desired != AddressFamily::Any {
+            return Ok(vec![self.lookup_host_by_family(host, desired).await?]);
+        }
+        let candidates = self.lookup_host(host).await?;
+        let result = first_of_each_family(&candidates);
+        anyhow::ensure!(!result.is_empty(), "host {host} has no addresses");
+        Ok(result)
+    }
+
+    /// Looks up the qcp service for a domain in DNS SRV records (`_qcp._udp.<domain>`).
+    ///
+    /// Returns the target host and port of the preferred record (see [`preferred_srv`]),
+    /// or None if there is no such record.
+    pub async fn lookup_srv(&self, domain: &str) -> anyhow::Result<Option<(String, u16)>> {
+        let name = format!("_qcp._udp.{domain}");
+        let resolver = self.dns_client()?;
+        let lookup = self.with_timeout(&name, resolver.srv_lookup(name.as_str()));
+        let records = match lookup.await? {
+            Ok(records) => records,
+            Err(e) if e.is_no_records_found() || e.is_nx_domain() => return Ok(None),
+            Err(e) => return Err(e).with_context(|| format!("SRV lookup for {name} failed")),
+        };
+        let Some(srv) = preferred_srv(records.iter()) else {
+            return Ok(None);
+        };
+        // RFC 2782: a target of "." means the service is decidedly not available
+        anyhow::ensure!(
+            !srv.target().is_root(),
+            "the SRV record {name} says the service is not available"
+        );
+        let target = srv.target().to_utf8();
+        let target = target.strip_suffix('.').unwrap_or(&target).to_string();
+        Ok(Some((target, srv.port())))
+    }
+
+    /// Looks up all the addresses of a host
+    async fn lookup_host(&self, host: &str) -> anyhow::Result<Vec<IpAddr>> {
+        let failed = || format!("host name lookup for {host} failed");
+        if self.server.is_some() {
+            let resolver = self.dns_client()?;
+            let found = self
+                .with_timeout(host, resolver.lookup_ip(host))
+                .await?
+                .with_context(failed)?;
+            return Ok(found.iter().collect());
+        }
+        if self.timeout.is_zero() {
+            return dns_lookup::lookup_host(host).with_context(failed);
+        }
+        // The system resolver blocks, and can't be cancelled. Rather than tie up a thread of the runtime,
+        // which would delay its shutdown until the lookup gave up, we leave it to a thread of its own.
+        let (tx, rx) = tokio::sync::oneshot::channel();
+        let name = host.to_owned();
+        let _ = std::thread::spawn(move || tx.send(dns_lookup::lookup_host(&name)));
+        self.with_timeout(host, rx)
+            .await?
+            .context("host name lookup thread failed")?
+            .with_context(failed)
+    }
+
+    /// A DNS client which asks our DNS server, or failing that the servers in the system configuration
+    fn dns_client(&self) -> anyhow::Result<TokioResolver> {
+        let Some(server) = self.server else {
+            return Ok(TokioResolver::builder_tokio()
+                .context("could not read the system DNS configuration")?
+                .build());
+        };
+        let servers = NameServerConfigGroup::from_ips_clear(&[server.ip()], server.port(), true);
+        let mut builder = TokioResolver::builder_with_config(
+            ResolverConfig::from_parts(None, vec![], servers),
+            TokioConnectionProvider::default(),
+        );
+        // We want both families, for dual-stack connection attempts
+        builder.options_mut().ip_strategy = LookupIpStrategy::Ipv4AndIpv6;
+        Ok(builder.build())
+    }
+
+    /// Applies our time limit, if any, to a lookup of `what`
+    async fn with_timeout<T>(
+        &self,
+        what: &str,
+        lookup: impl Future<Output = T>,
+    ) -> anyhow::Result<T> {
+        if self.timeout.is_zero() {
+            return Ok(lookup.await);
+        }
+        tokio::time::timeout(self.timeout, lookup)
+            .await
+            .map_err(|_| {
+                anyhow::anyhow!(
+                    "DNS lookup for {what} timed out after {}s",
+                    self.timeout.as_secs()
+                )
+            })
+    }
+}
+
+/// Chooses among SRV records: the lowest priority value wins, then the greatest weight.
+///
+/// (RFC 2782 has us choose randomly according to weight, but we only make one connection,
+/// and being predictable is more useful.)
+fn preferred_srv<'a>(records: impl IntoIterator<Item = &'a SRV>) -> Option<&'a SRV> {
+    records
+        .into_iter()
+        .min_by_key(|r| (r.priority(), std::cmp::Reverse(r.weight())))
+}
+
+fn first_of_each_family(addresses: &[IpAddr]) -> Vec<IpAddr> {
+    let mut result = Vec::with_capacity(2);
+    for addr in addresses {
+        if !result
+            .iter()
+            .any(|r: &IpAddr| r.is_ipv4() == addr.is_ipv4())
+        {
+            result.push(*addr);
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use std::net::IpAddr;
+
+    use hickory_resolver::proto::rr::{rdata::SRV, Name};
+
+    use super::{first_of_each_family, preferred_srv};
+
+    #[test]
+    fn one_of_each() {
+        let addrs: Vec<IpAddr> = ["2001:db8::1", "2001:db8::2", "192.0.2.1", "192.0.2.2"]
+            .iter()
+            .map(|s| s.parse().unwrap())
+            .collect();
+        assert_eq!(first_of_each_family(&addrs), [addrs[0], addrs[2]]);
+        assert_eq!(first_of_each_family(&addrs[2..]), [addrs[2]]);
+        assert!(first_of_each_family(&[]).is_empty());
+    }
+
+    #[test]
+    fn srv_preference() {
+        let srv = |priority, weight, target: &str| {
+            SRV::new(priority, weight, 12345, Name::from_ascii(target).unwrap())
+        };
+        let records = [
+            srv(20, 100, "backup.example.com."),
+            srv(10, 5, "light.example.com."),
+            srv(10, 50, "heavy.example.com."),
+        ];
+        let chosen = preferred_srv(&records).unwrap();
+        assert_eq!(chosen.target().to_utf8(), "heavy.example.com.");
+        assert!(preferred_srv(&[]).is_none());
+    }
 }
@@ -0,0 +1,120 @@
+//! Classification of failures, for the process exit status
+// (c) 2024 Ross Younger
+
+//! # Exit status
+//! | Status | Meaning |
+//! | ------ | ------- |
+//! | 0 | Success |
+//! | 1 | A transfer failed, or some other error occurred |
+//! | 2 | Invalid arguments or configuration |
+//! | 3 | Could not connect to the remote host, or the connection timed out |
+//! | 4 | ssh could not authenticate: the login was refused, or host key verification failed |
+//!
+//! Errors are tagged with a [`FailureKind`] as they are raised; anything untagged counts as [`FailureKind::Transfer`].
+
+use std::fmt;
+
+/// The category of a failure, which determines the exit status
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FailureKind {
+    /// A file transfer or protocol error, or anything not otherwise classified
+    Transfer,
+    /// Invalid arguments or configuration
+    Config,
+    /// Could not connect to the remote host, or the connection timed out
+    Connection,
+    /// ssh could not authenticate to the remote host
+    Authentication,
+}
+
+impl FailureKind {
+    /// The process exit status for this kind of failure
+    #[must_use]
+    pub fn exit_code(self) -> u8 {
+        match self {
+            FailureKind::Transfer => 1,
+            FailureKind::Config => 2,
+            FailureKind::Connection => 3,
+            FailureKind::Authentication => 4,
+        }
+    }
+
+    /// Classifies an error; errors which have not been tagged count as `Transfer`
+    #[must_use]
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .downcast_ref::<Failure>()
+            .map_or(FailureKind::Transfer, |f| f.kind)
+    }
+
+    /// Tags an error with this kind, unless it has already been classified
+    pub(crate) fn tag(self, error: anyhow::Error) -> anyhow::Error {
+        if error.downcast_ref::<Failure>().is_some() {
+            error
+        } else {
+            Failure { kind: self, error }.into()
+        }
+    }
+}
+
+/// An error tagged with its [`FailureKind`].
+/// This displays exactly as the underlying error does.
+#[derive(Debug)]
+struct Failure {
+    kind: FailureKind,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Failure {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // anyhow prints the rest of the chain itself, via source()
+        write!(f, "{}", self.error)
+    }
+}
+
+impl std::error::Error for Failure {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Extension trait for tagging the error in a `Result` with a [`FailureKind`]
+pub(crate) trait Classify<T> {
+    /// Tags the error, if any, unless it has already been classified
+    fn classify(self, kind: FailureKind) -> anyhow::Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> Classify<T> for Result<T, E> {
+    fn classify(self, kind: FailureKind) -> anyhow::Result<T> {
+        self.map_err(|e| kind.tag(e.into()))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use anyhow::{anyhow, Context as _};
+
+    use super::{Classify as _, FailureKind};
+
+    #[test]
+    fn classification() {
+        let untagged = anyhow!("plain");
+        assert_eq!(FailureKind::of(&untagged), FailureKind::Transfer);
+
+        let tagged = Err::<(), _>(anyhow!("inner").context("outer"))
+            .classify(FailureKind::Connection)
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&tagged), FailureKind::Connection);
+        // Tagging does not change how the error is displayed
+        assert_eq!(tagged.to_string(), "outer");
+        assert_eq!(format!("{tagged:#}"), "outer: inner");
+
+        // The first classification sticks, and survives further context
+        let retagged = Err::<(), _>(tagged)
+            .classify(FailureKind::Config)
+            .context("more")
+            .unwrap_err();
+        assert_eq!(FailureKind::of(&retagged), FailureKind::Connection);
+        assert_eq!(FailureKind::Authentication.exit_code(), 4);
+    }
+}
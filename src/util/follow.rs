@@ -0,0 +1,157 @@
+//! Following a file which is being appended to, as `tail -F` does
+// (c) 2024 Ross Younger
+
+use std::path::{Path, PathBuf};
+
+use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+use tokio::time::Duration;
+
+use crate::protocol::session::FollowEvent;
+
+/// How often to look at a followed file, once everything in it has been read
+pub const POLL_INTERVAL: Duration = Duration::from_millis(500);
+
+/// Reads a file as it grows, and notices if it is rotated or truncated
+#[derive(Debug)]
+pub struct Follower {
+    path: PathBuf,
+    file: tokio::fs::File,
+    /// How much of the file has been read
+    position: u64,
+}
+
+impl Follower {
+    /// Starts following an open file, from `offset`.
+    ///
+    /// If the file is shorter than `offset`, it is taken to have been replaced, and is read from the beginning.
+    pub async fn new(path: &Path, mut file: tokio::fs::File, offset: u64) -> std::io::Result<Self> {
+        let len = file.metadata().await?.len();
+        let position = if offset > len {
+            tracing::debug!(
+                "{} is shorter than offset {offset}; starting from the beginning",
+                path.display()
+            );
+            0
+        } else {
+            offset
+        };
+        let _ = file.seek(std::io::SeekFrom::Start(position)).await?;
+        Ok(Self {
+            path: path.to_path_buf(),
+            file,
+            position,
+        })
+    }
+
+    /// Reads whatever is new in the file into `buf`.
+    ///
+    /// Returns [`FollowEvent::Data`] if anything was read, or what happened to the file once everything in it
+    /// has been read: None if nothing, otherwise [`FollowEvent::Rotated`] or [`FollowEvent::Truncated`],
+    /// after which there is nothing more to follow.
+    pub async fn next(&mut self, buf: &mut [u8]) -> std::io::Result<Option<FollowEvent>> {
+        loop {
+            let n = self.file.read(buf).await?;
+            if n > 0 {
+                self.position += n as u64;
+                return Ok(Some(FollowEvent::Data(n as u64)));
+            }
+            let meta = self.file.metadata().await?;
+            if meta.len() > self.position {
+                // It grew after we read it
+                continue;
+            }
+            if meta.len() < self.position {
+                return Ok(Some(FollowEvent::Truncated));
+            }
+            // While nothing is at the path, the file may still be written to through its old name
+            return Ok(match tokio::fs::metadata(&self.path).await {
+                Ok(current) if !super::io::same_file(&current, &meta) => Some(FollowEvent::Rotated),
+                _ => None,
+            });
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::io::Write as _;
+
+    use super::Follower;
+    use crate::protocol::session::FollowEvent;
+
+    async fn follow(path: &std::path::Path, offset: u64) -> Follower {
+        let file = tokio::fs::File::open(path).await.unwrap();
+        Follower::new(path, file, offset).await.unwrap()
+    }
+
+    fn append(path: &std::path::Path, data: &[u8]) {
+        let mut file = std::fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(data).unwrap();
+    }
+
+    #[tokio::test]
+    async fn growing() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("log");
+        std::fs::write(&path, b"hello ").unwrap();
+        let mut follower = follow(&path, 2).await;
+        let mut buf = [0u8; 64];
+        assert_eq!(
+            follower.next(&mut buf).await.unwrap(),
+            Some(FollowEvent::Data(4))
+        );
+        assert_eq!(&buf[..4], b"llo ");
+        assert_eq!(follower.next(&mut buf).await.unwrap(), None);
+        append(&path, b"world");
+        assert_eq!(
+            follower.next(&mut buf).await.unwrap(),
+            Some(FollowEvent::Data(5))
+        );
+        assert_eq!(&buf[..5], b"world");
+
+        // An offset beyond the end means the file was replaced
+        let mut follower = follow(&path, 1000).await;
+        assert_eq!(
+            follower.next(&mut buf).await.unwrap(),
+            Some(FollowEvent::Data(11))
+        );
+    }
+
+    #[tokio::test]
+    async fn truncated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("log");
+        std::fs::write(&path, b"hello").unwrap();
+        let mut follower = follow(&path, 5).await;
+        let mut buf = [0u8; 64];
+        assert_eq!(follower.next(&mut buf).await.unwrap(), None);
+        std::fs::write(&path, b"hi").unwrap();
+        assert_eq!(
+            follower.next(&mut buf).await.unwrap(),
+            Some(FollowEvent::Truncated)
+        );
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn rotated() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("log");
+        std::fs::write(&path, b"old").unwrap();
+        let mut follower = follow(&path, 3).await;
+        let mut buf = [0u8; 64];
+        std::fs::rename(&path, tmp.path().join("log.1")).unwrap();
+        // Nothing at the path yet; the old file is still followed
+        append(&tmp.path().join("log.1"), b" end");
+        assert_eq!(
+            follower.next(&mut buf).await.unwrap(),
+            Some(FollowEvent::Data(4))
+        );
+        assert_eq!(follower.next(&mut buf).await.unwrap(), None);
+        std::fs::write(&path, b"new").unwrap();
+        assert_eq!(
+            follower.next(&mut buf).await.unwrap(),
+            Some(FollowEvent::Rotated)
+        );
+    }
+}
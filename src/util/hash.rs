@@ -0,0 +1,160 @@
+//! Content hashing helpers
+// (c) 2024 Ross Younger
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncRead, AsyncWrite, ReadBuf};
+
+/// An [`AsyncWrite`] adapter that computes the BLAKE3 hash of the data written through it.
+///
+/// Hashing is optional; if disabled, this is a simple passthrough.
+#[derive(Debug)]
+pub struct HashingWriter<W> {
+    inner: W,
+    hasher: Option<blake3::Hasher>,
+}
+
+impl<W: AsyncWrite + Unpin> HashingWriter<W> {
+    /// Constructor. If `enabled` is false, no hash is computed.
+    pub fn new(inner: W, enabled: bool) -> Self {
+        Self {
+            inner,
+            hasher: enabled.then(blake3::Hasher::new),
+        }
+    }
+
+    /// Returns the hash of the data written so far, if enabled
+    #[must_use]
+    pub fn hash(&self) -> Option<blake3::Hash> {
+        self.hasher.as_ref().map(blake3::Hasher::finalize)
+    }
+
+    /// Unwraps this adapter, returning the underlying writer
+    pub fn into_inner(self) -> W {
+        self.inner
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for HashingWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        let result = Pin::new(&mut this.inner).poll_write(cx, buf);
+        if let (Poll::Ready(Ok(n)), Some(hasher)) = (&result, this.hasher.as_mut()) {
+            let _ = hasher.update(&buf[..*n]);
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+/// An [`AsyncRead`] adapter that computes the BLAKE3 hash of the data read through it, and counts it.
+#[derive(Debug)]
+pub struct HashingReader<R> {
+    inner: R,
+    hasher: blake3::Hasher,
+    count: u64,
+}
+
+impl<R: AsyncRead + Unpin> HashingReader<R> {
+    /// Constructor
+    pub fn new(inner: R) -> Self {
+        Self {
+            inner,
+            hasher: blake3::Hasher::new(),
+            count: 0,
+        }
+    }
+
+    /// Returns the hash of the data read so far
+    #[must_use]
+    pub fn hash(&self) -> blake3::Hash {
+        self.hasher.finalize()
+    }
+
+    /// Returns the number of bytes read so far
+    #[must_use]
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+}
+
+impl<R: AsyncRead + Unpin> AsyncRead for HashingReader<R> {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let before = buf.filled().len();
+        let result = Pin::new(&mut this.inner).poll_read(cx, buf);
+        if let Poll::Ready(Ok(())) = result {
+            let new = &buf.filled()[before..];
+            let _ = this.hasher.update(new);
+            this.count += new.len() as u64;
+        }
+        result
+    }
+}
+
+/// Computes the BLAKE3 hash of everything that can be read from a reader
+pub async fn hash_reader<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<blake3::Hash> {
+    let mut hashing = HashingWriter::new(tokio::io::sink(), true);
+    let _ = tokio::io::copy(reader, &mut hashing).await?;
+    Ok(hashing.hash().unwrap_or_else(|| blake3::hash(&[]))) // can't fail, we enabled hashing
+}
+
+#[cfg(test)]
+mod test {
+    use super::{hash_reader, HashingReader, HashingWriter};
+    use tokio::io::{AsyncReadExt as _, AsyncWriteExt as _};
+
+    #[tokio::test]
+    async fn hash_matches() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let mut uut = HashingWriter::new(Vec::new(), true);
+        uut.write_all(&data[..10]).await.unwrap();
+        uut.write_all(&data[10..]).await.unwrap();
+        assert_eq!(uut.hash().unwrap(), blake3::hash(data));
+        assert_eq!(uut.into_inner(), data);
+    }
+
+    #[tokio::test]
+    async fn disabled() {
+        let mut uut = HashingWriter::new(Vec::new(), false);
+        uut.write_all(b"hello").await.unwrap();
+        assert!(uut.hash().is_none());
+        assert_eq!(uut.into_inner(), b"hello");
+    }
+
+    #[tokio::test]
+    async fn hashing_reader() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let mut uut = HashingReader::new(&data[..]);
+        let mut out = Vec::new();
+        let _ = uut.read_to_end(&mut out).await.unwrap();
+        assert_eq!(out, data);
+        assert_eq!(uut.count(), data.len() as u64);
+        assert_eq!(uut.hash(), blake3::hash(data));
+    }
+
+    #[tokio::test]
+    async fn reader() {
+        let data = b"The quick brown fox jumps over the lazy dog";
+        let hash = hash_reader(&mut &data[..]).await.unwrap();
+        assert_eq!(hash, blake3::hash(data));
+    }
+}
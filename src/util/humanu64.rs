@@ -12,6 +12,13 @@ use serde::{
 /// An integer field that may also be expressed using SI notation (k, M, G, etc).
 /// For example, `1k` and `1000` are the same.
 ///
+/// It may also be expressed as a percentage of a link capacity, in the form `PERCENT%@CAPACITY`.
+/// The capacity is a quantity of bytes (with optional SI units), unless it ends in `bit`,
+/// in which case it is a quantity of bits.
+/// The result is `CAPACITY × PERCENT ÷ 100`, converted to bytes if necessary and rounded down.
+/// For example, `80%@1Gbit` and `80%@125M` both resolve to 100,000,000 bytes.
+/// The percentage must be greater than 0 and no more than 100.
+///
 /// (Nerdy description: This is a newtype wrapper to `u64` that adds a flexible deserializer via `humanize_rs::bytes::Bytes<u64>`.)
 
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize)]
@@ -46,21 +53,61 @@ impl From<HumanU64> for String {
     }
 }
 
+#[allow(clippy::result_large_err)]
+impl HumanU64 {
+    /// Parses a quantity of bytes with optional SI units
+    fn parse_bytes(s: &str) -> Result<u64, figment::Error> {
+        use figment::error::Error as FigmentError;
+        Ok(Bytes::from_str(s)
+            .map_err(|_| {
+                FigmentError::invalid_value(
+                    de::Unexpected::Str(s),
+                    &"an integer with optional units (examples: `100`, `10M`, `42k`, `80%@1Gbit`)",
+                )
+            })?
+            .size())
+    }
+
+    /// Parses a link capacity, returning a quantity of bytes.
+    /// If the string ends in `bit`, it is interpreted as a number of bits.
+    fn parse_capacity(s: &str) -> Result<u64, figment::Error> {
+        let lower = s.to_ascii_lowercase();
+        if let Some(bits) = lower.strip_suffix("bit") {
+            Ok(Self::parse_bytes(bits)? / 8)
+        } else {
+            Self::parse_bytes(s)
+        }
+    }
+
+    /// Resolves an expression of the form `PERCENT%@CAPACITY`
+    fn parse_percentage(percent: &str, capacity: &str) -> Result<u64, figment::Error> {
+        use figment::error::Error as FigmentError;
+        let pct = percent.parse::<f64>().map_err(|_| {
+            FigmentError::invalid_value(de::Unexpected::Str(percent), &"a percentage")
+        })?;
+        if !(pct > 0. && pct <= 100.) {
+            return Err(FigmentError::custom(format!(
+                "invalid percentage `{percent}%` (must be greater than 0 and no more than 100)"
+            )));
+        }
+        let capacity = Self::parse_capacity(capacity)?;
+        #[allow(
+            clippy::cast_possible_truncation,
+            clippy::cast_precision_loss,
+            clippy::cast_sign_loss
+        )]
+        Ok((capacity as f64 * pct / 100.) as u64)
+    }
+}
+
 impl FromStr for HumanU64 {
     type Err = figment::Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
-        use figment::error::Error as FigmentError;
-        Ok(Self::new(
-            Bytes::from_str(s)
-                .map_err(|_| {
-                    FigmentError::invalid_value(
-                        de::Unexpected::Str(s),
-                        &"an integer with optional units (examples: `100`, `10M`, `42k`)",
-                    )
-                })?
-                .size(),
-        ))
+        if let Some((percent, capacity)) = s.split_once("%@") {
+            return Ok(Self::new(Self::parse_percentage(percent, capacity)?));
+        }
+        Ok(Self::new(Self::parse_bytes(s)?))
     }
 }
 
@@ -119,4 +166,28 @@ mod test {
         let result = HumanU64::from_str("12345k").unwrap();
         assert_eq!(*result, 12_345_000);
     }
+
+    #[test]
+    fn percentage_of_capacity() {
+        for (s, expected) in [
+            ("80%@1Gbit", 100_000_000),
+            ("80%@125M", 100_000_000),
+            ("100%@10Mbit", 1_250_000),
+            ("12.5%@1000", 125),
+        ] {
+            assert_eq!(*HumanU64::from_str(s).unwrap(), expected, "{s}");
+        }
+    }
+
+    #[test]
+    fn percentage_out_of_range() {
+        for s in ["0%@1G", "101%@1G", "-5%@1G", "x%@1G", "50%@wombat"] {
+            let _ = HumanU64::from_str(s).expect_err(s);
+        }
+    }
+
+    #[test]
+    fn deser_percentage() {
+        test_deser_str("\"50%@1Gbit\"", 62_500_000);
+    }
 }
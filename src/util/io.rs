@@ -3,7 +3,20 @@
 
 use crate::protocol::session::Status;
 use futures_util::TryFutureExt as _;
-use std::{fs::Metadata, io::ErrorKind, path::Path, path::PathBuf, str::FromStr as _};
+use std::{
+    fs::Metadata,
+    future::Future as _,
+    io::ErrorKind,
+    path::Path,
+    path::PathBuf,
+    pin::Pin,
+    task::{Context, Poll},
+    time::Duration,
+};
+use tokio::{
+    io::AsyncWrite,
+    time::{Instant, Sleep},
+};
 
 /// Opens a local file for reading, returning a filehandle and metadata.
 /// Error type is a tuple ready to send as a Status response.
@@ -39,33 +52,193 @@ pub async fn open_file(
     Ok((fh, meta))
 }
 
-/// Opens a local file for writing, from an incoming `FileHeader`
-#[allow(clippy::missing_panics_doc)]
-pub async fn create_truncate_file(
-    path: &str,
-    header: &crate::protocol::session::FileHeader,
-) -> anyhow::Result<tokio::fs::File> {
-    let mut dest_path = PathBuf::from_str(path).unwrap(); // this is marked as infallible
-    let dest_meta = tokio::fs::metadata(&dest_path).await;
-    if let Ok(meta) = dest_meta {
-        // if it's a file, proceed (overwriting)
-        if meta.is_dir() {
-            dest_path.push(header.filename.clone());
-        } else if meta.is_symlink() {
-            // TODO: Need to cope with this case; test whether it's a directory?
-            let deref = std::fs::read_link(&dest_path)?;
-            if std::fs::metadata(deref).is_ok_and(|meta| meta.is_dir()) {
-                dest_path.push(header.filename.clone());
-            }
-            // Else assume the link points to a file, which we will overwrite.
+/// Works out where to write a received file.
+///
+/// If `dest` is a directory, the file part of `filename` is appended to it.
+#[must_use]
+pub fn local_destination(dest: &str, filename: &str) -> PathBuf {
+    let mut dest_path = PathBuf::from(dest);
+    // N.B. is_dir() follows symlinks
+    if dest.is_empty() || dest_path.is_dir() {
+        if let Some(name) = Path::new(filename).file_name() {
+            dest_path.push(name);
         }
     }
+    dest_path
+}
 
-    let file = tokio::fs::File::create(dest_path).await?;
-    file.set_len(header.size).await?;
+/// Opens a local file for writing, ready for data to be written from `resume_offset`.
+///
+/// If `resume_offset` is 0, the file is created or truncated; otherwise, the existing data up to that point is kept.
+/// In either case, the file is extended to `size`. If `preallocate` is set, disk space for the whole file is reserved
+/// where possible.
+pub async fn open_destination(
+    path: &Path,
+    size: u64,
+    resume_offset: u64,
+    preallocate: bool,
+) -> anyhow::Result<tokio::fs::File> {
+    use tokio::io::AsyncSeekExt as _;
+    let file = if resume_offset == 0 {
+        tokio::fs::File::create(path).await
+    } else {
+        // Keep what is already there
+        tokio::fs::OpenOptions::new().write(true).open(path).await
+    };
+    let mut file = file.map_err(|e| anyhow::anyhow!("Could not write to destination: {e}"))?;
+    if resume_offset > 0 {
+        if file.metadata().await.map(|m| m.len()).unwrap_or_default() < resume_offset {
+            anyhow::bail!("Destination is shorter than the resume offset");
+        }
+        let _ = file
+            .seek(std::io::SeekFrom::Start(resume_offset))
+            .await
+            .map_err(|e| anyhow::anyhow!("Could not seek in destination file: {e}"))?;
+    }
+    if preallocate {
+        preallocate_file(&file, size);
+    }
+    file.set_len(size)
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not set destination file length: {e}"))?;
     Ok(file)
 }
 
+/// Reserves disk space for a file of the given size.
+///
+/// Unlike `set_len`, which may leave a sparse file, this allocates the whole extent up front.
+/// Failure is not an error; the caller sets the length anyway.
+#[cfg(target_os = "linux")]
+fn preallocate_file(file: &tokio::fs::File, size: u64) {
+    use nix::fcntl::{fallocate, FallocateFlags};
+    use std::os::fd::AsRawFd as _;
+    if size == 0 {
+        return; // fallocate rejects an empty range
+    }
+    let Ok(len) = size.try_into() else {
+        return;
+    };
+    // Without FALLOC_FL_KEEP_SIZE, this also extends the file
+    let _ = fallocate(file.as_raw_fd(), FallocateFlags::empty(), 0, len)
+        .inspect_err(|e| tracing::debug!("could not preallocate destination file: {e}"));
+}
+
+/// Reserves disk space for a file of the given size (not supported on this platform)
+#[cfg(not(target_os = "linux"))]
+fn preallocate_file(_file: &tokio::fs::File, _size: u64) {}
+
+/// Opens a local file for appending, creating it if necessary.
+///
+/// Returns the file, and its length before anything was appended.
+pub async fn open_for_append(path: &Path) -> anyhow::Result<(tokio::fs::File, u64)> {
+    use tokio::io::AsyncSeekExt as _;
+    let mut file = tokio::fs::OpenOptions::new()
+        .append(true)
+        .create(true)
+        .open(path)
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not write to destination: {e}"))?;
+    let len = file
+        .seek(std::io::SeekFrom::End(0))
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not seek in destination file: {e}"))?;
+    Ok((file, len))
+}
+
+/// The temporary file to receive into, before it is renamed over `dest`.
+///
+/// This is a hidden file alongside the destination, so the rename does not cross filesystems.
+#[must_use]
+pub fn temporary_destination(dest: &Path) -> PathBuf {
+    let mut name = std::ffi::OsString::from(".");
+    name.push(dest.file_name().unwrap_or_default());
+    name.push(".qcp-tmp");
+    dest.with_file_name(name)
+}
+
+/// Can the destination be replaced by renaming a temporary file over it?
+///
+/// This is true if it does not exist, or is a regular file.
+/// Symbolic links and special files (devices, pipes) must be written to in place.
+pub async fn is_replaceable(dest: &Path) -> bool {
+    match tokio::fs::symlink_metadata(dest).await {
+        Ok(meta) => meta.is_file(),
+        Err(e) => e.kind() == ErrorKind::NotFound,
+    }
+}
+
+/// Renames a completed temporary file over its destination.
+///
+/// If the destination exists, its permissions are carried over, as they would have been had it been overwritten.
+pub async fn rename_into_place(temp: &Path, dest: &Path) -> anyhow::Result<()> {
+    if let Ok(meta) = tokio::fs::metadata(dest).await {
+        let _ = tokio::fs::set_permissions(temp, meta.permissions())
+            .await
+            .inspect_err(|e| tracing::debug!("could not copy destination permissions: {e}"));
+    }
+    tokio::fs::rename(temp, dest).await.map_err(|e| {
+        anyhow::anyhow!(
+            "Could not rename {} to {}: {e}",
+            temp.display(),
+            dest.display()
+        )
+    })
+}
+
+/// Makes sure a file's data has reached the disk
+pub async fn sync_file(file: &tokio::fs::File, path: &Path) -> anyhow::Result<()> {
+    file.sync_all()
+        .await
+        .map_err(|e| anyhow::anyhow!("Could not sync {} to disk: {e}", path.display()))
+}
+
+/// Makes sure the directory entry for a file has reached the disk, so the file survives a crash.
+///
+/// This does nothing on platforms where directories cannot be synced.
+pub async fn sync_parent_directory(path: &Path) -> anyhow::Result<()> {
+    #[cfg(unix)]
+    {
+        let parent = match path.parent() {
+            Some(p) if !p.as_os_str().is_empty() => p,
+            _ => Path::new("."),
+        };
+        let dir = tokio::fs::File::open(parent)
+            .await
+            .map_err(|e| anyhow::anyhow!("Could not open {}: {e}", parent.display()))?;
+        sync_file(&dir, parent).await?;
+    }
+    #[cfg(not(unix))]
+    let _ = path;
+    Ok(())
+}
+
+/// Did an operation fail because the filesystem ran out of space?
+///
+/// This looks through the whole chain of `e`, as the I/O error is usually wrapped by the time it reaches us.
+#[must_use]
+pub fn is_disk_full(e: &anyhow::Error) -> bool {
+    e.chain()
+        .filter_map(|cause| cause.downcast_ref::<std::io::Error>())
+        .filter_map(std::io::Error::raw_os_error)
+        .any(is_disk_full_code)
+}
+
+#[cfg(unix)]
+fn is_disk_full_code(code: i32) -> bool {
+    code == nix::errno::Errno::ENOSPC as i32
+}
+
+#[cfg(windows)]
+fn is_disk_full_code(code: i32) -> bool {
+    // ERROR_HANDLE_DISK_FULL, ERROR_DISK_FULL
+    matches!(code, 39 | 112)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn is_disk_full_code(_code: i32) -> bool {
+    false
+}
+
 /// Can we write to a given path?
 pub async fn dest_is_writeable(dest: &PathBuf) -> bool {
     let meta = tokio::fs::metadata(dest).await;
@@ -74,3 +247,568 @@ pub async fn dest_is_writeable(dest: &PathBuf) -> bool {
         Err(_) => false,
     }
 }
+
+/// The modification time of a file in nanoseconds since the Unix epoch, as sent in a `FileHeader`.
+///
+/// Returns 0 if the platform or filesystem does not report it.
+#[must_use]
+pub fn mtime_nanos(meta: &Metadata) -> u64 {
+    meta.modified()
+        .ok()
+        .and_then(|t| t.duration_since(std::time::UNIX_EPOCH).ok())
+        .and_then(|d| u64::try_from(d.as_nanos()).ok())
+        .unwrap_or_default()
+}
+
+/// Deletes a file which has just been sent, unless it has changed since `sent` was read.
+///
+/// This is for `--remove-source-files`; a file which is still being written to must not be lost.
+pub async fn remove_if_unchanged(path: &Path, sent: &Metadata) -> std::io::Result<()> {
+    let meta = tokio::fs::metadata(path).await?;
+    if meta.len() != sent.len() || mtime_nanos(&meta) != mtime_nanos(sent) {
+        return Err(std::io::Error::other(
+            "the file changed while it was being sent",
+        ));
+    }
+    tokio::fs::remove_file(path).await
+}
+
+/// Whether two sets of metadata describe the same file, as opposed to different files which were at the same path.
+///
+/// On platforms where this cannot be told, they are assumed to be the same.
+#[must_use]
+pub fn same_file(a: &Metadata, b: &Metadata) -> bool {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt as _;
+        a.dev() == b.dev() && a.ino() == b.ino()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (a, b);
+        true
+    }
+}
+
+/// Sets the modification time of a file, given in nanoseconds since the Unix epoch (as sent in a `FileHeader`)
+pub fn set_mtime(path: &Path, mtime_nanos: u64) -> std::io::Result<()> {
+    let secs = i64::try_from(mtime_nanos / 1_000_000_000).unwrap_or(i64::MAX);
+    #[allow(clippy::cast_possible_truncation)] // it's less than 10^9
+    let nanos = (mtime_nanos % 1_000_000_000) as u32;
+    filetime::set_file_mtime(path, filetime::FileTime::from_unix_time(secs, nanos))
+}
+
+/// Sets the modification time of a file we received, if known (i.e. non-zero).
+///
+/// This is a best-effort operation; failure is logged but not fatal.
+pub fn apply_mtime(path: &Path, mtime_nanos: u64) {
+    if mtime_nanos == 0 {
+        return;
+    }
+    if let Err(e) = set_mtime(path, mtime_nanos) {
+        tracing::warn!("Could not set modification time of {}: {e}", path.display());
+    }
+}
+
+/// The Unix mode of a file (`st_mode`), as sent in a `FileHeader`.
+///
+/// Returns 0 on platforms without Unix permissions.
+#[must_use]
+pub fn mode_bits(meta: &Metadata) -> u32 {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        meta.permissions().mode()
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = meta;
+        0
+    }
+}
+
+/// The process umask.
+///
+/// This is read once, on first use, as reading it means briefly changing it.
+#[cfg(unix)]
+fn umask() -> u32 {
+    use nix::sys::stat::{umask, Mode};
+    static UMASK: std::sync::LazyLock<u32> = std::sync::LazyLock::new(|| {
+        let mask = umask(Mode::empty());
+        let _ = umask(mask);
+        #[allow(clippy::useless_conversion)] // mode_t is narrower on some platforms
+        mask.bits().into()
+    });
+    *UMASK
+}
+
+/// Computes the permission bits to apply to a received file.
+/// Unless `exact`, any bits set in the `umask` are cleared, as they would be for a newly created file.
+#[cfg(unix)]
+fn permission_bits(mode: u32, exact: bool, umask: u32) -> u32 {
+    let bits = mode & 0o7777;
+    if exact {
+        bits
+    } else {
+        bits & !umask
+    }
+}
+
+/// Sets the permissions of a file we received from a Unix mode, if known (i.e. non-zero).
+///
+/// Unless `exact`, the permissions are clamped by the process umask.
+/// This is a best-effort operation; failure is logged but not fatal.
+/// It does nothing on platforms without Unix permissions.
+pub fn apply_mode(path: &Path, mode: u32, exact: bool) {
+    if mode == 0 {
+        return;
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt as _;
+        let perms = std::fs::Permissions::from_mode(permission_bits(mode, exact, umask()));
+        if let Err(e) = std::fs::set_permissions(path, perms) {
+            tracing::warn!("Could not set permissions of {}: {e}", path.display());
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = (path, exact);
+}
+
+/// Creates a symbolic link at `linkpath` pointing to `target`.
+///
+/// An existing symbolic link at `linkpath` is replaced; anything else there is an error.
+/// Symbolic links are only supported on Unix platforms.
+pub async fn create_symlink(target: &str, linkpath: &Path) -> std::io::Result<()> {
+    if tokio::fs::symlink_metadata(linkpath)
+        .await
+        .is_ok_and(|m| m.file_type().is_symlink())
+    {
+        tokio::fs::remove_file(linkpath).await?;
+    }
+    #[cfg(unix)]
+    {
+        tokio::fs::symlink(target, linkpath).await
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = target;
+        Err(std::io::Error::new(
+            ErrorKind::Unsupported,
+            "symbolic links are not supported on this platform",
+        ))
+    }
+}
+
+/// Interprets a filename received from the remote as a path relative to some destination directory.
+///
+/// Returns `None` if the path is empty, absolute, or tries to escape the destination (e.g. with `..`).
+#[must_use]
+pub fn relative_path(filename: &str) -> Option<PathBuf> {
+    use std::path::Component;
+    let path = PathBuf::from(filename);
+    let mut components = path.components().peekable();
+    let _ = components.peek()?;
+    components
+        .all(|c| matches!(c, Component::Normal(_) | Component::CurDir))
+        .then_some(path)
+}
+
+/// Lists the contents of a directory tree, without following symbolic links.
+///
+/// Yields the path of each entry relative to `root`, and its metadata, as the entry is found,
+/// so a large tree is never held in memory. The root itself is not listed.
+/// Entries whose paths are not valid UTF-8 are skipped.
+///
+/// This does blocking I/O.
+pub fn list_tree(root: PathBuf) -> impl Iterator<Item = std::io::Result<(String, Metadata)>> {
+    walkdir::WalkDir::new(&root)
+        .min_depth(1)
+        .into_iter()
+        .filter_map(move |entry| tree_entry(&root, entry).transpose())
+}
+
+/// Helper for [`list_tree`]. Returns None if the entry is to be skipped.
+fn tree_entry(
+    root: &Path,
+    entry: walkdir::Result<walkdir::DirEntry>,
+) -> std::io::Result<Option<(String, Metadata)>> {
+    let entry = entry?;
+    let relative = entry
+        .path()
+        .strip_prefix(root)
+        .map_err(std::io::Error::other)?;
+    let Some(relative) = relative.to_str() else {
+        tracing::warn!(
+            "Skipping {}: filename is not valid UTF-8",
+            entry.path().display()
+        );
+        return Ok(None);
+    };
+    Ok(Some((relative.to_string(), entry.metadata()?)))
+}
+
+/// Limits the number of files concurrently doing disk I/O.
+///
+/// On spinning disks, many files being read or written at once can cause the disk to thrash,
+/// so aggregate throughput may be worse than transferring them one after another.
+#[derive(Clone, Debug, Default)]
+pub struct IoLimiter(Option<std::sync::Arc<tokio::sync::Semaphore>>);
+
+impl IoLimiter {
+    /// Constructor. A `limit` of 0 means unlimited.
+    #[must_use]
+    pub fn new(limit: u16) -> Self {
+        Self((limit != 0).then(|| std::sync::Arc::new(tokio::sync::Semaphore::new(limit.into()))))
+    }
+
+    /// Waits for permission to start disk I/O.
+    /// The permission is held until the returned value is dropped.
+    pub async fn acquire(&self) -> Option<tokio::sync::OwnedSemaphorePermit> {
+        // The semaphore is never closed, so acquisition cannot fail
+        self.0.as_ref()?.clone().acquire_owned().await.ok()
+    }
+}
+
+/// An [`AsyncWrite`] adapter which limits the rate at which data is written,
+/// using a token bucket.
+///
+/// The bucket holds up to a tenth of a second's worth of data, so short bursts are smoothed out.
+#[derive(Debug)]
+pub struct RateLimitedWriter<W> {
+    inner: W,
+    /// Bytes per second; 0 means unlimited
+    rate: u64,
+    /// Bytes we may currently write
+    tokens: f64,
+    /// When we last added tokens
+    refilled: Instant,
+    /// Set when we are waiting for more tokens
+    sleep: Option<Pin<Box<Sleep>>>,
+}
+
+impl<W: AsyncWrite + Unpin> RateLimitedWriter<W> {
+    /// Constructor. A `rate` of 0 means unlimited.
+    pub fn new(inner: W, rate: u64) -> Self {
+        let mut result = Self {
+            inner,
+            rate,
+            tokens: 0.0,
+            refilled: Instant::now(),
+            sleep: None,
+        };
+        result.tokens = result.capacity();
+        result
+    }
+
+    /// The size of the token bucket
+    #[allow(clippy::cast_precision_loss)]
+    fn capacity(&self) -> f64 {
+        (self.rate as f64 / 10.0).max(1.0)
+    }
+
+    fn refill(&mut self) {
+        let now = Instant::now();
+        #[allow(clippy::cast_precision_loss)]
+        let added = now.duration_since(self.refilled).as_secs_f64() * self.rate as f64;
+        self.tokens = (self.tokens + added).min(self.capacity());
+        self.refilled = now;
+    }
+}
+
+impl<W: AsyncWrite + Unpin> AsyncWrite for RateLimitedWriter<W> {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<std::io::Result<usize>> {
+        let this = self.get_mut();
+        if this.rate == 0 {
+            return Pin::new(&mut this.inner).poll_write(cx, buf);
+        }
+        // Wait until we may write a reasonable amount, so we don't dribble out tiny writes
+        #[allow(clippy::cast_precision_loss)]
+        let wanted = (buf.len() as f64).min(this.capacity());
+        loop {
+            if let Some(sleep) = this.sleep.as_mut() {
+                if sleep.as_mut().poll(cx).is_pending() {
+                    return Poll::Pending;
+                }
+                this.sleep = None;
+            }
+            this.refill();
+            if this.tokens >= wanted {
+                break;
+            }
+            #[allow(clippy::cast_precision_loss)]
+            let wait = (wanted - this.tokens) / this.rate as f64;
+            this.sleep = Some(Box::pin(tokio::time::sleep(Duration::from_secs_f64(wait))));
+        }
+        #[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+        let allowed = std::cmp::min(buf.len(), this.tokens as usize);
+        let result = Pin::new(&mut this.inner).poll_write(cx, &buf[..allowed]);
+        if let Poll::Ready(Ok(n)) = &result {
+            #[allow(clippy::cast_precision_loss)]
+            let n = *n as f64;
+            this.tokens -= n;
+        }
+        result
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<std::io::Result<()>> {
+        Pin::new(&mut self.get_mut().inner).poll_shutdown(cx)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{
+        is_disk_full, is_replaceable, list_tree, local_destination, mtime_nanos, open_destination,
+        open_for_append, relative_path, remove_if_unchanged, rename_into_place, set_mtime,
+        sync_file, sync_parent_directory, temporary_destination, IoLimiter, RateLimitedWriter,
+    };
+    use std::path::PathBuf;
+
+    #[cfg(unix)]
+    #[test]
+    fn disk_full() {
+        let full = std::io::Error::from_raw_os_error(nix::errno::Errno::ENOSPC as i32);
+        assert!(is_disk_full(&anyhow::Error::new(full).context("writing")));
+        let denied = std::io::Error::from_raw_os_error(nix::errno::Errno::EACCES as i32);
+        assert!(!is_disk_full(&denied.into()));
+        assert!(!is_disk_full(&anyhow::anyhow!("something else")));
+    }
+
+    #[tokio::test]
+    async fn remove_unchanged() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, "hello").unwrap();
+        let sent = std::fs::metadata(&path).unwrap();
+        std::fs::write(&path, "hello world").unwrap();
+        assert!(remove_if_unchanged(&path, &sent).await.is_err());
+        assert!(path.exists());
+
+        let sent = std::fs::metadata(&path).unwrap();
+        remove_if_unchanged(&path, &sent).await.unwrap();
+        assert!(!path.exists());
+    }
+
+    #[tokio::test]
+    async fn destinations() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dir = tmp.path().to_str().unwrap();
+        assert_eq!(
+            local_destination(dir, "/remote/file"),
+            tmp.path().join("file")
+        );
+        let file = tmp.path().join("other");
+        let file = file.to_str().unwrap();
+        assert_eq!(local_destination(file, "/remote/file"), PathBuf::from(file));
+        assert_eq!(local_destination("", "/remote/file"), PathBuf::from("file"));
+
+        // resuming keeps the existing data
+        let path = tmp.path().join("resumed");
+        std::fs::write(&path, "hello").unwrap();
+        assert!(open_destination(&path, 10, 6, false).await.is_err());
+        let mut f = open_destination(&path, 10, 5, false).await.unwrap();
+        tokio::io::AsyncWriteExt::write_all(&mut f, b"world")
+            .await
+            .unwrap();
+        tokio::io::AsyncWriteExt::flush(&mut f).await.unwrap();
+        drop(f);
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld");
+        let _ = open_destination(&path, 3, 0, false).await.unwrap();
+        assert_eq!(std::fs::read(&path).unwrap(), b"\0\0\0");
+    }
+
+    #[tokio::test]
+    async fn temporary() {
+        let tmp = tempfile::tempdir().unwrap();
+        let dest = tmp.path().join("file");
+        let staging = temporary_destination(&dest);
+        assert_eq!(staging, tmp.path().join(".file.qcp-tmp"));
+        assert!(is_replaceable(&dest).await);
+        std::fs::write(&dest, "old").unwrap();
+        assert!(is_replaceable(&dest).await);
+        assert!(!is_replaceable(tmp.path()).await);
+
+        std::fs::write(&staging, "new").unwrap();
+        rename_into_place(&staging, &dest).await.unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"new");
+        assert!(!staging.exists());
+
+        let f = tokio::fs::File::open(&dest).await.unwrap();
+        sync_file(&f, &dest).await.unwrap();
+        sync_parent_directory(&dest).await.unwrap();
+        sync_parent_directory(std::path::Path::new("relative"))
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn preallocate() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        let f = open_destination(&path, 100_000, 0, true).await.unwrap();
+        drop(f);
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 100_000);
+        let _ = open_destination(&path, 0, 0, true).await.unwrap();
+        assert_eq!(std::fs::metadata(&path).unwrap().len(), 0);
+    }
+
+    #[tokio::test]
+    async fn append() {
+        use tokio::io::AsyncWriteExt as _;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("log");
+        for (data, expected_len) in [(&b"hello"[..], 0), (&b"world"[..], 5)] {
+            let (mut f, len) = open_for_append(&path).await.unwrap();
+            assert_eq!(len, expected_len);
+            f.write_all(data).await.unwrap();
+            f.flush().await.unwrap();
+        }
+        assert_eq!(std::fs::read(&path).unwrap(), b"helloworld");
+        assert!(open_for_append(tmp.path()).await.is_err());
+    }
+
+    #[test]
+    fn mtime() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, "hello").unwrap();
+        let when = 1_234_567_890_123_456_789;
+        set_mtime(&path, when).unwrap();
+        let meta = std::fs::metadata(&path).unwrap();
+        // not all filesystems store nanoseconds
+        assert_eq!(mtime_nanos(&meta) / 1_000_000_000, when / 1_000_000_000);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn permissions() {
+        use super::permission_bits;
+        assert_eq!(permission_bits(0o100_755, false, 0o022), 0o755);
+        assert_eq!(permission_bits(0o100_777, false, 0o027), 0o750);
+        assert_eq!(permission_bits(0o100_777, true, 0o027), 0o777);
+        assert_eq!(permission_bits(0o104_755, true, 0o022), 0o4755);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn apply_permissions() {
+        use super::{apply_mode, mode_bits};
+        use std::os::unix::fs::PermissionsExt as _;
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        std::fs::write(&path, "hello").unwrap();
+        apply_mode(&path, 0o100_604, true);
+        let meta = std::fs::metadata(&path).unwrap();
+        assert_eq!(meta.permissions().mode() & 0o777, 0o604);
+        assert_eq!(mode_bits(&meta) & 0o777, 0o604);
+        // 0 means unknown, so leaves it alone
+        apply_mode(&path, 0, true);
+        assert_eq!(mode_bits(&std::fs::metadata(&path).unwrap()) & 0o777, 0o604);
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn symlinks() {
+        use super::create_symlink;
+        let tmp = tempfile::tempdir().unwrap();
+        let link = tmp.path().join("link");
+        create_symlink("target", &link).await.unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), PathBuf::from("target"));
+        // replaces an existing link
+        create_symlink("other", &link).await.unwrap();
+        assert_eq!(std::fs::read_link(&link).unwrap(), PathBuf::from("other"));
+        // but not a file
+        let file = tmp.path().join("file");
+        std::fs::write(&file, "hello").unwrap();
+        assert!(create_symlink("target", &file).await.is_err());
+    }
+
+    #[test]
+    fn relative_paths() {
+        assert_eq!(relative_path("file"), Some(PathBuf::from("file")));
+        assert_eq!(
+            relative_path("dir/sub/file"),
+            Some(PathBuf::from("dir/sub/file"))
+        );
+        assert_eq!(relative_path(""), None);
+        assert_eq!(relative_path("/etc/passwd"), None);
+        assert_eq!(relative_path("dir/../../file"), None);
+        assert_eq!(relative_path(".."), None);
+    }
+
+    #[test]
+    fn listing() {
+        let tmp = tempfile::tempdir().unwrap();
+        std::fs::create_dir_all(tmp.path().join("sub/empty")).unwrap();
+        std::fs::write(tmp.path().join("sub/file"), "hello").unwrap();
+        std::fs::write(tmp.path().join("top"), "").unwrap();
+        let mut listing = list_tree(tmp.path().to_path_buf())
+            .map(|entry| {
+                let (path, meta) = entry.unwrap();
+                (path, meta.is_dir(), meta.len())
+            })
+            .collect::<Vec<_>>();
+        listing.sort();
+        assert_eq!(listing[0], ("sub".into(), true, listing[0].2));
+        assert_eq!(listing[1], ("sub/empty".into(), true, listing[1].2));
+        assert_eq!(listing[2], ("sub/file".into(), false, 5));
+        assert_eq!(listing[3], ("top".into(), false, 0));
+        assert_eq!(listing.len(), 4);
+        let mut missing = list_tree(tmp.path().join("nonexistent"));
+        assert!(missing.next().unwrap().is_err());
+    }
+
+    #[tokio::test]
+    async fn unlimited() {
+        let uut = IoLimiter::new(0);
+        assert!(uut.acquire().await.is_none());
+    }
+
+    #[tokio::test]
+    async fn limited() {
+        let uut = IoLimiter::new(1);
+        let permit = uut.acquire().await;
+        assert!(permit.is_some());
+        let second = uut.clone();
+        let mut waiter = tokio::spawn(async move { second.acquire().await.is_some() });
+        // the second acquisition must wait for the first permit to be released
+        assert!(
+            tokio::time::timeout(std::time::Duration::from_millis(50), &mut waiter)
+                .await
+                .is_err()
+        );
+        drop(permit);
+        assert!(waiter.await.unwrap());
+    }
+
+    #[tokio::test]
+    async fn rate_limited() {
+        use tokio::io::AsyncWriteExt as _;
+        let data = vec![0u8; 300_000];
+        let start = std::time::Instant::now();
+        let mut uut = RateLimitedWriter::new(Vec::new(), 1_000_000);
+        uut.write_all(&data).await.unwrap();
+        // The first 100k may go at once; the rest takes 0.2s
+        let elapsed = start.elapsed();
+        assert!(
+            elapsed >= std::time::Duration::from_millis(180),
+            "{elapsed:?}"
+        );
+        assert!(elapsed < std::time::Duration::from_secs(2), "{elapsed:?}");
+        assert_eq!(uut.inner.len(), data.len());
+
+        let start = std::time::Instant::now();
+        let mut uut = RateLimitedWriter::new(Vec::new(), 0);
+        uut.write_all(&data).await.unwrap();
+        assert!(start.elapsed() < std::time::Duration::from_millis(100));
+    }
+}
@@ -0,0 +1,173 @@
+//! Memory-mapped reading of files being sent
+// (c) 2024 Ross Younger
+
+//! # Rationale
+//! Reading a large file through a buffer takes a system call, and a copy, for every buffer-full.
+//! Mapping the file into memory instead lets its pages be handed straight to the network stack.
+//!
+//! This is opt-in (`mmap`), because a mapping has its own failure mode: if the file is truncated by some other
+//! process while it is being sent, touching the missing pages kills qcp with SIGBUS.
+//! Small files gain nothing, so are read as usual (see [`MMAP_THRESHOLD`]), as are files which cannot be mapped
+//! and files on platforms where mapping is not supported.
+
+// Mapping a file is inherently unsafe; this module keeps that contained.
+#![allow(unsafe_code)]
+
+use std::{
+    pin::Pin,
+    task::{Context, Poll},
+};
+
+use tokio::io::{AsyncBufRead, AsyncRead, BufReader, ReadBuf};
+use tracing::debug;
+
+/// Files smaller than this are read as usual, even if `mmap` is set
+pub const MMAP_THRESHOLD: u64 = 1 << 20;
+
+/// How much of a mapping to hand out at a time, so that progress reporting and rate limiting stay smooth
+const CHUNK: usize = 256 * 1024;
+
+/// A reader for a file being sent
+pub type SourceReader = Box<dyn AsyncBufRead + Send + Unpin>;
+
+/// Prepares a file of length `len` to be sent from `offset` onwards.
+///
+/// If `mmap` is set and the file is large enough, the file is memory-mapped; otherwise it is read through
+/// a buffer of `buffer_size` bytes. The file must already be positioned at `offset`, in case it is read as usual.
+#[must_use]
+pub fn source_reader(
+    file: tokio::fs::File,
+    len: u64,
+    offset: u64,
+    buffer_size: usize,
+    mmap: bool,
+) -> SourceReader {
+    if mmap && len >= MMAP_THRESHOLD {
+        match MappedFile::new(&file, len, offset) {
+            Ok(mapped) => return Box::new(mapped),
+            Err(e) => debug!("could not map the file, so reading it instead: {e}"),
+        }
+    }
+    Box::new(BufReader::with_capacity(buffer_size, file))
+}
+
+/// A read-only mapping of a whole file, read from a given position
+#[derive(Debug)]
+struct MappedFile {
+    #[cfg(unix)]
+    data: std::ptr::NonNull<std::ffi::c_void>,
+    len: usize,
+    pos: usize,
+}
+
+// SAFETY: The mapping is owned by this struct, and is only ever read.
+unsafe impl Send for MappedFile {}
+
+#[cfg(unix)]
+impl MappedFile {
+    fn new(file: &tokio::fs::File, len: u64, offset: u64) -> std::io::Result<Self> {
+        use nix::sys::mman::{madvise, mmap, MapFlags, MmapAdvise, ProtFlags};
+        let len = usize::try_from(len).map_err(std::io::Error::other)?;
+        let length = std::num::NonZeroUsize::new(len)
+            .ok_or_else(|| std::io::Error::other("cannot map an empty file"))?;
+        let pos = usize::try_from(offset).unwrap_or(len).min(len);
+        // SAFETY: We ask for a fresh, private, read-only mapping, which nothing else refers to.
+        // The file could still be changed underneath us by another process; see the module documentation.
+        let data = unsafe {
+            mmap(
+                None,
+                length,
+                ProtFlags::PROT_READ,
+                MapFlags::MAP_PRIVATE,
+                file,
+                0,
+            )
+        }?;
+        // SAFETY: This is the mapping we just made. The advice is only a hint, so failure doesn't matter.
+        let _ = unsafe { madvise(data, len, MmapAdvise::MADV_SEQUENTIAL) };
+        Ok(Self { data, len, pos })
+    }
+
+    /// The next chunk of data
+    fn chunk(&self) -> &[u8] {
+        // SAFETY: The mapping is valid for `len` bytes until we are dropped, and is never written to.
+        let all = unsafe { std::slice::from_raw_parts(self.data.as_ptr().cast::<u8>(), self.len) };
+        &all[self.pos..self.len.min(self.pos + CHUNK)]
+    }
+}
+
+#[cfg(unix)]
+impl Drop for MappedFile {
+    fn drop(&mut self) {
+        // SAFETY: This is our mapping, and no slice of it outlives us.
+        let _ = unsafe { nix::sys::mman::munmap(self.data, self.len) };
+    }
+}
+
+#[cfg(not(unix))]
+impl MappedFile {
+    fn new(_file: &tokio::fs::File, _len: u64, _offset: u64) -> std::io::Result<Self> {
+        Err(std::io::Error::new(
+            std::io::ErrorKind::Unsupported,
+            "not supported on this platform",
+        ))
+    }
+
+    fn chunk(&self) -> &[u8] {
+        &[]
+    }
+}
+
+impl AsyncRead for MappedFile {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        _cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<std::io::Result<()>> {
+        let this = self.get_mut();
+        let chunk = this.chunk();
+        let n = chunk.len().min(buf.remaining());
+        buf.put_slice(&chunk[..n]);
+        this.pos += n;
+        Poll::Ready(Ok(()))
+    }
+}
+
+impl AsyncBufRead for MappedFile {
+    fn poll_fill_buf(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<std::io::Result<&[u8]>> {
+        Poll::Ready(Ok(self.get_mut().chunk()))
+    }
+
+    fn consume(self: Pin<&mut Self>, amt: usize) {
+        let this = self.get_mut();
+        this.pos = this.len.min(this.pos + amt);
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{source_reader, MMAP_THRESHOLD};
+    use tokio::io::{AsyncReadExt as _, AsyncSeekExt as _};
+
+    #[tokio::test]
+    async fn mapped_reads() {
+        let tmp = tempfile::tempdir().unwrap();
+        let path = tmp.path().join("file");
+        let data: Vec<u8> = (0..MMAP_THRESHOLD * 2).map(|i| (i % 251) as u8).collect();
+        std::fs::write(&path, &data).unwrap();
+        let len = data.len() as u64;
+
+        for (mmap, offset) in [(false, 0), (true, 0), (true, 12345), (true, len)] {
+            let mut file = tokio::fs::File::open(&path).await.unwrap();
+            let _ = file.seek(std::io::SeekFrom::Start(offset)).await.unwrap();
+            let mut reader = source_reader(file, len, offset, 8192, mmap);
+            let mut read = Vec::new();
+            let _ = reader.read_to_end(&mut read).await.unwrap();
+            assert_eq!(
+                read,
+                &data[usize::try_from(offset).unwrap()..],
+                "{mmap} {offset}"
+            );
+        }
+    }
+}
@@ -5,23 +5,37 @@ mod address_family;
 pub use address_family::AddressFamily;
 
 mod dns;
-pub use dns::lookup_host_by_family;
+pub use dns::Resolver;
 
 mod cert;
+pub(crate) use cert::key_log;
 pub use cert::Credentials;
 
+pub mod compress;
+pub mod failure;
+pub mod follow;
+pub mod hash;
 pub mod humanu64;
 pub mod io;
+pub mod mmap;
 pub mod socket;
 pub mod stats;
 pub mod time;
 
 mod tracing;
-pub use tracing::{setup as setup_tracing, TimeFormat};
+pub use tracing::{
+    setup as setup_tracing, stderr_is_colored, ColorMode, SyslogOptions, TimeFormat,
+};
 
 mod port_range;
 pub use port_range::PortRange;
 
+mod command_set;
+pub use command_set::CommandSet;
+
+mod allowed_paths;
+pub use allowed_paths::AllowedPaths;
+
 mod optionalify;
 pub use optionalify::{derive_deftly_template_Optionalify, insert_if_some};
 
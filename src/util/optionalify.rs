@@ -9,6 +9,7 @@ use figment::value::{Dict, Value};
 /// Helper function for `figment::Provider` implementation
 ///
 /// If the given `arg` is not None, inserts it into `dict` with key `arg_name`.
+#[allow(clippy::result_large_err)]
 pub fn insert_if_some<T>(
     dict: &mut Dict,
     arg_name: &str,
@@ -121,7 +122,6 @@ pub use derive_deftly_template_Optionalify;
 
 #[cfg(test)]
 mod test {
-    use super::derive_deftly_template_Optionalify;
     use derive_deftly::Deftly;
     use figment::{providers::Serialized, Figment};
 
@@ -63,7 +63,6 @@ pub fn set_udp_buffer_sizes(
                 .next()
                 .unwrap_or("<this program>".to_string()),
         );
-        // SOMEDAY: We might offer to set sysctl, write sysctl files, etc. if run as root.
     } else {
         debug!(
             "UDP buffer sizes set to {} send, {} receive",
@@ -74,6 +73,38 @@ pub fn set_udp_buffer_sizes(
     Ok(message)
 }
 
+/// The largest value a DSCP (Differentiated Services Code Point) may take; it is a 6-bit field
+pub const MAX_DSCP: u8 = 63;
+
+/// Sets the DSCP on a UDP socket, so outgoing packets are marked for QoS.
+/// 0 leaves the socket alone.
+///
+/// Failure to set the option is not fatal; we warn and carry on.
+/// # Errors
+/// If the DSCP is out of range, or the socket's local address cannot be read
+pub fn set_dscp(socket: &UdpSocket, dscp: u8) -> anyhow::Result<()> {
+    anyhow::ensure!(
+        dscp <= MAX_DSCP,
+        "dscp {dscp} is out of range (must be 0 to {MAX_DSCP})"
+    );
+    if dscp == 0 {
+        return Ok(());
+    }
+    // The DSCP is the top six bits of the TOS / Traffic Class byte; the bottom two are ECN
+    let tos = u32::from(dscp) << 2;
+    let sock = socket2::SockRef::from(socket);
+    let result = if socket.local_addr()?.is_ipv6() {
+        sock.set_tclass_v6(tos)
+    } else {
+        sock.set_tos(tos)
+    };
+    match result {
+        Ok(()) => debug!("set DSCP {dscp} on UDP socket"),
+        Err(e) => warn!("Unable to set DSCP {dscp} on UDP socket: {e}"),
+    }
+    Ok(())
+}
+
 /// Creates and binds a UDP socket for the address family necessary to reach the given peer address
 pub fn bind_unspecified_for(peer: &SocketAddr) -> anyhow::Result<std::net::UdpSocket> {
     let addr: SocketAddr = match peer {
@@ -83,14 +114,24 @@ pub fn bind_unspecified_for(peer: &SocketAddr) -> anyhow::Result<std::net::UdpSo
     Ok(UdpSocket::bind(addr)?)
 }
 
-/// Creates and binds a UDP socket from a restricted range of local ports, using the address family necessary to reach the given peer address
+/// Creates and binds a UDP socket from a restricted range of local ports, using the address family necessary to reach the given peer address.
+///
+/// If `local` is given, the socket is bound to that address, which must be of the same family as the peer.
 pub fn bind_range_for_peer(
     peer: &SocketAddr,
     range: PortRange,
+    local: Option<IpAddr>,
 ) -> anyhow::Result<std::net::UdpSocket> {
-    let addr: IpAddr = match peer {
-        SocketAddr::V4(_) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-        SocketAddr::V6(_) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    let addr: IpAddr = match (peer, local) {
+        (_, Some(local)) => {
+            anyhow::ensure!(
+                local.is_ipv4() == peer.is_ipv4(),
+                "bind address {local} is not in the same address family as the remote address {peer}"
+            );
+            local
+        }
+        (SocketAddr::V4(_), None) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (SocketAddr::V6(_), None) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
     };
     bind_range_for_address(addr, range)
 }
@@ -112,18 +153,53 @@ pub fn bind_range_for_address(
     anyhow::bail!("failed to bind a port in the given range");
 }
 
-/// Creates and binds a UDP socket from a restricted range of local ports, for the unspecified address of the given address family
+/// Creates and binds a UDP socket from a restricted range of local ports, for the unspecified address of the given address family.
+///
+/// If `local` is given, the socket is bound to that address, which must be of the given family.
 pub fn bind_range_for_family(
     family: ConnectionType,
     range: PortRange,
+    local: Option<IpAddr>,
 ) -> anyhow::Result<std::net::UdpSocket> {
-    let addr = match family {
-        ConnectionType::Ipv4 => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
-        ConnectionType::Ipv6 => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
+    let addr = match (family, local) {
+        (_, Some(local)) => {
+            anyhow::ensure!(
+                local.is_ipv4() == (family == ConnectionType::Ipv4),
+                "bind address {local} is not in the address family the client requested ({family:?})"
+            );
+            local
+        }
+        (ConnectionType::Ipv4, None) => IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        (ConnectionType::Ipv6, None) => IpAddr::V6(Ipv6Addr::UNSPECIFIED),
     };
     bind_range_for_address(addr, range)
 }
 
+/// Restricts a socket to sending and receiving via the named network interface (`SO_BINDTODEVICE`).
+/// An empty name leaves the socket alone.
+///
+/// This is only supported on Linux.
+/// # Errors
+/// If the interface does not exist, or the operating system does not allow it
+pub fn bind_to_interface(socket: &UdpSocket, interface: &str) -> anyhow::Result<()> {
+    if interface.is_empty() {
+        return Ok(());
+    }
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    {
+        socket2::SockRef::from(socket)
+            .bind_device(Some(interface.as_bytes()))
+            .map_err(|e| anyhow::anyhow!("binding UDP socket to interface {interface}: {e}"))?;
+        debug!("bound UDP socket to interface {interface}");
+        Ok(())
+    }
+    #[cfg(not(any(target_os = "linux", target_os = "android")))]
+    {
+        let _ = socket;
+        anyhow::bail!("bind_interface is only supported on Linux");
+    }
+}
+
 #[cfg(test)]
 mod test {
     use crate::util::tracing::setup_tracing_for_tests;
@@ -139,4 +215,31 @@ mod test {
         let _ = super::set_udp_buffer_sizes(&mut sock, Some(1_048_576), Some(10_485_760))?;
         Ok(())
     }
+
+    #[test]
+    fn dscp() -> anyhow::Result<()> {
+        let sock = UdpSocket::bind("127.0.0.1:0")?;
+        super::set_dscp(&sock, 10)?;
+        assert_eq!(socket2::SockRef::from(&sock).tos()?, 40);
+        super::set_dscp(&sock, 0)?;
+        assert_eq!(socket2::SockRef::from(&sock).tos()?, 40);
+        assert!(super::set_dscp(&sock, 64).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn bind_address_family() {
+        use super::{bind_range_for_family, bind_range_for_peer};
+        use crate::{protocol::control::ConnectionType, util::PortRange};
+        let localhost = "127.0.0.1".parse().unwrap();
+        let v4 = Some(localhost);
+        let v6 = Some("::1".parse().unwrap());
+        let peer = "192.0.2.1:1234".parse().unwrap();
+        let sock = bind_range_for_peer(&peer, PortRange::default(), v4).unwrap();
+        assert_eq!(sock.local_addr().unwrap().ip(), localhost);
+        assert!(bind_range_for_peer(&peer, PortRange::default(), v6).is_err());
+        assert!(bind_range_for_family(ConnectionType::Ipv6, PortRange::default(), v4).is_err());
+        let sock = bind_range_for_family(ConnectionType::Ipv4, PortRange::default(), None).unwrap();
+        assert!(sock.local_addr().unwrap().ip().is_unspecified());
+    }
 }
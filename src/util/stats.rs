@@ -2,7 +2,7 @@
 // (c) 2024 Ross Younger
 
 use human_repr::{HumanCount, HumanDuration, HumanThroughput};
-use num_format::ToFormattedString as _;
+use num_format::{Locale, ToFormattedString as _};
 use quinn::ConnectionStats;
 use std::{cmp, fmt::Display, time::Duration};
 use tracing::{info, warn};
@@ -58,8 +58,8 @@ pub fn process_statistics(
     remote_stats: ClosedownReport,
     bandwidth: &Configuration,
     show_statistics: bool,
+    locale: &Locale,
 ) {
-    let locale = &num_format::Locale::en;
     if payload_bytes != 0 {
         let size = payload_bytes.human_count_bytes();
         let rate = crate::util::stats::DataRate::new(payload_bytes, transport_time);
@@ -102,7 +102,11 @@ pub fn process_statistics(
         );
     }
 
-    let sender_sent_bytes = cmp::max(stats.udp_tx.bytes, remote_stats.sent_bytes);
+    let mut sender_sent_bytes = cmp::max(stats.udp_tx.bytes, remote_stats.sent_bytes);
+    if sender_sent_bytes < payload_bytes {
+        // Data flowed in both directions, so both ends were senders
+        sender_sent_bytes = stats.udp_tx.bytes + remote_stats.sent_bytes;
+    }
     if show_statistics {
         let cwnd = cmp::max(stats.path.cwnd, remote_stats.cwnd);
         info!(
@@ -120,8 +124,8 @@ pub fn process_statistics(
         );
         if payload_bytes != 0 {
             #[allow(clippy::cast_precision_loss)]
-            let overhead_pct =
-                100. * (sender_sent_bytes - payload_bytes) as f64 / payload_bytes as f64;
+            let overhead_pct = 100. * sender_sent_bytes.saturating_sub(payload_bytes) as f64
+                / payload_bytes as f64;
             info!(
                 "{} total bytes sent for {} bytes payload  ({:.2}% overhead/loss)",
                 sender_sent_bytes.to_formatted_string(locale),
@@ -144,6 +148,15 @@ pub fn process_statistics(
             rtt_param = stats.path.rtt.as_millis()+1, // round up
         );
     }
+
+    // Path MTU discovery may not reach the requested size on a short transfer, so only warn if it is well short.
+    let requested = bandwidth.udp_payload_size;
+    if requested != 0 && stats.path.current_mtu < requested / 10 * 9 {
+        warn!(
+            "Measured path MTU {} was much lower than the requested UDP payload size {requested}; the network path may not support it",
+            stats.path.current_mtu
+        );
+    }
 }
 
 #[cfg(test)]
@@ -4,14 +4,17 @@
 use std::{
     fs::File,
     io::Write,
+    str::FromStr as _,
     sync::{Arc, Mutex},
 };
 
-use anstream::eprintln;
+use anstream::{eprintln, ColorChoice};
 use anyhow::Context;
 use indicatif::MultiProgress;
 use serde::{de, Deserialize, Serialize};
 use strum::VariantNames as _;
+use syslog::{Formatter3164, Logger, LoggerBackend};
+use tracing::{Level, Metadata};
 use tracing_subscriber::{
     fmt::{
         time::{ChronoLocal, ChronoUtc},
@@ -23,6 +26,10 @@ use tracing_subscriber::{
 
 const FRIENDLY_FORMAT_LOCAL: &str = "%Y-%m-%d %H:%M:%SL";
 const FRIENDLY_FORMAT_UTC: &str = "%Y-%m-%d %H:%M:%SZ";
+const ISO_WEEK_FORMAT_LOCAL: &str = "%G-W%V-%u %H:%M:%SL";
+const UNIX_FORMAT: &str = "%s";
+/// Seconds since the epoch, followed by exactly three digits of the fractional second (no decimal point)
+const UNIX_MILLIS_FORMAT: &str = "%s%3f";
 
 /// Environment variable that controls what gets logged to stderr
 const STANDARD_ENV_VAR: &str = "RUST_LOG";
@@ -57,6 +64,14 @@ pub enum TimeFormat {
     /// `1997-11-12T09:55:06-06:00`
     /// `2010-03-14T18:32:03Z`
     Rfc3339,
+    /// Seconds since the Unix epoch, e.g. `1733097306`
+    Unix,
+    /// Milliseconds since the Unix epoch, e.g. `1733097306123`
+    #[strum(serialize = "unix-millis", serialize = "unixmillis")]
+    UnixMillis,
+    /// Local time with an ISO 8601 week date, as "year-Wweek-weekday HH:MM:SS", e.g. `2024-W49-1 23:55:06`
+    #[strum(serialize = "iso-week", serialize = "isoweek")]
+    IsoWeek,
 }
 
 impl<'de> Deserialize<'de> for TimeFormat {
@@ -72,6 +87,63 @@ impl<'de> Deserialize<'de> for TimeFormat {
     }
 }
 
+/// Selects whether to colour console output
+#[derive(
+    Copy,
+    Clone,
+    Debug,
+    Default,
+    Eq,
+    PartialEq,
+    strum::Display,
+    strum::EnumString,
+    strum::VariantNames,
+    clap::ValueEnum,
+    Serialize,
+)]
+#[strum(serialize_all = "lowercase")]
+#[serde(rename_all = "kebab-case")]
+pub enum ColorMode {
+    /// Colour output if it is going to a terminal, unless the `NO_COLOR` environment variable is set
+    #[default]
+    Auto,
+    /// Always colour output
+    Always,
+    /// Never colour output
+    Never,
+}
+
+impl<'de> Deserialize<'de> for ColorMode {
+    fn deserialize<D>(deserializer: D) -> std::result::Result<Self, D::Error>
+    where
+        D: serde::Deserializer<'de>,
+    {
+        let s = String::deserialize(deserializer)?;
+        let lower = s.to_ascii_lowercase();
+        // requires strum::EnumString && strum::VariantNames && #[strum(serialize_all = "lowercase")]
+        std::str::FromStr::from_str(&lower)
+            .map_err(|_| de::Error::unknown_variant(&s, ColorMode::VARIANTS))
+    }
+}
+
+impl ColorMode {
+    /// Applies this choice to all subsequent output via `anstream`, including log messages
+    pub fn apply(self) {
+        match self {
+            Self::Auto => ColorChoice::Auto,
+            Self::Always => ColorChoice::Always,
+            Self::Never => ColorChoice::Never,
+        }
+        .write_global();
+    }
+}
+
+/// Determines whether output to stderr is to be coloured, taking account of [`ColorMode::apply`]
+#[must_use]
+pub fn stderr_is_colored() -> bool {
+    anstream::AutoStream::choice(&std::io::stderr()) != ColorChoice::Never
+}
+
 /// Result type for `filter_for()`
 struct FilterResult {
     filter: EnvFilter,
@@ -136,10 +208,34 @@ where
             .with_writer(writer)
             .with_filter(filter)
             .boxed(),
+        TimeFormat::Unix => layer
+            .with_timer(ChronoUtc::new(UNIX_FORMAT.into()))
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+        TimeFormat::UnixMillis => layer
+            .with_timer(ChronoUtc::new(UNIX_MILLIS_FORMAT.into()))
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
+        TimeFormat::IsoWeek => layer
+            .with_timer(ChronoLocal::new(ISO_WEEK_FORMAT_LOCAL.into()))
+            .with_writer(writer)
+            .with_filter(filter)
+            .boxed(),
     }
 }
 
-/// Set up rust tracing, to console (via an optional `MultiProgress`) and optionally to file.
+/// Options for logging to the system log
+#[derive(Debug, Clone, Copy)]
+pub struct SyslogOptions<'a> {
+    /// The syslog facility name, e.g. `user` or `daemon`
+    pub facility: &'a str,
+    /// The program name to log as
+    pub ident: &'a str,
+}
+
+/// Set up rust tracing, to console (via an optional `MultiProgress`) and optionally to file and/or syslog.
 ///
 /// By default we log only our events (qcp), at a given trace level.
 /// This can be overridden by setting `RUST_LOG`.
@@ -152,6 +248,7 @@ pub fn setup(
     display: Option<&MultiProgress>,
     filename: &Option<String>,
     time_format: TimeFormat,
+    syslog: Option<SyslogOptions<'_>>,
 ) -> anyhow::Result<()> {
     let mut layers = Vec::new();
 
@@ -159,6 +256,7 @@ pub fn setup(
 
     let filter = filter_for(trace_level, STANDARD_ENV_VAR)?;
     // If we used the environment variable, show log targets; if we did not, we're only logging qcp, so do not show targets.
+    let ansi = stderr_is_colored();
 
     match display {
         None => {
@@ -167,7 +265,7 @@ pub fn setup(
                 filter.filter,
                 time_format,
                 filter.used_env,
-                true,
+                ansi,
             ));
         }
         Some(mp) => {
@@ -176,10 +274,10 @@ pub fn setup(
                 filter.filter,
                 time_format,
                 filter.used_env,
-                true,
+                ansi,
             ));
         }
-    };
+    }
 
     //////// File output
 
@@ -203,6 +301,24 @@ pub fn setup(
         ));
     }
 
+    //////// System log output
+
+    if let Some(options) = syslog {
+        let filter = filter_for(trace_level, STANDARD_ENV_VAR)?;
+        // syslog applies its own timestamps and severities
+        layers.push(
+            tracing_subscriber::fmt::layer()
+                .compact()
+                .without_time()
+                .with_level(false)
+                .with_target(filter.used_env)
+                .with_ansi(false)
+                .with_writer(SyslogWriter::connect(options)?)
+                .with_filter(filter.filter)
+                .boxed(),
+        );
+    }
+
     ////////
 
     tracing_subscriber::registry().with(layers).init();
@@ -236,6 +352,71 @@ impl Write for ProgressWriter {
     }
 }
 
+/// A [`MakeWriter`] which sends each event to the system log, at the corresponding severity
+struct SyslogWriter(Arc<Mutex<Logger<LoggerBackend, Formatter3164>>>);
+
+impl SyslogWriter {
+    fn connect(options: SyslogOptions<'_>) -> anyhow::Result<Self> {
+        let facility = syslog::Facility::from_str(options.facility)
+            .map_err(|()| anyhow::anyhow!("unknown syslog facility `{}`", options.facility))?;
+        let formatter = Formatter3164 {
+            facility,
+            hostname: None,
+            process: options.ident.to_string(),
+            pid: std::process::id(),
+        };
+        let logger = syslog::unix(formatter).context("Failed to connect to the system log")?;
+        Ok(Self(Arc::new(Mutex::new(logger))))
+    }
+}
+
+/// The writer for a single event
+struct SyslogEvent<'a> {
+    logger: &'a Mutex<Logger<LoggerBackend, Formatter3164>>,
+    level: Level,
+}
+
+impl Write for SyslogEvent<'_> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let msg = String::from_utf8_lossy(buf);
+        let msg = msg.trim_end();
+        let mut logger = self
+            .logger
+            .lock()
+            .map_err(|_| std::io::Error::other("syslog mutex poisoned"))?;
+        match self.level {
+            Level::ERROR => logger.err(msg),
+            Level::WARN => logger.warning(msg),
+            Level::INFO => logger.info(msg),
+            Level::DEBUG | Level::TRACE => logger.debug(msg),
+        }
+        .map_err(std::io::Error::other)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for SyslogWriter {
+    type Writer = SyslogEvent<'a>;
+
+    fn make_writer(&'a self) -> Self::Writer {
+        SyslogEvent {
+            logger: &self.0,
+            level: Level::INFO,
+        }
+    }
+
+    fn make_writer_for(&'a self, meta: &Metadata<'_>) -> Self::Writer {
+        SyslogEvent {
+            logger: &self.0,
+            level: *meta.level(),
+        }
+    }
+}
+
 #[cfg(test)]
 pub(crate) fn setup_tracing_for_tests() {
     tracing_subscriber::fmt()
@@ -243,3 +424,37 @@ pub(crate) fn setup_tracing_for_tests() {
         .with_max_level(tracing::Level::DEBUG)
         .init();
 }
+
+#[cfg(test)]
+mod test {
+    use clap::ValueEnum as _;
+
+    use super::{SyslogOptions, SyslogWriter, TimeFormat};
+
+    #[test]
+    fn unknown_facility() {
+        let err = SyslogWriter::connect(SyslogOptions {
+            facility: "nonesuch",
+            ident: "qcp",
+        })
+        .err()
+        .unwrap();
+        assert!(err.to_string().contains("nonesuch"));
+    }
+
+    #[test]
+    fn time_format_names() {
+        for format in TimeFormat::value_variants() {
+            let name = format.to_possible_value().unwrap().get_name().to_string();
+            assert_eq!(format.to_string(), name);
+            assert_eq!(
+                serde_json::to_string(format).unwrap(),
+                format!("\"{name}\"")
+            );
+            let parsed: TimeFormat = serde_json::from_str(&format!("\"{name}\"")).unwrap();
+            assert_eq!(parsed, *format);
+        }
+        let parsed: TimeFormat = serde_json::from_str("\"UnixMillis\"").unwrap();
+        assert_eq!(parsed, TimeFormat::UnixMillis);
+    }
+}